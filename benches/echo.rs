@@ -0,0 +1,77 @@
+#[macro_use]
+extern crate criterion;
+extern crate parity_ws as ws;
+
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{Criterion, Throughput};
+
+use ws::{CloseCode, Handler, Handshake, Message, Result, Sender, WebSocket};
+
+const SIZES: &[usize] = &[16, 1024, 64 * 1024];
+
+// Sends `payload` and waits for it to come back `iterations` times, then reports how long the
+// round trips took, excluding the handshake.
+struct Client {
+    out: Sender,
+    payload: Vec<u8>,
+    remaining: u64,
+    started: Instant,
+    elapsed: ChannelSender<Duration>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.started = Instant::now();
+        self.out.send(self.payload.clone())
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.elapsed.send(self.started.elapsed()).unwrap();
+            self.out.close(CloseCode::Normal)
+        } else {
+            self.out.send(self.payload.clone())
+        }
+    }
+}
+
+fn echo(c: &mut Criterion) {
+    let server = WebSocket::new(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let shutdown = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut group = c.benchmark_group("echo");
+    for &size in SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("{}", size), |b| {
+            b.iter_custom(|iterations| {
+                let (tx, rx) = channel();
+                ws::connect(url.clone(), |out| Client {
+                    out,
+                    payload: vec![0x5a; size],
+                    remaining: iterations,
+                    started: Instant::now(),
+                    elapsed: tx.clone(),
+                }).unwrap();
+                rx.recv().unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    shutdown.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+criterion_group!(benches, echo);
+criterion_main!(benches);
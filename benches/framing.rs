@@ -0,0 +1,137 @@
+#[macro_use]
+extern crate criterion;
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+
+use criterion::{black_box, BatchSize, Criterion, Throughput};
+
+use ws::proto::{check_header, decode_header};
+use ws::{CircularBuffer, Frame, OpCode};
+
+const SIZES: &[usize] = &[16, 1024, 64 * 1024];
+
+fn formatted(size: usize, masked: bool) -> Vec<u8> {
+    let mut frame = Frame::message(vec![0x5a; size], OpCode::Binary, true);
+    if masked {
+        frame.set_mask();
+    }
+    let mut buf = Vec::with_capacity(size + 14);
+    frame.format(&mut buf).unwrap();
+    buf
+}
+
+fn frame_parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_parse");
+    for &size in SIZES {
+        let bytes = formatted(size, true);
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function(format!("{}", size), |b| {
+            b.iter_batched(
+                || {
+                    let mut buf = CircularBuffer::new(bytes.len(), bytes.len());
+                    buf.write_all(&bytes).unwrap();
+                    buf
+                },
+                |mut buf| Frame::parse(&mut buf, u64::max_value()).unwrap().unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// Decodes and checks the headers of a stream of tiny frames of every kind, where the cost of the
+// header dominates that of the payload.
+fn header_decode(c: &mut Criterion) {
+    let frames = [
+        Frame::message(vec![0x5a; 4], OpCode::Text, true),
+        Frame::message(vec![0x5a; 4], OpCode::Binary, false),
+        Frame::message(vec![0x5a; 4], OpCode::Continue, true),
+        Frame::ping(vec![0x5a; 4]),
+        Frame::pong(vec![0x5a; 4]),
+    ];
+    let mut bytes = Vec::new();
+    for frame in frames.iter() {
+        frame.clone().set_mask().format(&mut bytes).unwrap();
+    }
+    let mut group = c.benchmark_group("header_decode");
+    group.throughput(Throughput::Elements(frames.len() as u64));
+    group.bench_function("tiny", |b| {
+        b.iter(|| {
+            let mut pos = 0;
+            while pos < bytes.len() {
+                let (header, len) = decode_header(black_box(&bytes[pos..]), u64::max_value())
+                    .unwrap()
+                    .unwrap();
+                check_header(&header, 0).unwrap();
+                pos += len + header.payload_len as usize;
+            }
+        })
+    });
+    group.finish();
+}
+
+fn frame_mask(c: &mut Criterion) {
+    let mut group = c.benchmark_group("frame_mask");
+    for &size in SIZES {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("mask/{}", size), |b| {
+            let mut out = Vec::with_capacity(size + 14);
+            b.iter_batched(
+                || Frame::message(vec![0x5a; size], OpCode::Binary, true),
+                |mut frame| {
+                    out.clear();
+                    frame.set_mask().format(&mut out).unwrap();
+                    black_box(&out);
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function(format!("unmask/{}", size), |b| {
+            let bytes = formatted(size, true);
+            b.iter_batched(
+                || {
+                    let mut buf = CircularBuffer::new(bytes.len(), bytes.len());
+                    buf.write_all(&bytes).unwrap();
+                    Frame::parse(&mut buf, u64::max_value()).unwrap().unwrap()
+                },
+                |mut frame| {
+                    frame.remove_mask();
+                    frame
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// Writes and reads through a buffer whose read position never lines up with the end of the
+// storage, so that every other operation has to wrap around.
+fn circular_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("circular_buffer");
+    for &size in SIZES {
+        let input = vec![0x5a; size];
+        let mut output = vec![0; size];
+        let mut buf = CircularBuffer::new(size + size / 2, size + size / 2);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("wraparound/{}", size), |b| {
+            b.iter(|| {
+                buf.write_all(black_box(&input)).unwrap();
+                buf.read_exact(&mut output).unwrap();
+                black_box(&output);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    frame_parse,
+    header_decode,
+    frame_mask,
+    circular_buffer
+);
+criterion_main!(benches);
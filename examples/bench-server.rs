@@ -1,16 +1,125 @@
-/// WebSocket server used for testing the bench example.
+extern crate clap;
+extern crate env_logger;
+#[cfg(feature = "ssl")]
+extern crate openssl;
+/// WebSocket echo server used for testing the bench example.
+///
+/// cargo run --release --example bench-server -- 127.0.0.1:3012 --max-connections 10000
+///
+/// When built with the ssl feature, passing `--cert` and `--key` serves over TLS.
 extern crate parity_ws as ws;
 
-use ws::{Builder, Sender, Settings};
+#[cfg(feature = "ssl")]
+use std::fs::File;
+#[cfg(feature = "ssl")]
+use std::io::Read;
+#[cfg(feature = "ssl")]
+use std::rc::Rc;
+
+use clap::{App, Arg};
+#[cfg(feature = "ssl")]
+use openssl::pkey::PKey;
+#[cfg(feature = "ssl")]
+use openssl::ssl::{SslAcceptor, SslMethod, SslStream};
+#[cfg(feature = "ssl")]
+use openssl::x509::X509;
+#[cfg(feature = "ssl")]
+use ws::util::TcpStream;
+use ws::{Builder, Handler, Message, Result, Sender, Settings};
+
+struct Echo {
+    out: Sender,
+    #[cfg(feature = "ssl")]
+    ssl: Option<Rc<SslAcceptor>>,
+}
+
+impl Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+
+    #[cfg(feature = "ssl")]
+    fn upgrade_ssl_server(&mut self, sock: TcpStream) -> Result<SslStream<TcpStream>> {
+        self.ssl
+            .as_ref()
+            .expect("TLS connection without a certificate")
+            .accept(sock)
+            .map_err(From::from)
+    }
+}
+
+#[cfg(feature = "ssl")]
+fn read_file(name: &str) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(name)?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
 
 fn main() {
+    env_logger::init();
+
+    let app = App::new("WS-RS Bench Server")
+        .about("Echo every message back to the client that sent it.")
+        .arg(
+            Arg::with_name("ADDR")
+                .help("Address on which to bind the server.")
+                .default_value("127.0.0.1:3012")
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .long("max-connections")
+                .help("Maximum number of simultaneous connections.")
+                .default_value("10000"),
+        );
+    #[cfg(feature = "ssl")]
+    let app = app
+        .arg(
+            Arg::with_name("cert")
+                .long("cert")
+                .takes_value(true)
+                .requires("key")
+                .help("Path to the SSL certificate PEM."),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .takes_value(true)
+                .requires("cert")
+                .help("Path to the SSL certificate key PEM."),
+        );
+    let matches = app.get_matches();
+
     let mut settings = Settings::default();
-    settings.max_connections = 10_000;
+    settings.max_connections = matches
+        .value_of("max-connections")
+        .unwrap()
+        .parse()
+        .expect("--max-connections must be a positive integer");
+
+    #[cfg(feature = "ssl")]
+    let ssl = match (matches.value_of("cert"), matches.value_of("key")) {
+        (Some(cert), Some(key)) => {
+            let cert = X509::from_pem(&read_file(cert).unwrap()).unwrap();
+            let pkey = PKey::private_key_from_pem(&read_file(key).unwrap()).unwrap();
+            let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+            builder.set_private_key(&pkey).unwrap();
+            builder.set_certificate(&cert).unwrap();
+            settings.encrypt_server = true;
+            Some(Rc::new(builder.build()))
+        }
+        _ => None,
+    };
 
     Builder::new()
         .with_settings(settings)
-        .build(|out: Sender| move |msg| out.send(msg))
+        .build(|out: Sender| Echo {
+            out,
+            #[cfg(feature = "ssl")]
+            ssl: ssl.clone(),
+        })
         .unwrap()
-        .listen("127.0.0.1:3012")
+        .listen(matches.value_of("ADDR").unwrap())
         .unwrap();
 }
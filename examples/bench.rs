@@ -1,79 +1,269 @@
+extern crate clap;
 extern crate env_logger;
-extern crate time;
 extern crate url;
-/// A simple, but immature, benchmark client for destroying other WebSocket frameworks and proving
-/// WS-RS's performance excellence. ;)
+/// A load generator for measuring the latency and throughput of a WebSocket echo server, such as
+/// the `bench-server` example.
+///
+/// Every connection sends messages of the given size and waits for them to be echoed back. With
+/// `--rate 0` (the default) a connection sends its next message as soon as the previous one
+/// returns, otherwise it sends at the given rate regardless of how many messages are in flight.
+///
+/// cargo run --release --example bench-server
+/// cargo run --release --example bench -- --connections 1000 --size 1024 --duration 30
+///
 /// Make sure you allow for enough connections in your OS (e.g. ulimit -Sn 10000).
 extern crate parity_ws as ws;
 
-// Try this against node for some fun
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-// TODO: Separate this example into a separate lib
-// TODO: num threads, num connections per thread, num concurrent connections per thread, num
-// messages per connection, length of message, text or binary
+use clap::{App, Arg};
+use ws::util::Token;
+use ws::{Builder, CloseCode, Error, Handler, Handshake, Message, Result, Sender, Settings};
 
-use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+const SEND: Token = Token(1);
 
-const CONNECTIONS: usize = 10_000; // simultaneous
-const MESSAGES: u32 = 10;
-static MESSAGE: &'static str = "TEST TEST TEST TEST TEST TEST TEST TEST";
+#[derive(Default)]
+struct Stats {
+    connected: usize,
+    errors: usize,
+    sent: u64,
+    received: u64,
+    bytes: u64,
+    latencies: Vec<u64>,
+}
 
-fn main() {
-    env_logger::init();
+struct Connection {
+    out: Sender,
+    payload: Vec<u8>,
+    period: Option<Duration>,
+    next_send: Instant,
+    deadline: Instant,
+    in_flight: VecDeque<Instant>,
+    stats: Rc<RefCell<Stats>>,
+}
 
-    let url = url::Url::parse("ws://127.0.0.1:3012").unwrap();
+impl Connection {
+    fn send(&mut self) -> Result<()> {
+        self.in_flight.push_back(Instant::now());
+        self.stats.borrow_mut().sent += 1;
+        self.out.send(self.payload.clone())
+    }
 
-    struct Connection {
-        out: Sender,
-        count: u32,
-        time: u64,
-        total: u64,
+    fn finished(&self) -> bool {
+        Instant::now() >= self.deadline
     }
 
-    impl Handler for Connection {
-        fn on_open(&mut self, _: Handshake) -> Result<()> {
-            self.out.send(MESSAGE)?;
-            self.count += 1;
-            self.time = time::precise_time_ns();
-            Ok(())
+    // Timers only fire every tick of the event loop, so send every message that has come due
+    // since the last one to keep up the requested rate.
+    fn send_due(&mut self, period: Duration) -> Result<()> {
+        let now = Instant::now();
+        while self.next_send <= now {
+            self.next_send += period;
+            self.send()?;
         }
+        let wait = self.next_send - now;
+        self.out.timeout(
+            wait.as_secs() * 1000 + u64::from(wait.subsec_millis()) + 1,
+            SEND,
+        )
+    }
+}
 
-        fn on_message(&mut self, msg: Message) -> Result<()> {
-            assert_eq!(msg.as_text().unwrap(), MESSAGE);
-            if self.count > MESSAGES {
-                self.out.close(CloseCode::Normal)?;
-            } else {
-                self.out.send(MESSAGE)?;
-                let time = time::precise_time_ns();
-                // println!("time {}", time -self.time);
-                self.total += time - self.time;
-                self.count += 1;
-                self.time = time;
+impl Handler for Connection {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.stats.borrow_mut().connected += 1;
+        self.next_send = Instant::now();
+        match self.period {
+            Some(period) => self.send_due(period),
+            None => self.send(),
+        }
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if let Some(sent) = self.in_flight.pop_front() {
+            let latency = sent.elapsed();
+            let mut stats = self.stats.borrow_mut();
+            stats.received += 1;
+            stats.bytes += msg.len() as u64;
+            stats
+                .latencies
+                .push(latency.as_secs() * 1_000_000 + u64::from(latency.subsec_micros()));
+        }
+
+        if self.finished() {
+            if self.in_flight.is_empty() {
+                return self.out.close(CloseCode::Normal);
+            }
+        } else if self.period.is_none() {
+            return self.send();
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        if event != SEND {
+            return Ok(());
+        }
+        if self.finished() {
+            if self.in_flight.is_empty() {
+                return self.out.close(CloseCode::Normal);
             }
-            Ok(())
+            return Ok(());
+        }
+        match self.period {
+            Some(period) => self.send_due(period),
+            None => Ok(()),
         }
     }
 
+    fn on_error(&mut self, err: Error) {
+        println!("Connection error: {}", err);
+        self.stats.borrow_mut().errors += 1;
+    }
+}
+
+fn percentile(sorted: &[u64], percent: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = (sorted.len() - 1) * percent / 100;
+    sorted[index] as f64 / 1000.0
+}
+
+fn report(stats: &mut Stats, elapsed: Duration) {
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    stats.latencies.sort();
+    let mean = if stats.latencies.is_empty() {
+        0.0
+    } else {
+        stats.latencies.iter().sum::<u64>() as f64 / stats.latencies.len() as f64 / 1000.0
+    };
+
+    println!("Connections: {} opened, {} errors", stats.connected, stats.errors);
+    println!(
+        "Messages:    {} sent, {} received in {:.2}s",
+        stats.sent, stats.received, secs
+    );
+    println!(
+        "Throughput:  {:.0} msg/s, {:.2} MiB/s",
+        stats.received as f64 / secs,
+        stats.bytes as f64 / secs / (1024.0 * 1024.0)
+    );
+    println!(
+        "Latency:     min {:.3}ms, mean {:.3}ms, p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+        percentile(&stats.latencies, 0),
+        mean,
+        percentile(&stats.latencies, 50),
+        percentile(&stats.latencies, 90),
+        percentile(&stats.latencies, 99),
+        percentile(&stats.latencies, 100),
+    );
+}
+
+fn main() {
+    env_logger::init();
+
+    let matches = App::new("WS-RS Load Generator")
+        .about("Measure the latency and throughput of a WebSocket echo server.")
+        .arg(
+            Arg::with_name("URL")
+                .help("The URL of the echo server.")
+                .default_value("ws://127.0.0.1:3012")
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("connections")
+                .long("connections")
+                .short("c")
+                .help("Number of simultaneous connections.")
+                .default_value("100"),
+        )
+        .arg(
+            Arg::with_name("size")
+                .long("size")
+                .short("s")
+                .help("Size of each message in bytes.")
+                .default_value("64"),
+        )
+        .arg(
+            Arg::with_name("rate")
+                .long("rate")
+                .short("r")
+                .help(
+                    "Messages per second sent by each connection. \
+                     0 sends the next message as soon as the previous one is echoed.",
+                )
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .long("duration")
+                .short("d")
+                .help("How long to send messages for, in seconds.")
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("tls")
+                .long("tls")
+                .help("Connect with TLS (wss://). Requires the ssl or nativetls feature."),
+        )
+        .get_matches();
+
+    let number = |name: &str| -> u64 {
+        matches
+            .value_of(name)
+            .unwrap()
+            .parse()
+            .unwrap_or_else(|_| panic!("--{} must be a non-negative integer", name))
+    };
+    let connections = number("connections") as usize;
+    let size = number("size") as usize;
+    let rate = number("rate");
+    let duration = Duration::from_secs(number("duration"));
+
+    let mut url = url::Url::parse(matches.value_of("URL").unwrap()).unwrap();
+    if matches.is_present("tls") {
+        url.set_scheme("wss").unwrap();
+    }
+    let period = match rate {
+        0 => None,
+        rate => Some(Duration::from_nanos(1_000_000_000 / rate)),
+    };
+
     let mut settings = Settings::default();
-    settings.max_connections = CONNECTIONS;
+    settings.max_connections = connections;
+    settings.queue_size = ::std::cmp::max(settings.queue_size, rate as usize * 2);
+
+    let stats = Rc::new(RefCell::new(Stats::default()));
+    let deadline = Instant::now() + duration;
 
     let mut ws = Builder::new()
         .with_settings(settings)
         .build(|out| Connection {
             out,
-            count: 0,
-            time: 0,
-            total: 0,
+            payload: vec![b'x'; size],
+            period,
+            next_send: Instant::now(),
+            deadline,
+            in_flight: VecDeque::new(),
+            stats: stats.clone(),
         })
         .unwrap();
 
-    for _ in 0..CONNECTIONS {
+    println!(
+        "Running {} connections against {} for {}s...",
+        connections,
+        url,
+        duration.as_secs()
+    );
+    for _ in 0..connections {
         ws.connect(url.clone()).unwrap();
     }
-    let start = time::precise_time_ns();
+    let start = Instant::now();
     ws.run().unwrap();
-    println!(
-        "Total time. {}",
-        (time::precise_time_ns() - start) / 1_000_000
-    )
+
+    report(&mut stats.borrow_mut(), start.elapsed());
 }
@@ -0,0 +1,295 @@
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use handshake::Response;
+use protocol::CloseCode;
+use snapshot::json_string;
+
+/// How a connection attempt ended. See `AuditRecord`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AuditOutcome {
+    /// The handshake completed, and the connection was open until it closed.
+    Closed,
+    /// The handshake was answered with a response other than 101 Switching Protocols, such as
+    /// a refusal by an `Authorizer`.
+    Rejected,
+    /// The connection was dropped before the handshake completed, such as by an IO error.
+    Failed,
+}
+
+impl AuditOutcome {
+    /// The name of the outcome in snake case, as it appears in the JSON of a record.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            AuditOutcome::Closed => "closed",
+            AuditOutcome::Rejected => "rejected",
+            AuditOutcome::Failed => "failed",
+        }
+    }
+}
+
+/// What happened to one connection attempt, from the moment its socket was accepted or
+/// connected until it was dropped. See `AuditSink`.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    /// When the attempt started.
+    pub timestamp: SystemTime,
+    /// How long the attempt lasted, including the handshake.
+    pub duration: Duration,
+    /// The connection_id identifying the connection within the WebSocket.
+    pub connection_id: u32,
+    /// Whether this is the client end of the connection.
+    pub client: bool,
+    /// The address of the other endpoint.
+    pub peer_addr: Option<SocketAddr>,
+    /// The local address of the connection.
+    pub local_addr: Option<SocketAddr>,
+    /// The resource that was requested in the handshake, if the connection opened.
+    pub resource: Option<String>,
+    /// How the attempt ended.
+    pub outcome: AuditOutcome,
+    /// The status of the handshake response, if one was sent or received.
+    pub status: Option<u16>,
+    /// The close code that the other endpoint sent, or `CloseCode::Abnormal` if an open
+    /// connection was dropped without one.
+    pub close_code: Option<CloseCode>,
+    /// The first error of the connection, if any.
+    pub error: Option<String>,
+    /// The number of bytes read from the socket, including the handshake.
+    pub bytes_received: u64,
+    /// The number of bytes written to the socket, including the handshake.
+    pub bytes_sent: u64,
+    /// The subprotocol that the handshake negotiated.
+    pub protocol: Option<String>,
+    /// The extensions that the handshake negotiated, as in the `Sec-WebSocket-Extensions`
+    /// header.
+    pub extensions: Option<String>,
+}
+
+impl AuditRecord {
+    /// Render the record as a single line of JSON without a trailing newline, with the timestamp
+    /// in RFC 3339 format in UTC and the duration in milliseconds. Missing values are `null`.
+    pub fn to_json(&self) -> String {
+        let string = |value: &Option<String>| {
+            value
+                .as_ref()
+                .map(|value| json_string(value))
+                .unwrap_or_else(|| "null".into())
+        };
+        let addr = |addr: Option<SocketAddr>| string(&addr.map(|addr| addr.to_string()));
+        let number = |value: Option<u16>| {
+            value
+                .map(|value| value.to_string())
+                .unwrap_or_else(|| "null".into())
+        };
+        format!(
+            "{{\"timestamp\":\"{}\",\"duration_ms\":{},\"connection_id\":{},\"client\":{},\
+             \"peer\":{},\"local\":{},\"resource\":{},\"outcome\":\"{}\",\"status\":{},\
+             \"close_code\":{},\"error\":{},\"bytes_received\":{},\"bytes_sent\":{},\
+             \"protocol\":{},\"extensions\":{}}}",
+            rfc3339(self.timestamp),
+            self.duration.as_millis(),
+            self.connection_id,
+            self.client,
+            addr(self.peer_addr),
+            addr(self.local_addr),
+            string(&self.resource),
+            self.outcome.as_str(),
+            number(self.status),
+            number(self.close_code.map(Into::into)),
+            string(&self.error),
+            self.bytes_received,
+            self.bytes_sent,
+            string(&self.protocol),
+            string(&self.extensions),
+        )
+    }
+}
+
+/// Receives one `AuditRecord` for every connection attempt of a WebSocket, as the connection is
+/// dropped. See `Settings::audit`.
+///
+/// Unlike the log, which is meant for debugging, audit records have a fixed structure, so they
+/// can be fed to a SIEM system or kept for compliance. The sink is called on the event loop, so
+/// it should not block for long. A `Mutex` around any writer is a sink that writes every record
+/// as a line of JSON:
+///
+/// ```no_run
+/// use std::fs::File;
+/// use std::sync::Mutex;
+/// use parity_ws::Builder;
+///
+/// let log = File::create("audit.jsonl").unwrap();
+/// let ws = Builder::new()
+///     .with_audit(Box::leak(Box::new(Mutex::new(log))))
+///     .build(|_| |_| Ok(()))
+///     .unwrap();
+/// ```
+pub trait AuditSink: Sync {
+    /// Take the record of a connection attempt.
+    fn record(&self, record: &AuditRecord);
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(&AuditRecord) + Sync,
+{
+    fn record(&self, record: &AuditRecord) {
+        self(record)
+    }
+}
+
+impl<W> AuditSink for Mutex<W>
+where
+    W: Write + Send,
+{
+    fn record(&self, record: &AuditRecord) {
+        let mut line = record.to_json();
+        line.push('\n');
+        let res = match self.lock() {
+            Ok(mut writer) => writer.write_all(line.as_bytes()),
+            Err(mut poisoned) => poisoned.get_mut().write_all(line.as_bytes()),
+        };
+        if let Err(err) = res {
+            error!("Unable to write audit record: {}", err);
+        }
+    }
+}
+
+impl fmt::Debug for dyn AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AuditSink")
+    }
+}
+
+// What a connection has found out about itself for its audit record so far.
+#[derive(Debug)]
+pub struct Attempt {
+    pub timestamp: SystemTime,
+    pub started: Instant,
+    pub peer_addr: Option<SocketAddr>,
+    pub received: u64,
+    // The bytes of the handshake that were written, the frames are counted by the connection.
+    pub handshake_sent: u64,
+    pub status: Option<u16>,
+    pub protocol: Option<String>,
+    pub extensions: Option<String>,
+    pub close_code: Option<CloseCode>,
+    pub error: Option<String>,
+}
+
+impl Attempt {
+    pub fn new(peer_addr: Option<SocketAddr>) -> Attempt {
+        Attempt {
+            timestamp: SystemTime::now(),
+            started: Instant::now(),
+            peer_addr,
+            received: 0,
+            handshake_sent: 0,
+            status: None,
+            protocol: None,
+            extensions: None,
+            close_code: None,
+            error: None,
+        }
+    }
+
+    // Note what the handshake response negotiated.
+    pub fn negotiated(&mut self, res: &Response) {
+        self.status = Some(res.status());
+        self.protocol = res.protocol().ok().and_then(|protocol| protocol.map(String::from));
+        self.extensions = res
+            .extensions()
+            .ok()
+            .filter(|extensions| !extensions.is_empty())
+            .map(|extensions| extensions.join(", "));
+    }
+
+    pub fn closed(&mut self, code: CloseCode) {
+        if self.close_code.is_none() {
+            self.close_code = Some(code);
+        }
+    }
+
+    pub fn failed(&mut self, err: &dyn fmt::Display) {
+        if self.error.is_none() {
+            self.error = Some(err.to_string());
+        }
+    }
+}
+
+// A time in RFC 3339 format in UTC, with milliseconds, such as `2024-05-01T12:00:00.000Z`.
+pub fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // The civil date of a number of days since the epoch, after Howard Hinnant's algorithm.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        since.subsec_millis()
+    )
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let leap = UNIX_EPOCH + Duration::from_millis(951_782_400_123);
+        assert_eq!(rfc3339(leap), "2000-02-29T00:00:00.123Z");
+        let later = UNIX_EPOCH + Duration::from_secs(1_718_454_245);
+        assert_eq!(rfc3339(later), "2024-06-15T12:24:05.000Z");
+    }
+
+    #[test]
+    fn record_json() {
+        let record = AuditRecord {
+            timestamp: UNIX_EPOCH,
+            duration: Duration::from_millis(1500),
+            connection_id: 3,
+            client: false,
+            peer_addr: Some("10.0.0.1:5000".parse().unwrap()),
+            local_addr: None,
+            resource: Some("/feed".into()),
+            outcome: AuditOutcome::Closed,
+            status: Some(101),
+            close_code: Some(CloseCode::Away),
+            error: None,
+            bytes_received: 300,
+            bytes_sent: 200,
+            protocol: Some("chat".into()),
+            extensions: None,
+        };
+        assert_eq!(
+            record.to_json(),
+            "{\"timestamp\":\"1970-01-01T00:00:00.000Z\",\"duration_ms\":1500,\
+             \"connection_id\":3,\"client\":false,\"peer\":\"10.0.0.1:5000\",\"local\":null,\
+             \"resource\":\"/feed\",\"outcome\":\"closed\",\"status\":101,\"close_code\":1001,\
+             \"error\":null,\"bytes_received\":300,\"bytes_sent\":200,\"protocol\":\"chat\",\
+             \"extensions\":null}"
+        );
+    }
+}
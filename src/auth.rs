@@ -0,0 +1,191 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use context::Context;
+use handshake::{reason_phrase, Request, Response};
+
+/// What an `Authorizer` decides about a handshake request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authorization {
+    /// Pass the request on to `Handler::on_request`.
+    Allow,
+    /// Refuse the request with an empty response with the given HTTP status, such as 403.
+    Deny {
+        /// The status of the response.
+        status: u16,
+    },
+    /// Refuse the request with a 401 response that tells the client how to authenticate, such
+    /// as with a `WWW-Authenticate` header.
+    Challenge {
+        /// The headers added to the response.
+        headers: Vec<(String, Vec<u8>)>,
+    },
+}
+
+impl Authorization {
+    // The response that refuses the request, unless it is allowed.
+    fn into_response(self) -> Option<Response> {
+        match self {
+            Authorization::Allow => None,
+            Authorization::Deny { status } => {
+                Some(Response::new(status, reason_phrase(status), Vec::new()))
+            }
+            Authorization::Challenge { headers } => {
+                let mut res = Response::new(401, reason_phrase(401), Vec::new());
+                res.headers_mut().extend(headers);
+                Some(res)
+            }
+        }
+    }
+}
+
+/// What the other endpoint presented when it asked to open a connection. See `Authorizer`.
+pub struct Credentials<'a> {
+    request: &'a Request,
+    peer_addr: Option<SocketAddr>,
+    certificate: Option<Vec<u8>>,
+    context: &'a Context,
+}
+
+impl<'a> Credentials<'a> {
+    /// The handshake request, with headers such as `Authorization` and the query of the
+    /// resource.
+    #[inline]
+    pub fn request(&self) -> &'a Request {
+        self.request
+    }
+
+    /// The address of the other endpoint.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// The DER encoded certificate that the other endpoint presented during the TLS handshake,
+    /// if the connection is encrypted and the client sent one.
+    #[inline]
+    pub fn certificate(&self) -> Option<&[u8]> {
+        self.certificate.as_ref().map(|cert| &cert[..])
+    }
+
+    /// The typed storage of the connection, where an authorizer can leave what it found out
+    /// about the client, such as its user id, for the handler. See `Sender::context`.
+    #[inline]
+    pub fn context(&self) -> &'a Context {
+        self.context
+    }
+}
+
+impl<'a> fmt::Debug for Credentials<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("resource", &self.request.resource())
+            .field("peer_addr", &self.peer_addr)
+            .field("certificate", &self.certificate.is_some())
+            .finish()
+    }
+}
+
+/// Decides whether a handshake request may open a connection, before any handler sees it. See
+/// `Settings::authorizer` and `WebSocket::with_authorizer`.
+///
+/// An authorizer is set once for the whole WebSocket, so authentication does not have to be
+/// repeated in every handler, and validators for schemes such as signed tokens can be shared
+/// between applications. It runs on the event loop, so it should not block. Requests that need
+/// a remote service to be checked can be deferred from `Handler::on_request` instead.
+///
+/// The factory makes the handler of a connection as soon as the connection is accepted, as the
+/// handler may have to negotiate TLS with `Handler::upgrade_ssl_server` before the request can
+/// be read, so `Factory::connection_made` has already been called when the authorizer runs. A
+/// refused request never reaches the handler though: neither `Handler::on_request` nor
+/// `Handler::on_open` is called, and the handler is handed to `Factory::connection_lost` once
+/// the refusal is sent.
+///
+/// A closure can be used as an authorizer:
+///
+/// ```
+/// use parity_ws::{Authorization, Builder, Credentials};
+///
+/// fn authorize(credentials: &Credentials) -> Authorization {
+///     match credentials.request().header("authorization") {
+///         Some(token) if token == b"Bearer secret" => Authorization::Allow,
+///         Some(_) => Authorization::Deny { status: 403 },
+///         None => Authorization::Challenge {
+///             headers: vec![("WWW-Authenticate".into(), b"Bearer".to_vec())],
+///         },
+///     }
+/// }
+///
+/// static AUTHORIZER: fn(&Credentials) -> Authorization = authorize;
+///
+/// let ws = Builder::new()
+///     .with_authorizer(&AUTHORIZER)
+///     .build(|_| |_| Ok(()))
+///     .unwrap();
+/// ```
+pub trait Authorizer: Sync {
+    /// Decide about a handshake request.
+    fn authorize(&self, credentials: &Credentials) -> Authorization;
+}
+
+impl<F> Authorizer for F
+where
+    F: Fn(&Credentials) -> Authorization + Sync,
+{
+    fn authorize(&self, credentials: &Credentials) -> Authorization {
+        self(credentials)
+    }
+}
+
+impl fmt::Debug for dyn Authorizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Authorizer")
+    }
+}
+
+// Ask the authorizer about a request, returning the response that refuses it unless it is
+// allowed.
+pub fn authorize(
+    authorizer: &dyn Authorizer,
+    request: &Request,
+    peer_addr: Option<SocketAddr>,
+    certificate: Option<Vec<u8>>,
+    context: &Context,
+) -> Option<Response> {
+    let credentials = Credentials {
+        request,
+        peer_addr,
+        certificate,
+        context,
+    };
+    let authorization = authorizer.authorize(&credentials);
+    if authorization != Authorization::Allow {
+        debug!("Refusing unauthorized request for {}.", request.resource());
+    }
+    authorization.into_response()
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn responses() {
+        assert!(Authorization::Allow.into_response().is_none());
+
+        let res = Authorization::Deny { status: 403 }.into_response().unwrap();
+        assert_eq!(res.status(), 403);
+        assert_eq!(res.reason(), "Forbidden");
+
+        let res = Authorization::Challenge {
+            headers: vec![("WWW-Authenticate".into(), b"Bearer".to_vec())],
+        }
+        .into_response()
+        .unwrap();
+        assert_eq!(res.status(), 401);
+        assert!(res
+            .headers()
+            .iter()
+            .any(|&(ref name, ref value)| name == "WWW-Authenticate" && value == b"Bearer"));
+    }
+}
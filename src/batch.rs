@@ -0,0 +1,242 @@
+//! The batch module packs many small records into one binary message.
+//!
+//! Every message costs a frame header, a pass through the event loop and, for small messages, a
+//! system call of its own, so sending records in batches is the main way to raise throughput.
+//! Each record in a batch is preceded by its length as an unsigned LEB128 varint, which takes a
+//! single byte for records shorter than 128 bytes.
+//!
+//! ```
+//! use parity_ws::batch::{records, Batch};
+//! use parity_ws::Message;
+//!
+//! let mut batch = Batch::new();
+//! batch.push(b"first");
+//! batch.push(b"second");
+//! let msg: Message = batch.into();
+//!
+//! let data = msg.into_data();
+//! let unpacked = records(&data).collect::<Result<Vec<_>, _>>().unwrap();
+//! assert_eq!(unpacked, vec![&b"first"[..], &b"second"[..]]);
+//! ```
+use message::Message;
+use result::{Error, Kind, Result};
+
+/// The length of the longest varint, which holds a 64 bit number.
+pub const MAX_VARINT_LEN: usize = 10;
+
+/// Append `value` to `buf` as an unsigned LEB128 varint, and return the number of bytes written.
+pub fn encode_varint(mut value: u64, buf: &mut Vec<u8>) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        len += 1;
+        if value == 0 {
+            buf.push(byte);
+            return len;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+/// Decode an unsigned LEB128 varint from the start of `buf`, returning the value and the number of
+/// bytes it took, or `None` if `buf` ends before the varint does. A varint that does not fit
+/// in 64 bits is a Protocol error.
+pub fn decode_varint(buf: &[u8]) -> Result<Option<(u64, usize)>> {
+    let mut value = 0u64;
+    for (i, &byte) in buf.iter().enumerate() {
+        if i == MAX_VARINT_LEN - 1 && byte > 1 {
+            return Err(Error::new(Kind::Protocol, "Varint is too large."));
+        }
+        value |= u64::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    Ok(None)
+}
+
+/// A binary message that records are being packed into.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    data: Vec<u8>,
+    records: usize,
+}
+
+impl Batch {
+    /// Create an empty batch.
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    /// Create an empty batch that can hold `capacity` bytes of records and their lengths without
+    /// reallocating.
+    pub fn with_capacity(capacity: usize) -> Batch {
+        Batch {
+            data: Vec::with_capacity(capacity),
+            records: 0,
+        }
+    }
+
+    /// Append a record to the batch.
+    pub fn push(&mut self, record: &[u8]) {
+        encode_varint(record.len() as u64, &mut self.data);
+        self.data.extend_from_slice(record);
+        self.records += 1;
+    }
+
+    /// The number of records in the batch.
+    pub fn len(&self) -> usize {
+        self.records
+    }
+
+    /// Whether the batch holds no records.
+    pub fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    /// The size of the payload of the message the batch makes, in bytes, such as to send the
+    /// batch once it has grown beyond a limit.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Remove all records, keeping the allocated memory for the next batch.
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.records = 0;
+    }
+
+    /// The packed records, in the format that `records` reads.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl From<Batch> for Message {
+    fn from(batch: Batch) -> Message {
+        Message::Binary(batch.data)
+    }
+}
+
+/// Iterate over the records that were packed into `data` by a `Batch`. A record whose length
+/// prefix is invalid or runs past the end of `data` yields a Protocol error, after which the
+/// iteration ends.
+pub fn records(data: &[u8]) -> Records<'_> {
+    Records { data }
+}
+
+/// An iterator over the records of a batch. See `records`.
+#[derive(Debug, Clone)]
+pub struct Records<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<&'a [u8]>;
+
+    fn next(&mut self) -> Option<Result<&'a [u8]>> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let (len, prefix) = match decode_varint(self.data) {
+            Ok(Some(decoded)) => decoded,
+            Ok(None) => {
+                self.data = &[];
+                return Some(Err(Error::new(
+                    Kind::Protocol,
+                    "Batch ends within the length of a record.",
+                )));
+            }
+            Err(err) => {
+                self.data = &[];
+                return Some(Err(err));
+            }
+        };
+        let rest = &self.data[prefix..];
+        if len > rest.len() as u64 {
+            self.data = &[];
+            return Some(Err(Error::new(
+                Kind::Protocol,
+                format!(
+                    "Record of {} bytes is longer than the {} bytes left in the batch.",
+                    len,
+                    rest.len()
+                ),
+            )));
+        }
+        let (record, rest) = rest.split_at(len as usize);
+        self.data = rest;
+        Some(Ok(record))
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn varints() {
+        for &value in &[
+            0,
+            1,
+            127,
+            128,
+            300,
+            16_384,
+            u64::from(u32::max_value()),
+            u64::max_value(),
+        ] {
+            let mut buf = Vec::new();
+            let len = encode_varint(value, &mut buf);
+            assert_eq!(len, buf.len());
+            assert_eq!(decode_varint(&buf).unwrap(), Some((value, len)));
+            assert_eq!(decode_varint(&buf[..len - 1]).unwrap(), None);
+        }
+        let mut buf = Vec::new();
+        assert_eq!(encode_varint(300, &mut buf), 2);
+        assert_eq!(buf, vec![0xAC, 0x02]);
+        assert_eq!(encode_varint(u64::max_value(), &mut buf), MAX_VARINT_LEN);
+
+        let mut too_large = vec![0xFF; MAX_VARINT_LEN - 1];
+        too_large.push(0x02);
+        assert!(decode_varint(&too_large).is_err());
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        let mut batch = Batch::with_capacity(1024);
+        let long = vec![9; 200];
+        batch.push(b"");
+        batch.push(b"short");
+        batch.push(&long);
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch.size(), 1 + 1 + 5 + 2 + 200);
+
+        let unpacked = records(batch.as_bytes())
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(unpacked, vec![&b""[..], &b"short"[..], &long[..]]);
+
+        batch.clear();
+        assert!(batch.is_empty());
+        assert_eq!(records(batch.as_bytes()).count(), 0);
+    }
+
+    #[test]
+    fn truncated() {
+        let mut batch = Batch::new();
+        batch.push(b"whole");
+        batch.push(b"cut off");
+        let data = batch.as_bytes();
+
+        let mut iter = records(&data[..data.len() - 1]);
+        assert_eq!(iter.next().unwrap().unwrap(), b"whole");
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+
+        let mut iter = records(&[0x80]);
+        assert!(iter.next().unwrap().is_err());
+        assert!(iter.next().is_none());
+    }
+}
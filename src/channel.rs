@@ -0,0 +1,79 @@
+use std::borrow::Borrow;
+use std::sync::mpsc;
+use std::thread;
+
+use url;
+
+use communication::Sender;
+use event::{Event, EventHandler};
+use result::{Error, Kind, Result};
+use WebSocket;
+
+/// Connect to a WebSocket server and receive the connection's events on a channel instead of
+/// through a `Handler`.
+///
+/// The connection runs on its own event loop in a background thread. At most `capacity` events
+/// are buffered; after that the event loop waits for the receiver, which in turn stops reading
+/// from the socket. The receiver disconnects once the connection has closed, and dropping it
+/// fails the connection.
+///
+/// # Examples
+///
+/// ```no_run
+/// use parity_ws::{connect_channel, CloseCode, Event};
+///
+/// let (out, events) = connect_channel("ws://127.0.0.1:3012", 16).unwrap();
+/// for event in events {
+///     match event {
+///         Event::Open(_) => out.send("Hello WebSocket").unwrap(),
+///         Event::Message(msg) => {
+///             println!("Got message: {}", msg);
+///             out.close(CloseCode::Normal).unwrap();
+///         }
+///         _ => (),
+///     }
+/// }
+/// ```
+pub fn connect_channel<U>(url: U, capacity: usize) -> Result<(Sender, mpsc::Receiver<Event>)>
+where
+    U: Borrow<str>,
+{
+    let parsed = url::Url::parse(url.borrow()).map_err(|err| {
+        Error::new(
+            Kind::Internal,
+            format!("Unable to parse {} as url due to {:?}", url.borrow(), err),
+        )
+    })?;
+
+    let (events, rx) = mpsc::sync_channel(capacity);
+    let (senders, sender) = mpsc::channel();
+    let errors = events.clone();
+    let mut ws = WebSocket::new(move |out| {
+        let _ = senders.send(out);
+        let events = events.clone();
+        EventHandler::new(move |event| {
+            events
+                .send(event)
+                .map_err(|_| Error::new(Kind::Internal, "Event receiver has been dropped."))
+        })
+    })?;
+    ws.connect(parsed)?;
+    thread::Builder::new()
+        .name("ws-channel".into())
+        .spawn(move || {
+            if let Err(err) = ws.run() {
+                let _ = errors.send(Event::Error(err));
+            }
+        })?;
+
+    match sender.recv() {
+        Ok(out) => Ok((out, rx)),
+        Err(_) => match rx.try_recv() {
+            Ok(Event::Error(err)) => Err(err),
+            _ => Err(Error::new(
+                Kind::Internal,
+                "Event loop stopped before the connection was created.",
+            )),
+        },
+    }
+}
@@ -0,0 +1,402 @@
+//! The checksum module provides an extension that detects corrupted messages.
+//!
+//! Once both endpoints agree on the `permessage-checksum` extension during the handshake, every
+//! data message carries a CRC32C of its payload in its last four bytes, in network byte order.
+//! A message whose checksum does not match fails the connection with a protocol error, instead
+//! of handing the handler data that was mangled along the way, such as by a faulty proxy.
+//!
+//! The checksum covers the payload as it is sent, so `ChecksumHandler` should be the outermost
+//! handler, wrapping a `DeflateHandler` if compression is used as well.
+//!
+//! ```no_run
+//! use parity_ws::checksum::ChecksumHandler;
+//! use parity_ws::listen;
+//!
+//! listen("127.0.0.1:3012", |out| ChecksumHandler::new(move |msg| out.send(msg))).unwrap()
+//! ```
+use std::io::Write;
+use std::mem::{replace, take};
+
+#[cfg(feature = "nativetls")]
+use native_tls::TlsStream as SslStream;
+#[cfg(feature = "ssl")]
+use openssl::ssl::SslStream;
+use url;
+
+use frame::Frame;
+use handler::{Decision, Handler};
+use handshake::{Handshake, Request, Response};
+use message::Message;
+use protocol::{CloseCode, CloseFrame, OpCode};
+use result::{Error, Kind, Result};
+#[cfg(any(feature = "ssl", feature = "nativetls"))]
+use util::TcpStream;
+use util::{Timeout, Token};
+
+const EXTENSION: &str = "permessage-checksum";
+const ALGORITHM: &str = "crc32c";
+const CHECKSUM_LEN: usize = 4;
+// The Castagnoli polynomial, in reversed bit order.
+const POLYNOMIAL: u32 = 0x82F6_3B78;
+
+static TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+// A CRC32C that is computed over a payload in pieces.
+#[derive(Debug, Clone, Copy)]
+struct Crc32c(u32);
+
+impl Crc32c {
+    fn new() -> Crc32c {
+        Crc32c(!0)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = TABLE[((self.0 ^ u32::from(byte)) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(data);
+    crc.finish()
+}
+
+// Whether the parameters of an offer or agreement name an algorithm that is supported. An offer
+// without parameters asks for the default algorithm.
+fn supported(ext: &str) -> bool {
+    let mut params = ext.split(';').map(|param| param.trim());
+    if params.next() != Some(EXTENSION) {
+        return false;
+    }
+    params.all(|param| {
+        let mut pair = param.splitn(2, '=');
+        pair.next().map(|name| name.trim()) == Some("algorithm")
+            && pair.next().map(|value| value.trim().trim_matches('"')) == Some(ALGORITHM)
+    })
+}
+
+/// A WebSocket handler that implements the permessage-checksum extension. See the module
+/// documentation.
+///
+/// If the other endpoint does not support the extension, messages are passed through unchanged.
+pub struct ChecksumHandler<H: Handler> {
+    inner: H,
+    active: bool,
+    // The checksum of the fragments of the message received so far.
+    crc: Crc32c,
+    // The last bytes received of a fragmented message, which may belong to its checksum.
+    tail: Vec<u8>,
+}
+
+impl<H: Handler> ChecksumHandler<H> {
+    /// Wrap a child handler to provide the permessage-checksum extension.
+    pub fn new(handler: H) -> ChecksumHandler<H> {
+        ChecksumHandler {
+            inner: handler,
+            active: false,
+            crc: Crc32c::new(),
+            tail: Vec::with_capacity(CHECKSUM_LEN),
+        }
+    }
+
+    /// Whether the extension was agreed on with the other endpoint.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+}
+
+impl<H: Handler> Handler for ChecksumHandler<H> {
+    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+        let mut req = self.inner.build_request(url)?;
+        req.add_extension(&format!("{}; algorithm={}", EXTENSION, ALGORITHM));
+        Ok(req)
+    }
+
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let mut res = self.inner.on_request(req)?;
+        if res.is_pending() || res.status() != 101 {
+            return Ok(res);
+        }
+        if req.extensions()?.iter().any(|ext| supported(ext)) {
+            trace!("Accepted {} offer.", EXTENSION);
+            self.active = true;
+            res.add_extension(&format!("{}; algorithm={}", EXTENSION, ALGORITHM));
+        }
+        Ok(res)
+    }
+
+    fn on_response(&mut self, res: &Response) -> Result<()> {
+        if let Some(ext) = res
+            .extensions()?
+            .iter()
+            .find(|ext| ext.starts_with(EXTENSION))
+        {
+            if !supported(ext) {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    format!("Unsupported extension agreement: {}", ext),
+                ));
+            }
+            self.active = true;
+        }
+        self.inner.on_response(res)
+    }
+
+    fn on_frame(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
+        if !self.active || frame.is_control() {
+            return self.inner.on_frame(frame);
+        }
+
+        let mut data = take(frame.payload_mut());
+        if !self.tail.is_empty() {
+            let mut joined = replace(&mut self.tail, Vec::with_capacity(CHECKSUM_LEN));
+            joined.extend_from_slice(&data);
+            data = joined;
+        }
+
+        if frame.is_final() {
+            if data.len() < CHECKSUM_LEN {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    "Message is too short to carry a checksum.",
+                ));
+            }
+            let sum = data.split_off(data.len() - CHECKSUM_LEN);
+            let expected = sum.iter().fold(0, |crc, &byte| crc << 8 | u32::from(byte));
+            let mut crc = replace(&mut self.crc, Crc32c::new());
+            crc.update(&data);
+            if crc.finish() != expected {
+                return Err(Error::new(Kind::Protocol, "Message checksum mismatch."));
+            }
+        } else {
+            // The checksum may be split over the last fragments, so the end of each fragment is
+            // held back until the next one arrives.
+            let keep = data.len().min(CHECKSUM_LEN);
+            self.tail = data.split_off(data.len() - keep);
+            self.crc.update(&data);
+        }
+
+        *frame.payload_mut() = data;
+        self.inner.on_frame(frame)
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        if let Some(mut frame) = self.inner.on_send_frame(frame)? {
+            if self.active && !frame.is_control() {
+                let crc = crc32c(frame.payload());
+                frame.payload_mut().extend_from_slice(&crc.to_be_bytes());
+            }
+            Ok(Some(frame))
+        } else {
+            Ok(None)
+        }
+    }
+
+    #[inline]
+    fn on_shutdown(&mut self) {
+        self.inner.on_shutdown()
+    }
+
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.inner.on_message(msg)
+    }
+
+    #[inline]
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.inner.on_close(code, reason)
+    }
+
+    #[inline]
+    fn on_close_frame(&mut self, frame: &CloseFrame) {
+        self.inner.on_close_frame(frame)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: Error) {
+        self.inner.on_error(err)
+    }
+
+    #[inline]
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        self.inner.on_timeout(event)
+    }
+
+    #[inline]
+    fn on_new_timeout(&mut self, tok: Token, timeout: Timeout) -> Result<()> {
+        self.inner.on_new_timeout(tok, timeout)
+    }
+
+    #[inline]
+    fn on_ack_timeout(&mut self, id: u64, msg: Message) -> Result<()> {
+        self.inner.on_ack_timeout(id, msg)
+    }
+
+    #[inline]
+    fn on_replay_detected(&mut self, id: u64, msg: Message) -> Result<()> {
+        self.inner.on_replay_detected(id, msg)
+    }
+
+    #[inline]
+    fn on_flush_after_reconnect(&mut self, count: usize) -> Result<()> {
+        self.inner.on_flush_after_reconnect(count)
+    }
+
+    #[inline]
+    fn on_unknown_frame(&mut self, frame: &Frame) -> Decision {
+        self.inner.on_unknown_frame(frame)
+    }
+
+    // A streamed payload can't be checked, so only stream frames without checksums.
+    #[inline]
+    fn on_large_frame_start(&mut self, opcode: OpCode, len: u64) -> Option<Box<dyn Write + Send>> {
+        if self.active {
+            None
+        } else {
+            self.inner.on_large_frame_start(opcode, len)
+        }
+    }
+
+    #[inline]
+    fn on_large_frame_end(&mut self, len: u64) -> Result<()> {
+        self.inner.on_large_frame_end(len)
+    }
+
+    #[inline]
+    fn on_peer_half_close(&mut self) -> Result<()> {
+        self.inner.on_peer_half_close()
+    }
+
+    #[inline]
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    fn upgrade_ssl_client(
+        &mut self,
+        stream: TcpStream,
+        url: &url::Url,
+    ) -> Result<SslStream<TcpStream>> {
+        self.inner.upgrade_ssl_client(stream, url)
+    }
+
+    #[inline]
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    fn upgrade_ssl_server(&mut self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        self.inner.upgrade_ssl_server(stream)
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use protocol::OpCode;
+
+    type Inner = fn(Message) -> Result<()>;
+
+    fn active() -> ChecksumHandler<Inner> {
+        let inner: Inner = |_| Ok(());
+        let mut handler = ChecksumHandler::new(inner);
+        handler.active = true;
+        handler
+    }
+
+    fn inactive() -> ChecksumHandler<Inner> {
+        let mut handler = active();
+        handler.active = false;
+        handler
+    }
+
+    #[test]
+    fn known_value() {
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(b""), 0);
+    }
+
+    #[test]
+    fn negotiation() {
+        assert!(supported("permessage-checksum"));
+        assert!(supported("permessage-checksum; algorithm=crc32c"));
+        assert!(!supported("permessage-checksum; algorithm=xxhash"));
+        assert!(!supported("permessage-deflate"));
+
+        let url = url::Url::parse("ws://localhost/").unwrap();
+        let mut client = inactive();
+        let req = client.build_request(&url).unwrap();
+        let mut server = inactive();
+        let res = server.on_request(&req).unwrap();
+        assert!(server.is_active());
+        client.on_response(&res).unwrap();
+        assert!(client.is_active());
+
+        let mut plain = inactive();
+        let res = plain.on_request(&Request::from_url(&url).unwrap()).unwrap();
+        assert!(!plain.is_active());
+        assert!(res.extensions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut handler = active();
+        let sent = handler
+            .on_send_frame(Frame::message(b"hello".to_vec(), OpCode::Text, true))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sent.payload().len(), 9);
+
+        let received = handler.on_frame(sent.clone()).unwrap().unwrap();
+        assert_eq!(received.payload(), b"hello");
+
+        let mut corrupted = sent;
+        corrupted.payload_mut()[0] ^= 1;
+        assert!(handler.on_frame(corrupted).is_err());
+    }
+
+    #[test]
+    fn checksum_split_over_fragments() {
+        let mut handler = active();
+        let sent = handler
+            .on_send_frame(Frame::message(b"hello".to_vec(), OpCode::Text, true))
+            .unwrap()
+            .unwrap();
+        let payload = sent.payload();
+
+        let first = Frame::message(payload[..7].to_vec(), OpCode::Text, false);
+        let first = handler.on_frame(first).unwrap().unwrap();
+        assert_eq!(first.payload(), b"hel");
+        let middle = Frame::message(payload[7..8].to_vec(), OpCode::Continue, false);
+        let middle = handler.on_frame(middle).unwrap().unwrap();
+        assert_eq!(middle.payload(), b"l");
+        let last = Frame::message(payload[8..].to_vec(), OpCode::Continue, true);
+        let last = handler.on_frame(last).unwrap().unwrap();
+        assert_eq!(last.payload(), b"o");
+    }
+}
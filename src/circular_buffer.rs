@@ -1,4 +1,5 @@
 use bytes::{Buf, BufMut};
+use iovec::IoVec;
 
 const MINIMUM_NON_EMPTY_CAPACITY: usize = 8;
 
@@ -28,6 +29,30 @@ impl CircularBuffer {
         }
     }
 
+    /// Create an empty `CircularBuffer` that reuses the memory of `storage`, whose length becomes
+    /// the initial capacity, with a limit capacity set to `max_capacity`.
+    pub fn with_storage(storage: Box<[u8]>, max_capacity: usize) -> Self {
+        assert!(storage.len() <= max_capacity);
+        let capacity = storage.len();
+        Self {
+            buffer: storage,
+            position: 0,
+            length: 0,
+            max_capacity,
+            initial_capacity: capacity,
+        }
+    }
+
+    /// Take the memory of the buffer so that it can be reused, discarding its contents.
+    pub fn into_storage(self) -> Box<[u8]> {
+        self.buffer
+    }
+
+    /// Whether the buffer still has the capacity that it was created with.
+    pub fn has_initial_capacity(&self) -> bool {
+        self.current_capacity() == self.initial_capacity
+    }
+
     /// Checks whenever there is anything to read in the buffer.
     pub fn is_empty(&self) -> bool {
         self.length == 0
@@ -125,6 +150,35 @@ impl CircularBuffer {
         self.length = length;
     }
 
+    /// The number of readable bytes that are contiguous in memory, from the read cursor on.
+    /// This is less than `remaining` when the data wraps around the end of the buffer.
+    pub fn contiguous_prefix_len(&self) -> usize {
+        self.bytes().len()
+    }
+
+    /// Rotate the buffer so that all of its readable bytes are contiguous, and return them.
+    /// The buffer is only rotated when the data wraps around its end, which moves its contents
+    /// once without allocating.
+    pub fn make_contiguous(&mut self) -> &mut [u8] {
+        if self.contiguous_prefix_len() < self.length {
+            self.buffer.rotate_left(self.position);
+            self.position = 0;
+        }
+        let (position, length) = (self.position, self.length);
+        &mut self.buffer[position..position + length]
+    }
+
+    /// Copy readable bytes into `dst` without consuming them, taking them from both halves of
+    /// the buffer when the data wraps around its end. Returns the number of bytes copied, which
+    /// is the smaller of `dst.len()` and `remaining`.
+    pub fn peek(&self, dst: &mut [u8]) -> usize {
+        let len = std::cmp::min(dst.len(), self.length);
+        let first = std::cmp::min(len, self.contiguous_prefix_len());
+        dst[..first].copy_from_slice(&self.buffer[self.position..self.position + first]);
+        dst[first..len].copy_from_slice(&self.buffer[..len - first]);
+        len
+    }
+
     pub fn read_exact_into_vec(&mut self, length: usize) -> Vec<u8> {
         assert!(length <= self.remaining());
         let mut output = Vec::with_capacity(length);
@@ -139,6 +193,51 @@ impl CircularBuffer {
         output
     }
 
+    /// Read up to `max` bytes from `reader` straight into the free space of the buffer, growing
+    /// it first if it is full. When the free space wraps around the end of the buffer, the part
+    /// at the start is read into as well once the part at the end has been filled. An error
+    /// after some bytes were read is left for the next call. Returns the number of bytes read,
+    /// which is zero at the end of the input, or when the buffer is at its maximum capacity.
+    pub fn write_from<R: std::io::Read>(&mut self, reader: &mut R, max: usize) -> std::io::Result<usize> {
+        if self.remaining_mut_without_realloc() == 0 && !self.grow_buffer() {
+            return Ok(0);
+        }
+
+        let capacity = self.current_capacity();
+        let end = (self.position + self.length) % capacity;
+        let free = std::cmp::min(self.remaining_mut_without_realloc(), max);
+        let first = std::cmp::min(free, capacity - end);
+
+        let mut total = 0;
+        for &(start, len) in &[(end, first), (0, free - first)] {
+            if len == 0 {
+                break;
+            }
+            let read = match reader.read(&mut self.buffer[start..start + len]) {
+                Ok(read) => read,
+                Err(_) if total > 0 => break,
+                Err(err) => return Err(err),
+            };
+            self.advance_mut_impl(read);
+            total += read;
+            if read < len {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Resize the buffer to `capacity`, within its limit, if its contents fit. Returns whether
+    /// the buffer was resized.
+    pub fn fit(&mut self, capacity: usize) -> bool {
+        let capacity = std::cmp::min(capacity, self.max_capacity);
+        if self.current_capacity() == capacity || self.length > capacity {
+            return false;
+        }
+        self.resize_buffer(capacity);
+        true
+    }
+
     pub fn apply_soft_limit(&mut self, limit: usize) {
         let limit = std::cmp::min(limit, self.max_capacity);
         if self.remaining() == 0 && self.current_capacity() > limit {
@@ -160,6 +259,21 @@ impl Buf for CircularBuffer {
         &self.buffer[self.position..std::cmp::min(self.position + self.length, self.current_capacity())]
     }
 
+    // Both halves of the data when it wraps around the end of the buffer.
+    fn bytes_vec<'a>(&'a self, dst: &mut [&'a IoVec]) -> usize {
+        let first = self.bytes();
+        let second = &self.buffer[..self.length - first.len()];
+        let mut count = 0;
+        for half in [first, second].iter().filter(|half| !half.is_empty()) {
+            if count == dst.len() {
+                break;
+            }
+            dst[count] = (*half).into();
+            count += 1;
+        }
+        count
+    }
+
     fn advance(&mut self, count: usize) {
         assert!(
             count <= self.remaining(),
@@ -400,4 +514,103 @@ mod test {
         assert_eq!(b.current_capacity(), 0);
         assert_eq!(b.bytes(), b"");
     }
+
+    #[test]
+    fn write_from_wrapped() {
+        use std::io::{Cursor, Error, ErrorKind, Read};
+
+        let mut b = CircularBuffer::new(8, 8);
+        b.write_all(b"012345").unwrap();
+        b.advance(4);
+        // The free space is the two bytes at the end and the four at the start.
+        let mut reader = Cursor::new(b"6789ABCDEF".to_vec());
+        assert_eq!(b.write_from(&mut reader, usize::max_value()).unwrap(), 6);
+        assert_eq!(b.read_exact_into_vec(8), b"456789AB");
+        assert_eq!(b.write_from(&mut reader, 3).unwrap(), 3);
+        assert_eq!(b.read_exact_into_vec(3), b"CDE");
+
+        // A reader that would block once the end has been filled.
+        struct Blocking(usize);
+        impl Read for Blocking {
+            fn read(&mut self, output: &mut [u8]) -> std::io::Result<usize> {
+                if self.0 == 0 {
+                    return Err(Error::new(ErrorKind::WouldBlock, "blocked"));
+                }
+                let len = std::cmp::min(self.0, output.len());
+                self.0 -= len;
+                Ok(len)
+            }
+        }
+        let mut b = CircularBuffer::new(8, 8);
+        b.write_all(b"0123456").unwrap();
+        b.advance(6);
+        assert_eq!(b.write_from(&mut Blocking(1), usize::max_value()).unwrap(), 1);
+        assert_eq!(b.write_from(&mut Blocking(3), usize::max_value()).unwrap(), 3);
+        assert!(b.write_from(&mut Blocking(0), usize::max_value()).is_err());
+        assert_eq!(b.remaining(), 5);
+    }
+
+    #[test]
+    fn make_contiguous() {
+        let mut b = CircularBuffer::new(8, 8);
+        assert_eq!(b.make_contiguous(), b"");
+        b.write_all(b"012345").unwrap();
+        b.advance(2);
+        assert_eq!(b.contiguous_prefix_len(), 4);
+        assert_eq!(b.make_contiguous(), b"2345");
+        assert_eq!(b.read_cursor(), (2, 4));
+
+        b.write_all(b"6789").unwrap();
+        assert_eq!(b.contiguous_prefix_len(), 6);
+        assert_eq!(b.make_contiguous(), b"23456789");
+        assert_eq!(b.contiguous_prefix_len(), 8);
+        assert_eq!(b.read_cursor(), (0, 8));
+        b.advance(8);
+        assert!(b.write_all(b"01234567").is_ok());
+    }
+
+    #[test]
+    fn peek_wrapped() {
+        let mut b = CircularBuffer::new(8, 8);
+        b.write_all(b"012345").unwrap();
+        b.advance(5);
+        b.write_all(b"6789").unwrap();
+        let mut dst = [0u8; 4];
+        assert_eq!(b.peek(&mut dst), 4);
+        assert_eq!(&dst, b"5678");
+        let mut dst = [0u8; 8];
+        assert_eq!(b.peek(&mut dst), 5);
+        assert_eq!(&dst[..5], b"56789");
+        assert_eq!(b.read_cursor(), (5, 5));
+    }
+
+    #[test]
+    fn bytes_vec_wrapped() {
+        let mut b = CircularBuffer::new(8, 8);
+        b.write_all(b"012345").unwrap();
+        b.advance(5);
+        b.write_all(b"6789").unwrap();
+        let mut chunks: [&IoVec; 4] = [(&b"\0"[..]).into(); 4];
+        assert_eq!(b.bytes_vec(&mut chunks), 2);
+        assert_eq!(&chunks[0][..], b"567");
+        assert_eq!(&chunks[1][..], b"89");
+        assert_eq!(b.bytes_vec(&mut chunks[..1]), 1);
+    }
+
+    #[test]
+    fn reuse_storage() {
+        let mut b = CircularBuffer::new(8, 16);
+        b.write_all(b"01234567").unwrap();
+        assert!(b.has_initial_capacity());
+        let storage = b.into_storage();
+        assert_eq!(storage.len(), 8);
+
+        let mut b = CircularBuffer::with_storage(storage, 16);
+        assert!(b.is_empty());
+        b.write_all(b"abc").unwrap();
+        assert_eq!(b.bytes(), b"abc");
+        b.write_all(b"defghi").unwrap();
+        assert!(!b.has_initial_capacity());
+        assert_eq!(b.current_capacity(), 16);
+    }
 }
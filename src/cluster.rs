@@ -0,0 +1,454 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use communication::Sender;
+use util::Token;
+use factory::Factory;
+use message::Message;
+use result::{Error, Kind, Result};
+
+use super::{Builder, Settings};
+
+/// How a `Cluster` chooses the event loop for each connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Hand connections to each event loop in turn.
+    RoundRobin,
+    /// Hand every connection from the same IP address to the same event loop, so that
+    /// connections from one peer can share state that is local to a loop.
+    PeerAddr,
+}
+
+/// A snapshot of the load on one of the event loops of a `Cluster`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct LoopMetrics {
+    /// The number of connections currently open on the loop.
+    pub connections: usize,
+    /// The number of connections that the loop has served in total.
+    pub served: usize,
+}
+
+#[derive(Default)]
+struct Stats {
+    connections: AtomicUsize,
+    served: AtomicUsize,
+}
+
+/// The cluster-wide address of a connection. Every connection on a `Cluster` finds its own
+/// address in its context, see `Sender::context`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    shard: usize,
+    token: Token,
+    connection_id: u32,
+}
+
+impl Address {
+    /// The index of the event loop that the connection lives on.
+    #[inline]
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+
+    /// The token identifying the connection within its event loop.
+    #[inline]
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// The connection_id identifying the connection within its event loop.
+    #[inline]
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+}
+
+/// Routes messages to connections on any event loop of a `Cluster` by their `Address`.
+///
+/// A sender can be used from any thread, so a handler on one loop can talk to a connection on
+/// another as soon as it has that connection's sender. The bus keeps the sender of every open
+/// connection and hands it out by address. Every connection on a cluster finds the bus in its
+/// context:
+///
+/// ```no_run
+/// # use parity_ws::Sender;
+/// use parity_ws::cluster::{Address, Bus};
+///
+/// # fn forward(out: &Sender, to: Address) -> parity_ws::Result<()> {
+/// let bus = out.context().get::<Bus>().expect("connection is part of a cluster");
+/// bus.send(to, "hello from another loop")
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Bus {
+    // The registry is owned by the cluster. The bus only refers to it, as it is also kept in the
+    // contexts of the senders that the registry holds.
+    senders: Weak<Registry>,
+}
+
+// The sender of each open connection of each loop, by token. A connection is removed when it
+// closes, so this holds at most `max_connections` senders per loop.
+type Registry = Vec<Mutex<HashMap<Token, Sender>>>;
+
+impl Bus {
+    fn register(&self, shard: usize, out: &Sender) {
+        let address = Address {
+            shard,
+            token: out.token(),
+            connection_id: out.connection_id(),
+        };
+        out.context().insert(address);
+        out.context().insert(self.clone());
+        if let Some(registry) = self.senders.upgrade() {
+            if let Ok(mut senders) = registry[shard].lock() {
+                senders.insert(out.token(), out.clone());
+            }
+        }
+    }
+
+    // Forget the connection at `address`, once it has closed. Called by the connection itself, as
+    // `Factory::connection_lost` does not say which connection was lost.
+    #[doc(hidden)]
+    pub fn unregister(&self, address: Address) {
+        if let Some(registry) = self.senders.upgrade() {
+            if let Ok(mut senders) = registry[address.shard].lock() {
+                // The token may belong to a newer connection already.
+                let current = senders.get(&address.token).map(Sender::connection_id);
+                if current == Some(address.connection_id) {
+                    senders.remove(&address.token);
+                }
+            }
+        }
+    }
+
+    /// The sender of the connection at `address`, or `None` if that connection has closed.
+    pub fn sender(&self, address: Address) -> Option<Sender> {
+        let registry = self.senders.upgrade()?;
+        let senders = registry.get(address.shard)?.lock().ok()?;
+        senders
+            .get(&address.token)
+            .filter(|out| out.connection_id() == address.connection_id)
+            .cloned()
+    }
+
+    /// Send a message to the connection at `address`.
+    pub fn send<M>(&self, address: Address, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        self.sender(address)
+            .ok_or_else(|| {
+                Error::new(
+                    Kind::Internal,
+                    format!("No connection at {:?} on the cluster.", address),
+                )
+            })?
+            .send(msg)
+    }
+}
+
+// Counts the connections of one loop and registers them on the bus.
+struct Counted<F> {
+    factory: F,
+    stats: Arc<Stats>,
+    shard: usize,
+    bus: Bus,
+}
+
+impl<F: Factory> Counted<F> {
+    fn opened(&self, out: &Sender) {
+        self.stats.connections.fetch_add(1, Ordering::Relaxed);
+        self.stats.served.fetch_add(1, Ordering::Relaxed);
+        self.bus.register(self.shard, out);
+    }
+}
+
+impl<F: Factory> Factory for Counted<F> {
+    type Handler = F::Handler;
+
+    fn connection_made(&mut self, out: Sender) -> F::Handler {
+        self.factory.connection_made(out)
+    }
+
+    fn on_shutdown(&mut self) {
+        self.factory.on_shutdown()
+    }
+
+    fn client_connected(&mut self, out: Sender) -> F::Handler {
+        self.opened(&out);
+        self.factory.client_connected(out)
+    }
+
+    fn server_connected(&mut self, out: Sender) -> F::Handler {
+        self.opened(&out);
+        self.factory.server_connected(out)
+    }
+
+    fn connection_settings(&mut self, peer: SocketAddr, settings: Settings) -> Settings {
+        self.factory.connection_settings(peer, settings)
+    }
+
+    fn on_slow_callback(&mut self, token: Token, callback: &'static str, duration: Duration) {
+        self.factory.on_slow_callback(token, callback, duration)
+    }
+
+    fn connection_lost(&mut self, handler: F::Handler) {
+        self.stats.connections.fetch_sub(1, Ordering::Relaxed);
+        self.factory.connection_lost(handler)
+    }
+}
+
+struct Loop {
+    out: Sender,
+    stats: Arc<Stats>,
+}
+
+/// Runs several WebSocket event loops on their own threads and spreads connections across them,
+/// for servers that need more than the one core that a single event loop can use.
+///
+/// Each loop has its own factory, built by calling the function passed to `Cluster::new` with
+/// the index of the loop. Connections are accepted by `listen` on the calling thread, or handed
+/// over with `serve_stream`, and placed on a loop according to the cluster's `Placement`.
+///
+/// ```no_run
+/// use parity_ws::cluster::{Cluster, Placement};
+///
+/// let cluster = Cluster::new(4, |_| |out: parity_ws::Sender| move |msg| out.send(msg))
+///     .unwrap()
+///     .with_placement(Placement::PeerAddr);
+/// let handle = cluster.handle();
+/// # let _ = handle;
+/// cluster.listen("127.0.0.1:3012").unwrap();
+/// ```
+pub struct Cluster {
+    handle: ClusterHandle,
+    threads: Vec<thread::JoinHandle<Result<()>>>,
+}
+
+impl Cluster {
+    /// Start `loops` event loops with the default settings, building the factory for each with
+    /// `build`.
+    pub fn new<F, B>(loops: usize, build: B) -> Result<Cluster>
+    where
+        F: Factory + Send + 'static,
+        B: FnMut(usize) -> F,
+    {
+        Cluster::with_settings(loops, Settings::default(), build)
+    }
+
+    /// Start `loops` event loops that each use `settings`, building the factory for each with
+    /// `build`. Limits such as `max_connections` apply to each loop separately, and so does
+    /// `single_session`, which does not stop an identity from having a connection on each loop.
+    pub fn with_settings<F, B>(loops: usize, settings: Settings, mut build: B) -> Result<Cluster>
+    where
+        F: Factory + Send + 'static,
+        B: FnMut(usize) -> F,
+    {
+        let loops = loops.max(1);
+        let registry: Arc<Registry> =
+            Arc::new((0..loops).map(|_| Mutex::new(HashMap::new())).collect());
+        let mut shards = Vec::with_capacity(loops);
+        let mut threads = Vec::with_capacity(loops);
+        for index in 0..loops {
+            let stats = Arc::new(Stats::default());
+            let factory = Counted {
+                factory: build(index),
+                stats: stats.clone(),
+                shard: index,
+                bus: Bus {
+                    senders: Arc::downgrade(&registry),
+                },
+            };
+            let (started, out) = mpsc::channel();
+            let thread = thread::Builder::new()
+                .name(format!("ws-cluster-{}", index))
+                .spawn(move || {
+                    let mut ws = match Builder::new().with_settings(settings).build(factory) {
+                        Ok(ws) => ws,
+                        Err(err) => {
+                            let details = err.to_string();
+                            let _ = started.send(Err(err));
+                            return Err(Error::new(Kind::Internal, details));
+                        }
+                    };
+                    ws.handler.keep_serving();
+                    let _ = started.send(Ok(ws.broadcaster()));
+                    ws.run().map(|_| ())
+                })?;
+            let out = out.recv()
+                .map_err(|_| Error::new(Kind::Internal, "Event loop failed to start."))??;
+            shards.push(Loop { out, stats });
+            threads.push(thread);
+        }
+
+        Ok(Cluster {
+            handle: ClusterHandle {
+                loops: Arc::new(shards),
+                registry,
+                placement: Placement::RoundRobin,
+                next: Arc::new(AtomicUsize::new(0)),
+                stopped: Arc::new(AtomicBool::new(false)),
+                listening: Arc::new(Mutex::new(None)),
+            },
+            threads,
+        })
+    }
+
+    /// Choose how connections are placed on the event loops. Default: `RoundRobin`.
+    pub fn with_placement(mut self, placement: Placement) -> Cluster {
+        self.handle.placement = placement;
+        self
+    }
+
+    /// A handle for broadcasting to, inspecting and stopping the cluster from other threads.
+    pub fn handle(&self) -> ClusterHandle {
+        self.handle.clone()
+    }
+
+    /// Place an accepted TCP stream on one of the event loops.
+    pub fn serve_stream(&self, stream: TcpStream) -> Result<()> {
+        self.handle.serve_stream(stream)
+    }
+
+    /// Accept connections on the given address and place them on the event loops, until the
+    /// cluster is shut down with `ClusterHandle::shutdown`. Returns once every loop has
+    /// finished.
+    pub fn listen<A>(self, addr_spec: A) -> Result<()>
+    where
+        A: ToSocketAddrs,
+    {
+        let listener = TcpListener::bind(addr_spec)?;
+        self.handle.listening(listener.local_addr()?);
+        for stream in listener.incoming() {
+            if self.handle.stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                Ok(stream) => {
+                    if let Err(err) = self.serve_stream(stream) {
+                        error!("Unable to hand tcp connection to an event loop: {:?}", err);
+                    }
+                }
+                Err(err) => error!(
+                    "Encountered an error {:?} while accepting tcp connection.",
+                    err
+                ),
+            }
+        }
+        self.run()
+    }
+
+    /// Wait for every event loop to finish, after `ClusterHandle::shutdown` has been called.
+    pub fn run(self) -> Result<()> {
+        let mut res = Ok(());
+        for thread in self.threads {
+            let finished = thread
+                .join()
+                .unwrap_or_else(|_| Err(Error::new(Kind::Internal, "Event loop panicked.")));
+            res = res.and(finished);
+        }
+        res
+    }
+}
+
+/// A handle to a running `Cluster`.
+#[derive(Clone)]
+pub struct ClusterHandle {
+    loops: Arc<Vec<Loop>>,
+    registry: Arc<Registry>,
+    placement: Placement,
+    next: Arc<AtomicUsize>,
+    stopped: Arc<AtomicBool>,
+    listening: Arc<Mutex<Option<SocketAddr>>>,
+}
+
+impl ClusterHandle {
+    fn listening(&self, addr: SocketAddr) {
+        if let Ok(mut listening) = self.listening.lock() {
+            *listening = Some(addr);
+        }
+    }
+
+    /// The number of event loops in the cluster.
+    pub fn loops(&self) -> usize {
+        self.loops.len()
+    }
+
+    /// The event loop that a connection from `peer` is placed on.
+    pub fn place(&self, peer: &SocketAddr) -> usize {
+        match self.placement {
+            Placement::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.loops.len(),
+            Placement::PeerAddr => {
+                let mut hasher = DefaultHasher::new();
+                peer.ip().hash(&mut hasher);
+                (hasher.finish() % self.loops.len() as u64) as usize
+            }
+        }
+    }
+
+    /// Place an accepted TCP stream on one of the event loops.
+    pub fn serve_stream(&self, stream: TcpStream) -> Result<()> {
+        let index = match stream.peer_addr() {
+            Ok(peer) => self.place(&peer),
+            Err(_) => self.next.fetch_add(1, Ordering::Relaxed) % self.loops.len(),
+        };
+        self.loops[index].out.serve_stream(stream)
+    }
+
+    /// The bus for addressing connections on any of the event loops.
+    pub fn bus(&self) -> Bus {
+        Bus {
+            senders: Arc::downgrade(&self.registry),
+        }
+    }
+
+    /// The broadcaster of each event loop, in order. See `WebSocket::broadcaster`.
+    pub fn broadcasters(&self) -> Vec<Sender> {
+        self.loops.iter().map(|shard| shard.out.clone()).collect()
+    }
+
+    /// Send a message to every connection on every event loop.
+    pub fn broadcast<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        let msg = msg.into();
+        self.loops
+            .iter()
+            .map(|shard| shard.out.broadcast(msg.clone()))
+            .fold(Ok(()), Result::and)
+    }
+
+    /// The current load on each event loop, in order.
+    pub fn metrics(&self) -> Vec<LoopMetrics> {
+        self.loops
+            .iter()
+            .map(|shard| LoopMetrics {
+                connections: shard.stats.connections.load(Ordering::Relaxed),
+                served: shard.stats.served.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Stop accepting connections and shut down every event loop.
+    pub fn shutdown(&self) -> Result<()> {
+        self.stopped.store(true, Ordering::SeqCst);
+        let listening = self.listening.lock().ok().and_then(|addr| *addr);
+        if let Some(addr) = listening {
+            // Wake the accepting thread, which then sees that the cluster has stopped.
+            let _ = TcpStream::connect(addr);
+        }
+        self.loops
+            .iter()
+            .map(|shard| shard.out.shutdown())
+            .fold(Ok(()), Result::and)
+    }
+}
@@ -1,27 +1,109 @@
 use std::borrow::Cow;
 use std::convert::Into;
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 
 use mio;
 use mio::Token;
 use mio_extras::timer::Timeout;
 use url;
 
+use connection::{ConnectionInfo, Priority};
+use context::Context;
+use handshake::{reason_phrase, Response};
 use io::ALL;
 use message;
-use protocol::CloseCode;
-use result::{Error, Result};
+use middleware;
+use protocol::{retry_reason, CloseCode};
+use result::{Error, Kind, Result};
+use snapshot::LoopStateSnapshot;
+use tap::{FrameObserver, Tap};
+use trace::TraceId;
+use Settings;
 use std::cmp::PartialEq;
 use std::hash::{Hash, Hasher};
 use std::fmt;
 
+// The address of the other endpoint, kept in the context of a connection once it is open.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// A predicate that selects the connections that a filtered broadcast is sent to.
+#[derive(Clone)]
+pub struct Filter(Arc<dyn Fn(&ConnectionInfo) -> bool + Send + Sync>);
+
+impl Filter {
+    #[inline]
+    pub fn matches(&self, info: &ConnectionInfo) -> bool {
+        (self.0)(info)
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Filter")
+    }
+}
+
+/// A TCP stream on its way to an event loop. Signals must be `Clone`, so the stream is shared
+/// and taken out by whoever handles the signal first.
+#[derive(Clone)]
+pub struct Handoff(Arc<Mutex<Option<TcpStream>>>);
+
+impl Handoff {
+    pub fn take(&self) -> Option<TcpStream> {
+        match self.0.lock() {
+            Ok(mut stream) => stream.take(),
+            Err(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for Handoff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Handoff")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Signal {
     Message(message::Message),
+    Filtered(message::Message, Filter),
+    Uncompressed(message::Message),
+    Traced(message::Message, TraceId),
     Close(CloseCode, Cow<'static, str>),
+    SendThenClose(message::Message, CloseCode, Cow<'static, str>),
+    // Boxed to keep commands, and the `Queue` errors that carry them, small.
+    Respond(Box<Response>),
+    Flush(mpsc::Sender<()>),
+    Join(usize, u64),
+    Leave(usize),
+    Group(usize, message::Message),
+    Disband(usize),
+    History(usize, usize),
+    Serve(Handoff),
+    Tap(Tap),
     Ping(Vec<u8>),
     Pong(Vec<u8>),
-    Connect(url::Url),
+    Nodelay(bool),
+    Keepalive(Option<Duration>),
+    Priority(Priority),
+    Diagnostics,
+    Dump(mpsc::Sender<LoopStateSnapshot>),
+    MaxConnections(usize),
+    Settings(Box<Settings>),
+    Connect(Vec<url::Url>),
     Shutdown,
+    PauseAccepting,
+    Drain {
+        timeout: Duration,
+        code: CloseCode,
+        retry_after: Option<Duration>,
+    },
+    Abort,
     Timeout { delay: u64, token: Token },
     Cancel(Timeout),
 }
@@ -49,11 +131,15 @@ impl Command {
 
 /// A representation of the output of the WebSocket connection. Use this to send messages to the
 /// other endpoint.
+///
+/// A sender carries every capability over its connection. Use `data`, `control` or `split` to
+/// hand out only the part that a piece of code needs.
 #[derive(Clone)]
 pub struct Sender {
     token: Token,
     channel: mio::channel::SyncSender<Command>,
     connection_id: u32,
+    context: Context,
 }
 
 impl fmt::Debug for Sender {
@@ -92,6 +178,7 @@ impl Sender {
             token,
             channel,
             connection_id,
+            context: Context::new(),
         }
     }
 
@@ -107,6 +194,45 @@ impl Sender {
         self.connection_id
     }
 
+    /// Typed storage for this connection, shared by every clone of this sender.
+    #[inline]
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// The address of the other endpoint, once the opening handshake has completed. Unlike the
+    /// `Handshake`, this is available from every clone of the sender, so handlers that need the
+    /// address only now and then do not have to keep the handshake around. `None` before the
+    /// connection opens and for the broadcaster.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.context.get::<PeerAddr>().map(|peer| peer.0)
+    }
+
+    /// A handle that can only send messages over this connection, for passing to code that has
+    /// no business closing the connection or scheduling timeouts on it.
+    #[inline]
+    pub fn data(&self) -> DataSender {
+        DataSender {
+            sender: self.clone(),
+        }
+    }
+
+    /// A handle that can only control this connection and the WebSocket, without sending
+    /// messages.
+    #[inline]
+    pub fn control(&self) -> ControlHandle {
+        ControlHandle {
+            sender: self.clone(),
+        }
+    }
+
+    /// Split this sender into its data and control capabilities.
+    #[inline]
+    pub fn split(self) -> (DataSender, ControlHandle) {
+        (self.data(), ControlHandle { sender: self })
+    }
+
     /// Send a message over the connection.
     #[inline]
     pub fn send<M>(&self, msg: M) -> Result<()>
@@ -122,6 +248,85 @@ impl Sender {
             .map_err(Error::from)
     }
 
+    /// Send a message to the endpoint of this connection without compressing it, even if the
+    /// permessage-deflate extension is in use.
+    ///
+    /// This avoids spending time on payloads that are too small to benefit from compression or
+    /// that are already compressed, such as images. Without the extension it is the same as
+    /// `send`.
+    #[inline]
+    pub fn send_uncompressed<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<message::Message>,
+    {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Uncompressed(msg.into()),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Send a message to the endpoint of this connection along with a trace ID, such as one taken
+    /// from the distributed trace that the message is part of. The `Settings::tracer` of this
+    /// endpoint is called once the message is queued and once it is written to the socket. With
+    /// `Settings::trace_ids` enabled, the ID is sent along with the message and handed to the
+    /// tracer and handler of the other endpoint. On the broadcaster of a WebSocket, the message
+    /// is sent to all connections with the same trace ID. See `TraceId`.
+    #[inline]
+    pub fn send_traced<M>(&self, msg: M, trace: TraceId) -> Result<()>
+    where
+        M: Into<message::Message>,
+    {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Traced(msg.into(), trace),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Request a notification for when everything sent over this connection so far has been
+    /// written to the socket.
+    ///
+    /// This makes it possible to send a final message and wait for it to go out before closing
+    /// the connection or shutting down the WebSocket. Waiting on the returned handle from the
+    /// thread running the event loop, such as from within a handler, blocks forever.
+    ///
+    /// The broadcaster of a WebSocket has no connection of its own to flush, so its handles
+    /// always fail.
+    #[inline]
+    pub fn flush(&self) -> Result<FlushHandle> {
+        let (done, flushed) = mpsc::channel();
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Flush(done),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)?;
+        Ok(FlushHandle { flushed })
+    }
+
+    /// Request a snapshot of the state of the WebSocket and all of its connections, such as when
+    /// a server seems stuck. The snapshot is taken by the event loop once it gets to the request,
+    /// so waiting on the returned handle from the thread running the event loop, such as from
+    /// within a handler, blocks forever. Use `WebSocket::dump_state` before the WebSocket runs.
+    #[inline]
+    pub fn dump_state(&self) -> Result<StateHandle> {
+        let (tx, snapshot) = mpsc::channel();
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Dump(tx),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)?;
+        Ok(StateHandle { snapshot })
+    }
+
     /// Send a message to the endpoints of all connections.
     ///
     /// Be careful with this method. It does not discriminate between client and server connections.
@@ -143,6 +348,84 @@ impl Sender {
             .map_err(Error::from)
     }
 
+    /// Send a message to the endpoints of the open connections for which `filter` returns true.
+    ///
+    /// The filter runs on the event loop for every open connection, so it can select connections
+    /// by what is known about them, such as the resource that they requested or values stored in
+    /// their context, without the application keeping its own index of connections. It should
+    /// be cheap, as it holds up the event loop.
+    ///
+    /// ```no_run
+    /// # use parity_ws::Sender;
+    /// # struct Authenticated;
+    /// # fn publish(out: &Sender) -> parity_ws::Result<()> {
+    /// out.broadcast_filtered("update", |conn| {
+    ///     conn.resource() == Some("/feed") && conn.context().contains::<Authenticated>()
+    /// })
+    /// # }
+    /// ```
+    #[inline]
+    pub fn broadcast_filtered<M, F>(&self, msg: M, filter: F) -> Result<()>
+    where
+        M: Into<message::Message>,
+        F: Fn(&ConnectionInfo) -> bool + Send + Sync + 'static,
+    {
+        self.channel
+            .send(Command {
+                token: ALL,
+                signal: Signal::Filtered(msg.into(), Filter(Arc::new(filter))),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Send a message that is sent again until the other endpoint acknowledges it, returning
+    /// the id of the message. The handlers of both endpoints must be wrapped with
+    /// `HandlerExt::with_acks`. See `middleware::Acked`.
+    #[inline]
+    pub fn send_reliable<M>(&self, msg: M) -> Result<u64>
+    where
+        M: Into<message::Message>,
+    {
+        middleware::send_reliable(self, msg.into())
+    }
+
+    /// The position of the session of reliable messages on this connection, to continue it on
+    /// another connection with `resume`. The handler must be wrapped with
+    /// `HandlerExt::with_acks`. See `middleware::Resumption`.
+    #[inline]
+    pub fn resumption(&self) -> Result<middleware::Resumption> {
+        middleware::resumption(self)
+    }
+
+    /// Continue a session of reliable messages that was exported from another connection with
+    /// `resumption`. Messages are numbered from where the session stopped, and reliable messages
+    /// that it had already received are acknowledged but not handled again.
+    #[inline]
+    pub fn resume(&self, resumption: &middleware::Resumption) -> Result<()> {
+        middleware::resume(self, resumption)
+    }
+
+    /// Create a new, empty group of connections on this WebSocket. See `Group`.
+    #[inline]
+    pub fn group(&self) -> Group {
+        Group {
+            inner: Arc::new(GroupInner {
+                id: NEXT_GROUP.fetch_add(1, Ordering::Relaxed),
+                channel: self.channel.clone(),
+            }),
+        }
+    }
+
+    /// Create a new, empty group that keeps the last `capacity` messages sent to it. Connections
+    /// that join the group receive these before any new messages. See `Group::add_since`.
+    #[inline]
+    pub fn group_with_history(&self, capacity: usize) -> Result<Group> {
+        let group = self.group();
+        group.command(ALL, 0, Signal::History(group.inner.id, capacity))?;
+        Ok(group)
+    }
+
     /// Send a close code to the other endpoint.
     #[inline]
     pub fn close(&self, code: CloseCode) -> Result<()> {
@@ -170,6 +453,76 @@ impl Sender {
             .map_err(Error::from)
     }
 
+    /// Close the connection with a code, such as `CloseCode::Restart` or `CloseCode::Again`,
+    /// and a reason that tells the other endpoint to connect again after `delay`. See
+    /// `CloseFrame::retry_after`.
+    #[inline]
+    pub fn close_with_retry_after(&self, code: CloseCode, delay: Duration) -> Result<()> {
+        self.close_with_reason(code, retry_reason(delay))
+    }
+
+    /// Send a final message and then close the connection with a code and reason.
+    ///
+    /// The message and the close frame are queued together, so the close can not overtake the
+    /// message, even if other threads are sending over the same connection.
+    #[inline]
+    pub fn send_then_close<M, S>(&self, msg: M, code: CloseCode, reason: S) -> Result<()>
+    where
+        M: Into<message::Message>,
+        S: Into<Cow<'static, str>>,
+    {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::SendThenClose(msg.into(), code, reason.into()),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Complete a handshake that was deferred by returning `Response::pending` from
+    /// `on_request`, by sending `response`. A response with a status other than 101 rejects the
+    /// connection.
+    ///
+    /// Responses for connections without a deferred handshake are ignored.
+    #[inline]
+    pub fn accept(&self, response: Response) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Respond(Box::new(response)),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Reject a handshake that was deferred by returning `Response::pending` from `on_request`,
+    /// by sending an empty response with the given HTTP status.
+    #[inline]
+    pub fn reject(&self, status: u16) -> Result<()> {
+        self.accept(Response::new(status, reason_phrase(status), Vec::new()))
+    }
+
+    /// Complete a deferred handshake with a `200 OK` response holding a JSON snapshot of the
+    /// WebSocket: the number of connections and listeners, its settings, and the state of every
+    /// connection along with the bytes waiting in its buffers. See `LoopStateSnapshot::to_json`
+    /// for the format. The request is usually a plain
+    /// HTTP request from a browser or `curl`, for which `on_request` returned
+    /// `Response::pending`. See `HandlerExt::with_diagnostics`.
+    ///
+    /// The snapshot reveals the addresses of all connected peers, so it should only be served
+    /// to trusted requests.
+    #[inline]
+    pub fn respond_with_diagnostics(&self) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Diagnostics,
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
     /// Send a ping to the other endpoint with the given test data.
     #[inline]
     pub fn ping(&self, data: Vec<u8>) -> Result<()> {
@@ -194,13 +547,155 @@ impl Sender {
             .map_err(Error::from)
     }
 
+    /// Enable or disable `TCP_NODELAY` on the socket of this connection while it is open, for
+    /// instance to disable Nagle's algorithm during a latency sensitive phase of the protocol
+    /// only. `Settings::tcp_nodelay` picks the initial value. On the broadcaster of a WebSocket,
+    /// this applies to all connections.
+    ///
+    /// A failure to change the option is logged, and leaves the connection open.
+    #[inline]
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Nodelay(nodelay),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Enable TCP keepalive on the socket of this connection, probing the other endpoint after it
+    /// has been idle for the given duration, or disable it with `None`. On the broadcaster of a
+    /// WebSocket, this applies to all connections.
+    ///
+    /// A failure to change the option is logged, and leaves the connection open.
+    #[inline]
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Keepalive(keepalive),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Change the priority of this connection, for instance once it has authenticated as an
+    /// administrator. `Settings::priority` picks the initial value.
+    ///
+    /// The broadcaster of a WebSocket has no connection of its own, so it cannot set priorities.
+    #[inline]
+    pub fn set_priority(&self, priority: Priority) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Priority(priority),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Attach an observer that is shown every frame received or sent on this connection from
+    /// now on, without being able to change them. See `FrameObserver`.
+    ///
+    /// The broadcaster of a WebSocket has no connection of its own, so it cannot attach taps.
+    #[inline]
+    pub fn tap(&self, observer: Box<dyn FrameObserver>) -> Result<()> {
+        if self.token == ALL {
+            return Err(Error::new(
+                Kind::Internal,
+                "Taps can only be attached to the sender of a connection.",
+            ));
+        }
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Tap(Tap::new(observer)),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Hand an accepted TCP stream to this WebSocket to serve as a server connection, like
+    /// `WebSocket::serve_stream`, but from any thread while the WebSocket is running.
+    #[inline]
+    pub fn serve_stream(&self, stream: TcpStream) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: ALL,
+                signal: Signal::Serve(Handoff(Arc::new(Mutex::new(Some(stream))))),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
     /// Queue a new connection on this WebSocket to the specified URL.
     #[inline]
     pub fn connect(&self, url: url::Url) -> Result<()> {
         self.channel
             .send(Command {
                 token: self.token,
-                signal: Signal::Connect(url),
+                signal: Signal::Connect(vec![url]),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Queue a new connection on this WebSocket to the first of the specified URLs, in order of
+    /// priority, that accepts it. When a URL cannot be resolved or its connection is refused, the
+    /// next one is tried. `Handshake::url` tells `on_open` which of them was connected to.
+    ///
+    /// All of the URLs must use the same scheme.
+    pub fn connect_any(&self, urls: Vec<url::Url>) -> Result<()> {
+        match urls.first() {
+            None => return Err(Error::new(Kind::Internal, "No url to connect to.")),
+            Some(first) => {
+                if urls.iter().any(|url| url.scheme() != first.scheme()) {
+                    return Err(Error::new(
+                        Kind::Internal,
+                        "Fallback urls must use the same scheme.",
+                    ));
+                }
+            }
+        }
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Connect(urls),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Change `Settings::max_connections` of the WebSocket while it runs, such as to take on more
+    /// load or to shed it, from any connection or the broadcaster. Raising the limit reserves
+    /// room for the new connections right away. Lowering it below the number of open
+    /// connections closes none of them, but new connections are refused until enough of them
+    /// have closed, and the room kept for closed connections is released where possible.
+    ///
+    /// The queue of the event loop keeps the size computed from the settings at build time.
+    #[inline]
+    pub fn set_max_connections(&self, max_connections: usize) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::MaxConnections(max_connections),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Replace the settings of the WebSocket while it runs, such as with limits that were
+    /// reloaded from a configuration file. Connections that are made afterwards use the new
+    /// settings, while open connections keep the settings they were made with. A change of
+    /// `Settings::max_connections` takes effect as with `set_max_connections`, but
+    /// `Settings::queue_size` keeps its value from build time.
+    #[inline]
+    pub fn reconfigure(&self, settings: Settings) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Settings(Box::new(settings)),
                 connection_id: self.connection_id,
             })
             .map_err(Error::from)
@@ -218,6 +713,79 @@ impl Sender {
             .map_err(Error::from)
     }
 
+    /// Stop accepting new connections, while the open ones carry on as before. The listeners
+    /// are no longer polled, so that connections wait in their backlog for a load balancer to
+    /// notice, and streams handed over with `serve_stream` are closed right away.
+    ///
+    /// This is the first phase of a rolling restart, followed by `drain` and, if need be,
+    /// `abort`.
+    #[inline]
+    pub fn pause_accepting(&self) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::PauseAccepting,
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Stop accepting new connections and start the closing handshake on every open
+    /// connection, with `CloseCode::Away`. The WebSocket stops running once all connections
+    /// have closed, or aborts the ones that are left after `timeout`, as with `abort`.
+    #[inline]
+    pub fn drain(&self, timeout: Duration) -> Result<()> {
+        self.drain_with(timeout, CloseCode::Away, None)
+    }
+
+    /// Drain the WebSocket before it restarts, as with `drain`, but close the connections with
+    /// `CloseCode::Restart` (1012) and a reason that tells clients to connect again after
+    /// `retry_after`, once the new instance is expected to be up.
+    #[inline]
+    pub fn restart(&self, timeout: Duration, retry_after: Duration) -> Result<()> {
+        self.drain_with(timeout, CloseCode::Restart, Some(retry_after))
+    }
+
+    /// Drain an overloaded WebSocket, as with `drain`, but close the connections with
+    /// `CloseCode::Again` (1013) and a reason that tells clients to try again after
+    /// `retry_after`, or to connect to another instance.
+    #[inline]
+    pub fn shed(&self, timeout: Duration, retry_after: Duration) -> Result<()> {
+        self.drain_with(timeout, CloseCode::Again, Some(retry_after))
+    }
+
+    fn drain_with(
+        &self,
+        timeout: Duration,
+        code: CloseCode,
+        retry_after: Option<Duration>,
+    ) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Drain {
+                    timeout,
+                    code,
+                    retry_after,
+                },
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Drop every connection without a closing handshake and stop the WebSocket right away.
+    /// Handlers of open connections see `on_close` with `CloseCode::Abnormal`.
+    #[inline]
+    pub fn abort(&self) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Abort,
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
     /// Schedule a `token` to be sent to the WebSocket Handler's `on_timeout` method
     /// after `ms` milliseconds
     #[inline]
@@ -247,3 +815,506 @@ impl Sender {
             .map_err(Error::from)
     }
 }
+
+/// Waits for the messages that were sent before `Sender::flush` was called to be written to the
+/// socket.
+#[derive(Debug)]
+pub struct FlushHandle {
+    flushed: mpsc::Receiver<()>,
+}
+
+impl FlushHandle {
+    /// Block until the messages have been written. Fails with a `ConnectionAborted` IO error if
+    /// the connection ends first.
+    pub fn wait(self) -> Result<()> {
+        self.flushed.recv().map_err(|_| aborted())
+    }
+
+    /// Block until the messages have been written, failing with a `TimedOut` IO error if that
+    /// takes longer than `timeout`.
+    pub fn wait_timeout(self, timeout: Duration) -> Result<()> {
+        self.flushed.recv_timeout(timeout).map_err(|err| match err {
+            mpsc::RecvTimeoutError::Timeout => Error::new(
+                Kind::Io(io::Error::new(io::ErrorKind::TimedOut, "Flush timed out.")),
+                "Timed out waiting for the connection to flush.",
+            ),
+            mpsc::RecvTimeoutError::Disconnected => aborted(),
+        })
+    }
+}
+
+/// Waits for the snapshot requested with `Sender::dump_state`.
+#[derive(Debug)]
+pub struct StateHandle {
+    snapshot: mpsc::Receiver<LoopStateSnapshot>,
+}
+
+impl StateHandle {
+    /// Block until the snapshot has been taken. Fails with a `ConnectionAborted` IO error if the
+    /// WebSocket stops first.
+    pub fn wait(self) -> Result<LoopStateSnapshot> {
+        self.snapshot.recv().map_err(|_| stopped())
+    }
+
+    /// Block until the snapshot has been taken, failing with a `TimedOut` IO error if that takes
+    /// longer than `timeout`, which suggests that the event loop is blocked.
+    pub fn wait_timeout(self, timeout: Duration) -> Result<LoopStateSnapshot> {
+        self.snapshot.recv_timeout(timeout).map_err(|err| match err {
+            mpsc::RecvTimeoutError::Timeout => Error::new(
+                Kind::Io(io::Error::new(io::ErrorKind::TimedOut, "Dump timed out.")),
+                "Timed out waiting for the event loop to dump its state.",
+            ),
+            mpsc::RecvTimeoutError::Disconnected => stopped(),
+        })
+    }
+}
+
+fn stopped() -> Error {
+    Error::new(
+        Kind::Io(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "WebSocket stopped before dumping its state.",
+        )),
+        "The WebSocket stopped before it dumped its state.",
+    )
+}
+
+fn aborted() -> Error {
+    Error::new(
+        Kind::Io(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "Connection ended before flushing.",
+        )),
+        "The connection ended before it was flushed.",
+    )
+}
+
+/// The data plane of a `Sender`. It can send messages to the other endpoint and broadcast them,
+/// but it cannot close the connection, shut down the WebSocket or schedule timeouts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DataSender {
+    sender: Sender,
+}
+
+impl DataSender {
+    /// A Token identifying the connection within the WebSocket.
+    #[inline]
+    pub fn token(&self) -> Token {
+        self.sender.token()
+    }
+
+    /// A connection_id identifying the connection within the WebSocket.
+    #[inline]
+    pub fn connection_id(&self) -> u32 {
+        self.sender.connection_id()
+    }
+
+    /// Send a message over the connection.
+    #[inline]
+    pub fn send<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<message::Message>,
+    {
+        self.sender.send(msg)
+    }
+
+    /// Send a message over the connection without compressing it. See
+    /// `Sender::send_uncompressed`.
+    #[inline]
+    pub fn send_uncompressed<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<message::Message>,
+    {
+        self.sender.send_uncompressed(msg)
+    }
+
+    /// Send a message over the connection along with a trace ID. See `Sender::send_traced`.
+    #[inline]
+    pub fn send_traced<M>(&self, msg: M, trace: TraceId) -> Result<()>
+    where
+        M: Into<message::Message>,
+    {
+        self.sender.send_traced(msg, trace)
+    }
+
+    /// Request a notification for when everything sent so far has been written to the socket.
+    /// See `Sender::flush`.
+    #[inline]
+    pub fn flush(&self) -> Result<FlushHandle> {
+        self.sender.flush()
+    }
+
+    /// Send a message to the endpoints of all connections. See `Sender::broadcast`.
+    #[inline]
+    pub fn broadcast<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<message::Message>,
+    {
+        self.sender.broadcast(msg)
+    }
+
+    /// Send a message to the endpoints of the open connections selected by `filter`. See
+    /// `Sender::broadcast_filtered`.
+    #[inline]
+    pub fn broadcast_filtered<M, F>(&self, msg: M, filter: F) -> Result<()>
+    where
+        M: Into<message::Message>,
+        F: Fn(&ConnectionInfo) -> bool + Send + Sync + 'static,
+    {
+        self.sender.broadcast_filtered(msg, filter)
+    }
+}
+
+impl From<Sender> for DataSender {
+    fn from(sender: Sender) -> DataSender {
+        DataSender { sender }
+    }
+}
+
+/// The control plane of a `Sender`. It can close the connection, ping the other endpoint, open
+/// new connections, shut down the WebSocket and manage timeouts, but it cannot send messages.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ControlHandle {
+    sender: Sender,
+}
+
+impl ControlHandle {
+    /// A Token identifying the connection within the WebSocket.
+    #[inline]
+    pub fn token(&self) -> Token {
+        self.sender.token()
+    }
+
+    /// A connection_id identifying the connection within the WebSocket.
+    #[inline]
+    pub fn connection_id(&self) -> u32 {
+        self.sender.connection_id()
+    }
+
+    /// Send a close code to the other endpoint.
+    #[inline]
+    pub fn close(&self, code: CloseCode) -> Result<()> {
+        self.sender.close(code)
+    }
+
+    /// Send a close code and provide a descriptive reason for closing.
+    #[inline]
+    pub fn close_with_reason<S>(&self, code: CloseCode, reason: S) -> Result<()>
+    where
+        S: Into<Cow<'static, str>>,
+    {
+        self.sender.close_with_reason(code, reason)
+    }
+
+    /// Complete a deferred handshake. See `Sender::accept`.
+    #[inline]
+    pub fn accept(&self, response: Response) -> Result<()> {
+        self.sender.accept(response)
+    }
+
+    /// Reject a deferred handshake. See `Sender::reject`.
+    #[inline]
+    pub fn reject(&self, status: u16) -> Result<()> {
+        self.sender.reject(status)
+    }
+
+    /// Complete a deferred handshake with a snapshot of the WebSocket. See
+    /// `Sender::respond_with_diagnostics`.
+    #[inline]
+    pub fn respond_with_diagnostics(&self) -> Result<()> {
+        self.sender.respond_with_diagnostics()
+    }
+
+    /// Send a ping to the other endpoint with the given test data.
+    #[inline]
+    pub fn ping(&self, data: Vec<u8>) -> Result<()> {
+        self.sender.ping(data)
+    }
+
+    /// Send a pong to the other endpoint responding with the given test data.
+    #[inline]
+    pub fn pong(&self, data: Vec<u8>) -> Result<()> {
+        self.sender.pong(data)
+    }
+
+    /// Enable or disable `TCP_NODELAY` on the socket. See `Sender::set_nodelay`.
+    #[inline]
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<()> {
+        self.sender.set_nodelay(nodelay)
+    }
+
+    /// Enable or disable TCP keepalive on the socket. See `Sender::set_keepalive`.
+    #[inline]
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> Result<()> {
+        self.sender.set_keepalive(keepalive)
+    }
+
+    /// Change the priority of this connection. See `Sender::set_priority`.
+    #[inline]
+    pub fn set_priority(&self, priority: Priority) -> Result<()> {
+        self.sender.set_priority(priority)
+    }
+
+    /// Attach an observer to the frames of this connection. See `Sender::tap`.
+    #[inline]
+    pub fn tap(&self, observer: Box<dyn FrameObserver>) -> Result<()> {
+        self.sender.tap(observer)
+    }
+
+    /// The address of the other endpoint. See `Sender::peer_addr`.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.sender.peer_addr()
+    }
+
+    /// Queue a new connection on this WebSocket to the specified URL.
+    #[inline]
+    pub fn connect(&self, url: url::Url) -> Result<()> {
+        self.sender.connect(url)
+    }
+
+    /// Change the connection limit of the WebSocket. See `Sender::set_max_connections`.
+    #[inline]
+    pub fn set_max_connections(&self, max_connections: usize) -> Result<()> {
+        self.sender.set_max_connections(max_connections)
+    }
+
+    /// Replace the settings of the WebSocket. See `Sender::reconfigure`.
+    #[inline]
+    pub fn reconfigure(&self, settings: Settings) -> Result<()> {
+        self.sender.reconfigure(settings)
+    }
+
+    /// Request that all connections terminate and that the WebSocket stop running.
+    #[inline]
+    pub fn shutdown(&self) -> Result<()> {
+        self.sender.shutdown()
+    }
+
+    /// Stop accepting new connections. See `Sender::pause_accepting`.
+    #[inline]
+    pub fn pause_accepting(&self) -> Result<()> {
+        self.sender.pause_accepting()
+    }
+
+    /// Close every connection and stop once they are gone. See `Sender::drain`.
+    #[inline]
+    pub fn drain(&self, timeout: Duration) -> Result<()> {
+        self.sender.drain(timeout)
+    }
+
+    /// Close every connection for a restart and stop. See `Sender::restart`.
+    #[inline]
+    pub fn restart(&self, timeout: Duration, retry_after: Duration) -> Result<()> {
+        self.sender.restart(timeout, retry_after)
+    }
+
+    /// Close every connection of an overloaded WebSocket and stop. See `Sender::shed`.
+    #[inline]
+    pub fn shed(&self, timeout: Duration, retry_after: Duration) -> Result<()> {
+        self.sender.shed(timeout, retry_after)
+    }
+
+    /// Drop every connection and stop right away. See `Sender::abort`.
+    #[inline]
+    pub fn abort(&self) -> Result<()> {
+        self.sender.abort()
+    }
+
+    /// Schedule a `token` to be sent to the WebSocket Handler's `on_timeout` method
+    /// after `ms` milliseconds
+    #[inline]
+    pub fn timeout(&self, ms: u64, token: Token) -> Result<()> {
+        self.sender.timeout(ms, token)
+    }
+
+    /// Queue the cancellation of a previously scheduled timeout. See `Sender::cancel`.
+    #[inline]
+    pub fn cancel(&self, timeout: Timeout) -> Result<()> {
+        self.sender.cancel(timeout)
+    }
+}
+
+impl From<Sender> for ControlHandle {
+    fn from(sender: Sender) -> ControlHandle {
+        ControlHandle { sender }
+    }
+}
+
+static NEXT_GROUP: AtomicUsize = AtomicUsize::new(0);
+
+/// A set of connections that messages can be sent to together.
+///
+/// Membership is kept by the event loop, so sending to a group costs a single command no matter
+/// how many connections it has, and connections leave their groups automatically when they
+/// disconnect. A group is reference counted: clones share the same members, and the event loop
+/// forgets the group once the last clone has been dropped.
+///
+/// A group created with `Sender::group_with_history` also keeps its most recent messages so
+/// that late subscribers can catch up. Messages sent to a group are numbered from zero in the
+/// order that the event loop receives them.
+///
+/// ```no_run
+/// # use parity_ws::{Group, Sender};
+/// # fn subscribe(feed: &Group, out: &Sender) -> parity_ws::Result<()> {
+/// feed.add(out)?;
+/// feed.send("a new subscriber has joined")
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Group {
+    inner: Arc<GroupInner>,
+}
+
+struct GroupInner {
+    id: usize,
+    channel: mio::channel::SyncSender<Command>,
+}
+
+impl Group {
+    fn command(&self, token: Token, connection_id: u32, signal: Signal) -> Result<()> {
+        self.inner
+            .channel
+            .send(Command {
+                token,
+                signal,
+                connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Add the connection of `member` to this group. Adding a connection that is already a
+    /// member has no effect. If the group keeps a history, the connection first receives the
+    /// messages in it.
+    #[inline]
+    pub fn add(&self, member: &Sender) -> Result<()> {
+        self.add_since(member, 0)
+    }
+
+    /// Add the connection of `member` to this group, replaying only the messages in the history
+    /// whose sequence number is at least `sequence`. A subscriber that has already seen the
+    /// first `n` messages of the group can rejoin with `n` to receive just the ones it missed.
+    ///
+    /// The history is only replayed to open connections, so a connection added before its
+    /// handshake completes only receives the messages sent after it joined.
+    #[inline]
+    pub fn add_since(&self, member: &Sender, sequence: u64) -> Result<()> {
+        self.command(
+            member.token,
+            member.connection_id,
+            Signal::Join(self.inner.id, sequence),
+        )
+    }
+
+    /// Remove the connection of `member` from this group.
+    #[inline]
+    pub fn remove(&self, member: &Sender) -> Result<()> {
+        self.command(member.token, member.connection_id, Signal::Leave(self.inner.id))
+    }
+
+    /// Send a message to every connection in this group.
+    #[inline]
+    pub fn send<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<message::Message>,
+    {
+        self.command(ALL, 0, Signal::Group(self.inner.id, msg.into()))
+    }
+}
+
+impl Drop for GroupInner {
+    fn drop(&mut self) {
+        let disband = self.channel.send(Command {
+            token: ALL,
+            signal: Signal::Disband(self.id),
+            connection_id: 0,
+        });
+        if disband.is_err() {
+            trace!("Unable to disband group {}, the WebSocket has stopped.", self.id);
+        }
+    }
+}
+
+impl fmt::Debug for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Group {{ id: {:?} }}", self.inner.id)
+    }
+}
+
+impl PartialEq for Group {
+    fn eq(&self, other: &Group) -> bool {
+        self.inner.id == other.inner.id
+    }
+}
+
+impl Eq for Group {}
+
+impl Hash for Group {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.inner.id.hash(state);
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn split_capabilities() {
+        let (tx, rx) = mio::channel::sync_channel(10);
+        let (data, control) = Sender::new(Token(1), tx, 7).split();
+        assert_eq!(data.token(), control.token());
+
+        data.send("hi").unwrap();
+        control.close(CloseCode::Normal).unwrap();
+        data.broadcast("all").unwrap();
+
+        let cmd = rx.try_recv().unwrap();
+        assert_eq!((cmd.token(), cmd.connection_id()), (Token(1), 7));
+        match cmd.into_signal() {
+            Signal::Message(msg) => assert_eq!(msg, message::Message::text("hi")),
+            signal => panic!("unexpected signal {:?}", signal),
+        }
+        match rx.try_recv().unwrap().into_signal() {
+            Signal::Close(CloseCode::Normal, _) => (),
+            signal => panic!("unexpected signal {:?}", signal),
+        }
+        assert_eq!(rx.try_recv().unwrap().token(), ALL);
+    }
+
+    #[test]
+    fn group_lifetime() {
+        let (tx, rx) = mio::channel::sync_channel(10);
+        let out = Sender::new(Token(3), tx, 9);
+        let group = out.group();
+        let other = out.group();
+        assert!(group != other);
+        drop(other);
+        match rx.try_recv().unwrap().into_signal() {
+            Signal::Disband(_) => (),
+            signal => panic!("unexpected signal {:?}", signal),
+        }
+
+        let clone = group.clone();
+        clone.add(&out).unwrap();
+        let cmd = rx.try_recv().unwrap();
+        assert_eq!((cmd.token(), cmd.connection_id()), (Token(3), 9));
+        let id = match cmd.into_signal() {
+            Signal::Join(id, 0) => id,
+            signal => panic!("unexpected signal {:?}", signal),
+        };
+        drop(clone);
+        assert!(rx.try_recv().is_err());
+
+        group.send("hi").unwrap();
+        drop(group);
+        match rx.try_recv().unwrap().into_signal() {
+            Signal::Group(group, _) => assert_eq!(group, id),
+            signal => panic!("unexpected signal {:?}", signal),
+        }
+        match rx.try_recv().unwrap().into_signal() {
+            Signal::Disband(group) => assert_eq!(group, id),
+            signal => panic!("unexpected signal {:?}", signal),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use toml::value::{Table, Value};
+
+use communication::Sender;
+use result::{Error, Kind, Result};
+use Settings;
+
+// How often a watched configuration file is checked for changes.
+const POLL: Duration = Duration::from_millis(500);
+
+// Settings that decide how the event loop and the protocol work rather than limit them. Changing
+// them under a running server would leave its connections and listeners inconsistent, so a watched
+// file may not set them.
+const FIXED: &[&str] = &[
+    "queue_size",
+    "panic_on_new_connection",
+    "panic_on_shutdown",
+    "panic_on_internal",
+    "panic_on_capacity",
+    "panic_on_protocol",
+    "panic_on_encoding",
+    "panic_on_queue",
+    "panic_on_io",
+    "panic_on_timeout",
+    "shutdown_on_interrupt",
+    "masking_strict",
+    "key_strict",
+    "method_strict",
+    "encrypt_server",
+    "ipv6_only",
+    "socks5_proxy",
+];
+
+// Parse a TOML document whose top level holds the settings.
+fn parse(text: &str) -> Result<Table> {
+    toml::from_str(text)
+        .map_err(|err| Error::new(Kind::Internal, format!("Invalid configuration: {}", err)))
+}
+
+fn mismatch(key: &str, expected: &str) -> Error {
+    Error::new(
+        Kind::Internal,
+        format!("Invalid configuration: {} must be {}.", key, expected),
+    )
+}
+
+fn integer(key: &str, value: &Value) -> Result<u64> {
+    match *value {
+        Value::Integer(integer) if integer >= 0 => Ok(integer as u64),
+        _ => Err(mismatch(key, "a non-negative integer")),
+    }
+}
+
+fn size(key: &str, value: &Value) -> Result<usize> {
+    integer(key, value).map(|integer| integer as usize)
+}
+
+fn boolean(key: &str, value: &Value) -> Result<bool> {
+    match *value {
+        Value::Boolean(boolean) => Ok(boolean),
+        _ => Err(mismatch(key, "a boolean")),
+    }
+}
+
+// Durations are given in milliseconds, and 0 turns them off.
+fn millis(key: &str, value: &Value) -> Result<Option<Duration>> {
+    integer(key, value).map(|ms| {
+        if ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(ms))
+        }
+    })
+}
+
+fn count(key: &str, value: &Value) -> Result<u32> {
+    let integer = integer(key, value)?;
+    if integer > u64::from(u32::MAX) {
+        return Err(mismatch(key, "at most 4294967295"));
+    }
+    Ok(integer as u32)
+}
+
+// Limits are given as integers, and 0 turns them off.
+fn limit(key: &str, value: &Value) -> Result<Option<u32>> {
+    count(key, value).map(|limit| if limit == 0 { None } else { Some(limit) })
+}
+
+// Addresses are given as strings, and an empty one turns them off.
+fn address(key: &str, value: &Value) -> Result<Option<SocketAddr>> {
+    match *value {
+        Value::String(ref addr) if addr.is_empty() => Ok(None),
+        Value::String(ref addr) => addr
+            .parse::<SocketAddr>()
+            .map(Some)
+            .map_err(|_| mismatch(key, "a socket address")),
+        _ => Err(mismatch(key, "a socket address")),
+    }
+}
+
+// Apply a configuration to the settings. Keys that are not present keep their values. A `live`
+// configuration, for a running server, may only change its limits.
+pub fn apply(settings: &mut Settings, text: &str, live: bool) -> Result<()> {
+    for (key, value) in &parse(text)? {
+        let key = key.as_str();
+        if live && FIXED.contains(&key) {
+            return Err(Error::new(
+                Kind::Internal,
+                format!(
+                    "Invalid configuration: {} can not be changed while running.",
+                    key
+                ),
+            ));
+        }
+        match key {
+            "max_connections" => settings.max_connections = size(key, value)?,
+            "max_accepts_per_sec" => settings.max_accepts_per_sec = limit(key, value)?,
+            "accept_burst" => settings.accept_burst = count(key, value)?,
+            "queue_size" => settings.queue_size = size(key, value)?,
+            "panic_on_new_connection" => settings.panic_on_new_connection = boolean(key, value)?,
+            "panic_on_shutdown" => settings.panic_on_shutdown = boolean(key, value)?,
+            "fragments_capacity" => settings.fragments_capacity = size(key, value)?,
+            "fragments_grow" => settings.fragments_grow = boolean(key, value)?,
+            "fragment_size" => settings.fragment_size = size(key, value)?,
+            "compress_min_size" => settings.compress_min_size = size(key, value)?,
+            "max_fragment_size" => settings.max_fragment_size = size(key, value)?,
+            "frame_header_read_limit" => settings.frame_header_read_limit = size(key, value)?,
+            "empty_read_limit" => settings.empty_read_limit = size(key, value)?,
+            "write_stall_timeout_ms" => settings.write_stall_timeout_ms = integer(key, value)?,
+            "in_buffer_capacity" => settings.in_buffer_capacity = size(key, value)?,
+            "in_buffer_capacity_hard_limit" => {
+                settings.in_buffer_capacity_hard_limit = size(key, value)?
+            }
+            "in_buffer_capacity_soft_limit" => {
+                settings.in_buffer_capacity_soft_limit = size(key, value)?
+            }
+            "out_buffer_capacity" => settings.out_buffer_capacity = size(key, value)?,
+            "out_buffer_capacity_hard_limit" => {
+                settings.out_buffer_capacity_hard_limit = size(key, value)?
+            }
+            "out_buffer_capacity_soft_limit" => {
+                settings.out_buffer_capacity_soft_limit = size(key, value)?
+            }
+            "idle_shrink_interval" => settings.idle_shrink_interval = millis(key, value)?,
+            "idle_shrink_target" => settings.idle_shrink_target = size(key, value)?,
+            "buffer_pool_size" => settings.buffer_pool_size = size(key, value)?,
+            "spill_threshold" => settings.spill_threshold = size(key, value)?,
+            "strict_preallocation" => settings.strict_preallocation = boolean(key, value)?,
+            "panic_on_internal" => settings.panic_on_internal = boolean(key, value)?,
+            "panic_on_capacity" => settings.panic_on_capacity = boolean(key, value)?,
+            "panic_on_protocol" => settings.panic_on_protocol = boolean(key, value)?,
+            "panic_on_encoding" => settings.panic_on_encoding = boolean(key, value)?,
+            "panic_on_queue" => settings.panic_on_queue = boolean(key, value)?,
+            "panic_on_io" => settings.panic_on_io = boolean(key, value)?,
+            "panic_on_timeout" => settings.panic_on_timeout = boolean(key, value)?,
+            "shutdown_on_interrupt" => settings.shutdown_on_interrupt = boolean(key, value)?,
+            "masking_strict" => settings.masking_strict = boolean(key, value)?,
+            "key_strict" => settings.key_strict = boolean(key, value)?,
+            "method_strict" => settings.method_strict = boolean(key, value)?,
+            "encrypt_server" => settings.encrypt_server = boolean(key, value)?,
+            "tcp_nodelay" => settings.tcp_nodelay = boolean(key, value)?,
+            "ipv6_only" => settings.ipv6_only = boolean(key, value)?,
+            "close_linger" => settings.close_linger = millis(key, value)?,
+            "socks5_proxy" => settings.socks5_proxy = address(key, value)?,
+            "bind_local_addr" => settings.bind_local_addr = address(key, value)?,
+            "trace_ids" => settings.trace_ids = boolean(key, value)?,
+            "slow_callback_threshold" => settings.slow_callback_threshold = millis(key, value)?,
+            "watchdog_timeout_ms" => settings.watchdog_timeout_ms = integer(key, value)?,
+            _ => {
+                return Err(Error::new(
+                    Kind::Internal,
+                    format!("Unknown configuration key: {}", key),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+// Read a configuration file and apply it to a copy of the settings.
+pub fn load(path: &Path, mut settings: Settings, live: bool) -> Result<Settings> {
+    let text = fs::read_to_string(path)?;
+    apply(&mut settings, &text, live)?;
+    Ok(settings)
+}
+
+fn version(path: &Path) -> Option<(SystemTime, u64)> {
+    fs::metadata(path)
+        .and_then(|meta| Ok((meta.modified()?, meta.len())))
+        .ok()
+}
+
+// Apply the configuration file to the settings of the event loop now and whenever the file
+// changes, until the returned handle is dropped. Every reload starts from `base`, so a key that is
+// removed from the file goes back to its value from before the file was watched. Open connections
+// keep their copies of the settings, so a reload reaches new connections only. Only limits can be
+// changed, see `FIXED`.
+pub fn watch(path: PathBuf, base: Settings, out: Sender) -> Result<Arc<()>> {
+    let mut seen = version(&path);
+    out.reconfigure(load(&path, base, true)?)?;
+
+    let handle = Arc::new(());
+    let alive: Weak<()> = Arc::downgrade(&handle);
+    thread::Builder::new()
+        .name("ws-config".into())
+        .spawn(move || {
+            while alive.upgrade().is_some() {
+                thread::sleep(POLL);
+                let current = version(&path);
+                if current == seen {
+                    continue;
+                }
+                seen = current;
+                match load(&path, base, true) {
+                    Ok(settings) => {
+                        info!("Reloading settings from {}.", path.display());
+                        if out.reconfigure(settings).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => error!(
+                        "Keeping the current settings, unable to reload {}: {}",
+                        path.display(),
+                        err
+                    ),
+                }
+            }
+        })?;
+    Ok(handle)
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        let table = parse(
+            "# limits\n\
+             max_connections = 10_000\n\
+             tcp_nodelay = true # inline\n\
+             socks5_proxy = '127.0.0.1:1080'\n",
+        )
+        .unwrap();
+        assert_eq!(table["max_connections"], Value::Integer(10_000));
+        assert_eq!(table["tcp_nodelay"], Value::Boolean(true));
+        assert_eq!(
+            table["socks5_proxy"],
+            Value::String("127.0.0.1:1080".into())
+        );
+        assert!(parse("max_connections").is_err());
+    }
+
+    #[test]
+    fn apply_settings() {
+        let mut settings = Settings::default();
+        apply(
+            &mut settings,
+            "max_connections = 5\nclose_linger = 250\nslow_callback_threshold = 0\n\
+             bind_local_addr = \"192.0.2.1:0\"\nmax_accepts_per_sec = 200\naccept_burst = 50\n",
+            false,
+        )
+        .unwrap();
+        assert_eq!(settings.max_connections, 5);
+        assert_eq!(settings.max_accepts_per_sec, Some(200));
+        assert_eq!(settings.accept_burst, 50);
+        assert_eq!(settings.bind_local_addr, Some("192.0.2.1:0".parse().unwrap()));
+        assert_eq!(settings.close_linger, Some(Duration::from_millis(250)));
+        assert_eq!(settings.slow_callback_threshold, None);
+
+        assert!(apply(&mut settings, "accept_burst = 4294967296", false).is_err());
+        assert!(apply(&mut settings, "max_accepts_per_sec = -1", false).is_err());
+        assert!(apply(&mut settings, "max_connection = 5", false).is_err());
+        assert!(apply(&mut settings, "tcp_nodelay = 1", false).is_err());
+        assert!(apply(&mut settings, "socks5_proxy = \"proxy\"", false).is_err());
+        assert!(apply(&mut settings, "max_connections = 1.5", false).is_err());
+        assert!(apply(&mut settings, "[server]\nmax_connections = 5", false).is_err());
+
+        // A running server only takes new limits.
+        assert!(apply(&mut settings, "encrypt_server = true", false).is_ok());
+        assert!(apply(&mut settings, "encrypt_server = true", true).is_err());
+        assert!(apply(&mut settings, "panic_on_io = true", true).is_err());
+        assert!(apply(&mut settings, "max_connections = 7", true).is_ok());
+        assert_eq!(settings.max_connections, 7);
+    }
+}
@@ -1,35 +1,69 @@
 use std::borrow::Borrow;
 use std::collections::VecDeque;
-use std::io::{Cursor, Read, Write};
+use std::cmp::{max, min};
+use std::io::{Cursor, IoSlice, Read, Write};
 use std::mem::replace;
-use std::net::SocketAddr;
+use std::net::{Shutdown, SocketAddr};
 use std::str::from_utf8;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use bytes::BufMut;
+use bytes::{Buf, BufMut};
 use mio::tcp::TcpStream;
 use mio::{Ready, Token};
 use mio_extras::timer::Timeout;
 use url;
 
 #[cfg(feature = "nativetls")]
-use native_tls::HandshakeError;
+use native_tls::{HandshakeError, TlsStream as SslStream};
 #[cfg(feature = "ssl")]
-use openssl::ssl::HandshakeError;
+use openssl::ssl::{HandshakeError, SslStream};
 
 use circular_buffer::CircularBuffer;
+use cluster::{Address, Bus};
+use communication::PeerAddr;
+use context::Context;
+use audit::{Attempt, AuditOutcome, AuditRecord};
+use auth::{authorize, Authorizer};
+use cors::Cors;
+#[cfg(feature = "testing-hooks")]
+use faults::{Faults, Faulty};
 use frame::Frame;
-use handler::Handler;
-use handshake::{Handshake, Request, Response};
-use message::Message;
-use protocol::{CloseCode, OpCode};
-use result::{Error, Kind, Result};
+use handler::{Decision, Handler};
+use io::{connect, url_to_addrs};
+use handshake::{constant_time_eq, head_len, recase, Handshake, Request, Response};
+use message::{decode_text, Message};
+use proto::{apply_mask, decode_close_code, decode_header, HeaderError, MAX_HEADER_LEN};
+use protocol::{CloseCode, CloseFrame, OpCode};
+use result::{Error, ErrorContext, Kind, Phase, Result};
+use session::{Identity, Sessions};
+use snapshot::{ConnectionPhase, ConnectionSnapshot};
+use spill::Spill;
 use stream::{Stream, TryReadBuf, TryWriteBuf};
+use tap::{Direction, FrameObserver};
+use trace::{self, TraceId};
 
 use self::Endpoint::*;
 use self::State::*;
 
 use super::Settings;
 
+// The transport of a connection, with the faults of `Settings::faults` injected into it when
+// the testing hooks are enabled.
+#[cfg(feature = "testing-hooks")]
+macro_rules! transport {
+    ($conn:ident) => {
+        Faulty::new(&mut $conn.socket, &mut $conn.faults)
+    };
+}
+
+#[cfg(not(feature = "testing-hooks"))]
+macro_rules! transport {
+    ($conn:ident) => {
+        $conn.socket
+    };
+}
+
 #[derive(Debug)]
 pub enum State {
     // Tcp connection accepted, waiting for handshake to complete
@@ -59,7 +93,6 @@ impl State {
         }
     }
 
-    #[allow(dead_code)]
     #[inline]
     pub fn is_open(&self) -> bool {
         match *self {
@@ -77,6 +110,273 @@ impl State {
     }
 }
 
+/// The class of a connection, which decides the order in which the event loop handles the
+/// connections that are ready at the same time. The reads and writes of connections with a
+/// higher priority are done first, so that for instance an operator console stays responsive
+/// while bulk clients saturate the event loop. Connections of the same priority are handled in
+/// the order their events arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Handled before all other connections, for control and administration.
+    High,
+    /// Handled after the high priority connections, along with the queue, timers and listeners.
+    Normal,
+    /// Handled after everything else, for bulk transfers.
+    Low,
+}
+
+/// A view of a connection that is given to the filter of `Sender::broadcast_filtered`.
+#[derive(Debug)]
+pub struct ConnectionInfo<'a> {
+    token: Token,
+    connection_id: u32,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    resource: Option<&'a str>,
+    client: bool,
+    context: &'a Context,
+}
+
+impl<'a> ConnectionInfo<'a> {
+    /// The token identifying the connection within the WebSocket.
+    #[inline]
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// The connection_id identifying the connection within the WebSocket.
+    #[inline]
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+
+    /// The address of the other endpoint.
+    #[inline]
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// The local address of the connection.
+    #[inline]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// The resource that was requested in the handshake, such as `/feed`.
+    #[inline]
+    pub fn resource(&self) -> Option<&'a str> {
+        self.resource
+    }
+
+    /// Whether this is the client end of the connection.
+    #[inline]
+    pub fn is_client(&self) -> bool {
+        self.client
+    }
+
+    /// The typed storage of the connection. See `Sender::context`.
+    #[inline]
+    pub fn context(&self) -> &'a Context {
+        self.context
+    }
+}
+
+// Callbacks that took longer than `Settings::slow_callback_threshold`, shared by all connections
+// and reported to the factory by the event loop.
+pub type SlowLog = Arc<Mutex<Vec<(Token, &'static str, Duration)>>>;
+
+// The buffers of closed connections, kept by the event loop to be reused by new connections. See
+// `Settings::buffer_pool_size`.
+pub type BufferPool = Arc<Mutex<Vec<Box<[u8]>>>>;
+
+// The state that the event loop shares with all of its connections.
+#[derive(Clone)]
+pub struct Shared {
+    pub slow: SlowLog,
+    pub pool: BufferPool,
+    pub sessions: Arc<Sessions>,
+    // Set with `WebSocket::with_authorizer`, and used instead of `Settings::authorizer`.
+    pub authorizer: Option<Arc<dyn Authorizer + Send>>,
+}
+
+// Create a buffer, reusing one from the pool if there is one of the initial capacity.
+fn pooled_buffer(pool: &BufferPool, capacity: usize, max_capacity: usize) -> CircularBuffer {
+    let capacity = std::cmp::min(capacity, max_capacity);
+    if capacity > 0 {
+        if let Ok(mut pool) = pool.lock() {
+            if let Some(index) = pool.iter().position(|storage| storage.len() == capacity) {
+                return CircularBuffer::with_storage(pool.swap_remove(index), max_capacity);
+            }
+        }
+    }
+    CircularBuffer::new(capacity, max_capacity)
+}
+
+// Return the memory of a buffer to the pool. Buffers that grew or were shrunk are freed instead,
+// since new connections start out with buffers of the initial capacity.
+fn recycle(pool: &mut Vec<Box<[u8]>>, limit: usize, buffer: CircularBuffer) {
+    if pool.len() < limit && buffer.has_initial_capacity() && buffer.current_capacity() > 0 {
+        pool.push(buffer.into_storage());
+    }
+}
+
+// The sizes of the last frames that a connection received, which `Settings::in_buffer_adaptive`
+// tunes its incoming buffer to.
+struct FrameSizes {
+    sizes: [usize; 64],
+    count: usize,
+}
+
+impl FrameSizes {
+    fn new() -> FrameSizes {
+        FrameSizes {
+            sizes: [0; 64],
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, size: usize) {
+        self.sizes[self.count % self.sizes.len()] = size;
+        self.count = self.count.wrapping_add(1);
+    }
+
+    // The size that 95 percent of the recent frames fit in, once there are enough to tell.
+    fn percentile_95(&self) -> Option<usize> {
+        let len = min(self.count, self.sizes.len());
+        if len < 16 {
+            return None;
+        }
+        let mut sorted = self.sizes;
+        let sorted = &mut sorted[..len];
+        sorted.sort_unstable();
+        Some(sorted[(len * 95).div_ceil(100) - 1])
+    }
+}
+
+// Wraps the handler of a connection to measure its callbacks. The methods shadow the ones of
+// `Handler`, so that every call the connection makes is measured.
+struct Timed<H> {
+    inner: H,
+    token: Token,
+    threshold: Option<Duration>,
+    slow: SlowLog,
+}
+
+impl<H> Timed<H>
+where
+    H: Handler,
+{
+    #[inline]
+    fn time<T, F>(&mut self, callback: &'static str, call: F) -> T
+    where
+        F: FnOnce(&mut H) -> T,
+    {
+        let threshold = match self.threshold {
+            Some(threshold) => threshold,
+            None => return call(&mut self.inner),
+        };
+        let start = Instant::now();
+        let result = call(&mut self.inner);
+        let elapsed = start.elapsed();
+        if elapsed >= threshold {
+            if let Ok(mut slow) = self.slow.lock() {
+                slow.push((self.token, callback, elapsed));
+            }
+        }
+        result
+    }
+
+    fn on_shutdown(&mut self) {
+        self.time("on_shutdown", |h| h.on_shutdown())
+    }
+
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.time("on_open", |h| h.on_open(shake))
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.time("on_message", |h| h.on_message(msg))
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.time("on_close", |h| h.on_close(code, reason))
+    }
+
+    fn on_close_frame(&mut self, frame: &CloseFrame) {
+        self.time("on_close_frame", |h| h.on_close_frame(frame))
+    }
+
+    fn on_error(&mut self, err: Error) {
+        self.time("on_error", |h| h.on_error(err))
+    }
+
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        self.time("on_request", |h| h.on_request(req))
+    }
+
+    fn on_response(&mut self, res: &Response) -> Result<()> {
+        self.time("on_response", |h| h.on_response(res))
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        self.time("on_timeout", |h| h.on_timeout(event))
+    }
+
+    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
+        self.time("on_new_timeout", |h| h.on_new_timeout(event, timeout))
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.time("on_frame", |h| h.on_frame(frame))
+    }
+
+    fn on_unknown_frame(&mut self, frame: &Frame) -> Decision {
+        self.time("on_unknown_frame", |h| h.on_unknown_frame(frame))
+    }
+
+    fn on_large_frame_start(&mut self, opcode: OpCode, len: u64) -> Option<Box<dyn Write + Send>> {
+        self.time("on_large_frame_start", |h| h.on_large_frame_start(opcode, len))
+    }
+
+    fn on_large_frame_end(&mut self, len: u64) -> Result<()> {
+        self.time("on_large_frame_end", |h| h.on_large_frame_end(len))
+    }
+
+    fn on_peer_half_close(&mut self) -> Result<()> {
+        self.time("on_peer_half_close", |h| h.on_peer_half_close())
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.time("on_send_frame", |h| h.on_send_frame(frame))
+    }
+
+    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+        self.time("build_request", |h| h.build_request(url))
+    }
+
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    fn upgrade_ssl_client(
+        &mut self,
+        stream: TcpStream,
+        url: &url::Url,
+    ) -> Result<SslStream<TcpStream>> {
+        self.time("upgrade_ssl_client", |h| h.upgrade_ssl_client(stream, url))
+    }
+
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    fn upgrade_ssl_server(&mut self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        self.time("upgrade_ssl_server", |h| h.upgrade_ssl_server(stream))
+    }
+}
+
+// A frame whose payload is being streamed to the sink from `Handler::on_large_frame_start`.
+struct LargeFrame {
+    sink: Box<dyn Write + Send>,
+    mask: Option<[u8; 4]>,
+    len: u64,
+    written: u64,
+}
+
 pub struct Connection<H>
 where
     H: Handler,
@@ -91,26 +391,77 @@ where
 
     in_buffer: CircularBuffer,
     out_buffer: CircularBuffer,
+    frame_sizes: FrameSizes,
+    // Outgoing bytes beyond `Settings::spill_threshold`, which follow those in `out_buffer`.
+    spill: Option<Spill>,
 
-    handler: H,
+    handler: Timed<H>,
+    pool: BufferPool,
+    sessions: Arc<Sessions>,
+    authorizer: Option<Arc<dyn Authorizer + Send>>,
+    // The identity that this connection holds the session of.
+    session: Option<String>,
 
     addresses: Vec<SocketAddr>,
+    // The urls to try in turn once the addresses of the current one have been exhausted.
+    fallbacks: VecDeque<url::Url>,
 
     settings: Settings,
     connection_id: u32,
+    context: Context,
+    priority: Priority,
+    // The resource requested in the handshake, once the connection is open.
+    resource: Option<String>,
+    attempt: Attempt,
+
+    // Reads that have not completed the frame header that the input buffer ends with.
+    header_reads: usize,
+    empty_reads: usize,
+    linger: Option<Timeout>,
+    lingering: bool,
+    // Whether the peer shut down its side of the connection while there was still data to send.
+    peer_closed: bool,
+    pending: bool,
+    // Flush requests along with the number of buffered bytes still to be written before each.
+    flushes: Vec<(usize, mpsc::Sender<()>)>,
+    // The traced messages that are waiting to be written, by the number of bytes left until
+    // their last byte is written.
+    traces: Vec<(usize, TraceId)>,
+    // The number of bytes written to the socket so far, and when the outgoing bytes up to each
+    // offset were queued, to tell how long the oldest of them has been waiting.
+    written: u64,
+    queued: VecDeque<(u64, Instant)>,
+    taps: Vec<Box<dyn FrameObserver>>,
+    // The phase that the taps were last told about.
+    reported: ConnectionPhase,
+    // The opcode of the received frame being processed, for the context of errors.
+    opcode: Option<OpCode>,
+    large: Option<LargeFrame>,
+    #[cfg(feature = "testing-hooks")]
+    faults: Option<Faults>,
 }
 
 impl<H> Connection<H>
 where
     H: Handler,
 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         tok: Token,
         sock: TcpStream,
         handler: H,
         settings: Settings,
         connection_id: u32,
+        context: Context,
+        shared: Shared,
     ) -> Connection<H> {
+        let Shared {
+            slow,
+            pool,
+            sessions,
+            authorizer,
+        } = shared;
+        let attempt = Attempt::new(sock.peer_addr().ok());
         Connection {
             token: tok,
             socket: Stream::tcp(sock),
@@ -121,18 +472,60 @@ where
             endpoint: Endpoint::Server,
             events: Ready::empty(),
             fragments: VecDeque::with_capacity(settings.fragments_capacity),
-            in_buffer: CircularBuffer::new(
-                settings.in_buffer_capacity,
-                settings.in_buffer_capacity_hard_limit,
-            ),
-            out_buffer: CircularBuffer::new(
-                settings.out_buffer_capacity,
-                settings.out_buffer_capacity_hard_limit,
-            ),
-            handler,
+            in_buffer: if settings.strict_preallocation {
+                pooled_buffer(&pool, settings.in_buffer_capacity, settings.in_buffer_capacity)
+            } else {
+                pooled_buffer(
+                    &pool,
+                    settings.in_buffer_capacity,
+                    settings.in_buffer_capacity_hard_limit,
+                )
+            },
+            frame_sizes: FrameSizes::new(),
+            out_buffer: if settings.strict_preallocation {
+                pooled_buffer(&pool, settings.out_buffer_capacity, settings.out_buffer_capacity)
+            } else {
+                pooled_buffer(
+                    &pool,
+                    settings.out_buffer_capacity,
+                    settings.out_buffer_capacity_hard_limit,
+                )
+            },
+            handler: Timed {
+                inner: handler,
+                token: tok,
+                threshold: settings.slow_callback_threshold,
+                slow,
+            },
+            pool,
+            sessions,
+            authorizer,
+            session: None,
             addresses: Vec::new(),
+            fallbacks: VecDeque::new(),
             settings,
             connection_id,
+            context,
+            priority: settings.priority,
+            resource: None,
+            attempt,
+            header_reads: 0,
+            empty_reads: 0,
+            linger: None,
+            lingering: false,
+            peer_closed: false,
+            pending: false,
+            flushes: Vec::new(),
+            traces: Vec::new(),
+            taps: Vec::new(),
+            reported: ConnectionPhase::Connecting,
+            opcode: None,
+            large: None,
+            spill: None,
+            written: 0,
+            queued: VecDeque::new(),
+            #[cfg(feature = "testing-hooks")]
+            faults: settings.faults.map(Faults::new),
         }
     }
 
@@ -141,10 +534,85 @@ where
         Ok(())
     }
 
-    pub fn as_client(&mut self, url: url::Url, addrs: Vec<SocketAddr>) -> Result<()> {
+    // Used when the handshake request was already read by another HTTP server. The request is
+    // recorded as if it had been received on this socket and the response is queued immediately.
+    pub fn as_upgraded_server(&mut self, request: &Request) -> Result<()> {
         if let Connecting(ref mut req_buf, _) = self.state {
-            let req = self.handler.build_request(&url)?;
+            request.format(req_buf.get_mut())?;
+        } else {
+            return Err(Error::new(
+                Kind::Internal,
+                "Tried to upgrade connection while not connecting.",
+            ));
+        }
+        request.validate()?;
+        trace!("Upgraded handshake request received: \n{}", request);
+        if self.answer(request)? {
+            self.events.insert(Ready::writable());
+        } else {
+            self.events.insert(Ready::readable());
+        }
+        Ok(())
+    }
+
+    // Queue the response to a handshake request, returning false if the handler deferred it.
+    // The authorizer and the single session policy see the request first, and a request that
+    // they refuse never reaches the handler.
+    fn answer(&mut self, request: &Request) -> Result<bool> {
+        let authorizer: Option<&dyn Authorizer> = match self.authorizer {
+            Some(ref authorizer) => Some(&**authorizer),
+            None => self.settings.authorizer,
+        };
+        let refusal = match authorizer {
+            Some(authorizer) if !is_preflight(self.settings.cors, request) => authorize(
+                authorizer,
+                request,
+                self.socket.peer_addr().ok(),
+                self.socket.peer_certificate(),
+                &self.context,
+            ),
+            _ => None,
+        };
+        let refusal = match refusal {
+            None => self.sessions.refusal(self.settings.single_session, &self.context),
+            refusal => refusal,
+        };
+        let mut response = match (self.settings.cors, refusal) {
+            (_, Some(refusal)) => refusal,
+            (Some(cors), None) if Cors::is_preflight(request) => {
+                trace!("Answering preflight request.");
+                cors.preflight(request)
+            }
+            _ => self.handler.on_request(request)?,
+        };
+        if response.is_pending() {
+            trace!("Deferring handshake response.");
+            self.pending = true;
+            return Ok(false);
+        }
+        if let Some(cors) = self.settings.cors {
+            cors.apply(request, &mut response);
+        }
+        identify(response.headers_mut(), "Server", self.settings.server_ident);
+        recase(response.headers_mut(), self.settings.header_case);
+        if let Connecting(_, ref mut res) = self.state {
+            response.format(res.get_mut())?;
+        }
+        Ok(true)
+    }
+
+    pub fn as_client(
+        &mut self,
+        url: url::Url,
+        addrs: Vec<SocketAddr>,
+        fallbacks: VecDeque<url::Url>,
+    ) -> Result<()> {
+        if let Connecting(ref mut req_buf, _) = self.state {
+            let mut req = self.handler.build_request(&url)?;
+            identify(req.headers_mut(), "User-Agent", self.settings.user_agent);
+            recase(req.headers_mut(), self.settings.header_case);
             self.addresses = addrs;
+            self.fallbacks = fallbacks;
             self.events.insert(Ready::writable());
             self.endpoint = Endpoint::Client(url);
             req.format(req_buf.get_mut())
@@ -173,6 +641,7 @@ where
             Err(Error {
                 kind: Kind::SslHandshake(handshake_err),
                 details,
+                ..
             }) => match handshake_err {
                 HandshakeError::SetupFailure(_) => {
                     Err(Error::new(Kind::SslHandshake(handshake_err), details))
@@ -186,6 +655,7 @@ where
             Err(Error {
                 kind: Kind::SslHandshake(handshake_err),
                 details,
+                ..
             }) => match handshake_err {
                 HandshakeError::Failure(_) => {
                     Err(Error::new(Kind::SslHandshake(handshake_err), details))
@@ -199,6 +669,12 @@ where
         }
     }
 
+    /// Use a stream whose TLS handshake has already been completed elsewhere.
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    pub fn encrypted(&mut self, stream: SslStream<TcpStream>) {
+        self.socket = Stream::tls_live(stream);
+    }
+
     pub fn token(&self) -> Token {
         self.token
     }
@@ -211,6 +687,76 @@ where
         self.connection_id
     }
 
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority
+    }
+
+    pub fn tap(&mut self, observer: Box<dyn FrameObserver>) {
+        self.taps.push(observer)
+    }
+
+    #[inline]
+    fn observe(&mut self, direction: Direction, frame: &Frame) {
+        for tap in &mut self.taps {
+            tap.on_frame(direction, frame);
+        }
+    }
+
+    // Shrink the buffers that are mostly drained, such as after a burst of traffic.
+    pub fn shrink_buffers(&mut self, target: usize) {
+        if !self.settings.strict_preallocation {
+            self.in_buffer.apply_soft_limit(target);
+            self.out_buffer.apply_soft_limit(target);
+        }
+    }
+
+    fn phase(&self) -> ConnectionPhase {
+        match self.state {
+            Connecting(..) => ConnectionPhase::Connecting,
+            Open => ConnectionPhase::Open,
+            AwaitingClose => ConnectionPhase::AwaitingClose,
+            RespondingClose => ConnectionPhase::RespondingClose,
+            FinishedClose => ConnectionPhase::FinishedClose,
+        }
+    }
+
+    // Tell the taps that the state machine moved to another phase, along with the frame that
+    // caused it.
+    fn transitioned(&mut self, cause: Option<(Direction, OpCode)>) {
+        let phase = self.phase();
+        if phase != self.reported {
+            for tap in &mut self.taps {
+                tap.on_transition(self.reported, phase, cause);
+            }
+            self.reported = phase;
+        }
+    }
+
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        ConnectionSnapshot {
+            token: self.token,
+            connection_id: self.connection_id,
+            phase: self.phase(),
+            client: self.is_client(),
+            priority: self.priority,
+            peer_addr: self.socket.peer_addr().ok(),
+            local_addr: self.socket.local_addr().ok(),
+            resource: self.resource.clone(),
+            in_buffered: self.in_buffer.remaining(),
+            out_buffered: self.out_buffer.remaining(),
+            in_capacity: self.in_buffer.current_capacity(),
+            out_capacity: self.out_buffer.current_capacity(),
+            fragments: self.fragments.len(),
+            handshake_deferred: self.pending,
+            lingering: self.lingering,
+            flushes: self.flushes.len(),
+        }
+    }
+
     fn peer_addr(&self) -> String {
         if let Ok(addr) = self.socket.peer_addr() {
             addr.to_string()
@@ -219,21 +765,55 @@ where
         }
     }
 
+    // The next address to try for a client connection. Once the addresses of the current url are
+    // exhausted, the next fallback url that resolves replaces it, and the handshake request is
+    // built again for that url.
+    fn next_address(&mut self) -> Result<Option<SocketAddr>> {
+        while self.addresses.is_empty() {
+            let url = match self.fallbacks.pop_front() {
+                Some(url) => url,
+                None => return Ok(None),
+            };
+            self.addresses = match url_to_addrs(&url) {
+                Ok(addresses) => addresses,
+                Err(err) => {
+                    debug!("Unable to resolve fallback {}: {}", url, err);
+                    continue;
+                }
+            };
+            if let Connecting(ref mut req_buf, _) = self.state {
+                let mut req = self.handler.build_request(&url)?;
+                identify(req.headers_mut(), "User-Agent", self.settings.user_agent);
+                recase(req.headers_mut(), self.settings.header_case);
+                req_buf.get_mut().clear();
+                req.format(req_buf.get_mut())?;
+            }
+            debug!("Falling back to {}.", url);
+            self.endpoint = Client(url);
+        }
+        Ok(self.addresses.pop())
+    }
+
     // Resetting may be necessary in order to try all possible addresses for a server
     #[cfg(any(feature = "ssl", feature = "nativetls"))]
     pub fn reset(&mut self) -> Result<()> {
-        // if self.is_client() {
-        if let Client(ref url) = self.endpoint {
-            if let Connecting(ref mut req, ref mut res) = self.state {
-                req.set_position(0);
-                res.set_position(0);
+        if self.is_client() {
+            if self.state.is_connecting() {
+                let next = self.next_address()?;
+                if let Connecting(ref mut req, ref mut res) = self.state {
+                    req.set_position(0);
+                    res.set_position(0);
+                }
                 self.events.remove(Ready::readable());
                 self.events.insert(Ready::writable());
 
-                if let Some(ref addr) = self.addresses.pop() {
-                    let sock = TcpStream::connect(addr)?;
+                if let Some(ref addr) = next {
+                    let sock = connect(addr, &self.settings)?;
                     if self.socket.is_tls() {
-                        let ssl_stream = self.handler.upgrade_ssl_client(sock, url);
+                        let ssl_stream = match self.endpoint {
+                            Client(ref url) => self.handler.upgrade_ssl_client(sock, url),
+                            Server => unreachable!("Only client connections are reset."),
+                        };
                         match ssl_stream {
                             Ok(stream) => {
                                 self.socket = Stream::tls_live(stream);
@@ -243,6 +823,7 @@ where
                             Err(Error {
                                 kind: Kind::SslHandshake(handshake_err),
                                 details,
+                                ..
                             }) => match handshake_err {
                                 HandshakeError::SetupFailure(_) => {
                                     Err(Error::new(Kind::SslHandshake(handshake_err), details))
@@ -256,6 +837,7 @@ where
                             Err(Error {
                                 kind: Kind::SslHandshake(handshake_err),
                                 details,
+                                ..
                             }) => match handshake_err {
                                 HandshakeError::Failure(_) => {
                                     Err(Error::new(Kind::SslHandshake(handshake_err), details))
@@ -294,14 +876,17 @@ where
     #[cfg(not(any(feature = "ssl", feature = "nativetls")))]
     pub fn reset(&mut self) -> Result<()> {
         if self.is_client() {
-            if let Connecting(ref mut req, ref mut res) = self.state {
-                req.set_position(0);
-                res.set_position(0);
+            if self.state.is_connecting() {
+                let next = self.next_address()?;
+                if let Connecting(ref mut req, ref mut res) = self.state {
+                    req.set_position(0);
+                    res.set_position(0);
+                }
                 self.events.remove(Ready::readable());
                 self.events.insert(Ready::writable());
 
-                if let Some(ref addr) = self.addresses.pop() {
-                    let sock = TcpStream::connect(addr)?;
+                if let Some(ref addr) = next {
+                    let sock = connect(addr, &self.settings)?;
                     self.socket = Stream::tcp(sock);
                     Ok(())
                 } else {
@@ -328,6 +913,22 @@ where
         self.events
     }
 
+    pub fn is_open(&self) -> bool {
+        self.state.is_open()
+    }
+
+    pub fn info(&self) -> ConnectionInfo<'_> {
+        ConnectionInfo {
+            token: self.token,
+            connection_id: self.connection_id,
+            peer_addr: self.socket.peer_addr().ok(),
+            local_addr: self.socket.local_addr().ok(),
+            resource: self.resource.as_deref(),
+            client: self.is_client(),
+            context: &self.context,
+        }
+    }
+
     pub fn is_client(&self) -> bool {
         match self.endpoint {
             Client(_) => true,
@@ -343,13 +944,70 @@ where
     }
 
     pub fn shutdown(&mut self) {
+        self.shutdown_with(CloseCode::Away, "Shutting down.")
+    }
+
+    /// Close the connection because the WebSocket is going away, with the given code and
+    /// reason.
+    pub fn shutdown_with(&mut self, code: CloseCode, reason: &str) {
         self.handler.on_shutdown();
-        if let Err(err) = self.send_close(CloseCode::Away, "Shutting down.") {
+        if let Err(err) = self.send_close(code, reason) {
+            let err = err.with_context(self.error_context());
             self.handler.on_error(err);
             self.disconnect()
         }
     }
 
+    /// Half-close the socket once the closing handshake has completed, so that the peer sees an
+    /// orderly shutdown instead of a reset if it is still sending. Returns whether the connection
+    /// is now lingering, in which case incoming data is discarded until the peer closes its side.
+    pub fn linger(&mut self) -> bool {
+        match self.state {
+            FinishedClose if !self.lingering && self.settings.close_linger.is_some() => (),
+            _ => return false,
+        }
+        if let Err(err) = self.socket.evented().shutdown(Shutdown::Write) {
+            debug!("Unable to half-close connection to {}: {}", self.peer_addr(), err);
+            return false;
+        }
+        trace!("Lingering on closed connection to {}.", self.peer_addr());
+        self.lingering = true;
+        self.events = Ready::readable();
+        true
+    }
+
+    pub fn is_lingering(&self) -> bool {
+        self.lingering
+    }
+
+    pub fn set_linger_timeout(&mut self, timeout: Timeout) {
+        self.linger = Some(timeout)
+    }
+
+    pub fn take_linger_timeout(&mut self) -> Option<Timeout> {
+        self.linger.take()
+    }
+
+    pub fn stop_lingering(&mut self) {
+        trace!("Finished lingering on connection to {}.", self.peer_addr());
+        self.events = Ready::empty()
+    }
+
+    // Read and discard whatever the peer sends until it closes its side of the connection.
+    fn drain(&mut self) {
+        loop {
+            let discard = self.in_buffer.remaining();
+            if discard > 0 {
+                self.in_buffer.advance(discard);
+            }
+            match self.buffer_in() {
+                Ok(Some(len)) if len > 0 => continue,
+                Ok(None) => return,
+                _ => return self.stop_lingering(),
+            }
+        }
+    }
+
     #[inline]
     pub fn new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
         self.handler.on_new_timeout(event, timeout)
@@ -360,7 +1018,29 @@ where
         self.handler.on_timeout(event)
     }
 
+    // Where on this connection an error occurred, which is the frame being processed if the
+    // error was returned while reading.
+    fn error_context(&mut self) -> ErrorContext {
+        ErrorContext {
+            token: self.token,
+            // The socket forgets the address once the peer has reset the connection.
+            peer_addr: self
+                .socket
+                .peer_addr()
+                .ok()
+                .or_else(|| self.context.get::<PeerAddr>().map(|peer| peer.0)),
+            phase: match self.state {
+                Connecting(..) => Phase::Handshake,
+                Open => Phase::Open,
+                AwaitingClose | RespondingClose | FinishedClose => Phase::Closing,
+            },
+            opcode: self.opcode.take(),
+        }
+    }
+
     pub fn error(&mut self, err: Error) {
+        let err = err.with_context(self.error_context());
+        self.attempt.failed(&err);
         match self.state {
             Connecting(_, ref mut res) => match err.kind {
                 #[cfg(feature = "ssl")]
@@ -437,6 +1117,15 @@ where
                         }
                         self.disconnect()
                     }
+                    Kind::Stalled => {
+                        let reason = format!("{}", err);
+
+                        self.handler.on_error(err);
+                        if let Err(err) = self.send_close(CloseCode::Policy, reason) {
+                            self.handler.on_error(err);
+                        }
+                        self.disconnect()
+                    }
                     Kind::Protocol => {
                         if self.settings.panic_on_protocol {
                             panic!("Panicking on protocol error -- {}", err);
@@ -492,14 +1181,111 @@ where
         match self.state {
             RespondingClose | FinishedClose | Connecting(_, _) => (),
             _ => {
+                self.attempt.closed(CloseCode::Abnormal);
                 self.handler.on_close(CloseCode::Abnormal, "");
             }
         }
         self.events = Ready::empty()
     }
 
-    pub fn consume(self) -> H {
-        self.handler
+    // Complete a handshake that was deferred by `on_request`.
+    pub fn respond(&mut self, mut response: Response) -> Result<()> {
+        if !self.pending {
+            debug!(
+                "Ignoring handshake response for {} without a deferred handshake.",
+                self.peer_addr()
+            );
+            return Ok(());
+        }
+        if response.is_pending() {
+            return Ok(());
+        }
+        trace!("Completing deferred handshake with {}.", self.peer_addr());
+        if let Connecting(ref req, ref mut res) = self.state {
+            self.pending = false;
+            if let Some(cors) = self.settings.cors {
+                if let Ok(Some(request)) = Request::parse(req.get_ref()) {
+                    cors.apply(&request, &mut response);
+                }
+            }
+            identify(response.headers_mut(), "Server", self.settings.server_ident);
+            recase(response.headers_mut(), self.settings.header_case);
+            response.format(res.get_mut())?;
+            self.events.remove(Ready::readable());
+            self.events.insert(Ready::writable());
+        }
+        Ok(())
+    }
+
+    pub fn consume(mut self) -> H {
+        // Only a connection that completed the closing handshake ends its TLS session cleanly.
+        if let FinishedClose = self.state {
+            self.socket.close_notify();
+        }
+        if let Some(sink) = self.settings.audit {
+            sink.record(&self.audit_record());
+        }
+        if let Some(ref identity) = self.session {
+            self.sessions.release(identity, self.token, self.connection_id);
+        }
+        // A connection of a cluster leaves the bus, which would otherwise keep its sender.
+        if let (Some(bus), Some(address)) =
+            (self.context.get::<Bus>(), self.context.get::<Address>())
+        {
+            bus.unregister(address);
+        }
+        let limit = self.settings.buffer_pool_size;
+        if limit > 0 {
+            if let Ok(mut pool) = self.pool.lock() {
+                recycle(&mut pool, limit, self.in_buffer);
+                recycle(&mut pool, limit, self.out_buffer);
+            }
+        }
+        self.handler.inner
+    }
+
+    // Take the session of the identity of the connection, if there may only be one for each
+    // identity. Returns false if another connection holds it and keeps it.
+    fn claim_session(&mut self) -> bool {
+        let policy = match self.settings.single_session {
+            Some(policy) => policy,
+            None => return true,
+        };
+        let identity = match self.context.get::<Identity>() {
+            Some(identity) => identity.0,
+            None => return true,
+        };
+        if !self.sessions.claim(&identity, self.token, self.connection_id, policy) {
+            return false;
+        }
+        self.session = Some(identity);
+        true
+    }
+
+    fn audit_record(&self) -> AuditRecord {
+        let attempt = &self.attempt;
+        let outcome = match attempt.status {
+            _ if self.resource.is_some() => AuditOutcome::Closed,
+            Some(status) if status != 101 => AuditOutcome::Rejected,
+            _ => AuditOutcome::Failed,
+        };
+        AuditRecord {
+            timestamp: attempt.timestamp,
+            duration: attempt.started.elapsed(),
+            connection_id: self.connection_id,
+            client: self.is_client(),
+            peer_addr: attempt.peer_addr.or_else(|| self.socket.peer_addr().ok()),
+            local_addr: self.socket.local_addr().ok(),
+            resource: self.resource.clone(),
+            outcome,
+            status: attempt.status,
+            close_code: attempt.close_code,
+            error: attempt.error.clone(),
+            bytes_received: attempt.received,
+            bytes_sent: attempt.handshake_sent + self.written,
+            protocol: attempt.protocol.clone(),
+            extensions: attempt.extensions.clone(),
+        }
     }
 
     fn write_handshake(&mut self) -> Result<()> {
@@ -507,7 +1293,8 @@ where
             match self.endpoint {
                 Server => {
                     let mut done = false;
-                    if self.socket.try_write_buf(res)?.is_some() {
+                    if let Some(len) = transport!(self).try_write_buf(res)? {
+                        self.attempt.handshake_sent += len as u64;
                         if res.position() as usize == res.get_ref().len() {
                             done = true
                         }
@@ -517,7 +1304,8 @@ where
                     }
                 }
                 Client(_) => {
-                    if self.socket.try_write_buf(req)?.is_some() {
+                    if let Some(len) = transport!(self).try_write_buf(req)? {
+                        self.attempt.handshake_sent += len as u64;
                         if req.position() as usize == req.get_ref().len() {
                             trace!(
                                 "Finished writing handshake request to {}",
@@ -535,7 +1323,8 @@ where
             }
         }
 
-        if let Connecting(ref req, ref res) = replace(&mut self.state, Open) {
+        if let Connecting(req, res) = replace(&mut self.state, Open) {
+            self.transitioned(None);
             trace!(
                 "Finished writing handshake response to {}",
                 self.peer_addr()
@@ -547,6 +1336,7 @@ where
                     // An error should already have been sent for the first time it failed to
                     // parse. We don't call disconnect here because `on_open` hasn't been called yet.
                     self.state = FinishedClose;
+                    self.transitioned(None);
                     self.events = Ready::empty();
                     return Ok(());
                 }
@@ -558,18 +1348,46 @@ where
                     "Failed to parse response after handshake is complete.",
                 )
             })?;
+            self.attempt.negotiated(&response);
 
             if response.status() != 101 {
                 self.events = Ready::empty();
                 return Ok(());
             } else {
-                self.handler.on_open(Handshake {
+                let mut raw_request = req.into_inner();
+                let head = head_len(&raw_request);
+                // Anything the client sent after its request was held back until now, and may
+                // only be read as frames, under the same limits as any other frames.
+                self.in_buffer.write_all(&raw_request[head..])?;
+                raw_request.truncate(head);
+                self.resource = Some(request.resource().into());
+                let peer_addr = self.socket.peer_addr().ok();
+                if let Some(addr) = peer_addr {
+                    self.context.insert(PeerAddr(addr));
+                }
+                let claimed = self.claim_session();
+                self.handler.on_open(Handshake::new(
                     request,
                     response,
-                    peer_addr: self.socket.peer_addr().ok(),
-                    local_addr: self.socket.local_addr().ok(),
-                })?;
-                debug!("Connection to {} is now open.", self.peer_addr());
+                    peer_addr,
+                    self.socket.local_addr().ok(),
+                    raw_request,
+                    res.into_inner(),
+                ))?;
+                if !claimed {
+                    self.send_close(
+                        CloseCode::Policy,
+                        "Another connection is open for this identity.",
+                    )?;
+                }
+                debug!(
+                    token = self.token().0;
+                    "Connection to {} is now open.",
+                    self.peer_addr()
+                );
+                if !self.in_buffer.is_empty() {
+                    self.read_frames()?;
+                }
                 self.events.insert(Ready::readable());
                 self.check_events();
                 return Ok(());
@@ -586,23 +1404,48 @@ where
         if let Connecting(ref mut req, ref mut res) = self.state {
             match self.endpoint {
                 Server => {
-                    if let Some(read) = self.socket.try_read_buf(req.get_mut())? {
+                    if self.settings.strict_preallocation && is_full(req.get_ref()) {
+                        return Err(Error::new(
+                            Kind::Capacity,
+                            "Handshake request exceeded the preallocated buffer.",
+                        ));
+                    }
+                    if let Some(read) = transport!(self).try_read_buf(req.get_mut())? {
+                        self.attempt.received += read as u64;
                         if read == 0 {
                             self.events = Ready::empty();
                             return Ok(());
                         }
+                        if self.pending {
+                            // Hold on to anything sent while the handshake is deferred, it is
+                            // read as frames once the connection opens.
+                            if req.get_ref().len() > self.settings.in_buffer_capacity_hard_limit {
+                                return Err(Error::new(
+                                    Kind::Capacity,
+                                    "Received too much data while the handshake was deferred.",
+                                ));
+                            }
+                            return Ok(());
+                        }
                         if let Some(ref request) = Request::parse(req.get_ref())? {
                             trace!("Handshake request received: \n{}", request);
-                            let response = self.handler.on_request(request)?;
-                            response.format(res.get_mut())?;
-                            self.events.remove(Ready::readable());
-                            self.events.insert(Ready::writable());
+                            if self.answer(request)? {
+                                self.events.remove(Ready::readable());
+                                self.events.insert(Ready::writable());
+                            }
                         }
                     }
                     return Ok(());
                 }
                 Client(_) => {
-                    if self.socket.try_read_buf(res.get_mut())?.is_some() {
+                    if self.settings.strict_preallocation && is_full(res.get_ref()) {
+                        return Err(Error::new(
+                            Kind::Capacity,
+                            "Handshake response exceeded the preallocated buffer.",
+                        ));
+                    }
+                    if let Some(read) = transport!(self).try_read_buf(res.get_mut())? {
+                        self.attempt.received += read as u64;
                         // TODO: see if this can be optimized with drain
                         let end = {
                             let data = res.get_ref();
@@ -625,7 +1468,8 @@ where
             }
         }
 
-        if let Connecting(ref req, ref res) = replace(&mut self.state, Open) {
+        if let Connecting(req, res) = replace(&mut self.state, Open) {
+            self.transitioned(None);
             trace!(
                 "Finished reading handshake response from {}",
                 self.peer_addr()
@@ -646,6 +1490,7 @@ where
             })?;
 
             trace!("Handshake response received: \n{}", response);
+            self.attempt.negotiated(&response);
 
             if response.status() != 101 {
                 if response.status() != 301 && response.status() != 302 {
@@ -658,7 +1503,7 @@ where
             if self.settings.key_strict {
                 let req_key = request.hashed_key()?;
                 let res_key = from_utf8(response.key()?)?;
-                if req_key != res_key {
+                if !constant_time_eq(req_key.as_bytes(), res_key.as_bytes()) {
                     return Err(Error::new(
                         Kind::Protocol,
                         format!(
@@ -670,12 +1515,23 @@ where
             }
 
             self.handler.on_response(&response)?;
-            self.handler.on_open(Handshake {
+            self.resource = Some(request.resource().into());
+            let peer_addr = self.socket.peer_addr().ok();
+            if let Some(addr) = peer_addr {
+                self.context.insert(PeerAddr(addr));
+            }
+            let mut shake = Handshake::new(
                 request,
                 response,
-                peer_addr: self.socket.peer_addr().ok(),
-                local_addr: self.socket.local_addr().ok(),
-            })?;
+                peer_addr,
+                self.socket.local_addr().ok(),
+                req.into_inner(),
+                res.into_inner(),
+            );
+            if let Client(ref url) = self.endpoint {
+                shake.url = Some(url.to_string());
+            }
+            self.handler.on_open(shake)?;
 
             // check to see if there is anything to read already
             if !self.in_buffer.is_empty() {
@@ -692,7 +1548,10 @@ where
     }
 
     pub fn read(&mut self) -> Result<()> {
-        if self.socket.is_negotiating() {
+        if self.lingering {
+            self.drain();
+            Ok(())
+        } else if self.socket.is_negotiating() {
             trace!("Performing TLS negotiation on {}.", self.peer_addr());
             self.socket.clear_negotiating()?;
             self.write()
@@ -702,18 +1561,23 @@ where
                 self.read_handshake()
             } else {
                 trace!("Ready to read messages from {}.", self.peer_addr());
+                let mut empty = true;
                 while let Some(len) = self.buffer_in()? {
+                    empty = false;
                     self.read_frames()?;
+                    if len > 0 {
+                        self.check_header_progress()?;
+                    }
                     if len == 0 {
-                        if self.events.is_writable() {
-                            self.events.remove(Ready::readable());
+                        if self.buffered() > 0 {
+                            self.half_close()?;
                         } else {
                             self.disconnect()
                         }
                         break;
                     }
                 }
-                Ok(())
+                self.check_empty_read(empty)
             };
 
             if self.socket.is_negotiating() && res.is_ok() {
@@ -724,14 +1588,176 @@ where
         }
     }
 
+    // The peer will send nothing more, but there is still data for it. Keep writing, including
+    // any close frame answering one the peer sent, and drop the connection once it is flushed.
+    fn half_close(&mut self) -> Result<()> {
+        self.events.remove(Ready::readable());
+        if self.peer_closed {
+            return Ok(());
+        }
+        trace!(
+            "Connection to {} was half-closed by the peer, flushing {} bytes.",
+            self.peer_addr(),
+            self.buffered()
+        );
+        self.peer_closed = true;
+        self.handler.on_peer_half_close()
+    }
+
+    // Guard against peers that trickle a frame header, by counting the reads that end without
+    // completing one. Complete frames have been parsed out of the input buffer, so whatever
+    // remains without a complete header is a partial one.
+    fn check_header_progress(&mut self) -> Result<()> {
+        if self.in_buffer.is_empty() || Frame::has_complete_header(&mut self.in_buffer) {
+            self.header_reads = 0;
+            return Ok(());
+        }
+        self.header_reads += 1;
+        if self.header_reads > self.settings.frame_header_read_limit {
+            return Err(Error::new(
+                Kind::Stalled,
+                format!(
+                    "Received {} reads without completing a frame header.",
+                    self.header_reads
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // Guard against peers that keep the connection readable without sending any data.
+    fn check_empty_read(&mut self, empty: bool) -> Result<()> {
+        if !empty {
+            self.empty_reads = 0;
+        } else {
+            self.empty_reads += 1;
+            if self.empty_reads > self.settings.empty_read_limit {
+                return Err(Error::new(
+                    Kind::Stalled,
+                    format!(
+                        "Received {} consecutive reads without any data.",
+                        self.empty_reads
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // The next complete frame in the input buffer. Frames that are too large to buffer are
+    // streamed to the sink of the handler on the way, if it takes them.
+    fn next_frame(&mut self, max_size: u64) -> Result<Option<Frame>> {
+        loop {
+            if self.large.is_some() {
+                self.stream_large()?;
+                if self.large.is_some() {
+                    return Ok(None);
+                }
+            }
+            if let Some(frame) = self.next_small_frame(max_size) {
+                return Ok(Some(frame));
+            }
+            if !self.start_large(max_size)? {
+                return Frame::parse(&mut self.in_buffer, max_size);
+            }
+        }
+    }
+
+    fn next_small_frame(&mut self, max_size: u64) -> Option<Frame> {
+        let frame = small_frame(&mut self.in_buffer, max_size);
+        if let Some(ref frame) = frame {
+            trace!("Parsed header of small frame {:?}", frame);
+        }
+        frame
+    }
+
+    // Hand the frame at the front of the input buffer over to a sink if it is too large to
+    // buffer and the handler takes it, consuming its header.
+    fn start_large(&mut self, max_size: u64) -> Result<bool> {
+        let mut head = [0u8; MAX_HEADER_LEN];
+        let len = self.in_buffer.peek(&mut head);
+        let (header, header_len) = match decode_header(&head[..len], u64::max_value()) {
+            Ok(Some(decoded)) => decoded,
+            _ => return Ok(false),
+        };
+        if header.payload_len <= max_size
+            || !header.finished
+            || header.rsv_bits() != 0
+            || !self.fragments.is_empty()
+            || !self.state.is_open()
+        {
+            return Ok(false);
+        }
+        match header.opcode {
+            OpCode::Text | OpCode::Binary => (),
+            _ => return Ok(false),
+        }
+        if self.settings.masking_strict && header.mask.is_some() == self.is_client() {
+            return Ok(false);
+        }
+
+        let sink = match self
+            .handler
+            .on_large_frame_start(header.opcode, header.payload_len)
+        {
+            Some(sink) => sink,
+            None => return Ok(false),
+        };
+        trace!(
+            "Streaming {:?} frame of {} bytes from {}.",
+            header.opcode,
+            header.payload_len,
+            self.peer_addr()
+        );
+        self.opcode = Some(header.opcode);
+        self.in_buffer.advance(header_len);
+        self.large = Some(LargeFrame {
+            sink,
+            mask: header.mask,
+            len: header.payload_len,
+            written: 0,
+        });
+        Ok(true)
+    }
+
+    // Write as much of the streamed payload as has been received to its sink.
+    fn stream_large(&mut self) -> Result<()> {
+        if let Some(ref mut large) = self.large {
+            let mut chunk = [0u8; 4096];
+            while large.written < large.len && !self.in_buffer.is_empty() {
+                let want = min(large.len - large.written, chunk.len() as u64) as usize;
+                let read = self.in_buffer.read(&mut chunk[..want])?;
+                if let Some(mut mask) = large.mask {
+                    // Pick the mask up where the previous chunk left it.
+                    mask.rotate_left((large.written % 4) as usize);
+                    apply_mask(&mut chunk[..read], &mask);
+                }
+                large.sink.write_all(&chunk[..read])?;
+                large.written += read as u64;
+            }
+            if large.written < large.len {
+                return Ok(());
+            }
+            large.sink.flush()?;
+        }
+        if let Some(large) = self.large.take() {
+            trace!("Streamed frame of {} bytes from {}.", large.len, self.peer_addr());
+            drop(large.sink);
+            self.handler.on_large_frame_end(large.len)?;
+        }
+        Ok(())
+    }
+
     fn read_frames(&mut self) -> Result<()> {
         let max_size = self.settings.max_fragment_size as u64;
-        while let Some(mut frame) = Frame::parse(&mut self.in_buffer, max_size)? {
+        while let Some(mut frame) = self.next_frame(max_size)? {
             match self.state {
                 // Ignore data received after receiving close frame
                 RespondingClose | FinishedClose => continue,
                 _ => (),
             }
+            self.opcode = Some(frame.opcode());
+            self.frame_sizes.push(frame.len());
 
             if self.settings.masking_strict {
                 if frame.is_masked() {
@@ -753,8 +1779,20 @@ where
 
             // This is safe whether or not a frame is masked.
             frame.remove_mask();
+            self.observe(Direction::Inbound, &frame);
 
             if let Some(frame) = self.handler.on_frame(frame)? {
+                if let OpCode::Reserved(code) = frame.opcode() {
+                    match self.handler.on_unknown_frame(&frame) {
+                        Decision::Drop => trace!("Dropped frame with reserved opcode {:?}", frame),
+                        Decision::Deliver => {
+                            trace!("Delivering frame with reserved opcode {:?}", frame);
+                            self.handler.on_message(Message::Binary(frame.into_data()))?;
+                        }
+                        Decision::Fail => return Err(HeaderError::BadOpcode(code).into()),
+                    }
+                    continue;
+                }
                 if frame.is_final() {
                     match frame.opcode() {
                         // singleton data frames
@@ -765,9 +1803,10 @@ where
                             if !self.fragments.is_empty() {
                                 return Err(Error::new(Kind::Protocol, "Received unfragmented text frame while processing fragmented message."));
                             }
-                            let msg = Message::text(String::from_utf8(frame.into_data())
-                                .map_err(|err| err.utf8_error())?);
-                            self.handler.on_message(msg)?;
+                            let mut data = frame.into_data();
+                            let trace = self.decode(OpCode::Text, &mut data)?;
+                            let msg = decode_text(data, self.settings.utf8_policy)?;
+                            self.deliver(msg, trace)?;
                         }
                         OpCode::Binary => {
                             trace!("Received binary frame {:?}", frame);
@@ -776,8 +1815,9 @@ where
                             if !self.fragments.is_empty() {
                                 return Err(Error::new(Kind::Protocol, "Received unfragmented binary frame while processing fragmented message."));
                             }
-                            let data = frame.into_data();
-                            self.handler.on_message(Message::binary(data))?;
+                            let mut data = frame.into_data();
+                            let trace = self.decode(OpCode::Binary, &mut data)?;
+                            self.deliver(Message::binary(data), trace)?;
                         }
                         // control frames
                         OpCode::Close => {
@@ -794,18 +1834,15 @@ where
                             } else {
                                 // Starting handshake, will send the responding close frame
                                 self.state = RespondingClose;
+                                self.transitioned(Some((Direction::Inbound, OpCode::Close)));
                             }
 
-                            let mut close_code = [0u8; 2];
-                            let mut data = Cursor::new(frame.into_data());
-                            if let 2 = data.read(&mut close_code)? {
-                                let raw_code: u16 =
-                                    (u16::from(close_code[0]) << 8) | (u16::from(close_code[1]));
+                            let payload = frame.into_data();
+                            if let Some(raw_code) = decode_close_code(&payload) {
                                 trace!(
-                                    "Connection to {} received raw close code: {:?}, {:?}",
+                                    "Connection to {} received raw close code: {:?}",
                                     self.peer_addr(),
-                                    raw_code,
-                                    close_code
+                                    raw_code
                                 );
                                 let named = CloseCode::from(raw_code);
                                 if let CloseCode::Other(code) = named {
@@ -827,15 +1864,15 @@ where
                                         ));
                                     }
                                 }
-                                let has_reason = {
-                                    if let Ok(reason) = from_utf8(&data.get_ref()[2..]) {
-                                        self.handler.on_close(named, reason); // note reason may be an empty string
-                                        true
-                                    } else {
-                                        self.handler.on_close(named, "");
-                                        false
-                                    }
-                                };
+                                let reason = from_utf8(&payload[2..]).map(String::from);
+                                let has_reason = reason.is_ok();
+                                self.attempt.closed(named);
+                                self.handler.on_close_frame(&CloseFrame {
+                                    code: named,
+                                    // note reason may be an empty string
+                                    reason: reason.unwrap_or_default(),
+                                    payload,
+                                });
 
                                 if let CloseCode::Abnormal = named {
                                     return Err(Error::new(
@@ -847,16 +1884,6 @@ where
                                         Kind::Protocol,
                                         "Received no status close code from endpoint.",
                                     ));
-                                } else if let CloseCode::Restart = named {
-                                    return Err(Error::new(
-                                        Kind::Protocol,
-                                        "Restart close code is not supported.",
-                                    ));
-                                } else if let CloseCode::Again = named {
-                                    return Err(Error::new(
-                                        Kind::Protocol,
-                                        "Try again later close code is not supported.",
-                                    ));
                                 } else if let CloseCode::Tls = named {
                                     return Err(Error::new(
                                         Kind::Protocol,
@@ -871,6 +1898,7 @@ where
                                         }
                                     } else {
                                         self.state = FinishedClose;
+                                        self.transitioned(Some((Direction::Inbound, OpCode::Close)));
                                     }
                                 }
                             } else {
@@ -878,11 +1906,17 @@ where
                                 // protocol, so we don't trigger an error.
                                 // "If there is no such data in the Close control frame,
                                 // _The WebSocket Connection Close Reason_ is the empty string."
-                                self.handler.on_close(CloseCode::Status, "");
+                                self.attempt.closed(CloseCode::Status);
+                                self.handler.on_close_frame(&CloseFrame {
+                                    code: CloseCode::Status,
+                                    reason: String::new(),
+                                    payload,
+                                });
                                 if !self.state.is_closing() {
                                     self.send_close(CloseCode::Empty, "")?;
                                 } else {
                                     self.state = FinishedClose;
+                                    self.transitioned(Some((Direction::Inbound, OpCode::Close)));
                                 }
                             }
                         }
@@ -911,15 +1945,15 @@ where
                                             data.extend(frame.into_data());
                                         }
                                         data.extend(frame.into_data());
+                                        let trace = self.decode(OpCode::Text, &mut data)?;
 
-                                        let string = String::from_utf8(data)
-                                            .map_err(|err| err.utf8_error())?;
+                                        let msg = decode_text(data, self.settings.utf8_policy)?;
 
                                         trace!(
                                             "Calling handler with constructed message: {:?}",
-                                            string
+                                            msg
                                         );
-                                        self.handler.on_message(Message::text(string))?;
+                                        self.deliver(msg, trace)?;
                                     }
                                     OpCode::Binary => {
                                         trace!("Constructing binary message from fragments: {:?} -> {:?} -> {:?}", first, self.fragments.iter().collect::<Vec<&Frame>>(), frame);
@@ -931,12 +1965,13 @@ where
                                         }
 
                                         data.extend(frame.into_data());
+                                        let trace = self.decode(OpCode::Binary, &mut data)?;
 
                                         trace!(
                                             "Calling handler with constructed message: {:?}",
                                             data
                                         );
-                                        self.handler.on_message(Message::binary(data))?;
+                                        self.deliver(Message::binary(data), trace)?;
                                     }
                                     _ => {
                                         return Err(Error::new(
@@ -962,7 +1997,7 @@ where
                         ));
                     } else {
                         trace!("Received non-final fragment frame {:?}", frame);
-                        if !self.settings.fragments_grow
+                        if (!self.settings.fragments_grow || self.settings.strict_preallocation)
                             && self.settings.fragments_capacity == self.fragments.len()
                         {
                             return Err(Error::new(Kind::Capacity, "Exceeded max fragments."));
@@ -973,11 +2008,35 @@ where
                 }
             }
         }
+        self.opcode = None;
 
-        self.in_buffer.apply_soft_limit(self.settings.in_buffer_capacity_soft_limit);
+        if !self.settings.strict_preallocation {
+            self.in_buffer.apply_soft_limit(self.settings.in_buffer_capacity_soft_limit);
+            if self.settings.in_buffer_adaptive {
+                self.tune_in_buffer();
+            }
+        }
         Ok(())
     }
 
+    // Resize the incoming buffer toward the size that most of the recent frames fit in.
+    fn tune_in_buffer(&mut self) {
+        if let Some(size) = self.frame_sizes.percentile_95() {
+            let capacity = max(
+                size.next_power_of_two(),
+                self.settings.in_buffer_adaptive_min,
+            );
+            let capacity = min(capacity, self.settings.in_buffer_capacity_soft_limit);
+            if self.in_buffer.fit(capacity) {
+                trace!(
+                    "Tuned incoming buffer of {} to {} bytes.",
+                    self.peer_addr(),
+                    capacity
+                );
+            }
+        }
+    }
+
     pub fn write(&mut self) -> Result<()> {
         if self.socket.is_negotiating() {
             trace!("Performing TLS negotiation on {}.", self.peer_addr());
@@ -992,13 +2051,25 @@ where
 
                 // Start out assuming that this write will clear the whole buffer
                 self.events.remove(Ready::writable());
+                self.unspill()?;
 
-                if let Some(len) = self.socket.try_write_buf(&mut self.out_buffer)? {
+                if let Some(len) = transport!(self)
+                    .try_write_buf_with(&mut self.out_buffer, self.settings.write_policy)?
+                {
                     trace!("Wrote {} bytes to {}", len, self.peer_addr());
-                    self.out_buffer.apply_soft_limit(self.settings.out_buffer_capacity_soft_limit);
+                    self.flushed(len);
+                    if !self.settings.strict_preallocation {
+                        self.out_buffer
+                            .apply_soft_limit(self.settings.out_buffer_capacity_soft_limit);
+                    }
 
-                    let finished = len == 0 || self.out_buffer.is_empty();
+                    let finished = len == 0 || self.buffered() == 0;
                     if finished {
+                        if self.peer_closed && self.buffered() == 0 {
+                            trace!("Flushed half-closed connection to {}.", self.peer_addr());
+                            self.disconnect();
+                            return Ok(());
+                        }
                         match self.state {
                             // we are are a server that is closing and just wrote out our confirming
                             // close frame, let's disconnect
@@ -1024,7 +2095,62 @@ where
         }
     }
 
+    // Notify `done` once everything that is buffered now has been written to the socket.
+    pub fn flush(&mut self, done: mpsc::Sender<()>) {
+        let remaining = self.buffered();
+        if remaining == 0 {
+            let _ = done.send(());
+        } else {
+            self.flushes.push((remaining, done));
+        }
+    }
+
+    fn flushed(&mut self, len: usize) {
+        self.written += len as u64;
+        while let Some(&(end, _)) = self.queued.front() {
+            if end > self.written {
+                break;
+            }
+            self.queued.pop_front();
+        }
+
+        for &mut (ref mut remaining, ref done) in self.flushes.iter_mut() {
+            *remaining = remaining.saturating_sub(len);
+            if *remaining == 0 {
+                let _ = done.send(());
+            }
+        }
+        self.flushes.retain(|&(remaining, _)| remaining > 0);
+
+        if let Some(tracer) = self.settings.tracer {
+            for &mut (ref mut remaining, trace) in self.traces.iter_mut() {
+                *remaining = remaining.saturating_sub(len);
+                if *remaining == 0 {
+                    tracer.on_flush(self.token, trace);
+                }
+            }
+            self.traces.retain(|&(remaining, _)| remaining > 0);
+        }
+    }
+
     pub fn send_message(&mut self, msg: Message) -> Result<()> {
+        self.send_message_with(msg, true, None)
+    }
+
+    pub fn send_uncompressed(&mut self, msg: Message) -> Result<()> {
+        self.send_message_with(msg, false, None)
+    }
+
+    pub fn send_traced(&mut self, msg: Message, trace: TraceId) -> Result<()> {
+        self.send_message_with(msg, true, Some(trace))
+    }
+
+    fn send_message_with(
+        &mut self,
+        msg: Message,
+        compressible: bool,
+        trace: Option<TraceId>,
+    ) -> Result<()> {
         if self.state.is_closing() {
             trace!(
                 "Connection is closing. Ignoring request to send message {:?} to {}.",
@@ -1036,11 +2162,19 @@ where
 
         let opcode = msg.opcode();
         trace!("Message opcode {:?}", opcode);
-        let data = msg.into_data();
+        let mut data = msg.into_data();
+        if let Some(transform) = self.settings.transform {
+            transform.encode(opcode, &mut data)?;
+        }
+        if self.settings.trace_ids {
+            trace::wrap(&mut data, trace);
+        }
 
-        if let Some(frame) = self.handler
-            .on_send_frame(Frame::message(data, opcode, true))?
-        {
+        let compressible = compressible && data.len() >= self.settings.compress_min_size;
+        let mut frame = Frame::message(data, opcode, true);
+        frame.set_compressible(compressible);
+
+        if let Some(frame) = self.handler.on_send_frame(frame)? {
             if frame.payload().len() > self.settings.fragment_size {
                 trace!("Chunking at {:?}.", self.settings.fragment_size);
                 // note this copies the data, so it's actually somewhat expensive to fragment
@@ -1077,13 +2211,49 @@ where
             } else {
                 trace!("Sending unfragmented message frame.");
                 // true means that the message is done
-                self.buffer_frame(frame)?;
+                self.write_frame(frame)?;
+            }
+            if let (Some(tracer), Some(trace)) = (self.settings.tracer, trace) {
+                tracer.on_enqueue(self.token, trace);
+                match self.buffered() {
+                    0 => tracer.on_flush(self.token, trace),
+                    remaining => self.traces.push((remaining, trace)),
+                }
             }
         }
         self.check_events();
         Ok(())
     }
 
+    // Take the trace envelope and the transformation off an incoming payload.
+    #[inline]
+    fn decode(&self, opcode: OpCode, data: &mut Vec<u8>) -> Result<Option<TraceId>> {
+        let trace = if self.settings.trace_ids {
+            trace::unwrap(data)?
+        } else {
+            None
+        };
+        if let Some(transform) = self.settings.transform {
+            transform.decode(opcode, data)?;
+        }
+        Ok(trace)
+    }
+
+    // Pass a received message to the handler, with its trace ID in the context while it runs.
+    fn deliver(&mut self, msg: Message, trace: Option<TraceId>) -> Result<()> {
+        let trace = match trace {
+            Some(trace) => trace,
+            None => return self.handler.on_message(msg),
+        };
+        if let Some(tracer) = self.settings.tracer {
+            tracer.on_deliver(self.token, trace);
+        }
+        self.context.insert(trace);
+        let res = self.handler.on_message(msg);
+        self.context.remove::<TraceId>();
+        res
+    }
+
     #[inline]
     pub fn send_ping(&mut self, data: Vec<u8>) -> Result<()> {
         if self.state.is_closing() {
@@ -1161,6 +2331,8 @@ where
         {
             self.buffer_frame(frame)?;
         }
+        // The close frame was observed by now, so taps can tell its code.
+        self.transitioned(Some((Direction::Outbound, OpCode::Close)));
 
         trace!("Connection to {} is now closing.", self.peer_addr());
 
@@ -1170,8 +2342,10 @@ where
 
     fn check_events(&mut self) {
         if !self.state.is_connecting() {
-            self.events.insert(Ready::readable());
-            if !self.out_buffer.is_empty() {
+            if !self.peer_closed {
+                self.events.insert(Ready::readable());
+            }
+            if self.buffered() > 0 {
                 self.events.insert(Ready::writable());
             }
         }
@@ -1179,6 +2353,7 @@ where
 
     fn buffer_frame(&mut self, mut frame: Frame) -> Result<()> {
         self.check_buffer_out(&frame)?;
+        self.observe(Direction::Outbound, &frame);
 
         if self.is_client() {
             frame.set_mask();
@@ -1186,12 +2361,113 @@ where
 
         trace!("Buffering frame to {}:\n{}", self.peer_addr(), frame);
 
-        frame.format(&mut self.out_buffer)?;
+        if self.spills(&frame) {
+            let mut data = Vec::with_capacity(frame.len());
+            frame.format(&mut data)?;
+            if self.spill.is_none() {
+                self.spill = Some(Spill::new()?);
+            }
+            if let Some(ref mut spill) = self.spill {
+                spill.push(&data)?;
+            }
+        } else {
+            frame.format(&mut self.out_buffer)?;
+        }
+        if self.settings.write_stall_timeout_ms > 0 {
+            let end = self.written + self.buffered() as u64;
+            self.queued.push_back((end, Instant::now()));
+        }
+        Ok(())
+    }
+
+    // Write a frame straight to the socket if `write_direct` is set and nothing is waiting ahead
+    // of it, and buffer only what the socket did not take. Otherwise the frame is buffered.
+    fn write_frame(&mut self, mut frame: Frame) -> Result<()> {
+        if !self.settings.write_direct
+            || !self.state.is_open()
+            || self.socket.is_negotiating()
+            || self.buffered() > 0
+            || self.spills(&frame)
+        {
+            return self.buffer_frame(frame);
+        }
+        self.check_buffer_out(&frame)?;
+        self.observe(Direction::Outbound, &frame);
+
+        if self.is_client() {
+            frame.set_mask();
+        }
+
+        trace!("Writing frame to {}:\n{}", self.peer_addr(), frame);
+
+        let mut head = [0u8; MAX_HEADER_LEN];
+        let head_len = frame.encode(&mut head);
+        let written = {
+            let bufs = [IoSlice::new(&head[..head_len]), IoSlice::new(frame.payload())];
+            transport!(self).try_write_vectored(&bufs)?.unwrap_or(0)
+        };
+        trace!("Wrote {} bytes to {} directly.", written, self.peer_addr());
+        if written < head_len {
+            self.out_buffer.write_all(&head[written..head_len])?;
+            self.out_buffer.write_all(frame.payload())?;
+        } else {
+            self.out_buffer
+                .write_all(&frame.payload()[written - head_len..])?;
+        }
+        self.flushed(written);
+
+        if self.settings.write_stall_timeout_ms > 0 && self.buffered() > 0 {
+            let end = self.written + self.buffered() as u64;
+            self.queued.push_back((end, Instant::now()));
+        }
         Ok(())
     }
 
+    // Fail with a Stalled error once the oldest outgoing byte has waited longer than `timeout`.
+    pub fn check_write_stall(&self, timeout: Duration) -> Result<()> {
+        match self.queued.front() {
+            Some(&(_, queued)) if queued.elapsed() > timeout => Err(Error::new(
+                Kind::Stalled,
+                format!(
+                    "Outgoing data has not been written for {} ms, the peer stopped reading.",
+                    queued.elapsed().as_millis()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Whether a frame goes to the spill file rather than the outgoing buffer, which it must
+    // while anything is left in the file to keep the frames in order.
+    fn spills(&self, frame: &Frame) -> bool {
+        let threshold = self.settings.spill_threshold;
+        self.spill.as_ref().map_or(0, Spill::pending) > 0
+            || (threshold > 0 && self.out_buffer.remaining() + frame.len() > threshold)
+    }
+
+    // Move spilled bytes back into the outgoing buffer, up to the spill threshold, or all of
+    // them if spilling has been turned off since.
+    fn unspill(&mut self) -> Result<()> {
+        if let Some(ref mut spill) = self.spill {
+            let room = match self.settings.spill_threshold {
+                0 => spill.pending(),
+                threshold => threshold.saturating_sub(self.out_buffer.remaining()),
+            };
+            let moved = spill.refill(&mut self.out_buffer, room)?;
+            if moved > 0 {
+                trace!("Read back {} spilled bytes, {} left.", moved, spill.pending());
+            }
+        }
+        Ok(())
+    }
+
+    // The number of outgoing bytes waiting to be written, in memory and spilled.
+    fn buffered(&self) -> usize {
+        self.out_buffer.remaining() + self.spill.as_ref().map_or(0, Spill::pending)
+    }
+
     fn check_buffer_out(&mut self, frame: &Frame) -> Result<()> {
-        if self.out_buffer.remaining_mut() < frame.len() {
+        if !self.spills(frame) && self.out_buffer.remaining_mut() < frame.len() {
             return Err(Error::new(
                 Kind::Capacity,
                 "Reached the limit of the output buffer for the connection.",
@@ -1208,11 +2484,138 @@ where
                 "Reached the limit of the input buffer for the connection.",
             ));
         }
-        if let Some(len) = self.socket.try_read_buf(&mut self.in_buffer)? {
+        if let Some(len) = transport!(self).try_read_circular(&mut self.in_buffer)? {
             trace!("Buffered {}.", len);
+            self.attempt.received += len as u64;
             Ok(Some(len))
         } else {
             Ok(None)
         }
     }
 }
+
+// Whether a request is a preflight request that is answered according to the CORS policy.
+fn is_preflight(cors: Option<&Cors>, request: &Request) -> bool {
+    cors.is_some() && Cors::is_preflight(request)
+}
+
+// Whether reading into a handshake buffer would have to grow it.
+#[inline]
+fn is_full(buf: &Vec<u8>) -> bool {
+    buf.len() == buf.capacity()
+}
+
+// Add a header identifying this endpoint to a handshake, unless the handler has set it already.
+fn identify(headers: &mut Vec<(String, Vec<u8>)>, name: &str, ident: Option<&str>) {
+    if let Some(ident) = ident {
+        if !headers.iter().any(|&(ref key, _)| key.eq_ignore_ascii_case(name)) {
+            headers.push((name.into(), ident.into()));
+        }
+    }
+}
+
+// The next frame if it is a whole message that lies in the contiguous front of the buffer, which
+// is the case for most small messages. Its header is decoded in place and its payload copied
+// once, into the Vec that the message later takes over without copying again, skipping the
+// scratch copies and the second header decode of `start_large` and `Frame::parse`. The payload
+// still goes through a `Frame` because `on_frame`, taps and extensions work on frames. Anything
+// else, including frames that wrap around the end of the buffer and invalid headers, is left to
+// the general path.
+fn small_frame(buffer: &mut CircularBuffer, max_size: u64) -> Option<Frame> {
+    let (frame, len) = {
+        let bytes = Buf::bytes(buffer);
+        let (header, header_len) = match decode_header(bytes, max_size) {
+            Ok(Some(decoded)) => decoded,
+            _ => return None,
+        };
+        match header.opcode {
+            OpCode::Text | OpCode::Binary if header.finished => (),
+            _ => return None,
+        }
+        // The length is compared before it is cast, so that no payload length can overflow.
+        if header.payload_len > (bytes.len() - header_len) as u64 {
+            return None;
+        }
+        let len = header_len + header.payload_len as usize;
+        let payload = bytes[header_len..len].to_vec();
+        (Frame::from_header(&header, payload), len)
+    };
+    buffer.advance(len);
+    Some(frame)
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::io::Write;
+
+    fn encoded(mut frame: Frame) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        frame.format(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn small_frame_contiguous() {
+        let mut buffer = CircularBuffer::new(64, 64);
+        let text = Frame::message(b"hello".to_vec(), OpCode::Text, true);
+        let binary = Frame::message(vec![1, 2, 3], OpCode::Binary, true);
+        buffer.write_all(&encoded(text)).unwrap();
+        buffer.write_all(&encoded(binary)).unwrap();
+
+        let frame = small_frame(&mut buffer, 64).unwrap();
+        assert_eq!(frame.opcode(), OpCode::Text);
+        assert_eq!(frame.payload(), b"hello");
+        let frame = small_frame(&mut buffer, 64).unwrap();
+        assert_eq!(frame.opcode(), OpCode::Binary);
+        assert_eq!(frame.payload(), &[1, 2, 3]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn small_frame_wrapping() {
+        let bytes = encoded(Frame::message(b"hello".to_vec(), OpCode::Text, true));
+        let mut buffer = CircularBuffer::new(16, 16);
+        // Move the read cursor close to the end, so that the frame wraps around it.
+        buffer.write_all(&[0; 12]).unwrap();
+        buffer.advance(11);
+        buffer.write_all(&bytes).unwrap();
+        buffer.advance(1);
+        assert!(buffer.contiguous_prefix_len() < bytes.len());
+
+        assert!(small_frame(&mut buffer, 64).is_none());
+        assert_eq!(buffer.remaining(), bytes.len());
+        let frame = Frame::parse(&mut buffer, 64).unwrap().unwrap();
+        assert_eq!(frame.payload(), b"hello");
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn small_frame_leaves_others() {
+        let mut buffer = CircularBuffer::new(64, 64);
+        let fragment = encoded(Frame::message(b"hel".to_vec(), OpCode::Text, false));
+        buffer.write_all(&fragment).unwrap();
+        assert!(small_frame(&mut buffer, 64).is_none());
+        assert_eq!(buffer.remaining(), fragment.len());
+
+        let mut buffer = CircularBuffer::new(64, 64);
+        let ping = encoded(Frame::ping(b"p".to_vec()));
+        buffer.write_all(&ping).unwrap();
+        assert!(small_frame(&mut buffer, 64).is_none());
+        assert_eq!(buffer.remaining(), ping.len());
+
+        // Lengths that do not fit in memory are left to the general path as well.
+        let mut buffer = CircularBuffer::new(64, 64);
+        let huge = [0x81, 127, 0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 1];
+        buffer.write_all(&huge).unwrap();
+        assert!(small_frame(&mut buffer, u64::MAX).is_none());
+        assert_eq!(buffer.remaining(), huge.len());
+
+        // Incomplete frames wait for the rest of their payload.
+        let mut buffer = CircularBuffer::new(64, 64);
+        let bytes = encoded(Frame::message(b"hello".to_vec(), OpCode::Text, true));
+        buffer.write_all(&bytes[..4]).unwrap();
+        assert!(small_frame(&mut buffer, 64).is_none());
+        assert_eq!(buffer.remaining(), 4);
+    }
+}
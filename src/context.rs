@@ -0,0 +1,141 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type Map = HashMap<TypeId, Box<dyn Any + Send>>;
+
+/// Typed storage attached to a connection, holding at most one value of each type.
+///
+/// Every `Sender` for a connection shares the same context, so values stored while handling the
+/// handshake, such as the result of authenticating the request or a route parsed from its
+/// resource, are available in later callbacks and to a `Factory` that keeps the connection's
+/// sender, without keeping a separate map keyed by token.
+///
+/// ```
+/// # use parity_ws::{Handler, Handshake, Message, Request, Response, Result, Sender};
+/// struct User(String);
+///
+/// struct Server {
+///     out: Sender,
+/// }
+///
+/// impl Handler for Server {
+///     fn on_request(&mut self, req: &Request) -> Result<Response> {
+///         if let Some(user) = req.header("x-user") {
+///             let user = String::from_utf8_lossy(user).into_owned();
+///             self.out.context().insert(User(user));
+///         }
+///         Response::from_request(req)
+///     }
+///
+///     fn on_message(&mut self, msg: Message) -> Result<()> {
+///         let name = self.out.context().with(|user: &mut User| user.0.clone());
+///         self.out.send(format!("{}: {}", name.unwrap_or_default(), msg))
+///     }
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct Context {
+    values: Arc<Mutex<Map>>,
+}
+
+impl Context {
+    /// Create an empty context.
+    pub fn new() -> Context {
+        Context::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Map> {
+        // A panic while holding the lock can not leave the map itself inconsistent.
+        self.values.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Store a value, returning the value of the same type that it replaces.
+    pub fn insert<T: Any + Send>(&self, value: T) -> Option<T> {
+        self.lock()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok())
+            .map(|old| *old)
+    }
+
+    /// A copy of the stored value of type `T`.
+    pub fn get<T: Any + Send + Clone>(&self) -> Option<T> {
+        self.with(|value: &mut T| value.clone())
+    }
+
+    /// Call `func` with the stored value of type `T`, returning its result, or `None` if there
+    /// is no such value. The context is locked while `func` runs, so it must not use the
+    /// context itself.
+    pub fn with<T, F, R>(&self, func: F) -> Option<R>
+    where
+        T: Any + Send,
+        F: FnOnce(&mut T) -> R,
+    {
+        self.lock()
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_mut())
+            .map(func)
+    }
+
+    /// Whether a value of type `T` is stored.
+    pub fn contains<T: Any + Send>(&self) -> bool {
+        self.lock().contains_key(&TypeId::of::<T>())
+    }
+
+    /// Remove and return the stored value of type `T`.
+    pub fn remove<T: Any + Send>(&self) -> Option<T> {
+        self.lock()
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+
+    /// Remove every stored value.
+    pub fn clear(&self) {
+        self.lock().clear()
+    }
+}
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Context {{ values: {} }}", self.lock().len())
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct User(&'static str);
+
+    #[test]
+    fn typed_values() {
+        let context = Context::new();
+        assert_eq!(context.get::<User>(), None);
+        assert_eq!(context.insert(User("alice")), None);
+        assert_eq!(context.insert(7u32), None);
+        assert_eq!(context.insert(User("bob")), Some(User("alice")));
+
+        assert_eq!(context.get::<User>(), Some(User("bob")));
+        assert_eq!(context.with(|n: &mut u32| {
+            *n += 1;
+            *n
+        }), Some(8));
+        assert!(!context.contains::<u64>());
+
+        assert_eq!(context.remove::<u32>(), Some(8));
+        assert!(!context.contains::<u32>());
+        context.clear();
+        assert!(!context.contains::<User>());
+    }
+
+    #[test]
+    fn shared_between_clones() {
+        let context = Context::new();
+        let other = context.clone();
+        other.insert(User("alice"));
+        assert_eq!(context.get::<User>(), Some(User("alice")));
+    }
+}
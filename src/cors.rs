@@ -0,0 +1,210 @@
+use handshake::{Request, Response};
+
+/// Which cross-origin requests a server answers, for endpoints that also receive requests from
+/// browser scripts, such as health checks made with `XMLHttpRequest` or `fetch`. See
+/// `Settings::cors`.
+///
+/// Browsers send a preflight `OPTIONS` request before a cross-origin request with custom
+/// headers, and only make the request itself when the preflight is answered with the matching
+/// `Access-Control-*` headers.
+///
+/// ```
+/// use parity_ws::{Cors, Settings};
+///
+/// static CORS: Cors = Cors {
+///     origins: &["https://app.example.com"],
+///     headers: &["authorization"],
+///     credentials: true,
+///     max_age: Some(600),
+/// };
+///
+/// let mut settings = Settings::default();
+/// settings.cors = Some(&CORS);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cors {
+    /// The origins that are allowed, such as `https://app.example.com`. An origin of `*` allows
+    /// every origin.
+    pub origins: &'static [&'static str],
+    /// The request headers that cross-origin requests may have, besides the ones that browsers
+    /// allow anyway. These are compared case insensitively.
+    pub headers: &'static [&'static str],
+    /// Whether cross-origin requests may carry credentials such as cookies.
+    pub credentials: bool,
+    /// How long browsers may cache the answer to a preflight request, in seconds.
+    pub max_age: Option<u32>,
+}
+
+impl Cors {
+    /// Whether a request is a preflight request rather than a handshake.
+    pub fn is_preflight(req: &Request) -> bool {
+        req.method() == "OPTIONS"
+    }
+
+    // The origin of the request, if it is allowed.
+    fn allowed_origin<'r>(&self, req: &'r Request) -> Option<&'r str> {
+        let origin = req.origin().ok()??;
+        if self
+            .origins
+            .iter()
+            .any(|allowed| *allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+        {
+            Some(origin)
+        } else {
+            None
+        }
+    }
+
+    // Add the headers that allow the origin of the request, which a wildcard only covers for
+    // requests without credentials.
+    fn allow(&self, origin: &str, res: &mut Response) {
+        let headers = res.headers_mut();
+        if self.origins.contains(&"*") && !self.credentials {
+            headers.push(("Access-Control-Allow-Origin".into(), b"*".to_vec()));
+        } else {
+            headers.push(("Access-Control-Allow-Origin".into(), origin.into()));
+            headers.push(("Vary".into(), b"Origin".to_vec()));
+        }
+        if self.credentials {
+            headers.push(("Access-Control-Allow-Credentials".into(), b"true".to_vec()));
+        }
+    }
+
+    /// Answer a preflight request. The answer allows the `GET` method and those of the
+    /// requested headers that are allowed, or is a 403 Forbidden response if the origin is not
+    /// allowed.
+    pub fn preflight(&self, req: &Request) -> Response {
+        let origin = match self.allowed_origin(req) {
+            Some(origin) => origin,
+            None => return Response::new(403, "Forbidden", Vec::new()),
+        };
+        let mut res = Response::new(204, "No Content", Vec::new());
+        self.allow(origin, &mut res);
+        res.headers_mut().push((
+            "Access-Control-Allow-Methods".into(),
+            b"GET, OPTIONS".to_vec(),
+        ));
+
+        let requested = req
+            .header("access-control-request-headers")
+            .map(|requested| String::from_utf8_lossy(requested).into_owned())
+            .unwrap_or_default();
+        let allowed = requested
+            .split(',')
+            .map(str::trim)
+            .filter(|name| {
+                self.headers
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(name))
+            })
+            .collect::<Vec<_>>();
+        if !allowed.is_empty() {
+            res.headers_mut().push((
+                "Access-Control-Allow-Headers".into(),
+                allowed.join(", ").into(),
+            ));
+        }
+        if let Some(max_age) = self.max_age {
+            res.headers_mut()
+                .push(("Access-Control-Max-Age".into(), max_age.to_string().into()));
+        }
+        res
+    }
+
+    /// Add the headers that allow the origin of a request to a response, such as the response
+    /// to an upgrade request. Nothing is added if the origin is not allowed, or the response
+    /// already has an `Access-Control-Allow-Origin` header.
+    pub fn apply(&self, req: &Request, res: &mut Response) {
+        if res
+            .headers()
+            .iter()
+            .any(|&(ref name, _)| name.eq_ignore_ascii_case("access-control-allow-origin"))
+        {
+            return;
+        }
+        if let Some(origin) = self.allowed_origin(req) {
+            self.allow(origin, res);
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    static CORS: Cors = Cors {
+        origins: &["https://app.example.com"],
+        headers: &["Authorization"],
+        credentials: true,
+        max_age: Some(600),
+    };
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        Request::from_parts(
+            method,
+            "/",
+            headers
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+
+    fn header<'r>(res: &'r Response, name: &str) -> Option<&'r [u8]> {
+        res.headers()
+            .iter()
+            .find(|&&(ref key, _)| key.eq_ignore_ascii_case(name))
+            .map(|&(_, ref value)| &value[..])
+    }
+
+    #[test]
+    fn preflight() {
+        let req = request(
+            "OPTIONS",
+            &[
+                ("Origin", "https://app.example.com"),
+                ("Access-Control-Request-Headers", "authorization, x-debug"),
+            ],
+        );
+        assert!(Cors::is_preflight(&req));
+        let res = CORS.preflight(&req);
+        assert_eq!(res.status(), 204);
+        assert_eq!(
+            header(&res, "access-control-allow-origin"),
+            Some(&b"https://app.example.com"[..])
+        );
+        assert_eq!(
+            header(&res, "access-control-allow-headers"),
+            Some(&b"authorization"[..])
+        );
+        assert_eq!(header(&res, "access-control-max-age"), Some(&b"600"[..]));
+        assert_eq!(
+            header(&res, "access-control-allow-credentials"),
+            Some(&b"true"[..])
+        );
+
+        let req = request("OPTIONS", &[("Origin", "https://evil.example.com")]);
+        assert_eq!(CORS.preflight(&req).status(), 403);
+    }
+
+    #[test]
+    fn apply() {
+        let any = Cors {
+            origins: &["*"],
+            headers: &[],
+            credentials: false,
+            max_age: None,
+        };
+        let req = request("GET", &[("Origin", "https://a.example.com")]);
+        let mut res = Response::new(101, "Switching Protocols", Vec::new());
+        any.apply(&req, &mut res);
+        any.apply(&req, &mut res);
+        assert_eq!(header(&res, "access-control-allow-origin"), Some(&b"*"[..]));
+        // Along with the Content-Length header of a new response.
+        assert_eq!(res.headers().len(), 2);
+
+        let mut res = Response::new(101, "Switching Protocols", Vec::new());
+        CORS.apply(&req, &mut res);
+        assert_eq!(header(&res, "access-control-allow-origin"), None);
+    }
+}
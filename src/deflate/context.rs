@@ -8,6 +8,14 @@ use result::{Error, Kind, Result};
 
 const ZLIB_VERSION: &'static str = "1.2.8\0";
 
+// The Adler-32 checksum of a dictionary, which zlib uses to identify it.
+pub fn dictionary_id(dictionary: &[u8]) -> u32 {
+    unsafe {
+        let initial = ffi::adler32(0, ::std::ptr::null(), 0);
+        ffi::adler32(initial, dictionary.as_ptr(), dictionary.len() as c_uint) as u32
+    }
+}
+
 trait Context {
     fn stream(&mut self) -> &mut ffi::z_stream;
 
@@ -55,6 +63,22 @@ trait Context {
     }
 }
 
+// Compress a message and decompress it again in memory, to check that zlib works.
+pub fn self_test() -> Result<()> {
+    let data = b"permessage-deflate permessage-deflate permessage-deflate".repeat(64);
+    let mut compressed = Vec::new();
+    Compressor::new(15).compress(&data, &mut compressed)?;
+    let mut decompressed = Vec::new();
+    Decompressor::new(15).decompress(&compressed, &mut decompressed)?;
+    if decompressed != data || compressed.len() >= data.len() {
+        return Err(Error::new(
+            Kind::Internal,
+            "Compressing and decompressing a message did not give it back.",
+        ));
+    }
+    Ok(())
+}
+
 pub struct Compressor {
     // Box the z_stream to ensure it isn't moved. Moving the z_stream
     // causes zlib to fail, because it maintains internal pointers.
@@ -101,6 +125,24 @@ impl Compressor {
         })
     }
 
+    // Prime the sliding window with a dictionary. This must be done before anything is
+    // compressed, and again after every reset.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> Result<()> {
+        match unsafe {
+            ffi::deflateSetDictionary(
+                self.stream.as_mut(),
+                dictionary.as_ptr(),
+                dictionary.len() as c_uint,
+            )
+        } {
+            ffi::Z_OK => Ok(()),
+            code => Err(Error::new(
+                Kind::Protocol,
+                format!("Failed to set compression dictionary: {}", code),
+            )),
+        }
+    }
+
     pub fn reset(&mut self) -> Result<()> {
         match unsafe { ffi::deflateReset(self.stream.as_mut()) } {
             ffi::Z_OK => Ok(()),
@@ -169,6 +211,24 @@ impl Decompressor {
         })
     }
 
+    // Prime the sliding window with the dictionary that the compressor used. This must be done
+    // before anything is decompressed, and again after every reset.
+    pub fn set_dictionary(&mut self, dictionary: &[u8]) -> Result<()> {
+        match unsafe {
+            ffi::inflateSetDictionary(
+                self.stream.as_mut(),
+                dictionary.as_ptr(),
+                dictionary.len() as c_uint,
+            )
+        } {
+            ffi::Z_OK => Ok(()),
+            code => Err(Error::new(
+                Kind::Protocol,
+                format!("Failed to set decompression dictionary: {}", code),
+            )),
+        }
+    }
+
     pub fn reset(&mut self) -> Result<()> {
         match unsafe { ffi::inflateReset(self.stream.as_mut()) } {
             ffi::Z_OK => Ok(()),
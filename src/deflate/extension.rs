@@ -1,4 +1,7 @@
+use std::cmp;
+use std::io::Write;
 use std::mem::replace;
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "ssl")]
 use openssl::ssl::SslStream;
@@ -6,17 +9,18 @@ use openssl::ssl::SslStream;
 use native_tls::TlsStream as SslStream;
 use url;
 
+use context::Context;
 use frame::Frame;
-use handler::Handler;
+use handler::{Decision, Handler};
 use handshake::{Handshake, Request, Response};
 use message::Message;
-use protocol::{CloseCode, OpCode};
+use protocol::{CloseCode, CloseFrame, OpCode};
 use result::{Error, Kind, Result};
 #[cfg(any(feature = "ssl", feature = "nativetls"))]
 use util::TcpStream;
 use util::{Timeout, Token};
 
-use super::context::{Compressor, Decompressor};
+use super::context::{dictionary_id, Compressor, Decompressor};
 
 /// Deflate Extension Handler Settings
 #[derive(Debug, Clone, Copy)]
@@ -33,6 +37,19 @@ pub struct DeflateSettings {
     /// will fail if this endpoint is a client and the server requests no context takeover.
     /// Default: true
     pub accept_no_context_takeover: bool,
+    /// Indicates whether the client resets its sliding window for each message it compresses. A
+    /// client with this setting offers `client_no_context_takeover` and resets its window even
+    /// if the server does not echo it, while a server requires it in its response. This only
+    /// affects the messages sent by the client, so the direction from the server keeps its
+    /// context unless `server_no_context_takeover` is set as well.
+    /// Default: false
+    pub client_no_context_takeover: bool,
+    /// Indicates whether the server resets its sliding window for each message it compresses. A
+    /// client with this setting requests `server_no_context_takeover`, while a server includes
+    /// it in its response whether or not the client asked for it. This only affects the
+    /// messages sent by the server.
+    /// Default: false
+    pub server_no_context_takeover: bool,
     /// The number of WebSocket frames to store when defragmenting an incoming fragmented
     /// compressed message.
     /// This setting may be different from the `fragments_capacity` setting of the WebSocket in order to
@@ -43,6 +60,14 @@ pub struct DeflateSettings {
     /// exceeded. If this is not true, a capacity error will be triggered instead.
     /// Default: true
     pub fragments_grow: bool,
+    /// A preset dictionary that both endpoints prime their sliding windows with, such as a
+    /// sample of typical messages, which greatly improves the compression of short, repetitive
+    /// messages. The dictionary is only used if the other endpoint is configured with the same
+    /// one, which is agreed on with a `dictionary_id` extension parameter that carries its
+    /// Adler-32 checksum. Otherwise, the connection falls back on plain permessage-deflate. A
+    /// dictionary for a single connection can be given with `DeflateHandler::with_dictionary`.
+    /// Default: None
+    pub dictionary: Option<&'static [u8]>,
 }
 
 impl Default for DeflateSettings {
@@ -51,9 +76,77 @@ impl Default for DeflateSettings {
             max_window_bits: 15,
             request_no_context_takeover: false,
             accept_no_context_takeover: true,
+            client_no_context_takeover: false,
+            server_no_context_takeover: false,
             fragments_capacity: 10,
             fragments_grow: true,
+            dictionary: None,
+        }
+    }
+}
+
+// The value of a server_max_window_bits or client_max_window_bits parameter, which may be
+// quoted, or None if the parameter has no value.
+fn window_bits(param: &str) -> Result<Option<u8>> {
+    let value = match param.splitn(2, '=').nth(1) {
+        Some(value) => value.trim().trim_matches('"'),
+        None => return Ok(None),
+    };
+    match value.parse() {
+        Ok(bits) if bits >= 8 && bits <= 15 => Ok(Some(bits)),
+        _ => Err(Error::new(
+            Kind::Protocol,
+            format!("Invalid extension parameter: {}", param),
+        )),
+    }
+}
+
+/// Statistics about the compression of the messages on a connection, which help to decide
+/// whether permessage-deflate pays off for a particular mix of payloads.
+///
+/// Only messages that were compressed or decompressed are counted, so messages below
+/// `Settings::compress_min_size`, or sent while the extension was declined, do not affect the ratio.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompressionStats {
+    /// The number of messages that were compressed before being sent.
+    pub messages_compressed: u64,
+    /// The payload bytes of the sent messages before compression.
+    pub sent_uncompressed: u64,
+    /// The payload bytes of the sent messages after compression.
+    pub sent_compressed: u64,
+    /// The time spent compressing sent messages.
+    pub compress_time: Duration,
+    /// The number of received messages that were decompressed.
+    pub messages_decompressed: u64,
+    /// The payload bytes of the received messages as they arrived.
+    pub received_compressed: u64,
+    /// The payload bytes of the received messages after decompression.
+    pub received_uncompressed: u64,
+    /// The time spent decompressing received messages.
+    pub decompress_time: Duration,
+}
+
+impl CompressionStats {
+    /// The size of all compressed payloads relative to their uncompressed size, in both
+    /// directions, or 1.0 if nothing has been compressed yet. Lower is better.
+    pub fn ratio(&self) -> f64 {
+        let uncompressed = self.sent_uncompressed + self.received_uncompressed;
+        if uncompressed == 0 {
+            return 1.0;
         }
+        (self.sent_compressed + self.received_compressed) as f64 / uncompressed as f64
+    }
+
+    /// The number of payload bytes that compression kept off the wire in both directions. This
+    /// is negative if compression made the payloads larger.
+    pub fn bytes_saved(&self) -> i64 {
+        (self.sent_uncompressed + self.received_uncompressed) as i64
+            - (self.sent_compressed + self.received_compressed) as i64
+    }
+
+    /// The total time spent compressing and decompressing.
+    pub fn time(&self) -> Duration {
+        self.compress_time + self.decompress_time
     }
 }
 
@@ -88,6 +181,10 @@ impl DeflateBuilder {
             decompress_reset: false,
             pass: false,
             settings: self.settings,
+            stats: CompressionStats::default(),
+            context: None,
+            dictionary: self.settings.dictionary.map(|dictionary| dictionary.to_vec()),
+            use_dictionary: false,
             inner: handler,
         }
     }
@@ -107,6 +204,10 @@ pub struct DeflateHandler<H: Handler> {
     decompress_reset: bool,
     pass: bool,
     settings: DeflateSettings,
+    stats: CompressionStats,
+    context: Option<Context>,
+    dictionary: Option<Vec<u8>>,
+    use_dictionary: bool,
     inner: H,
 }
 
@@ -123,10 +224,91 @@ impl<H: Handler> DeflateHandler<H> {
             decompress_reset: false,
             pass: false,
             settings: settings,
+            stats: CompressionStats::default(),
+            context: None,
+            dictionary: None,
+            use_dictionary: false,
             inner: handler,
         }
     }
 
+    /// Keep the compression statistics of the connection up to date in its context, where the
+    /// child handler can read them through its sender.
+    ///
+    /// ```
+    /// # use parity_ws::deflate::{CompressionStats, DeflateHandler};
+    /// # use parity_ws::{Message, Sender};
+    /// # fn factory(out: Sender) -> impl parity_ws::Handler {
+    /// let context = out.context().clone();
+    /// DeflateHandler::new(move |msg: Message| {
+    ///     if let Some(stats) = out.context().get::<CompressionStats>() {
+    ///         println!("compression ratio: {:.2}", stats.ratio());
+    ///     }
+    ///     out.send(msg)
+    /// })
+    /// .with_context(context)
+    /// # }
+    /// ```
+    pub fn with_context(mut self, context: Context) -> DeflateHandler<H> {
+        context.insert(self.stats);
+        self.context = Some(context);
+        self
+    }
+
+    /// The compression statistics of the connection so far.
+    pub fn stats(&self) -> CompressionStats {
+        self.stats
+    }
+
+    /// Use a preset dictionary on this connection, such as one that was learned from the
+    /// messages of an earlier connection to the same endpoint, in place of
+    /// `DeflateSettings::dictionary`. It must be given before the handshake.
+    pub fn with_dictionary(mut self, dictionary: Vec<u8>) -> DeflateHandler<H> {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    // Whether the other endpoint offered or agreed on the dictionary of this endpoint.
+    fn dictionary_matches(&self, param: &str) -> bool {
+        let id = param.splitn(2, '=').nth(1).map(|id| id.trim());
+        match (id, self.dictionary.as_ref()) {
+            (Some(id), Some(dictionary)) => id == dictionary_id(dictionary).to_string(),
+            _ => false,
+        }
+    }
+
+    // Size the sliding windows as agreed on with the other endpoint.
+    fn configure_windows(&mut self, com_bits: u8, dec_bits: u8) {
+        if com_bits != self.settings.max_window_bits {
+            self.com = Compressor::new(com_bits as i8);
+        }
+        if dec_bits != self.settings.max_window_bits {
+            self.dec = Decompressor::new(dec_bits as i8);
+        }
+    }
+
+    // Prime the compressor with the dictionary, if one was agreed on.
+    fn prime_compressor(&mut self) -> Result<()> {
+        match self.dictionary {
+            Some(ref dictionary) if self.use_dictionary => self.com.set_dictionary(dictionary),
+            _ => Ok(()),
+        }
+    }
+
+    // Prime the decompressor with the dictionary, if one was agreed on.
+    fn prime_decompressor(&mut self) -> Result<()> {
+        match self.dictionary {
+            Some(ref dictionary) if self.use_dictionary => self.dec.set_dictionary(dictionary),
+            _ => Ok(()),
+        }
+    }
+
+    fn publish(&self) {
+        if let Some(ref context) = self.context {
+            context.insert(self.stats);
+        }
+    }
+
     #[doc(hidden)]
     #[inline]
     fn decline(&mut self, mut res: Response) -> Result<Response> {
@@ -150,15 +332,29 @@ impl<H: Handler> Handler for DeflateHandler<H> {
         } else {
             req_ext.push_str("; client_max_window_bits")
         }
-        if self.settings.request_no_context_takeover {
+        if self.settings.request_no_context_takeover || self.settings.server_no_context_takeover {
             req_ext.push_str("; server_no_context_takeover")
         }
+        if self.settings.client_no_context_takeover {
+            req_ext.push_str("; client_no_context_takeover")
+        }
+        if let Some(ref dictionary) = self.dictionary {
+            // Offer the dictionary first, and plain compression in case it is not known.
+            req.add_extension(&format!(
+                "{}; dictionary_id={}",
+                req_ext,
+                dictionary_id(dictionary)
+            ));
+        }
         req.add_extension(&req_ext);
         Ok(req)
     }
 
     fn on_request(&mut self, req: &Request) -> Result<Response> {
         let mut res = self.inner.on_request(req)?;
+        if res.is_pending() {
+            return Ok(res);
+        }
 
         'ext: for req_ext in req.extensions()?
             .iter()
@@ -169,16 +365,33 @@ impl<H: Handler> Handler for DeflateHandler<H> {
             let mut c_takeover = false;
             let mut s_max = false;
             let mut c_max = false;
+            let mut com_bits = self.settings.max_window_bits;
+            let mut dec_bits = self.settings.max_window_bits;
+            let mut dictionary = false;
 
             for param in req_ext.split(';') {
                 match param.trim() {
                     "permessage-deflate" => res_ext.push_str("permessage-deflate"),
+                    param if param.starts_with("dictionary_id") => {
+                        if dictionary {
+                            return self.decline(res);
+                        } else if self.dictionary_matches(param) {
+                            dictionary = true;
+                            res_ext.push_str("; ");
+                            res_ext.push_str(param);
+                        } else {
+                            // Look for an offer without the dictionary.
+                            continue 'ext;
+                        }
+                    }
                     "server_no_context_takeover" => {
                         if s_takeover {
                             return self.decline(res);
                         } else {
                             s_takeover = true;
-                            if self.settings.accept_no_context_takeover {
+                            if self.settings.accept_no_context_takeover
+                                || self.settings.server_no_context_takeover
+                            {
                                 self.compress_reset = true;
                                 res_ext.push_str("; server_no_context_takeover");
                             } else {
@@ -200,22 +413,15 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                             return self.decline(res);
                         } else {
                             s_max = true;
-                            let mut param_iter = param.split('=');
-                            param_iter.next(); // we already know the name
-                            if let Some(window_bits_str) = param_iter.next() {
-                                if let Ok(window_bits) = window_bits_str.trim().parse() {
-                                    if window_bits >= 9 && window_bits <= 15 {
-                                        if window_bits < self.settings.max_window_bits as i8 {
-                                            self.com = Compressor::new(window_bits);
-                                            res_ext.push_str("; ");
-                                            res_ext.push_str(param)
-                                        }
-                                    } else {
-                                        return self.decline(res);
-                                    }
-                                } else {
-                                    return self.decline(res);
+                            match window_bits(param) {
+                                // zlib can not compress with a window of 8 bits, so look for an
+                                // offer that allows a larger one.
+                                Ok(Some(8)) => continue 'ext,
+                                Ok(Some(bits)) => {
+                                    com_bits = cmp::min(bits, self.settings.max_window_bits)
                                 }
+                                // The parameter requires a value.
+                                _ => return self.decline(res),
                             }
                         }
                     }
@@ -224,29 +430,13 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                             return self.decline(res);
                         } else {
                             c_max = true;
-                            let mut param_iter = param.split('=');
-                            param_iter.next(); // we already know the name
-                            if let Some(window_bits_str) = param_iter.next() {
-                                if let Ok(window_bits) = window_bits_str.trim().parse() {
-                                    if window_bits >= 9 && window_bits <= 15 {
-                                        if window_bits < self.settings.max_window_bits as i8 {
-                                            self.dec = Decompressor::new(window_bits);
-                                            res_ext.push_str("; ");
-                                            res_ext.push_str(param);
-                                            continue;
-                                        }
-                                    } else {
-                                        return self.decline(res);
-                                    }
-                                } else {
-                                    return self.decline(res);
+                            match window_bits(param) {
+                                Ok(Some(bits)) => {
+                                    dec_bits = cmp::min(bits, self.settings.max_window_bits)
                                 }
+                                Ok(None) => (),
+                                Err(_) => return self.decline(res),
                             }
-                            res_ext.push_str("; ");
-                            res_ext.push_str(&format!(
-                                "client_max_window_bits={}",
-                                self.settings.max_window_bits
-                            ))
                         }
                     }
                     _ => {
@@ -257,24 +447,34 @@ impl<H: Handler> Handler for DeflateHandler<H> {
             }
 
             if !res_ext.contains("client_no_context_takeover")
-                && self.settings.request_no_context_takeover
+                && (self.settings.request_no_context_takeover
+                    || self.settings.client_no_context_takeover)
             {
                 self.decompress_reset = true;
                 res_ext.push_str("; client_no_context_takeover");
             }
 
-            if !res_ext.contains("server_max_window_bits") {
-                res_ext.push_str("; ");
-                res_ext.push_str(&format!(
-                    "server_max_window_bits={}",
-                    self.settings.max_window_bits
-                ))
+            if !res_ext.contains("server_no_context_takeover")
+                && self.settings.server_no_context_takeover
+            {
+                self.compress_reset = true;
+                res_ext.push_str("; server_no_context_takeover");
             }
 
-            if !res_ext.contains("client_max_window_bits") && self.settings.max_window_bits < 15 {
+            // A client that can't limit its window may use one larger than this endpoint allows.
+            if !c_max && self.settings.max_window_bits < 15 {
                 continue;
             }
 
+            res_ext.push_str(&format!("; server_max_window_bits={}", com_bits));
+            if c_max {
+                res_ext.push_str(&format!("; client_max_window_bits={}", dec_bits));
+            }
+
+            self.configure_windows(com_bits, dec_bits);
+            self.use_dictionary = dictionary;
+            self.prime_compressor()?;
+            self.prime_decompressor()?;
             res.add_extension(&res_ext);
             return Ok(res);
         }
@@ -291,9 +491,26 @@ impl<H: Handler> Handler for DeflateHandler<H> {
             let mut c_takeover = false;
             let mut s_max = false;
             let mut c_max = false;
+            let mut com_bits = self.settings.max_window_bits;
+            let mut dec_bits = self.settings.max_window_bits;
 
             for param in res_ext.split(';') {
                 match param.trim() {
+                    param if param.starts_with("dictionary_id") => {
+                        if self.use_dictionary {
+                            return Err(Error::new(
+                                Kind::Protocol,
+                                "Duplicate extension parameter dictionary_id",
+                            ));
+                        } else if self.dictionary_matches(param) {
+                            self.use_dictionary = true;
+                        } else {
+                            return Err(Error::new(
+                                Kind::Protocol,
+                                format!("Agreed on an unknown dictionary: {}", param),
+                            ));
+                        }
+                    }
                     "permessage-deflate" => {
                         if name {
                             return Err(Error::new(
@@ -323,7 +540,9 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                             ));
                         } else {
                             c_takeover = true;
-                            if self.settings.accept_no_context_takeover {
+                            if self.settings.accept_no_context_takeover
+                                || self.settings.client_no_context_takeover
+                            {
                                 self.compress_reset = true;
                             } else {
                                 return Err(Error::new(
@@ -341,31 +560,19 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                             ));
                         } else {
                             s_max = true;
-                            let mut param_iter = param.split('=');
-                            param_iter.next(); // we already know the name
-                            if let Some(window_bits_str) = param_iter.next() {
-                                if let Ok(window_bits) = window_bits_str.trim().parse() {
-                                    if window_bits >= 9 && window_bits <= 15 {
-                                        if window_bits as u8 != self.settings.max_window_bits {
-                                            self.dec = Decompressor::new(window_bits);
-                                        }
-                                    } else {
-                                        return Err(Error::new(
-                                            Kind::Protocol,
-                                            format!(
-                                                "Invalid server_max_window_bits parameter: {}",
-                                                window_bits
-                                            ),
-                                        ));
-                                    }
-                                } else {
+                            // The server may not use a larger window than was offered.
+                            match window_bits(param) {
+                                Ok(Some(bits)) if bits <= self.settings.max_window_bits => {
+                                    dec_bits = bits
+                                }
+                                _ => {
                                     return Err(Error::new(
                                         Kind::Protocol,
                                         format!(
                                             "Invalid server_max_window_bits parameter: {}",
-                                            window_bits_str
+                                            param
                                         ),
-                                    ));
+                                    ))
                                 }
                             }
                         }
@@ -378,31 +585,24 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                             ));
                         } else {
                             c_max = true;
-                            let mut param_iter = param.split('=');
-                            param_iter.next(); // we already know the name
-                            if let Some(window_bits_str) = param_iter.next() {
-                                if let Ok(window_bits) = window_bits_str.trim().parse() {
-                                    if window_bits >= 9 && window_bits <= 15 {
-                                        if window_bits as u8 != self.settings.max_window_bits {
-                                            self.com = Compressor::new(window_bits);
-                                        }
-                                    } else {
-                                        return Err(Error::new(
-                                            Kind::Protocol,
-                                            format!(
-                                                "Invalid client_max_window_bits parameter: {}",
-                                                window_bits
-                                            ),
-                                        ));
-                                    }
-                                } else {
+                            match window_bits(param) {
+                                Ok(Some(8)) => {
+                                    return Err(Error::new(
+                                        Kind::Protocol,
+                                        "Unable to compress with a window of 8 bits.",
+                                    ))
+                                }
+                                Ok(Some(bits)) if bits <= self.settings.max_window_bits => {
+                                    com_bits = bits
+                                }
+                                _ => {
                                     return Err(Error::new(
                                         Kind::Protocol,
                                         format!(
                                             "Invalid client_max_window_bits parameter: {}",
-                                            window_bits_str
+                                            param
                                         ),
-                                    ));
+                                    ))
                                 }
                             }
                         }
@@ -416,6 +616,19 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                     }
                 }
             }
+            // The server must agree to the window that was offered for it.
+            if !s_max && self.settings.max_window_bits < 15 {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    "The server did not agree to server_max_window_bits.",
+                ));
+            }
+            if self.settings.client_no_context_takeover {
+                self.compress_reset = true;
+            }
+            self.configure_windows(com_bits, dec_bits);
+            self.prime_compressor()?;
+            self.prime_decompressor()?;
         } else {
             self.pass = true
         }
@@ -461,22 +674,33 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                                 compressed.extend(frag.into_data())
                             }
 
+                            self.stats.received_compressed += compressed.len() as u64;
                             compressed.extend(&[0, 0, 255, 255]);
+                            let start = Instant::now();
                             self.dec.decompress(&compressed, &mut decompressed)?;
+                            self.stats.decompress_time += start.elapsed();
                             frame = Frame::message(decompressed, opcode, true);
                         }
                     } else {
                         let mut decompressed = Vec::with_capacity(frame.payload().len() * 2);
+                        self.stats.received_compressed += frame.payload().len() as u64;
                         frame.payload_mut().extend(&[0, 0, 255, 255]);
 
+                        let start = Instant::now();
                         self.dec.decompress(frame.payload(), &mut decompressed)?;
+                        self.stats.decompress_time += start.elapsed();
 
                         *frame.payload_mut() = decompressed;
                     }
 
                     if self.decompress_reset {
-                        self.dec.reset()?
+                        self.dec.reset()?;
+                        self.prime_decompressor()?;
                     }
+
+                    self.stats.messages_decompressed += 1;
+                    self.stats.received_uncompressed += frame.payload().len() as u64;
+                    self.publish();
                 }
             }
         }
@@ -485,7 +709,10 @@ impl<H: Handler> Handler for DeflateHandler<H> {
 
     fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
         if let Some(mut frame) = self.inner.on_send_frame(frame)? {
-            if !self.pass && !frame.is_control() {
+            if !self.pass
+                && !frame.is_control()
+                && frame.is_compressible()
+            {
                 debug_assert!(
                     frame.is_final(),
                     "Received non-final frame from upstream handler!"
@@ -497,14 +724,21 @@ impl<H: Handler> Handler for DeflateHandler<H> {
 
                 frame.set_rsv1(true);
                 let mut compressed = Vec::with_capacity(frame.payload().len());
+                let start = Instant::now();
                 self.com.compress(frame.payload(), &mut compressed)?;
+                self.stats.compress_time += start.elapsed();
                 let len = compressed.len();
                 compressed.truncate(len - 4);
+                self.stats.messages_compressed += 1;
+                self.stats.sent_uncompressed += frame.payload().len() as u64;
+                self.stats.sent_compressed += compressed.len() as u64;
                 *frame.payload_mut() = compressed;
 
                 if self.compress_reset {
-                    self.com.reset()?
+                    self.com.reset()?;
+                    self.prime_compressor()?;
                 }
+                self.publish();
             }
             Ok(Some(frame))
         } else {
@@ -532,6 +766,11 @@ impl<H: Handler> Handler for DeflateHandler<H> {
         self.inner.on_close(code, reason)
     }
 
+    #[inline]
+    fn on_close_frame(&mut self, frame: &CloseFrame) {
+        self.inner.on_close_frame(frame)
+    }
+
     #[inline]
     fn on_error(&mut self, err: Error) {
         self.inner.on_error(err)
@@ -547,6 +786,41 @@ impl<H: Handler> Handler for DeflateHandler<H> {
         self.inner.on_new_timeout(tok, timeout)
     }
 
+    #[inline]
+    fn on_ack_timeout(&mut self, id: u64, msg: Message) -> Result<()> {
+        self.inner.on_ack_timeout(id, msg)
+    }
+
+    #[inline]
+    fn on_replay_detected(&mut self, id: u64, msg: Message) -> Result<()> {
+        self.inner.on_replay_detected(id, msg)
+    }
+
+    #[inline]
+    fn on_flush_after_reconnect(&mut self, count: usize) -> Result<()> {
+        self.inner.on_flush_after_reconnect(count)
+    }
+
+    #[inline]
+    fn on_unknown_frame(&mut self, frame: &Frame) -> Decision {
+        self.inner.on_unknown_frame(frame)
+    }
+
+    #[inline]
+    fn on_large_frame_start(&mut self, opcode: OpCode, len: u64) -> Option<Box<dyn Write + Send>> {
+        self.inner.on_large_frame_start(opcode, len)
+    }
+
+    #[inline]
+    fn on_large_frame_end(&mut self, len: u64) -> Result<()> {
+        self.inner.on_large_frame_end(len)
+    }
+
+    #[inline]
+    fn on_peer_half_close(&mut self) -> Result<()> {
+        self.inner.on_peer_half_close()
+    }
+
     #[inline]
     #[cfg(any(feature = "ssl", feature = "nativetls"))]
     fn upgrade_ssl_client(
@@ -563,3 +837,254 @@ impl<H: Handler> Handler for DeflateHandler<H> {
         self.inner.upgrade_ssl_server(stream)
     }
 }
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    type Inner = fn(Message) -> Result<()>;
+
+    fn handler(settings: DeflateSettings) -> DeflateHandler<Inner> {
+        let inner: Inner = |_| Ok(());
+        DeflateBuilder::new().with_settings(settings).build(inner)
+    }
+
+    fn send(handler: &mut DeflateHandler<Inner>, frame: Frame) -> Frame {
+        handler.on_send_frame(frame).unwrap().unwrap()
+    }
+
+    #[test]
+    fn uncompressed_frames() {
+        let mut handler = handler(DeflateSettings::default());
+
+        let mut frame = Frame::message(vec![b'a'; 1024], OpCode::Binary, true);
+        frame.set_compressible(false);
+        let frame = send(&mut handler, frame);
+        assert!(!frame.has_rsv1());
+        assert_eq!(frame.payload(), &vec![b'a'; 1024]);
+    }
+
+    #[test]
+    fn compression_stats() {
+        let settings = DeflateSettings::default();
+        let mut sender = handler(settings);
+        let context = Context::new();
+        let mut receiver = handler(settings).with_context(context.clone());
+        assert_eq!(context.get(), Some(CompressionStats::default()));
+
+        let mut small = Frame::message(vec![b'a'; 16], OpCode::Binary, true);
+        small.set_compressible(false);
+        send(&mut sender, small);
+        let frame = send(&mut sender, Frame::message(vec![b'a'; 1024], OpCode::Binary, true));
+        let compressed = frame.payload().len() as u64;
+        let stats = sender.stats();
+        assert_eq!(stats.messages_compressed, 1);
+        assert_eq!(stats.sent_uncompressed, 1024);
+        assert_eq!(stats.sent_compressed, compressed);
+        assert!(stats.ratio() < 0.1);
+        assert_eq!(stats.bytes_saved(), 1024 - compressed as i64);
+
+        let frame = receiver.on_frame(frame).unwrap().unwrap();
+        assert_eq!(frame.payload(), &vec![b'a'; 1024]);
+        let stats: CompressionStats = context.get().unwrap();
+        assert_eq!(stats, receiver.stats());
+        assert_eq!(stats.messages_decompressed, 1);
+        assert_eq!(stats.received_compressed, compressed);
+        assert_eq!(stats.received_uncompressed, 1024);
+        assert_eq!(stats.messages_compressed, 0);
+    }
+
+    // Negotiate the extension between a client and a server, returning the agreed extension.
+    fn negotiate(
+        client: &mut DeflateHandler<Inner>,
+        server: &mut DeflateHandler<Inner>,
+    ) -> Option<String> {
+        let url = url::Url::parse("ws://localhost/").unwrap();
+        let req = client.build_request(&url).unwrap();
+        let res = server.on_request(&req).unwrap();
+        client.on_response(&res).unwrap();
+        let extensions = res.extensions().unwrap();
+        extensions.first().map(|ext| ext.to_string())
+    }
+
+    // Whether the second of two identical messages compresses better than the first, which
+    // it only does if the sender keeps its sliding window between messages.
+    fn takes_over_context(
+        sender: &mut DeflateHandler<Inner>,
+        receiver: &mut DeflateHandler<Inner>,
+    ) -> bool {
+        let message = b"the quick brown fox jumps over the lazy dog".to_vec();
+        let mut sizes = Vec::new();
+        for _ in 0..2 {
+            let frame = send(sender, Frame::message(message.clone(), OpCode::Text, true));
+            sizes.push(frame.payload().len());
+            let frame = receiver.on_frame(frame).unwrap().unwrap();
+            assert_eq!(frame.payload(), &message);
+        }
+        sizes[1] < sizes[0]
+    }
+
+    #[test]
+    fn context_takeover() {
+        let mut client = handler(DeflateSettings::default());
+        let mut server = handler(DeflateSettings::default());
+        let agreed = negotiate(&mut client, &mut server).unwrap();
+        assert!(!agreed.contains("no_context_takeover"));
+        assert!(takes_over_context(&mut client, &mut server));
+        assert!(takes_over_context(&mut server, &mut client));
+
+        // Only the messages of the client are compressed without context.
+        let mut settings = DeflateSettings::default();
+        settings.client_no_context_takeover = true;
+        let mut client = handler(settings);
+        let mut server = handler(DeflateSettings::default());
+        let agreed = negotiate(&mut client, &mut server).unwrap();
+        assert!(agreed.contains("client_no_context_takeover"));
+        assert!(!agreed.contains("server_no_context_takeover"));
+        assert!(!takes_over_context(&mut client, &mut server));
+        assert!(takes_over_context(&mut server, &mut client));
+
+        // A server may reset its own window without being asked to.
+        let mut settings = DeflateSettings::default();
+        settings.server_no_context_takeover = true;
+        let mut client = handler(DeflateSettings::default());
+        let mut server = handler(settings);
+        let agreed = negotiate(&mut client, &mut server).unwrap();
+        assert!(agreed.contains("server_no_context_takeover"));
+        assert!(!agreed.contains("client_no_context_takeover"));
+        assert!(takes_over_context(&mut client, &mut server));
+        assert!(!takes_over_context(&mut server, &mut client));
+
+        // A server that resets its own window agrees to do so even if it would refuse others.
+        let mut settings = DeflateSettings::default();
+        settings.accept_no_context_takeover = false;
+        settings.server_no_context_takeover = true;
+        let mut client = handler(settings);
+        let mut server = handler(settings);
+        let agreed = negotiate(&mut client, &mut server).unwrap();
+        assert!(agreed.contains("server_no_context_takeover"));
+        assert!(!takes_over_context(&mut server, &mut client));
+    }
+
+    // A message that repeats a pseudo-random block of 2 KB, which deflate only shrinks if the
+    // repetition is within the window of the compressor.
+    fn repetition() -> Vec<u8> {
+        let mut state = 1u32;
+        let block: Vec<u8> = (0..2048)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect();
+        [&block[..], &block[..]].concat()
+    }
+
+    // Send a message from one endpoint to the other, returning the size of the compressed
+    // payload.
+    fn compressed_size(
+        sender: &mut DeflateHandler<Inner>,
+        receiver: &mut DeflateHandler<Inner>,
+        message: &[u8],
+    ) -> usize {
+        let frame = send(sender, Frame::message(message.to_vec(), OpCode::Binary, true));
+        let size = frame.payload().len();
+        let frame = receiver.on_frame(frame).unwrap().unwrap();
+        assert_eq!(frame.payload(), message);
+        size
+    }
+
+    #[test]
+    fn window_bits() {
+        let message = repetition();
+        let mut small = DeflateSettings::default();
+        small.max_window_bits = 10;
+
+        let mut client = handler(DeflateSettings::default());
+        let mut server = handler(DeflateSettings::default());
+        let agreed = negotiate(&mut client, &mut server).unwrap();
+        assert!(agreed.contains("server_max_window_bits=15"));
+        assert!(agreed.contains("client_max_window_bits=15"));
+        assert!(compressed_size(&mut client, &mut server, &message) < 3072);
+
+        // Either endpoint limits the windows of both directions.
+        for &(client_settings, server_settings) in &[
+            (small, DeflateSettings::default()),
+            (DeflateSettings::default(), small),
+        ] {
+            let mut client = handler(client_settings);
+            let mut server = handler(server_settings);
+            let agreed = negotiate(&mut client, &mut server).unwrap();
+            assert!(agreed.contains("server_max_window_bits=10"), "{}", agreed);
+            assert!(agreed.contains("client_max_window_bits=10"), "{}", agreed);
+            assert!(compressed_size(&mut client, &mut server, &message) > 3072);
+            assert!(compressed_size(&mut server, &mut client, &message) > 3072);
+        }
+
+        // Offers like those of browsers, with quoted values and a window that zlib can't
+        // compress with.
+        let url = url::Url::parse("ws://localhost/").unwrap();
+        let mut req = Request::from_url(&url).unwrap();
+        req.add_extension("permessage-deflate; server_max_window_bits=8");
+        req.add_extension(
+            "permessage-deflate; server_max_window_bits=\"12\"; client_max_window_bits",
+        );
+        let mut server = handler(DeflateSettings::default());
+        let res = server.on_request(&req).unwrap();
+        let agreed = res.extensions().unwrap()[0].to_string();
+        assert!(agreed.contains("server_max_window_bits=12"), "{}", agreed);
+        assert!(agreed.contains("client_max_window_bits=15"), "{}", agreed);
+
+        // A server that uses a larger window than the client offered fails the connection.
+        let mut client = handler(small);
+        let mut res = Response::from_request(&client.build_request(&url).unwrap()).unwrap();
+        res.add_extension(
+            "permessage-deflate; server_max_window_bits=12; client_max_window_bits=10",
+        );
+        assert!(client.on_response(&res).is_err());
+    }
+
+    #[test]
+    fn dictionary() {
+        static DICTIONARY: &[u8] = br#"{"type":"tick","symbol":"","bid":,"ask":}"#;
+        let tick = br#"{"type":"tick","symbol":"ABC","bid":1.25,"ask":1.5}"#.to_vec();
+
+        let mut settings = DeflateSettings::default();
+        settings.dictionary = Some(DICTIONARY);
+        settings.request_no_context_takeover = true;
+        let mut client = handler(settings);
+        let mut server = handler(settings);
+        let agreed = negotiate(&mut client, &mut server).unwrap();
+        assert!(agreed.contains("dictionary_id"));
+
+        let mut plain_client = handler(DeflateSettings::default());
+        let mut plain_server = handler(DeflateSettings::default());
+        negotiate(&mut plain_client, &mut plain_server).unwrap();
+        let plain = send(&mut plain_client, Frame::message(tick.clone(), OpCode::Text, true));
+
+        // The window is reset after every message, so each one is primed with the dictionary.
+        for _ in 0..2 {
+            let frame = send(&mut client, Frame::message(tick.clone(), OpCode::Text, true));
+            assert!(frame.payload().len() < plain.payload().len());
+            let frame = server.on_frame(frame).unwrap().unwrap();
+            assert_eq!(frame.payload(), &tick);
+        }
+
+        // A server with another dictionary agrees on plain compression instead.
+        let mut other = settings;
+        other.dictionary = Some(b"something else");
+        let mut client = handler(settings);
+        let mut server = handler(other);
+        let agreed = negotiate(&mut client, &mut server).unwrap();
+        assert!(!agreed.contains("dictionary_id"));
+        let frame = send(&mut client, Frame::message(tick.clone(), OpCode::Text, true));
+        let frame = server.on_frame(frame).unwrap().unwrap();
+        assert_eq!(frame.payload(), &tick);
+
+        // A dictionary for a single connection takes the place of the one in the settings.
+        let mut client = handler(DeflateSettings::default()).with_dictionary(DICTIONARY.to_vec());
+        let mut server = handler(settings);
+        assert!(negotiate(&mut client, &mut server)
+            .unwrap()
+            .contains("dictionary_id"));
+    }
+}
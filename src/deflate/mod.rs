@@ -6,4 +6,7 @@ extern crate libz_sys as ffi;
 mod context;
 mod extension;
 
-pub use self::extension::{DeflateBuilder, DeflateHandler, DeflateSettings};
+pub use self::extension::{CompressionStats, DeflateBuilder, DeflateHandler, DeflateSettings};
+
+#[doc(hidden)]
+pub use self::context::self_test;
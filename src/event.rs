@@ -0,0 +1,163 @@
+use frame::Frame;
+use handler::Handler;
+use handshake::Handshake;
+use message::Message;
+use protocol::{CloseCode, OpCode};
+use result::{Error, Kind, Result};
+
+/// Something that happened on a connection, for consumers that would rather receive events than
+/// implement every method of a `Handler`. Events are produced by `EventHandler` and by
+/// `connect_channel`.
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Event {
+    /// The opening handshake completed. See `Handler::on_open`.
+    Open(Handshake),
+    /// A complete message arrived. See `Handler::on_message`.
+    Message(Message),
+    /// A frame arrived, before it is processed any further. Only delivered by an `EventHandler`
+    /// created with `with_frames`. See `Handler::on_frame`.
+    Frame(Frame),
+    /// A pong arrived with the given application data.
+    Pong(Vec<u8>),
+    /// The connection closed. See `Handler::on_close`.
+    Close {
+        /// The close code sent by the other endpoint, if any.
+        code: CloseCode,
+        /// The reason given for closing.
+        reason: String,
+    },
+    /// An error occurred on the connection. See `Handler::on_error`.
+    Error(Error),
+    /// The WebSocket is shutting down. See `Handler::on_shutdown`.
+    Shutdown,
+}
+
+/// A `Handler` that passes every event on the connection to a single callback.
+///
+/// An error returned from the callback for an `Open`, `Message`, `Frame` or `Pong` event is
+/// handled like an error returned from the corresponding `Handler` method. Errors returned for
+/// other events are only logged, because the connection is already going away.
+///
+/// # Examples
+///
+/// ```no_run
+/// use parity_ws::{listen, Event, EventHandler};
+///
+/// listen("127.0.0.1:3012", |out| {
+///     EventHandler::new(move |event| match event {
+///         Event::Message(msg) => out.send(msg),
+///         event => {
+///             println!("{:?}", event);
+///             Ok(())
+///         }
+///     })
+/// }).unwrap()
+/// ```
+pub struct EventHandler<F> {
+    callback: F,
+    frames: bool,
+}
+
+impl<F> EventHandler<F>
+where
+    F: FnMut(Event) -> Result<()>,
+{
+    /// Wrap a callback that receives connection events.
+    pub fn new(callback: F) -> EventHandler<F> {
+        EventHandler {
+            callback,
+            frames: false,
+        }
+    }
+
+    /// Also deliver a copy of every incoming frame as `Event::Frame`.
+    pub fn with_frames(mut self) -> EventHandler<F> {
+        self.frames = true;
+        self
+    }
+
+    fn notify(&mut self, event: Event) {
+        if let Err(err) = (self.callback)(event) {
+            debug!("Event callback failed: {}", err);
+        }
+    }
+}
+
+impl<F> Handler for EventHandler<F>
+where
+    F: FnMut(Event) -> Result<()>,
+{
+    fn on_shutdown(&mut self) {
+        self.notify(Event::Shutdown)
+    }
+
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        (self.callback)(Event::Open(shake))
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        (self.callback)(Event::Message(msg))
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.notify(Event::Close {
+            code,
+            reason: reason.into(),
+        })
+    }
+
+    fn on_error(&mut self, err: Error) {
+        self.notify(Event::Error(err))
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        if self.frames {
+            (self.callback)(Event::Frame(frame.clone()))?;
+        }
+        if frame.opcode() == OpCode::Pong {
+            (self.callback)(Event::Pong(frame.payload().clone()))?;
+        }
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            return Err(Error::new(
+                Kind::Protocol,
+                "Encountered frame with reserved bits set.",
+            ));
+        }
+        Ok(Some(frame))
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn callback_events() {
+        let mut events = Vec::new();
+        {
+            let mut handler = EventHandler::new(|event| {
+                events.push(format!("{:?}", event));
+                Ok(())
+            }).with_frames();
+            handler.on_message(Message::text("hi")).unwrap();
+            handler.on_frame(Frame::pong(vec![1])).unwrap();
+            handler.on_close(CloseCode::Normal, "bye");
+            handler.on_shutdown();
+        }
+        assert_eq!(events.len(), 5);
+        assert!(events[0].starts_with("Message("));
+        assert!(events[1].starts_with("Frame("));
+        assert_eq!(events[2], "Pong([1])");
+        assert_eq!(events[3], "Close { code: Normal, reason: \"bye\" }");
+        assert_eq!(events[4], "Shutdown");
+    }
+
+    #[test]
+    fn reserved_bits() {
+        let mut handler = EventHandler::new(|_| Ok(()));
+        let mut frame = Frame::message(vec![], OpCode::Text, true);
+        frame.set_rsv2(true);
+        assert!(handler.on_frame(frame).is_err());
+    }
+}
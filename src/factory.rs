@@ -1,5 +1,10 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
 use communication::Sender;
 use handler::Handler;
+use util::Token;
+use Settings;
 
 /// A trait for creating new WebSocket handlers.
 pub trait Factory {
@@ -91,6 +96,32 @@ pub trait Factory {
         self.connection_made(ws)
     }
 
+    /// Called when a TCP connection is accepted, before its handler is made, with the address
+    /// of the other endpoint and the settings of the WebSocket. The returned settings are used
+    /// for this connection only, so that for instance trusted networks can be given larger
+    /// buffers and payloads than the rest of the internet.
+    ///
+    /// Settings that apply to the whole WebSocket rather than to a connection, such as
+    /// `max_connections`, `queue_size` and the `panic_on_*` flags, are not affected.
+    ///
+    /// The default implementation returns the settings unchanged.
+    #[inline]
+    fn connection_settings(&mut self, _: SocketAddr, settings: Settings) -> Settings {
+        settings
+    }
+
+    /// Called when a callback of the handler of the connection identified by `token` took at
+    /// least `Settings::slow_callback_threshold`, with the name of the `Handler` method, such as
+    /// `"on_message"`, and the time it took. Slow callbacks are reported once per turn of the
+    /// event loop, after the events that caused them have been handled, so the connection may
+    /// be gone by then.
+    ///
+    /// The default implementation logs a warning.
+    #[inline]
+    fn on_slow_callback(&mut self, token: Token, callback: &'static str, duration: Duration) {
+        warn!("Handler of {:?} spent {:?} in {}.", token, duration, callback);
+    }
+
     /// Called when a TCP connection is lost with the handler that was
     /// setup for that connection.
     ///
@@ -165,6 +196,37 @@ mod test {
         factory.connection_made(Sender::new(mio::Token(0), chn, 0));
     }
 
+    #[test]
+    fn connection_settings() {
+        struct X;
+
+        impl Factory for X {
+            type Handler = M;
+            fn connection_made(&mut self, _: Sender) -> M {
+                M
+            }
+            fn connection_settings(&mut self, peer: SocketAddr, settings: Settings) -> Settings {
+                if peer.ip().is_loopback() {
+                    Settings {
+                        max_fragment_size: 1 << 20,
+                        ..settings
+                    }
+                } else {
+                    settings
+                }
+            }
+        }
+
+        let settings = Settings {
+            max_fragment_size: 1024,
+            ..Settings::default()
+        };
+        let local = X.connection_settings("127.0.0.1:80".parse().unwrap(), settings);
+        let remote = X.connection_settings("192.0.2.1:80".parse().unwrap(), settings);
+        assert_eq!(local.max_fragment_size, 1 << 20);
+        assert_eq!(remote.max_fragment_size, 1024);
+    }
+
     #[test]
     fn connection_lost() {
         struct X;
@@ -0,0 +1,245 @@
+//! Deterministic failures injected at the transport layer, for testing how applications cope
+//! with adverse network conditions. Only available with the `testing-hooks` feature.
+
+use std::cmp::{max, min};
+use std::io;
+use std::io::ErrorKind::{ConnectionReset, WouldBlock};
+use std::time::{Duration, Instant};
+
+/// A failure injected into a single read or write of a connection's transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Transfer at most this many bytes, at least one, in this operation.
+    Short(usize),
+    /// Fail this and the following operations in the same direction with `WouldBlock`, this
+    /// many times in total, as if the socket were repeatedly not ready.
+    WouldBlock(usize),
+    /// Report `WouldBlock` for this and the following operations in the same direction until
+    /// the duration has passed. The connection keeps polling the socket in the meantime.
+    Delay(Duration),
+    /// Fail this and every later operation, in both directions, with `ConnectionReset`.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Read,
+    Write,
+}
+
+/// A script of faults, each triggered by the index of a read or write on the transport.
+///
+/// Every connection made with a plan in `Settings::faults` follows the script independently,
+/// counting its own operations from zero. All calls made on the transport are counted,
+/// including those failed by an earlier fault, so a plan always plays out the same way.
+/// A server reads the handshake request in its first read and a client writes it in its first
+/// write, so faults at index 0 hit the opening handshake.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use parity_ws::{Fault, FaultPlan, Settings};
+///
+/// // Drop the connection half way through reading the handshake response.
+/// let mid_handshake = FaultPlan::new().read(0, Fault::Short(16)).read(1, Fault::Disconnect);
+///
+/// // A storm of 100 spurious `WouldBlock`s on reads and a slow first message.
+/// let adverse = FaultPlan::new()
+///     .read(2, Fault::WouldBlock(100))
+///     .write(1, Fault::Delay(Duration::from_millis(200)));
+///
+/// let mut settings = Settings::default();
+/// settings.faults = Some(Box::leak(Box::new(adverse)));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FaultPlan {
+    steps: Vec<(Op, usize, Fault)>,
+}
+
+impl FaultPlan {
+    /// An empty plan, which does not change the behavior of the transport.
+    pub fn new() -> FaultPlan {
+        FaultPlan::default()
+    }
+
+    /// Inject `fault` into the read with the given zero based index.
+    pub fn read(mut self, index: usize, fault: Fault) -> FaultPlan {
+        self.steps.push((Op::Read, index, fault));
+        self
+    }
+
+    /// Inject `fault` into the write with the given zero based index.
+    pub fn write(mut self, index: usize, fault: Fault) -> FaultPlan {
+        self.steps.push((Op::Write, index, fault));
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct Direction {
+    count: usize,
+    blocked: usize,
+    until: Option<Instant>,
+}
+
+/// The progress of a single connection through a `FaultPlan`.
+#[derive(Debug)]
+pub struct Faults {
+    plan: &'static FaultPlan,
+    reads: Direction,
+    writes: Direction,
+    disconnected: bool,
+}
+
+impl Faults {
+    pub fn new(plan: &'static FaultPlan) -> Faults {
+        Faults {
+            plan,
+            reads: Direction::default(),
+            writes: Direction::default(),
+            disconnected: false,
+        }
+    }
+
+    // Count an operation of up to `len` bytes and decide how many bytes it may transfer.
+    fn next(&mut self, op: Op, len: usize) -> io::Result<usize> {
+        if self.disconnected {
+            return Err(io::Error::new(ConnectionReset, "Injected disconnect."));
+        }
+        let dir = match op {
+            Op::Read => &mut self.reads,
+            Op::Write => &mut self.writes,
+        };
+        let index = dir.count;
+        dir.count += 1;
+
+        let mut limit = len;
+        for &(_, _, fault) in self
+            .plan
+            .steps
+            .iter()
+            .filter(|&&(o, i, _)| o == op && i == index)
+        {
+            match fault {
+                Fault::Short(most) => limit = min(limit, max(most, 1)),
+                Fault::WouldBlock(times) => dir.blocked += times,
+                Fault::Delay(delay) => dir.until = Some(Instant::now() + delay),
+                Fault::Disconnect => self.disconnected = true,
+            }
+        }
+
+        if self.disconnected {
+            trace!("Injecting a disconnect into {:?} {}.", op, index);
+            return Err(io::Error::new(ConnectionReset, "Injected disconnect."));
+        }
+        if dir.blocked > 0 {
+            dir.blocked -= 1;
+            return Err(io::Error::new(WouldBlock, "Injected would block."));
+        }
+        if let Some(until) = dir.until {
+            if Instant::now() < until {
+                return Err(io::Error::new(WouldBlock, "Injected delay."));
+            }
+            dir.until = None;
+        }
+        Ok(limit)
+    }
+}
+
+/// A transport with the faults of a connection applied to its reads and writes.
+pub struct Faulty<'a, S: 'a> {
+    inner: &'a mut S,
+    faults: &'a mut Option<Faults>,
+}
+
+impl<'a, S> Faulty<'a, S> {
+    pub fn new(inner: &'a mut S, faults: &'a mut Option<Faults>) -> Faulty<'a, S> {
+        Faulty { inner, faults }
+    }
+}
+
+impl<'a, S: io::Read> io::Read for Faulty<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self.faults {
+            Some(ref mut faults) if !buf.is_empty() => {
+                let len = faults.next(Op::Read, buf.len())?;
+                self.inner.read(&mut buf[..len])
+            }
+            _ => self.inner.read(buf),
+        }
+    }
+}
+
+impl<'a, S: io::Write> io::Write for Faulty<'a, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self.faults {
+            Some(ref mut faults) if !buf.is_empty() => {
+                let len = faults.next(Op::Write, buf.len())?;
+                self.inner.write(&buf[..len])
+            }
+            _ => self.inner.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        match *self.faults {
+            // A fault applies to a single write, so only the first buffer is written.
+            Some(_) => {
+                let buf = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &buf[..]);
+                self.write(buf)
+            }
+            None => self.inner.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn leak(plan: FaultPlan) -> Option<Faults> {
+        Some(Faults::new(Box::leak(Box::new(plan))))
+    }
+
+    #[test]
+    fn plan() {
+        let mut faults = leak(
+            FaultPlan::new()
+                .read(0, Fault::Short(2))
+                .read(1, Fault::WouldBlock(2))
+                .write(1, Fault::Disconnect),
+        );
+        let mut source: &[u8] = b"hello";
+        let mut buf = [0; 8];
+        {
+            let mut io = Faulty::new(&mut source, &mut faults);
+            assert_eq!(io.read(&mut buf).unwrap(), 2);
+            assert_eq!(io.read(&mut buf).unwrap_err().kind(), WouldBlock);
+            assert_eq!(io.read(&mut buf).unwrap_err().kind(), WouldBlock);
+            assert_eq!(io.read(&mut buf).unwrap(), 3);
+        }
+
+        let mut sink = io::Cursor::new(Vec::new());
+        let mut io = Faulty::new(&mut sink, &mut faults);
+        assert_eq!(io.write(b"abc").unwrap(), 3);
+        assert_eq!(io.write(b"def").unwrap_err().kind(), ConnectionReset);
+        assert_eq!(io.write(b"ghi").unwrap_err().kind(), ConnectionReset);
+        assert_eq!(io.read(&mut buf).unwrap_err().kind(), ConnectionReset);
+    }
+
+    #[test]
+    fn delay() {
+        let mut faults = leak(FaultPlan::new().write(0, Fault::Delay(Duration::from_millis(20))));
+        let mut sink = Vec::new();
+        let mut io = Faulty::new(&mut sink, &mut faults);
+        assert_eq!(io.write(b"abc").unwrap_err().kind(), WouldBlock);
+        ::std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(io.write(b"abc").unwrap(), 3);
+    }
+}
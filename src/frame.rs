@@ -1,23 +1,24 @@
+use std::cmp::min;
 use std::default::Default;
 use std::fmt;
-use std::io::{ErrorKind, Read, Write};
+use std::io::Write;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use bytes::Buf;
+use iovec::IoVec;
 use rand;
 
-use circular_buffer::CircularBuffer;
-use protocol::{CloseCode, OpCode};
-use result::{Error, Kind, Result};
-
-fn apply_mask(buf: &mut [u8], mask: &[u8; 4]) {
-    let iter = buf.iter_mut().zip(mask.iter().cycle());
-    for (byte, &key) in iter {
-        *byte ^= key
-    }
-}
+use proto::{
+    apply_mask, check_header, decode_header, encode_close_code, encode_header, Header,
+    MAX_HEADER_LEN,
+};
+use protocol::{CloseCode, CloseFrame, OpCode};
+use result::Result;
 
 /// A struct representing a WebSocket frame.
+///
+/// Besides being passed to `Handler::on_frame` and friends, frames can be built with
+/// `Frame::builder`, written with `Frame::format` and read back with `Frame::parse` without an
+/// event loop, for tools such as fuzzers, proxies and recorders.
 #[derive(Debug, Clone)]
 pub struct Frame {
     finished: bool,
@@ -29,6 +30,8 @@ pub struct Frame {
     mask: Option<[u8; 4]>,
 
     payload: Vec<u8>,
+
+    compressible: bool,
 }
 
 impl Frame {
@@ -36,21 +39,7 @@ impl Frame {
     /// This is the length of the header + the length of the payload.
     #[inline]
     pub fn len(&self) -> usize {
-        let mut header_length = 2;
-        let payload_len = self.payload().len();
-        if payload_len > 125 {
-            if payload_len <= u16::max_value() as usize {
-                header_length += 2;
-            } else {
-                header_length += 8;
-            }
-        }
-
-        if self.is_masked() {
-            header_length += 4;
-        }
-
-        header_length + payload_len
+        self.header().encoded_len() + self.payload.len()
     }
 
     /// Return `false`: a frame is never empty since it has a header.
@@ -95,22 +84,26 @@ impl Frame {
         self.opcode.is_control()
     }
 
+    /// Check the frame against the rules of its opcode, with `allowed_rsv` holding the reserved
+    /// bits that negotiated extensions use. See `proto::check_header`.
+    #[inline]
+    pub fn check(&self, allowed_rsv: u8) -> Result<()> {
+        Ok(check_header(&self.header(), allowed_rsv)?)
+    }
+
     /// Get a reference to the frame's payload.
     #[inline]
     pub fn payload(&self) -> &Vec<u8> {
         &self.payload
     }
 
-    // Test whether the frame is masked.
-    #[doc(hidden)]
+    /// Test whether the frame is masked.
     #[inline]
     pub fn is_masked(&self) -> bool {
         self.mask.is_some()
     }
 
-    // Get an optional reference to the frame's mask.
-    #[doc(hidden)]
-    #[allow(dead_code)]
+    /// Get an optional reference to the frame's mask.
     #[inline]
     pub fn mask(&self) -> Option<&[u8; 4]> {
         self.mask.as_ref()
@@ -145,6 +138,20 @@ impl Frame {
         self
     }
 
+    /// Test whether extensions may compress this frame. This is false for the frames of messages
+    /// sent with `Sender::send_uncompressed`, or smaller than `Settings::compress_min_size`.
+    #[inline]
+    pub fn is_compressible(&self) -> bool {
+        self.compressible
+    }
+
+    /// Allow or forbid extensions to compress this frame.
+    #[inline]
+    pub fn set_compressible(&mut self, compressible: bool) -> &mut Frame {
+        self.compressible = compressible;
+        self
+    }
+
     /// Set the OpCode.
     #[allow(dead_code)]
     #[inline]
@@ -160,21 +167,19 @@ impl Frame {
         &mut self.payload
     }
 
-    // Generate a new mask for this frame.
-    //
-    // This method simply generates and stores the mask. It does not change the payload data.
-    // Instead, the payload data will be masked with the generated mask when the frame is sent
-    // to the other endpoint.
-    #[doc(hidden)]
+    /// Generate a new random mask for this frame.
+    ///
+    /// This method simply generates and stores the mask. It does not change the payload data.
+    /// Instead, the payload data will be masked with the generated mask when the frame is
+    /// formatted.
     #[inline]
     pub fn set_mask(&mut self) -> &mut Frame {
         self.mask = Some(rand::random());
         self
     }
 
-    // This method unmasks the payload and should only be called on frames that are actually
-    // masked. In other words, those frames that have just been received from a client endpoint.
-    #[doc(hidden)]
+    /// Unmask the payload of a frame that was parsed with a mask, such as one sent by a client
+    /// endpoint. Frames without a mask are left as they are.
     #[inline]
     pub fn remove_mask(&mut self) -> &mut Frame {
         self.mask
@@ -188,6 +193,13 @@ impl Frame {
         self.payload
     }
 
+    /// Start building a frame with the given opcode, which may be any opcode including reserved
+    /// and invalid ones. See `FrameBuilder`.
+    #[inline]
+    pub fn builder(opcode: OpCode) -> FrameBuilder {
+        FrameBuilder::new(opcode)
+    }
+
     /// Create a new data frame.
     #[inline]
     pub fn message(data: Vec<u8>, code: OpCode, finished: bool) -> Frame {
@@ -233,9 +245,7 @@ impl Frame {
         let payload = if let CloseCode::Empty = code {
             Vec::new()
         } else {
-            let u: u16 = code.into();
-            let raw = [(u >> 8) as u8, u as u8];
-            [&raw, reason.as_bytes()].concat()
+            [&encode_close_code(code.into())[..], reason.as_bytes()].concat()
         };
 
         Frame {
@@ -244,193 +254,237 @@ impl Frame {
         }
     }
 
-    /// Parse the input stream into a frame.
-    pub fn parse(cursor: &mut CircularBuffer, max_payload_length: u64) -> Result<Option<Frame>> {
-        let size = cursor.remaining();
-        let initial = cursor.read_cursor();
-        trace!("Position in buffer {:?}", initial);
-
-        let mut head = [0u8; 2];
-        if cursor.read(&mut head)? != 2 {
-            cursor.set_read_cursor(initial);
-            return Ok(None);
-        }
-
-        trace!("Parsed headers {:?}", head);
-
-        let first = head[0];
-        let second = head[1];
-        trace!("First: {:b}", first);
-        trace!("Second: {:b}", second);
-
-        let finished = first & 0x80 != 0;
-
-        let rsv1 = first & 0x40 != 0;
-        let rsv2 = first & 0x20 != 0;
-        let rsv3 = first & 0x10 != 0;
-
-        let opcode = OpCode::from(first & 0x0F);
-        trace!("Opcode: {:?}", opcode);
-
-        let masked = second & 0x80 != 0;
-        trace!("Masked: {:?}", masked);
-
-        let mut header_length = 2;
-
-        let mut length = u64::from(second & 0x7F);
-
-        if let Some(length_nbytes) = match length {
-            126 => Some(2),
-            127 => Some(8),
-            _ => None,
-        } {
-            match cursor.read_uint::<BigEndian>(length_nbytes) {
-                Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
-                    cursor.set_read_cursor(initial);
-                    return Ok(None);
-                }
-                Err(err) => {
-                    return Err(Error::from(err));
-                }
-                Ok(read) => {
-                    length = read;
-                }
-            };
-            header_length += length_nbytes as u64;
-        }
-        trace!("Payload length: {}", length);
-
-        if length > max_payload_length {
-            return Err(Error::new(
-                Kind::Protocol,
-                format!(
-                    "Rejected frame with payload length exceeding defined max: {}.",
-                    max_payload_length
-                ),
-            ));
+    // The header that this frame is sent with.
+    fn header(&self) -> Header {
+        Header {
+            finished: self.finished,
+            rsv1: self.rsv1,
+            rsv2: self.rsv2,
+            rsv3: self.rsv3,
+            opcode: self.opcode,
+            mask: self.mask,
+            payload_len: self.payload.len() as u64,
         }
+    }
 
-        let mask = if masked {
-            let mut mask_bytes = [0u8; 4];
-            if cursor.read(&mut mask_bytes)? != 4 {
-                cursor.set_read_cursor(initial);
-                return Ok(None);
-            } else {
-                header_length += 4;
-                Some(mask_bytes)
+    /// Parse a frame from the front of `buf`.
+    ///
+    /// Returns `None` without consuming anything when `buf` does not hold a complete frame yet.
+    /// Otherwise the frame is consumed from `buf`, with its payload still masked if it has a mask,
+    /// see `remove_mask`. Frames with a payload longer than `max_payload_length` are rejected
+    /// with a `Kind::Capacity` error, and invalid headers with a `Kind::Protocol` error, which
+    /// both discard the rest of `buf`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io::Cursor;
+    /// use parity_ws::{Frame, OpCode};
+    ///
+    /// let mut buf = Cursor::new(vec![0x81, 0x02, b'h', b'i', 0x89]);
+    /// let frame = Frame::parse(&mut buf, 1024).unwrap().unwrap();
+    /// assert_eq!(frame.opcode(), OpCode::Text);
+    /// assert_eq!(frame.payload(), b"hi");
+    /// // The start of the next frame stays in the buffer until the rest of it arrives.
+    /// assert!(Frame::parse(&mut buf, 1024).unwrap().is_none());
+    /// ```
+    pub fn parse<B: Buf>(buf: &mut B, max_payload_length: u64) -> Result<Option<Frame>> {
+        // Copy as much of the header as the buffer holds without consuming it, so a header that
+        // is split across chunks, such as one that wraps around the end of a circular buffer, is
+        // decoded from the scratch instead.
+        let mut head = [0u8; MAX_HEADER_LEN];
+        let len = peek(buf, &mut head);
+        let (header, header_len) = match decode_header(&head[..len], max_payload_length) {
+            Ok(Some(decoded)) => decoded,
+            Ok(None) => return Ok(None),
+            Err(err) => {
+                // Nothing after an invalid header can be framed, so drop it rather than fail
+                // on it again while the connection closes.
+                let remaining = buf.remaining();
+                buf.advance(remaining);
+                return Err(err.into());
             }
-        } else {
-            None
         };
+        trace!("Parsed header {:?}", header);
 
-        match length.checked_add(header_length) {
-            Some(l) if (size as u64) < l => {
-                cursor.set_read_cursor(initial);
-                return Ok(None);
-            }
+        match header.payload_len.checked_add(header_len as u64) {
+            Some(len) if (buf.remaining() as u64) < len => return Ok(None),
             Some(_) => (),
             None => return Ok(None),
         };
 
-        let data = cursor.read_exact_into_vec(length as usize);
+        buf.advance(header_len);
+        let data = take(buf, header.payload_len as usize);
 
-        // Disallow bad opcode
-        if let OpCode::Bad = opcode {
-            return Err(Error::new(
-                Kind::Protocol,
-                format!("Encountered invalid opcode: {}", first & 0x0F),
-            ));
+        if header.opcode == OpCode::Close && header.payload_len > 125 {
+            debug!("Received close frame with payload length exceeding 125. Morphing to protocol close frame.");
+            return Ok(Some(Frame::close(
+                CloseCode::Protocol,
+                "Received close frame with payload length exceeding 125.",
+            )));
         }
 
-        // control frames must have length <= 125
-        match opcode {
-            OpCode::Ping | OpCode::Pong if length > 125 => {
-                return Err(Error::new(
-                    Kind::Protocol,
-                    format!(
-                        "Rejected WebSocket handshake.Received control frame with length: {}.",
-                        length
-                    ),
-                ))
-            }
-            OpCode::Close if length > 125 => {
-                debug!("Received close frame with payload length exceeding 125. Morphing to protocol close frame.");
-                return Ok(Some(Frame::close(
-                    CloseCode::Protocol,
-                    "Received close frame with payload length exceeding 125.",
-                )));
-            }
-            _ => (),
-        }
+        Ok(Some(Frame::from_header(&header, data)))
+    }
 
-        let frame = Frame {
-            finished,
-            rsv1,
-            rsv2,
-            rsv3,
-            opcode,
-            mask,
-            payload: data,
-        };
+    // The frame that was received with `header`, with its payload still masked.
+    #[doc(hidden)]
+    pub fn from_header(header: &Header, payload: Vec<u8>) -> Frame {
+        Frame {
+            finished: header.finished,
+            rsv1: header.rsv1,
+            rsv2: header.rsv2,
+            rsv3: header.rsv3,
+            opcode: header.opcode,
+            mask: header.mask,
+            payload,
+            compressible: true,
+        }
+    }
 
-        Ok(Some(frame))
+    // Test whether the buffer begins with a complete frame header, without consuming anything.
+    #[doc(hidden)]
+    pub fn has_complete_header<B: Buf>(buf: &mut B) -> bool {
+        let mut head = [0u8; MAX_HEADER_LEN];
+        let len = peek(buf, &mut head);
+        // An invalid header is complete enough to be rejected.
+        decode_header(&head[..len], u64::max_value())
+            .map(|header| header.is_some())
+            .unwrap_or(true)
     }
 
-    /// Write a frame out to a buffer
+    /// Write a frame out to a buffer, masking the payload if the frame has a mask.
     pub fn format<W>(&mut self, w: &mut W) -> Result<()>
     where
         W: Write,
     {
-        let mut one = 0u8;
-        let code: u8 = self.opcode.into();
-        if self.is_final() {
-            one |= 0x80;
-        }
-        if self.has_rsv1() {
-            one |= 0x40;
-        }
-        if self.has_rsv2() {
-            one |= 0x20;
-        }
-        if self.has_rsv3() {
-            one |= 0x10;
-        }
-        one |= code;
+        let mut head = [0u8; MAX_HEADER_LEN];
+        let len = self.encode(&mut head);
+        w.write_all(&head[..len])?;
+        w.write_all(&self.payload)?;
+        Ok(())
+    }
 
-        let mut two = 0u8;
-        if self.is_masked() {
-            two |= 0x80;
+    // Encode the header into `head` and mask the payload, so that the frame goes on the wire as
+    // the returned number of bytes of `head` followed by the payload.
+    #[doc(hidden)]
+    pub fn encode(&mut self, head: &mut [u8; MAX_HEADER_LEN]) -> usize {
+        let len = encode_header(&self.header(), head);
+        if let Some(mask) = self.mask.take() {
+            apply_mask(&mut self.payload, &mask);
         }
+        len
+    }
+}
 
-        match self.payload.len() {
-            len if len < 126 => {
-                two |= len as u8;
-            }
-            len if len <= 65535 => {
-                two |= 126;
-            }
-            _ => {
-                two |= 127;
-            }
-        }
-        w.write_all(&[one, two])?;
-
-        if let Some(length_bytes) = match self.payload.len() {
-            len if len < 126 => None,
-            len if len <= 65535 => Some(2),
-            _ => Some(8),
-        } {
-            w.write_uint::<BigEndian>(self.payload.len() as u64, length_bytes)?;
-        }
+// Copy bytes from the front of `buf` into `dst` without consuming them, from as many of its
+// chunks as needed. Returns the number of bytes copied.
+fn peek<B: Buf>(buf: &B, dst: &mut [u8]) -> usize {
+    // Every chunk holds at least a byte, so this is enough to fill a header. An `IoVec` can't
+    // be empty, so the slots start out pointing at a placeholder.
+    let mut chunks: [&IoVec; MAX_HEADER_LEN] = [(&b"\0"[..]).into(); MAX_HEADER_LEN];
+    let count = buf.bytes_vec(&mut chunks);
+    let mut len = 0;
+    for chunk in &chunks[..count] {
+        let copy = min(dst.len() - len, chunk.len());
+        dst[len..len + copy].copy_from_slice(&chunk[..copy]);
+        len += copy;
+    }
+    len
+}
 
-        if self.is_masked() {
-            let mask = self.mask.take().unwrap();
-            apply_mask(&mut self.payload, &mask);
-            w.write_all(&mask)?;
+// Consume `len` bytes from the front of `buf`, which must hold them.
+fn take<B: Buf>(buf: &mut B, len: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(len);
+    while output.len() < len {
+        let copy = min(len - output.len(), buf.bytes().len());
+        output.extend_from_slice(&buf.bytes()[..copy]);
+        buf.advance(copy);
+    }
+    output
+}
+
+/// A builder for frames with any combination of header fields, including ones that this
+/// library would never send, such as reserved opcodes, reserved bits, or fragmented control
+/// frames.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Cursor;
+/// use parity_ws::{Frame, OpCode};
+///
+/// let mut frame = Frame::builder(OpCode::Text)
+///     .fin(false)
+///     .rsv1(true)
+///     .mask([1, 2, 3, 4])
+///     .payload("Hello")
+///     .build();
+///
+/// let mut bytes = Vec::new();
+/// frame.format(&mut bytes).unwrap();
+///
+/// let mut parsed = Frame::parse(&mut Cursor::new(bytes), 1024).unwrap().unwrap();
+/// assert!(!parsed.is_final() && parsed.has_rsv1());
+/// parsed.remove_mask();
+/// assert_eq!(parsed.payload(), b"Hello");
+/// ```
+#[derive(Debug, Clone)]
+pub struct FrameBuilder {
+    frame: Frame,
+}
+
+impl FrameBuilder {
+    /// A final, unmasked frame with the given opcode and an empty payload.
+    pub fn new(opcode: OpCode) -> FrameBuilder {
+        FrameBuilder {
+            frame: Frame {
+                opcode,
+                ..Frame::default()
+            },
         }
+    }
 
-        w.write_all(&self.payload)?;
-        Ok(())
+    /// Set whether this is the final frame of a message.
+    pub fn fin(mut self, finished: bool) -> FrameBuilder {
+        self.frame.finished = finished;
+        self
+    }
+
+    /// Set the first reserved bit.
+    pub fn rsv1(mut self, rsv1: bool) -> FrameBuilder {
+        self.frame.rsv1 = rsv1;
+        self
+    }
+
+    /// Set the second reserved bit.
+    pub fn rsv2(mut self, rsv2: bool) -> FrameBuilder {
+        self.frame.rsv2 = rsv2;
+        self
+    }
+
+    /// Set the third reserved bit.
+    pub fn rsv3(mut self, rsv3: bool) -> FrameBuilder {
+        self.frame.rsv3 = rsv3;
+        self
+    }
+
+    /// Mask the payload with the given key when the frame is formatted, as clients must.
+    /// Use `Frame::set_mask` on the built frame for a random key.
+    pub fn mask(mut self, key: [u8; 4]) -> FrameBuilder {
+        self.frame.mask = Some(key);
+        self
+    }
+
+    /// Set the unmasked payload.
+    pub fn payload<P: Into<Vec<u8>>>(mut self, payload: P) -> FrameBuilder {
+        self.frame.payload = payload.into();
+        self
+    }
+
+    /// Build the frame.
+    pub fn build(self) -> Frame {
+        self.frame
     }
 }
 
@@ -444,6 +498,16 @@ impl Default for Frame {
             opcode: OpCode::Close,
             mask: None,
             payload: Vec::new(),
+            compressible: true,
+        }
+    }
+}
+
+impl From<CloseFrame> for Frame {
+    fn from(close: CloseFrame) -> Frame {
+        Frame {
+            payload: close.payload,
+            ..Frame::default()
         }
     }
 }
@@ -480,6 +544,7 @@ payload: 0x{}
 mod test {
     #![allow(unused_imports, unused_variables, dead_code)]
     use super::*;
+    use circular_buffer::CircularBuffer;
     use protocol::OpCode;
 
     #[test]
@@ -488,4 +553,93 @@ mod test {
         let view = format!("{}", f);
         view.contains("payload:");
     }
+
+    #[test]
+    fn complete_header() {
+        use std::io::Write;
+
+        let mut buf = CircularBuffer::new(0, 64);
+        assert!(!Frame::has_complete_header(&mut buf));
+        buf.write_all(&[0x81, 0xFE, 0x00]).unwrap();
+        assert!(!Frame::has_complete_header(&mut buf));
+        buf.write_all(&[0x80, 1, 2, 3]).unwrap();
+        assert!(!Frame::has_complete_header(&mut buf));
+        buf.write_all(&[4]).unwrap();
+        assert!(Frame::has_complete_header(&mut buf));
+        assert_eq!(buf.remaining(), 8);
+    }
+
+    #[test]
+    fn parse_wrapped_frame() {
+        use std::io::Write;
+
+        let mut buf = CircularBuffer::new(16, 16);
+        buf.write_all(&[0; 12]).unwrap();
+        buf.write_all(&[0x82]).unwrap();
+        buf.advance(12);
+        // The header and payload wrap around the end of the buffer.
+        buf.write_all(&[0x84, 1, 2, 3, 4, 1 ^ 1, 2 ^ 2, 3 ^ 3]).unwrap();
+        assert!(buf.contiguous_prefix_len() < 6);
+        assert!(Frame::parse(&mut buf, 100).unwrap().is_none());
+        buf.write_all(&[4 ^ 4]).unwrap();
+        let mut frame = Frame::parse(&mut buf, 100).unwrap().unwrap();
+        frame.remove_mask();
+        assert_eq!(frame.payload(), &vec![1, 2, 3, 4]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn parse_long_header_split_at_wrap() {
+        use std::io::Write;
+
+        let mut buf = CircularBuffer::new(320, 320);
+        buf.write_all(&[0; 315]).unwrap();
+        buf.write_all(&[0x82]).unwrap();
+        buf.advance(315);
+        // A 64-bit length header whose length field is split across the end of the buffer.
+        buf.write_all(&[0x7f, 0, 0, 0, 0, 0, 0, 1, 0]).unwrap();
+        assert!(buf.contiguous_prefix_len() < 10);
+        assert!(Frame::parse(&mut buf, 1024).unwrap().is_none());
+        buf.write_all(&[7; 256]).unwrap();
+        let frame = Frame::parse(&mut buf, 1024).unwrap().unwrap();
+        assert_eq!(frame.payload(), &vec![7; 256]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn invalid_header_is_discarded() {
+        use std::io::Write;
+
+        let mut buf = CircularBuffer::new(0, 64);
+        buf.write_all(b"\x82\x7e\x01\x00 and whatever follows").unwrap();
+        assert!(Frame::parse(&mut buf, 100).is_err());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn build_and_parse() {
+        let mut frame = Frame::builder(OpCode::Reserved(0xB))
+            .fin(false)
+            .rsv2(true)
+            .rsv3(true)
+            .mask([9, 8, 7, 6])
+            .payload(vec![1, 2, 3])
+            .build();
+        let mut bytes = Vec::new();
+        frame.format(&mut bytes).unwrap();
+        assert_eq!(bytes, vec![0x3B, 0x83, 9, 8, 7, 6, 1 ^ 9, 2 ^ 8, 3 ^ 7]);
+
+        // The header is split across both halves of a chained buffer.
+        let mut buf = Buf::chain(
+            ::std::io::Cursor::new(bytes[..3].to_vec()),
+            ::std::io::Cursor::new(bytes[3..].to_vec()),
+        );
+        let mut parsed = Frame::parse(&mut buf, 100).unwrap().unwrap();
+        assert_eq!(parsed.opcode(), OpCode::Reserved(0xB));
+        assert!(!parsed.is_final() && !parsed.has_rsv1() && parsed.has_rsv2() && parsed.has_rsv3());
+        assert_eq!(parsed.mask(), Some(&[9, 8, 7, 6]));
+        parsed.remove_mask();
+        assert_eq!(parsed.payload(), &vec![1, 2, 3]);
+        assert!(!buf.has_remaining());
+    }
 }
@@ -1,4 +1,5 @@
-use log::Level::Error as ErrorLevel;
+use std::io::Write;
+
 #[cfg(feature = "nativetls")]
 use native_tls::{TlsConnector, TlsStream as SslStream};
 #[cfg(feature = "ssl")]
@@ -7,14 +8,26 @@ use url;
 
 use frame::Frame;
 use handshake::{Handshake, Request, Response};
+use logging::{self, LogLevel};
 use message::Message;
-use protocol::CloseCode;
+use protocol::{CloseCode, CloseFrame, OpCode};
 use result::{Error, Kind, Result};
 use util::{Timeout, Token};
 
 #[cfg(any(feature = "ssl", feature = "nativetls"))]
 use util::TcpStream;
 
+/// What happens to an incoming frame with a reserved opcode. See `Handler::on_unknown_frame`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Ignore the frame.
+    Drop,
+    /// Pass the payload of the frame on to `on_message` as a binary message.
+    Deliver,
+    /// Fail the connection with a Protocol (1002) close code.
+    Fail,
+}
+
 /// The core trait of this library.
 /// Implementing this trait provides the business logic of the WebSocket application.
 pub trait Handler {
@@ -50,6 +63,17 @@ pub trait Handler {
         debug!("Connection closing due to ({:?}) {}", code, reason);
     }
 
+    /// Called with the parsed close frame any time this endpoint receives one, including the
+    /// raw payload. A close frame with an empty payload has the code `CloseCode::Status` (1005).
+    ///
+    /// By default this method calls `on_close` with the code and reason of the frame. A reason
+    /// that is not valid UTF-8 is passed on as an empty string, and the connection is then
+    /// closed with an Invalid (1007) close code.
+    #[inline]
+    fn on_close_frame(&mut self, frame: &CloseFrame) {
+        self.on_close(frame.code, &frame.reason)
+    }
+
     /// Called when an error occurs on the WebSocket.
     fn on_error(&mut self, err: Error) {
         // Ignore connection reset errors by default, but allow library clients to see them by
@@ -61,7 +85,7 @@ pub trait Handler {
         }
 
         error!("{:?}", err);
-        if !log_enabled!(ErrorLevel) {
+        if !logging::enabled(LogLevel::Error, module_path!()) {
             println!(
                 "Encountered an error: {}\nEnable a logger to see more information.",
                 err
@@ -81,6 +105,9 @@ pub trait Handler {
     /// the WebSocket protocol, and implementors should use the `Response::from_request` method and
     /// then modify the resulting response as necessary in order to maintain conformance.
     ///
+    /// To decide on the handshake without blocking the event loop, return `Response::pending` and
+    /// complete the handshake later with `Sender::accept` or `Sender::reject`.
+    ///
     /// This method will not be called when the handler represents a client endpoint. Use
     /// `build_request` to provide an initial handshake request.
     ///
@@ -201,6 +228,36 @@ pub trait Handler {
         Ok(())
     }
 
+    /// Called when a message sent with `Sender::send_reliable` was not acknowledged by the other
+    /// endpoint after every retry, with the id that `send_reliable` returned. This is only called
+    /// on handlers wrapped with `HandlerExt::with_acks`.
+    #[inline]
+    fn on_ack_timeout(&mut self, id: u64, msg: Message) -> Result<()> {
+        debug!("Message {} was not acknowledged: {:?}", id, msg);
+        Ok(())
+    }
+
+    /// Called instead of `on_message` with a reliable message whose id was already received, or
+    /// that is too old to tell, which may be an attempt to replay it. This is only called on
+    /// handlers wrapped with `HandlerExt::with_acks` whose replay window is enabled with
+    /// `Acked::replay_window`. Returning an error fails the connection.
+    #[inline]
+    fn on_replay_detected(&mut self, id: u64, msg: Message) -> Result<()> {
+        debug!("Rejected replayed message {}: {:?}", id, msg);
+        Ok(())
+    }
+
+    /// Called after `on_open` with the number of messages that were queued with
+    /// `EarlyQueue::send` while the client was disconnected, once they have been handed to the
+    /// new connection. This is only called on handlers wrapped with
+    /// `HandlerExt::with_early_queue`, and only if messages were queued. Returning an error
+    /// fails the connection.
+    #[inline]
+    fn on_flush_after_reconnect(&mut self, count: usize) -> Result<()> {
+        debug!("Sent {} messages queued while disconnected.", count);
+        Ok(())
+    }
+
     // frame events
 
     /// A method for handling incoming frames.
@@ -228,6 +285,57 @@ pub trait Handler {
         }
     }
 
+    /// A method for handling incoming frames with an opcode that the protocol reserves for
+    /// further frame types, such as the frames of an experimental extension that both endpoints
+    /// agreed on out-of-band. It is called with each such frame that `on_frame` returns, whether
+    /// or not it is final, and the returned `Decision` says what happens to the frame.
+    ///
+    /// By default this method fails the connection, as the protocol requires.
+    #[inline]
+    fn on_unknown_frame(&mut self, frame: &Frame) -> Decision {
+        debug!("Handler received frame with reserved opcode: {}", frame);
+        Decision::Fail
+    }
+
+    /// A method for receiving a final text or binary frame whose payload is longer than
+    /// `Settings::max_fragment_size`, such as a file transfer, without buffering it in memory.
+    ///
+    /// Returning a sink streams the payload into it as it arrives, unmasked but otherwise
+    /// untouched, so a text payload is not validated and `on_frame` and `on_message` are not
+    /// called for it. Once the whole payload has been written, the sink is flushed and dropped,
+    /// and `on_large_frame_end` is called. If the connection fails or closes first, the sink is
+    /// dropped with a partial payload.
+    ///
+    /// By default this method returns `None`, which fails the connection with a Protocol error
+    /// as for any frame that is too long. Frames with reserved bits set, such as compressed ones,
+    /// and fragments of a larger message are never streamed.
+    #[inline]
+    fn on_large_frame_start(&mut self, opcode: OpCode, len: u64) -> Option<Box<dyn Write + Send>> {
+        debug!("Handler received {:?} frame of {} bytes.", opcode, len);
+        None
+    }
+
+    /// Called when the whole payload of a frame accepted by `on_large_frame_start` has been
+    /// written to its sink.
+    #[inline]
+    fn on_large_frame_end(&mut self, len: u64) -> Result<()> {
+        debug!("Handler streamed a frame of {} bytes.", len);
+        Ok(())
+    }
+
+    /// Called when the other endpoint shuts down its side of the connection, so that nothing
+    /// more will be read from it, while this endpoint may still have data to send.
+    ///
+    /// Outgoing data already buffered, including a close frame answering one the peer sent
+    /// first, is still written out before the connection is dropped. If the peer did not
+    /// complete the closing handshake, `on_close` is then called with `CloseCode::Abnormal`.
+    /// Returning an error fails the connection right away.
+    #[inline]
+    fn on_peer_half_close(&mut self) -> Result<()> {
+        debug!("Handler received a half-close from the peer.");
+        Ok(())
+    }
+
     /// A method for handling outgoing frames.
     ///
     /// This method provides very low-level access to the details of the WebSocket protocol. It may
@@ -283,7 +391,9 @@ pub trait Handler {
     /// A method for wrapping a client TcpStream with Ssl Authentication machinery
     ///
     /// Override this method to customize how the connection is encrypted. By default
-    /// this will use the Server Name Indication extension in conformance with RFC6455.
+    /// this will use the Server Name Indication extension in conformance with RFC6455. Each
+    /// connection negotiates a new TLS session; to resume the sessions of earlier connections
+    /// instead, override this method to connect through a `TlsSessionCache`.
     #[inline]
     #[cfg(feature = "ssl")]
     fn upgrade_ssl_client(
@@ -398,12 +508,8 @@ mod test {
         let url = url::Url::parse("wss://127.0.0.1:3012").unwrap();
         let req = Request::from_url(&url).unwrap();
         let res = Response::from_request(&req).unwrap();
-        h.on_open(Handshake {
-            request: req,
-            response: res,
-            peer_addr: None,
-            local_addr: None,
-        }).unwrap();
+        h.on_open(Handshake::new(req, res, None, None, Vec::new(), Vec::new()))
+            .unwrap();
         h.on_message(message::Message::Text("testme".to_owned()))
             .unwrap();
         h.on_close(CloseCode::Normal, "");
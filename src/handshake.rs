@@ -1,76 +1,91 @@
 use std::fmt;
 use std::io::Write;
+use std::mem::replace;
 use std::net::SocketAddr;
 use std::str::from_utf8;
 
 use httparse;
-use rand;
-use sha1::{self, Digest};
 use url;
 
+use proto::{accept_key, encode_base64, generate_key};
 use result::{Error, Kind, Result};
 
-static WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-static BASE64: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
 const MAX_HEADERS: usize = 124;
 
-fn generate_key() -> String {
-    let key: [u8; 16] = rand::random();
-    encode_base64(&key)
+// The length of an HTTP message head, including the blank line that terminates it.
+pub fn head_len(buf: &[u8]) -> usize {
+    buf.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+        .unwrap_or_else(|| buf.len())
 }
 
-pub fn hash_key(key: &[u8]) -> String {
-    let mut hasher = sha1::Sha1::new();
-
-    hasher.input(key);
-    hasher.input(WS_GUID.as_bytes());
-
-    encode_base64(&hasher.result())
+// Compare two byte strings in time that depends only on their lengths, so that a peer cannot
+// learn how much of an expected value it has guessed from how quickly it is rejected.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
 }
 
-// This code is based on rustc_serialize base64 STANDARD
-fn encode_base64(data: &[u8]) -> String {
-    let len = data.len();
-    let mod_len = len % 3;
+/// How the names of the headers of outgoing handshakes are written. Header names are case
+/// insensitive, but some gateways and proxies only recognize them in a particular case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderCase {
+    /// Write the names as they were set, which is `Sec-WebSocket-Key` and the like for the
+    /// headers that this library adds.
+    Preserve,
+    /// Write the names in lower case, such as `sec-websocket-key`.
+    Lower,
+    /// Write the names of the WebSocket headers as RFC 6455 spells them, and capitalize each
+    /// word of other names, such as `Sec-WebSocket-Key` and `X-Request-Id`.
+    Canonical,
+}
 
-    let mut encoded = vec![b'='; (len + 2) / 3 * 4];
-    {
-        let mut in_iter = data[..len - mod_len].iter().map(|&c| u32::from(c));
-        let mut out_iter = encoded.iter_mut();
-
-        let enc = |val| BASE64[val as usize];
-        let mut write = |val| *out_iter.next().unwrap() = val;
-
-        while let (Some(one), Some(two), Some(three)) =
-            (in_iter.next(), in_iter.next(), in_iter.next())
-        {
-            let g24 = one << 16 | two << 8 | three;
-            write(enc((g24 >> 18) & 63));
-            write(enc((g24 >> 12) & 63));
-            write(enc((g24 >> 6) & 63));
-            write(enc(g24 & 63));
-        }
+// The headers whose canonical spelling is not just their capitalized words.
+static CANONICAL: &[&str] = &[
+    "Sec-WebSocket-Accept",
+    "Sec-WebSocket-Extensions",
+    "Sec-WebSocket-Key",
+    "Sec-WebSocket-Protocol",
+    "Sec-WebSocket-Version",
+    "WWW-Authenticate",
+];
+
+fn canonical(name: &str) -> String {
+    if let Some(known) = CANONICAL.iter().find(|known| known.eq_ignore_ascii_case(name)) {
+        return (*known).into();
+    }
+    let mut capitalize = true;
+    name.chars()
+        .map(|c| {
+            let c = if capitalize {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            };
+            capitalize = c == '-';
+            c
+        })
+        .collect()
+}
 
-        match mod_len {
-            1 => {
-                let pad = (u32::from(data[len - 1])) << 16;
-                write(enc((pad >> 18) & 63));
-                write(enc((pad >> 12) & 63));
-            }
-            2 => {
-                let pad = (u32::from(data[len - 2])) << 16 | (u32::from(data[len - 1])) << 8;
-                write(enc((pad >> 18) & 63));
-                write(enc((pad >> 12) & 63));
-                write(enc((pad >> 6) & 63));
-            }
-            _ => (),
+// Rewrite the names of the headers of an outgoing handshake in the given case.
+pub fn recase(headers: &mut [(String, Vec<u8>)], case: HeaderCase) {
+    for &mut (ref mut name, _) in headers.iter_mut() {
+        match case {
+            HeaderCase::Preserve => return,
+            HeaderCase::Lower => name.make_ascii_lowercase(),
+            HeaderCase::Canonical => *name = canonical(name),
         }
     }
-
-    String::from_utf8(encoded).unwrap()
 }
 
 /// A struct representing the two halves of the WebSocket handshake.
+///
+/// The raw bytes of the handshake are kept in private fields, so a handshake can not be built
+/// with a struct literal. Use `Handshake::new` instead.
 #[derive(Debug)]
 pub struct Handshake {
     /// The HTTP request sent to begin the handshake.
@@ -82,9 +97,61 @@ pub struct Handshake {
     pub peer_addr: Option<SocketAddr>,
     /// The socket address of this endpoint.
     pub local_addr: Option<SocketAddr>,
+    /// The url that a client connection was established to, which is one of the fallbacks when
+    /// the connection was queued with `connect_any`. This is `None` for server connections.
+    pub url: Option<String>,
+    raw_request: Vec<u8>,
+    raw_response: Vec<u8>,
 }
 
 impl Handshake {
+    /// Build a handshake from its two halves and the exact bytes that were exchanged for them,
+    /// which `raw_request` and `raw_response` return, such as to call `Handler::on_open` of a
+    /// handler in a test. The `url` of the handshake is `None`.
+    ///
+    /// ```
+    /// use parity_ws::{Handshake, Request, Response};
+    ///
+    /// let url = "ws://127.0.0.1:3012".parse().unwrap();
+    /// let req = Request::from_url(&url).unwrap();
+    /// let res = Response::from_request(&req).unwrap();
+    /// let mut raw_request = Vec::new();
+    /// req.format(&mut raw_request).unwrap();
+    /// let shake = Handshake::new(req, res, None, None, raw_request, Vec::new());
+    /// assert!(shake.raw_request().starts_with(b"GET / HTTP/1.1"));
+    /// ```
+    pub fn new(
+        request: Request,
+        response: Response,
+        peer_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+        raw_request: Vec<u8>,
+        raw_response: Vec<u8>,
+    ) -> Handshake {
+        Handshake {
+            request,
+            response,
+            peer_addr,
+            local_addr,
+            url: None,
+            raw_request,
+            raw_response,
+        }
+    }
+
+    /// Get the exact bytes of the HTTP request that were exchanged during the handshake.
+    #[inline]
+    pub fn raw_request(&self) -> &[u8] {
+        &self.raw_request
+    }
+
+    /// Get the exact bytes of the HTTP response that were exchanged during the handshake.
+    /// This does not include any WebSocket frames that may have followed the response.
+    #[inline]
+    pub fn raw_response(&self) -> &[u8] {
+        &self.raw_response
+    }
+
     /// Get the IP address of the remote connection.
     ///
     /// This is the preferred method of obtaining the client's IP address.
@@ -166,7 +233,7 @@ impl Request {
 
     /// Get the hashed WebSocket key from this request.
     pub fn hashed_key(&self) -> Result<String> {
-        Ok(hash_key(self.key()?))
+        Ok(accept_key(self.key()?))
     }
 
     /// Get the WebSocket protocol version from the request (should be 13).
@@ -323,6 +390,58 @@ impl Request {
         Ok(None)
     }
 
+    /// Check that this request asks for a WebSocket upgrade that this library can perform.
+    ///
+    /// This verifies the `Upgrade`, `Connection`, `Sec-WebSocket-Version` and
+    /// `Sec-WebSocket-Key` headers. It is useful when a request has been parsed by another HTTP
+    /// server and is being handed off to this library.
+    pub fn validate(&self) -> Result<()> {
+        let has_token = |header: &str, token: &str| {
+            self.header(header)
+                .and_then(|val| from_utf8(val).ok())
+                .map(|val| {
+                    val.split(',')
+                        .any(|t| t.trim().eq_ignore_ascii_case(token))
+                })
+                .unwrap_or(false)
+        };
+
+        if !has_token("upgrade", "websocket") {
+            return Err(Error::new(
+                Kind::Protocol,
+                "Request does not ask for a websocket upgrade.",
+            ));
+        }
+        if !has_token("connection", "upgrade") {
+            return Err(Error::new(
+                Kind::Protocol,
+                "Request is missing the upgrade connection option.",
+            ));
+        }
+        if self.version()? != "13" {
+            return Err(Error::new(
+                Kind::Protocol,
+                "Unsupported WebSocket protocol version.",
+            ));
+        }
+        self.key()?;
+        Ok(())
+    }
+
+    /// Construct a request from its already parsed parts, for example when an HTTP request has
+    /// been received by another server and is being handed off to this library.
+    pub fn from_parts<M, P>(method: M, path: P, headers: Vec<(String, Vec<u8>)>) -> Request
+    where
+        M: Into<String>,
+        P: Into<String>,
+    {
+        Request {
+            path: path.into(),
+            method: method.into(),
+            headers,
+        }
+    }
+
     /// Attempt to parse an HTTP request from a buffer. If the buffer does not contain a complete
     /// request, this will return `Ok(None)`.
     pub fn parse(buf: &[u8]) -> Result<Option<Request>> {
@@ -330,19 +449,46 @@ impl Request {
         let mut req = httparse::Request::new(&mut headers);
         let parsed = req.parse(buf)?;
         if !parsed.is_partial() {
-            Ok(Some(Request {
+            let request = Request {
                 path: req.path.unwrap().into(),
                 method: req.method.unwrap().into(),
                 headers: req.headers
                     .iter()
                     .map(|h| (h.name.into(), h.value.into()))
                     .collect(),
-            }))
+            };
+            request.check_framing()?;
+            Ok(Some(request))
         } else {
             Ok(None)
         }
     }
 
+    // Reject requests that intermediaries could disagree about, so that a proxy in front of us
+    // cannot be made to see a different request than we do.
+    fn check_framing(&self) -> Result<()> {
+        let count = |name: &str| {
+            self.headers
+                .iter()
+                .filter(|&&(ref key, _)| key.eq_ignore_ascii_case(name))
+                .count()
+        };
+
+        if count("host") > 1 {
+            return Err(Error::new(
+                Kind::Protocol,
+                "Request has more than one Host header.",
+            ));
+        }
+        if count("content-length") > 0 && count("transfer-encoding") > 0 {
+            return Err(Error::new(
+                Kind::Protocol,
+                "Request has both Content-Length and Transfer-Encoding headers.",
+            ));
+        }
+        Ok(())
+    }
+
     /// Construct a new WebSocket handshake HTTP request from a url.
     pub fn from_url(url: &url::Url) -> Result<Request> {
         let query = if let Some(q) = url.query() {
@@ -385,6 +531,50 @@ impl Request {
         Ok(req)
     }
 
+    /// Construct a new WebSocket handshake HTTP request from a url, writing the given headers
+    /// exactly as provided.
+    ///
+    /// The headers are sent in the given order and with their original casing. Any headers
+    /// required by the WebSocket protocol that are not present in the list, compared without
+    /// regard to case, are appended after them using the values `from_url` would produce. This
+    /// is useful for gateways that are sensitive to the exact layout of the request.
+    ///
+    /// # Examples
+    /// ```ignore
+    /// let mut req = try!(Request::from_url_with_headers(url, vec![
+    ///     ("host".into(), "example.com".into()),
+    ///     ("X-Gateway-Token".into(), token.into()),
+    /// ]));
+    /// req.set_resource("/socket?b=2&a=1");
+    /// Ok(req)
+    /// ```
+    pub fn from_url_with_headers(
+        url: &url::Url,
+        headers: Vec<(String, Vec<u8>)>,
+    ) -> Result<Request> {
+        let mut req = Request::from_url(url)?;
+        let defaults = replace(&mut req.headers, headers);
+        for (key, val) in defaults {
+            if req.header(&key).is_none() {
+                req.headers.push((key, val));
+            }
+        }
+
+        debug!("Built request with ordered headers:\n{}", req);
+
+        Ok(req)
+    }
+
+    /// Set the path of the request, including any query, exactly as it will appear in the
+    /// request line.
+    #[inline]
+    pub fn set_resource<P>(&mut self, path: P)
+    where
+        P: Into<String>,
+    {
+        self.path = path.into()
+    }
+
     /// Write a request out to a buffer
     pub fn format<W>(&self, w: &mut W) -> Result<()>
     where
@@ -420,12 +610,28 @@ impl fmt::Display for Request {
 }
 
 /// The handshake response.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Response {
     status: u16,
     reason: String,
     headers: Vec<(String, Vec<u8>)>,
     body: Vec<u8>,
+    pending: bool,
+}
+
+// The reason phrase for the statuses that handshakes are commonly refused with.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Rejected",
+    }
 }
 
 impl Response {
@@ -441,9 +647,33 @@ impl Response {
             reason: reason.into(),
             headers: vec![("Content-Length".into(), body.len().to_string().into())],
             body,
+            pending: false,
         }
     }
 
+    /// A placeholder response that defers the handshake. Returning it from `Handler::on_request`
+    /// leaves the request unanswered until `Sender::accept` or `Sender::reject` is called, so
+    /// that the request can be checked, for example against a remote authentication service,
+    /// without blocking the event loop.
+    ///
+    /// A deferred response is sent as given, without passing through `on_request` again, so
+    /// extensions such as permessage-deflate are not negotiated for deferred handshakes.
+    pub fn pending() -> Response {
+        Response {
+            status: 0,
+            reason: String::new(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            pending: true,
+        }
+    }
+
+    /// Whether this is a placeholder for a deferred handshake. See `Response::pending`.
+    #[inline]
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
     /// Get the response body.
     #[inline]
     pub fn body(&self) -> &[u8] {
@@ -601,6 +831,7 @@ impl Response {
                     .map(|h| (h.name.into(), h.value.into()))
                     .collect(),
                 body: Vec::new(),
+                pending: false,
             }))
         } else {
             Ok(None)
@@ -620,6 +851,7 @@ impl Response {
                 ("Upgrade".into(), "websocket".into()),
             ],
             body: Vec::new(),
+            pending: false,
         };
 
         debug!("Built response from request:\n{}", res);
@@ -687,6 +919,9 @@ mod test {
             response: res,
             peer_addr: Some(SocketAddr::from_str("127.0.0.1:8888").unwrap()),
             local_addr: None,
+            url: None,
+            raw_request: buf,
+            raw_response: Vec::new(),
         };
         assert_eq!(shake.remote_addr().unwrap().unwrap(), "127.0.0.1");
     }
@@ -711,6 +946,9 @@ mod test {
             response: res,
             peer_addr: None,
             local_addr: None,
+            url: None,
+            raw_request: buf,
+            raw_response: Vec::new(),
         };
         assert_eq!(shake.remote_addr().unwrap().unwrap(), "192.168.1.1");
     }
@@ -734,7 +972,104 @@ mod test {
             response: res,
             peer_addr: None,
             local_addr: None,
+            url: None,
+            raw_request: buf,
+            raw_response: Vec::new(),
         };
         assert_eq!(shake.remote_addr().unwrap().unwrap(), "192.0.2.43");
     }
+
+    #[test]
+    fn validate_upgrade() {
+        let mut buf = Vec::with_capacity(2048);
+        write!(
+            &mut buf,
+            "GET / HTTP/1.1\r\n\
+             Connection: keep-alive, Upgrade\r\n\
+             Upgrade: WebSocket\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n"
+        ).unwrap();
+        let req = Request::parse(&buf).unwrap().unwrap();
+        assert!(req.validate().is_ok());
+
+        let mut headers = req.headers().clone();
+        headers.retain(|&(ref key, _)| key != "Upgrade");
+        let req = Request::from_parts("GET", "/", headers);
+        assert!(req.validate().is_err());
+    }
+
+    #[test]
+    fn ordered_headers() {
+        let url = url::Url::parse("ws://example.com:8080/ignored").unwrap();
+        let mut req = Request::from_url_with_headers(
+            &url,
+            vec![
+                ("upgrade".into(), "websocket".into()),
+                ("X-Custom".into(), "1".into()),
+                ("HOST".into(), "example.com".into()),
+            ],
+        ).unwrap();
+        req.set_resource("/path?b=2&a=1");
+
+        let mut buf = Vec::new();
+        req.format(&mut buf).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert!(text.starts_with(
+            "GET /path?b=2&a=1 HTTP/1.1\r\nupgrade: websocket\r\nX-Custom: 1\r\nHOST: example.com\r\n"
+        ));
+        assert!(text.contains("Sec-WebSocket-Key: "));
+        assert!(!text.contains("Upgrade: websocket"));
+        assert!(!text.contains("Host: "));
+    }
+
+    #[test]
+    fn accept_keys_compare() {
+        let key = accept_key(b"dGhlIHNhbXBsZSBub25jZQ==");
+        assert!(constant_time_eq(key.as_bytes(), b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert!(!constant_time_eq(key.as_bytes(), b"s3pPLMBiTxaQ9kYGzzhZRbK+xOp="));
+        assert!(!constant_time_eq(key.as_bytes(), b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo"));
+    }
+
+    #[test]
+    fn reject_smuggling() {
+        let parse = |extra: &str| {
+            let buf = format!(
+                "GET / HTTP/1.1\r\n\
+                 Host: example.com\r\n\
+                 Connection: Upgrade\r\n\
+                 Upgrade: websocket\r\n\
+                 {}\
+                 Sec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n",
+                extra
+            );
+            Request::parse(buf.as_bytes())
+        };
+
+        assert!(parse("").unwrap().is_some());
+        assert!(parse("Content-Length: 0\r\n").unwrap().is_some());
+        assert!(parse("host: evil.example.com\r\n").is_err());
+        assert!(parse("Content-Length: 5\r\nTransfer-Encoding: chunked\r\n").is_err());
+    }
+
+    #[test]
+    fn header_case() {
+        let mut headers = vec![
+            ("sec-websocket-key".to_string(), b"key".to_vec()),
+            ("x-REQUEST-id".to_string(), b"1".to_vec()),
+            ("Upgrade".to_string(), b"websocket".to_vec()),
+        ];
+        recase(&mut headers, HeaderCase::Preserve);
+        assert_eq!(headers[0].0, "sec-websocket-key");
+
+        recase(&mut headers, HeaderCase::Canonical);
+        let names = headers.iter().map(|h| h.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["Sec-WebSocket-Key", "X-Request-Id", "Upgrade"]);
+
+        recase(&mut headers, HeaderCase::Lower);
+        let names = headers.iter().map(|h| h.0.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["sec-websocket-key", "x-request-id", "upgrade"]);
+        assert_eq!(headers[2].1, b"websocket");
+    }
 }
@@ -1,31 +1,54 @@
 use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error as IoError, ErrorKind};
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::time::Duration;
+use std::net::{SocketAddr, TcpStream as StdTcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::usize;
 
 use mio;
 use mio::tcp::{TcpListener, TcpStream};
 use mio::{Poll, PollOpt, Ready, Token};
 use mio_extras;
+use net2::TcpBuilder;
 
 use url::Url;
 
 #[cfg(feature = "native_tls")]
 use native_tls::Error as SslError;
+#[cfg(feature = "nativetls")]
+use native_tls::TlsStream as SslStream;
+#[cfg(feature = "ssl")]
+use openssl::ssl::SslStream;
 
 use super::Settings;
+use auth::Authorizer;
 use communication::{Command, Sender, Signal};
-use connection::Connection;
+use connection::{Connection, ConnectionInfo, Priority, Shared};
 use factory::Factory;
-use slab::Slab;
+use handshake::{Request, Response};
+use logging;
+use message::Message;
+#[cfg(any(feature = "ssl", feature = "nativetls"))]
+use offload::{HandshakeAcceptor, HandshakePool};
+use protocol::{retry_reason, CloseCode};
 use result::{Error, Kind, Result};
+use session::Sessions;
+use slab::Slab;
+use snapshot::LoopStateSnapshot;
+use socks;
+use watchdog::Monitor;
 
-
+const TUNNELS: Token = Token(usize::MAX - 1);
+#[cfg(any(feature = "ssl", feature = "nativetls"))]
+const HANDSHAKES: Token = Token(usize::MAX - 2);
 const QUEUE: Token = Token(usize::MAX - 3);
 const TIMER: Token = Token(usize::MAX - 4);
 pub const ALL: Token = Token(usize::MAX - 5);
 const SYSTEM: Token = Token(usize::MAX - 6);
+const LINGER: Token = Token(usize::MAX - 7);
+// Listening sockets are registered with tokens counting down from here.
+const LISTENER: Token = Token(usize::MAX - 8);
 
 type Conn<F> = Connection<<F as Factory>::Handler>;
 
@@ -40,7 +63,7 @@ const CONNECTION_REFUSED: i32 = 111;
 #[cfg(windows)]
 const CONNECTION_REFUSED: i32 = 61;
 
-fn url_to_addrs(url: &Url) -> Result<Vec<SocketAddr>> {
+pub fn url_to_addrs(url: &Url) -> Result<Vec<SocketAddr>> {
     let host = url.host_str();
     if host.is_none() || (url.scheme() != "ws" && url.scheme() != "wss") {
         return Err(Error::new(
@@ -58,6 +81,53 @@ fn url_to_addrs(url: &Url) -> Result<Vec<SocketAddr>> {
     Ok(addrs)
 }
 
+// Connect a client socket to an address, bound to the local address and device of the
+// settings.
+pub fn connect(addr: &SocketAddr, settings: &Settings) -> ::std::io::Result<TcpStream> {
+    if settings.bind_local_addr.is_none() && settings.bind_device.is_none() {
+        return TcpStream::connect(addr);
+    }
+    let builder = match *addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    if let Some(device) = settings.bind_device {
+        bind_device(&builder, device)?;
+    }
+    if let Some(ref local) = settings.bind_local_addr {
+        builder.bind(local)?;
+    }
+    TcpStream::connect_stream(builder.to_tcp_stream()?, addr)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(builder: &TcpBuilder, device: &str) -> ::std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(
+            builder.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr() as *const libc::c_void,
+            device.len() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(IoError::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_: &TcpBuilder, _: &str) -> ::std::io::Result<()> {
+    Err(IoError::new(
+        ErrorKind::Other,
+        "Binding sockets to a device is only supported on Linux.",
+    ))
+}
+
 enum State {
     Active,
     Inactive,
@@ -78,11 +148,24 @@ pub struct Timeout {
     event: Token,
 }
 
+#[derive(Default)]
+struct GroupState {
+    // The members, by token, along with their connection ids.
+    members: HashMap<Token, u32>,
+    // The most recent messages, up to the history capacity of the group.
+    history: VecDeque<Message>,
+    capacity: usize,
+    // The number of messages sent to the group, which is the sequence number of the next one.
+    sent: u64,
+}
+
 pub struct Handler<F>
 where
     F: Factory,
 {
-    listener: Option<TcpListener>,
+    listeners: Vec<TcpListener>,
+    // Connections hold their handlers inline, and the slots of closed connections are reused, so
+    // that together with the buffer pool a new connection rarely allocates.
     connections: Slab<Conn<F>>,
     factory: F,
     settings: Settings,
@@ -90,7 +173,30 @@ where
     queue_tx: mio::channel::SyncSender<Command>,
     queue_rx: mio::channel::Receiver<Command>,
     timer: mio_extras::timer::Timer<Timeout>,
+    // The queue is sized at build time and keeps its size when the connection limit changes.
+    queue_capacity: usize,
     next_connection_id: u32,
+    // The number of timeouts that are scheduled and have not fired yet.
+    timers: usize,
+    shared: Shared,
+    groups: HashMap<usize, GroupState>,
+    serving: bool,
+    // Whether the listeners are polled for new connections.
+    accepting: bool,
+    // The connections that the accept rate allows now, and when they were last refilled.
+    accept_tokens: f64,
+    accept_refilled: Instant,
+    // Whether the listeners are left unpolled until the accept rate allows another connection.
+    throttled: bool,
+    // When connections that are still open after a drain are aborted.
+    deadline: Option<Instant>,
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    handshakes: Option<HandshakePool>,
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    pending_handshakes: usize,
+    tunnels: socks::Dialer,
+    // The SOCKS tunnels that are being opened for new connections.
+    pending_tunnels: usize,
 }
 
 impl<F> Handler<F>
@@ -98,14 +204,16 @@ where
     F: Factory,
 {
     pub fn new(factory: F, settings: Settings) -> Handler<F> {
-        let (tx, rx) = mio::channel::sync_channel(settings.max_connections * settings.queue_size);
+        let queue_capacity = settings.max_connections * settings.queue_size;
+        let (tx, rx) = mio::channel::sync_channel(queue_capacity);
         let timer = mio_extras::timer::Builder::default()
             .tick_duration(Duration::from_millis(TIMER_TICK_MILLIS))
             .num_slots(TIMER_WHEEL_SIZE)
             .capacity(TIMER_CAPACITY)
             .build();
+        let sessions = Arc::new(Sessions::new(tx.clone()));
         Handler {
-            listener: None,
+            listeners: Vec::new(),
             connections: Slab::with_capacity(settings.max_connections),
             factory,
             settings,
@@ -113,7 +221,30 @@ where
             queue_tx: tx,
             queue_rx: rx,
             timer,
+            queue_capacity,
             next_connection_id: 0,
+            timers: 0,
+            shared: Shared {
+                slow: Arc::new(Mutex::new(Vec::new())),
+                pool: Arc::new(Mutex::new(Vec::new())),
+                sessions,
+                authorizer: None,
+            },
+            groups: HashMap::new(),
+            serving: false,
+            accepting: true,
+            accept_tokens: settings.max_accepts_per_sec.map_or(0.0, |rate| {
+                f64::from(rate) + f64::from(settings.accept_burst)
+            }),
+            accept_refilled: Instant::now(),
+            throttled: false,
+            deadline: None,
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            handshakes: None,
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            pending_handshakes: 0,
+            tunnels: socks::Dialer::default(),
+            pending_tunnels: 0,
         }
     }
 
@@ -121,88 +252,232 @@ where
         Sender::new(ALL, self.queue_tx.clone(), 0)
     }
 
+    #[cfg(feature = "config")]
+    pub fn settings(&self) -> Settings {
+        self.settings
+    }
+
+    // Send the log events of the current thread to the logger of the WebSocket.
+    pub fn log_scope(&self) -> logging::Scope {
+        logging::scope(self.settings.logger)
+    }
+
     pub fn listen(&mut self, poll: &mut Poll, addr: &SocketAddr) -> Result<&mut Handler<F>> {
-        debug_assert!(
-            self.listener.is_none(),
-            "Attempted to listen for connections from two addresses on the same websocket."
-        );
+        let builder = match *addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => {
+                let builder = TcpBuilder::new_v6()?;
+                // Set explicitly, since the default differs between platforms.
+                builder.only_v6(self.settings.ipv6_only)?;
+                builder
+            }
+        };
+        // Mirror what libstd and mio do
+        if cfg!(unix) {
+            builder.reuse_address(true)?;
+        }
+        builder.bind(addr)?;
+        let tcp = TcpListener::from_std(builder.listen(1024)?)?;
 
-        let tcp = TcpListener::bind(addr)?;
-        // TODO: consider net2 in order to set reuse_addr
-        poll.register(&tcp, ALL, Ready::readable(), PollOpt::level())?;
-        self.listener = Some(tcp);
+        let tok = Token(LISTENER.0 - self.listeners.len());
+        poll.register(&tcp, tok, Ready::readable(), PollOpt::level())?;
+        self.listeners.push(tcp);
         Ok(self)
     }
 
     pub fn local_addr(&self) -> ::std::io::Result<SocketAddr> {
-        if let Some(ref listener) = self.listener {
+        if let Some(listener) = self.listeners.first() {
             listener.local_addr()
         } else {
             Err(IoError::new(ErrorKind::NotFound, "Not a listening socket"))
         }
     }
 
-    #[cfg(any(feature = "ssl", feature = "nativetls"))]
-    pub fn connect(&mut self, poll: &mut Poll, url: Url) -> Result<()> {
-        let settings = self.settings;
+    pub fn local_addrs(&self) -> ::std::io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect()
+    }
 
-        let (tok, addresses) = {
-            let (tok, entry, connection_id, handler) =
-                if self.connections.len() < settings.max_connections {
-                    let entry = self.connections.vacant_entry();
-                    let tok = Token(entry.key());
-                    let connection_id = self.next_connection_id;
-                    self.next_connection_id = self.next_connection_id.wrapping_add(1);
-                    (
-                        tok,
-                        entry,
-                        connection_id,
-                        self.factory.client_connected(Sender::new(
-                            tok,
-                            self.queue_tx.clone(),
-                            connection_id,
-                        )),
-                    )
-                } else {
-                    return Err(Error::new(
-                        Kind::Capacity,
-                        "Unable to add another connection to the event loop.",
-                    ));
-                };
+    pub fn connection_info(&self, token: Token) -> Option<ConnectionInfo<'_>> {
+        self.connections.get(token.into()).map(|conn| conn.info())
+    }
 
-            let mut addresses = match url_to_addrs(&url) {
-                Ok(addresses) => addresses,
-                Err(err) => {
-                    self.factory.connection_lost(handler);
-                    return Err(err);
+    #[inline]
+    fn listener(&self, token: Token) -> Option<&TcpListener> {
+        if token.0 <= LISTENER.0 {
+            self.listeners.get(LISTENER.0 - token.0)
+        } else {
+            None
+        }
+    }
+
+    // Connect to the first of the urls that can be reached, through the SOCKS proxy of the
+    // settings if there is one. The proxy negotiation blocks, so it is done on a thread of its own,
+    // and the connection is made once the tunnel is open. Errors that come up after this returns
+    // go to the connection that asked for it, as with `connect_failed`.
+    fn dial(
+        &mut self,
+        poll: &mut Poll,
+        urls: Vec<Url>,
+        origin: Token,
+        connection_id: u32,
+    ) -> Result<()> {
+        let proxy = match self.settings.socks5_proxy {
+            Some(proxy) => proxy,
+            None => return self.connect(poll, urls, None),
+        };
+        if self.connections.len() + self.pending_tunnels >= self.settings.max_connections {
+            return Err(Error::new(
+                Kind::Capacity,
+                "Unable to add another connection to the event loop.",
+            ));
+        }
+        self.tunnels.dial(proxy, urls, origin, connection_id)?;
+        self.pending_tunnels += 1;
+        Ok(())
+    }
+
+    // Make the connections of the SOCKS tunnels that were opened since the last call.
+    fn handle_tunnels(&mut self, poll: &mut Poll) {
+        while let Ok(tunnel) = self.tunnels.completed().try_recv() {
+            self.pending_tunnels -= 1;
+            let url = tunnel.urls[0].clone();
+            let urls = tunnel.urls;
+            let res = tunnel
+                .result
+                .and_then(|opened| self.connect(poll, urls, Some(opened)));
+            if let Err(err) = res {
+                self.connect_failed(tunnel.origin, tunnel.connection_id, &url, err);
+            }
+        }
+    }
+
+    // Hand an error in making a connection to the connection that asked for it, if it is still
+    // open, and log it otherwise.
+    fn connect_failed(&mut self, origin: Token, connection_id: u32, url: &Url, err: Error) {
+        match self.connections.get_mut(origin.into()) {
+            Some(conn) if conn.connection_id() == connection_id => conn.error(err),
+            _ => {
+                if self.settings.panic_on_new_connection {
+                    panic!("Unable to establish connection to {}: {:?}", url, err);
                 }
+                error!("Unable to establish connection to {}: {:?}", url, err);
+            }
+        }
+    }
+
+    // Make the connection of a client to the first of the urls that can be reached, or through
+    // the SOCKS tunnel that was opened to one of them, returning its token, its url, the other
+    // addresses of its host and the urls to fall back on.
+    fn open_client(
+        &mut self,
+        mut urls: Vec<Url>,
+        tunnel: Option<(usize, StdTcpStream)>,
+    ) -> Result<(Token, Url, Vec<SocketAddr>, VecDeque<Url>)> {
+        let settings = self.settings;
+        let (tok, entry, connection_id, context, handler) =
+            if self.connections.len() < settings.max_connections {
+                let entry = self.connections.vacant_entry();
+                let tok = Token(entry.key());
+                let connection_id = self.next_connection_id;
+                self.next_connection_id = self.next_connection_id.wrapping_add(1);
+                let out = Sender::new(tok, self.queue_tx.clone(), connection_id);
+                (
+                    tok,
+                    entry,
+                    connection_id,
+                    out.context().clone(),
+                    self.factory.client_connected(out),
+                )
+            } else {
+                return Err(Error::new(
+                    Kind::Capacity,
+                    "Unable to add another connection to the event loop.",
+                ));
             };
 
-            loop {
-                if let Some(addr) = addresses.pop() {
-                    if let Ok(sock) = TcpStream::connect(&addr) {
-                        if settings.tcp_nodelay {
-                            sock.set_nodelay(true)?
+        let found = match tunnel {
+            // The proxy has tried the urls before this one already, and the ones after it can not
+            // be fallen back on without going around the proxy.
+            Some((index, stream)) => match TcpStream::from_stream(stream) {
+                Ok(sock) => {
+                    urls.truncate(index + 1);
+                    Some((index, sock, Vec::new()))
+                }
+                Err(err) => {
+                    debug!("Unable to use the SOCKS tunnel to {}: {}", urls[index], err);
+                    None
+                }
+            },
+            None => {
+                // Try the urls in order of priority, leaving the ones after the first that can be
+                // connected to as fallbacks in case that connection is refused.
+                let mut found = None;
+                'urls: for (i, url) in urls.iter().enumerate() {
+                    let mut addresses = match url_to_addrs(url) {
+                        Ok(addresses) => addresses,
+                        Err(err) => {
+                            debug!("Unable to resolve {}: {}", url, err);
+                            continue;
+                        }
+                    };
+                    while let Some(addr) = addresses.pop() {
+                        match connect(&addr, &settings) {
+                            Ok(sock) => {
+                                // Replace the first addr in case ssl fails and we fallback
+                                #[cfg(any(feature = "ssl", feature = "nativetls"))]
+                                addresses.push(addr);
+                                found = Some((i, sock, addresses));
+                                break 'urls;
+                            }
+                            Err(err) => debug!("Unable to connect to {}: {}", addr, err),
                         }
-                        addresses.push(addr); // Replace the first addr in case ssl fails and we fallback
-                        entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
-                        break;
                     }
-                } else {
-                    self.factory.connection_lost(handler);
-                    return Err(Error::new(
-                        Kind::Internal,
-                        format!("Unable to obtain any socket address for {}", url),
-                    ));
                 }
+                found
             }
+        };
 
-            (tok, addresses)
+        let (index, sock, addresses) = match found {
+            Some(found) => found,
+            None => {
+                self.factory.connection_lost(handler);
+                return Err(Error::new(
+                    Kind::Internal,
+                    format!("Unable to obtain any socket address for {}", urls[0]),
+                ));
+            }
         };
+        if settings.tcp_nodelay {
+            sock.set_nodelay(true)?
+        }
+        entry.insert(Connection::new(
+            tok,
+            sock,
+            handler,
+            settings,
+            connection_id,
+            context,
+            self.shared.clone(),
+        ));
+        let fallbacks = VecDeque::from(urls.split_off(index + 1));
+        Ok((tok, urls.swap_remove(index), addresses, fallbacks))
+    }
+
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    pub fn connect(
+        &mut self,
+        poll: &mut Poll,
+        urls: Vec<Url>,
+        tunnel: Option<(usize, StdTcpStream)>,
+    ) -> Result<()> {
+        let (tok, url, addresses, fallbacks) = self.open_client(urls, tunnel)?;
 
         let will_encrypt = url.scheme() == "wss";
 
-        if let Err(error) = self.connections[tok.into()].as_client(url, addresses) {
+        if let Err(error) = self.connections[tok.into()].as_client(url, addresses, fallbacks) {
             let handler = self.connections.remove(tok.into()).consume();
             self.factory.connection_lost(handler);
             return Err(error);
@@ -265,61 +540,13 @@ where
     }
 
     #[cfg(not(any(feature = "ssl", feature = "nativetls")))]
-    pub fn connect(&mut self, poll: &mut Poll, url: Url) -> Result<()> {
-        let settings = self.settings;
-
-        let (tok, addresses) = {
-            let (tok, entry, connection_id, handler) =
-                if self.connections.len() < settings.max_connections {
-                    let entry = self.connections.vacant_entry();
-                    let tok = Token(entry.key());
-                    let connection_id = self.next_connection_id;
-                    self.next_connection_id = self.next_connection_id.wrapping_add(1);
-                    (
-                        tok,
-                        entry,
-                        connection_id,
-                        self.factory.client_connected(Sender::new(
-                            tok,
-                            self.queue_tx.clone(),
-                            connection_id,
-                        )),
-                    )
-                } else {
-                    return Err(Error::new(
-                        Kind::Capacity,
-                        "Unable to add another connection to the event loop.",
-                    ));
-                };
-
-            let mut addresses = match url_to_addrs(&url) {
-                Ok(addresses) => addresses,
-                Err(err) => {
-                    self.factory.connection_lost(handler);
-                    return Err(err);
-                }
-            };
-
-            loop {
-                if let Some(addr) = addresses.pop() {
-                    if let Ok(sock) = TcpStream::connect(&addr) {
-                        if settings.tcp_nodelay {
-                            sock.set_nodelay(true)?
-                        }
-                        entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
-                        break;
-                    }
-                } else {
-                    self.factory.connection_lost(handler);
-                    return Err(Error::new(
-                        Kind::Internal,
-                        format!("Unable to obtain any socket address for {}", url),
-                    ));
-                }
-            }
-
-            (tok, addresses)
-        };
+    pub fn connect(
+        &mut self,
+        poll: &mut Poll,
+        urls: Vec<Url>,
+        tunnel: Option<(usize, StdTcpStream)>,
+    ) -> Result<()> {
+        let (tok, url, addresses, fallbacks) = self.open_client(urls, tunnel)?;
 
         if url.scheme() == "wss" {
             let error = Error::new(
@@ -331,7 +558,7 @@ where
             return Err(error);
         }
 
-        if let Err(error) = self.connections[tok.into()].as_client(url, addresses) {
+        if let Err(error) = self.connections[tok.into()].as_client(url, addresses, fallbacks) {
             let handler = self.connections.remove(tok.into()).consume();
             self.factory.connection_lost(handler);
             return Err(error);
@@ -355,11 +582,28 @@ where
     }
 
     #[cfg(any(feature = "ssl", feature = "nativetls"))]
-    pub fn accept(&mut self, poll: &mut Poll, sock: TcpStream) -> Result<()> {
+    pub fn accept(
+        &mut self,
+        poll: &mut Poll,
+        sock: TcpStream,
+        upgrade: Option<Request>,
+    ) -> Result<()> {
+        self.accept_with(poll, sock, upgrade, None)
+    }
+
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    fn accept_with(
+        &mut self,
+        poll: &mut Poll,
+        sock: TcpStream,
+        upgrade: Option<Request>,
+        tls: Option<SslStream<TcpStream>>,
+    ) -> Result<()> {
         let factory = &mut self.factory;
         let settings = self.settings;
+        let conn_settings = factory.connection_settings(sock.peer_addr()?, settings);
 
-        if settings.tcp_nodelay {
+        if conn_settings.tcp_nodelay {
             sock.set_nodelay(true)?
         }
 
@@ -369,12 +613,18 @@ where
                 let tok = Token(entry.key());
                 let connection_id = self.next_connection_id;
                 self.next_connection_id = self.next_connection_id.wrapping_add(1);
-                let handler = factory.server_connected(Sender::new(
+                let out = Sender::new(tok, self.queue_tx.clone(), connection_id);
+                let context = out.context().clone();
+                let handler = factory.server_connected(out);
+                entry.insert(Connection::new(
                     tok,
-                    self.queue_tx.clone(),
+                    sock,
+                    handler,
+                    conn_settings,
                     connection_id,
+                    context,
+                    self.shared.clone(),
                 ));
-                entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
                 tok
             } else {
                 return Err(Error::new(
@@ -386,8 +636,16 @@ where
 
         let conn = &mut self.connections[tok.into()];
 
-        conn.as_server()?;
-        if settings.encrypt_server {
+        if let Some(request) = upgrade {
+            if let Err(err) = conn.as_upgraded_server(&request) {
+                conn.error(err);
+            }
+        } else {
+            conn.as_server()?;
+        }
+        if let Some(stream) = tls {
+            conn.encrypted(stream)
+        } else if conn_settings.encrypt_server {
             conn.encrypt()?
         }
 
@@ -411,11 +669,17 @@ where
     }
 
     #[cfg(not(any(feature = "ssl", feature = "nativetls")))]
-    pub fn accept(&mut self, poll: &mut Poll, sock: TcpStream) -> Result<()> {
+    pub fn accept(
+        &mut self,
+        poll: &mut Poll,
+        sock: TcpStream,
+        upgrade: Option<Request>,
+    ) -> Result<()> {
         let factory = &mut self.factory;
         let settings = self.settings;
+        let conn_settings = factory.connection_settings(sock.peer_addr()?, settings);
 
-        if settings.tcp_nodelay {
+        if conn_settings.tcp_nodelay {
             sock.set_nodelay(true)?
         }
 
@@ -425,12 +689,18 @@ where
                 let tok = Token(entry.key());
                 let connection_id = self.next_connection_id;
                 self.next_connection_id = self.next_connection_id.wrapping_add(1);
-                let handler = factory.server_connected(Sender::new(
+                let out = Sender::new(tok, self.queue_tx.clone(), connection_id);
+                let context = out.context().clone();
+                let handler = factory.server_connected(out);
+                entry.insert(Connection::new(
                     tok,
-                    self.queue_tx.clone(),
+                    sock,
+                    handler,
+                    conn_settings,
                     connection_id,
+                    context,
+                    self.shared.clone(),
                 ));
-                entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
                 tok
             } else {
                 return Err(Error::new(
@@ -442,8 +712,14 @@ where
 
         let conn = &mut self.connections[tok.into()];
 
-        conn.as_server()?;
-        if settings.encrypt_server {
+        if let Some(request) = upgrade {
+            if let Err(err) = conn.as_upgraded_server(&request) {
+                conn.error(err);
+            }
+        } else {
+            conn.as_server()?;
+        }
+        if conn_settings.encrypt_server {
             return Err(Error::new(
                 Kind::Protocol,
                 "The ssl feature is not enabled. Please enable it to use wss urls.",
@@ -469,7 +745,102 @@ where
             })
     }
 
+    pub fn set_authorizer(&mut self, authorizer: Arc<dyn Authorizer + Send>) {
+        self.shared.authorizer = Some(authorizer);
+    }
+
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    pub fn offload_tls_handshakes<A>(&mut self, acceptor: A, threads: usize) -> Result<()>
+    where
+        A: HandshakeAcceptor,
+    {
+        // On Windows, mio emulates readiness with overlapped I/O that it only starts once a
+        // stream is registered with the poll, so a worker could never read the handshake from
+        // an unregistered stream.
+        if cfg!(not(unix)) {
+            return Err(Error::new(
+                Kind::Internal,
+                "TLS handshake offloading is only supported on Unix.",
+            ));
+        }
+        if !self.settings.encrypt_server {
+            return Err(Error::new(
+                Kind::Internal,
+                "TLS handshake offloading requires the encrypt_server setting.",
+            ));
+        }
+        self.handshakes = Some(HandshakePool::new(acceptor, threads)?);
+        Ok(())
+    }
+
+    // Hand a newly accepted connection to the handshake workers.
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    fn offload_accept(&mut self, token: Token) {
+        let accepted = self
+            .listener(token)
+            .expect("No listener provided for server websocket connections")
+            .accept_std();
+        match accepted {
+            Ok((stream, addr)) => {
+                info!(peer = addr; "Accepted a new tcp connection from {}.", addr);
+                if self.connections.len() + self.pending_handshakes >= self.settings.max_connections
+                {
+                    error!("Unable to add another connection to the event loop.");
+                    if self.settings.panic_on_new_connection {
+                        panic!("Unable to add another connection to the event loop.");
+                    }
+                    return;
+                }
+                if let Some(ref pool) = self.handshakes {
+                    if let Err(err) = pool.offload(stream, addr) {
+                        error!("Unable to offload TLS handshake {:?}", err);
+                        return;
+                    }
+                }
+                self.pending_handshakes += 1;
+            }
+            Err(err) => error!(
+                "Encountered an error {:?} while accepting tcp connection.",
+                err
+            ),
+        }
+    }
+
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    fn handle_handshakes(&mut self, poll: &mut Poll) {
+        loop {
+            let completed = match self.handshakes {
+                Some(ref pool) => pool.completed().try_recv(),
+                None => return,
+            };
+            let (addr, res) = match completed {
+                Ok(completed) => completed,
+                Err(_) => return,
+            };
+            self.pending_handshakes -= 1;
+
+            match res {
+                Ok(stream) => {
+                    trace!("Completed TLS handshake with {}.", addr);
+                    let res = stream
+                        .get_ref()
+                        .try_clone()
+                        .map_err(Error::from)
+                        .and_then(|sock| self.accept_with(poll, sock, None, Some(stream)));
+                    if let Err(err) = res {
+                        error!("Unable to build WebSocket connection {:?}", err);
+                        if self.settings.panic_on_new_connection {
+                            panic!("Unable to build WebSocket connection {:?}", err);
+                        }
+                    }
+                }
+                Err(err) => info!("TLS handshake with {} failed: {}", addr, err),
+            }
+        }
+    }
+
     pub fn run(&mut self, poll: &mut Poll) -> Result<()> {
+        let _logger = self.log_scope();
         trace!("Running event loop");
         poll.register(
             &self.queue_rx,
@@ -478,12 +849,37 @@ where
             PollOpt::edge() | PollOpt::oneshot(),
         )?;
         poll.register(&self.timer, TIMER, Ready::readable(), PollOpt::edge())?;
+        poll.register(
+            self.tunnels.completed(),
+            TUNNELS,
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        #[cfg(any(feature = "ssl", feature = "nativetls"))]
+        {
+            if let Some(ref pool) = self.handshakes {
+                poll.register(
+                    pool.completed(),
+                    HANDSHAKES,
+                    Ready::readable(),
+                    PollOpt::edge(),
+                )?;
+            }
+        }
 
         self.state = State::Active;
         let result = self.event_loop(poll);
         self.state = State::Inactive;
 
+        #[cfg(any(feature = "ssl", feature = "nativetls"))]
+        let result = match self.handshakes {
+            Some(ref pool) => result.and(poll.deregister(pool.completed()).map_err(Error::from)),
+            None => result,
+        };
+
+        let tunnels = poll.deregister(self.tunnels.completed());
         result
+            .and(tunnels.map_err(Error::from))
             .and(poll.deregister(&self.timer).map_err(Error::from))
             .and(poll.deregister(&self.queue_rx).map_err(Error::from))
     }
@@ -491,9 +887,38 @@ where
     #[inline]
     fn event_loop(&mut self, poll: &mut Poll) -> Result<()> {
         let mut events = mio::Events::with_capacity(MAX_EVENTS);
+        let mut ready = Vec::with_capacity(MAX_EVENTS);
+        let watchdog_timeout = Duration::from_millis(self.settings.watchdog_timeout_ms);
+        let monitor = self
+            .settings
+            .watchdog
+            .map(|watchdog| Monitor::start(watchdog, watchdog_timeout, self.settings.logger));
+        let mut shrunk = Instant::now();
+        let mut swept = Instant::now();
         while self.state.is_active() {
+            let stall_timeout = match self.settings.write_stall_timeout_ms {
+                0 => None,
+                ms => Some(Duration::from_millis(ms)),
+            };
+            // Wake up while idle to keep ticking, to shrink buffers and to find stalled peers.
+            let poll_timeout = [
+                monitor.as_ref().map(|_| watchdog_timeout / 2),
+                self.settings
+                    .idle_shrink_interval
+                    .map(|interval| interval.checked_sub(shrunk.elapsed()).unwrap_or_default()),
+                stall_timeout.map(|timeout| {
+                    (timeout / 4)
+                        .checked_sub(swept.elapsed())
+                        .unwrap_or_default()
+                }),
+                self.deadline
+                    .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            ]
+            .iter()
+            .filter_map(|timeout| *timeout)
+            .min();
             trace!("Waiting for event");
-            let nevents = match poll.poll(&mut events, None) {
+            let nevents = match poll.poll(&mut events, poll_timeout) {
                 Ok(nevents) => nevents,
                 Err(err) => {
                     if err.kind() == ErrorKind::Interrupted {
@@ -511,9 +936,45 @@ where
             };
             trace!("Processing {} events", nevents);
 
-            for i in 0..nevents {
-                let evt = events.get(i).unwrap();
-                self.handle_event(poll, evt.token(), evt.kind());
+            ready.extend(
+                (0..nevents)
+                    .filter_map(|i| events.get(i))
+                    .map(|evt| (evt.token(), evt.kind())),
+            );
+            {
+                let connections = &self.connections;
+                by_priority(&mut ready, |token| {
+                    connections
+                        .get(token.into())
+                        .map(|conn| conn.priority())
+                        .unwrap_or(Priority::Normal)
+                });
+            }
+            for (token, kind) in ready.drain(..) {
+                self.handle_event(poll, token, kind);
+            }
+            self.report_slow_callbacks();
+            if let Some(interval) = self.settings.idle_shrink_interval {
+                if shrunk.elapsed() >= interval {
+                    self.shrink_buffers();
+                    shrunk = Instant::now();
+                }
+            }
+            if let Some(timeout) = stall_timeout {
+                if swept.elapsed() >= timeout / 4 {
+                    self.close_stalled(poll, timeout);
+                    swept = Instant::now();
+                }
+            }
+            if let Some(ref monitor) = monitor {
+                monitor.tick();
+            }
+            let expired = self
+                .deadline
+                .is_some_and(|deadline| deadline <= Instant::now());
+            if expired && self.state.is_active() {
+                debug!("Connections are still open after draining.");
+                self.abort();
             }
 
             self.check_count();
@@ -552,17 +1013,161 @@ where
         }
     }
 
+    // Stop polling the listeners, so that new connections wait in their backlog.
+    fn pause_accepting(&mut self, poll: &mut Poll) {
+        if !self.accepting {
+            return;
+        }
+        debug!("No longer accepting new connections.");
+        self.accepting = false;
+        if self.throttled {
+            return;
+        }
+        for listener in &self.listeners {
+            if let Err(err) = poll.deregister(listener) {
+                warn!("Unable to stop polling listener: {}", err);
+            }
+        }
+    }
+
+    // Take one of the connections that the accept rate allows, which are refilled continuously
+    // up to a second's worth plus the burst. Returns how long until the next one if there is
+    // none left.
+    fn accept_delay(&mut self) -> Option<Duration> {
+        let rate = match self.settings.max_accepts_per_sec {
+            Some(rate) if rate > 0 => f64::from(rate),
+            _ => return None,
+        };
+        let now = Instant::now();
+        let elapsed = now - self.accept_refilled;
+        self.accept_refilled = now;
+
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let capacity = rate + f64::from(self.settings.accept_burst);
+        self.accept_tokens = (self.accept_tokens + elapsed * rate).min(capacity);
+        if self.accept_tokens >= 1.0 {
+            self.accept_tokens -= 1.0;
+            return None;
+        }
+        let wait = (1.0 - self.accept_tokens) / rate;
+        Some(Duration::from_nanos((wait * 1e9).ceil() as u64))
+    }
+
+    // Stop polling the listeners until the accept rate allows another connection, so that the
+    // connections of a storm wait in the backlog instead of being accepted all at once.
+    fn throttle_accepts(&mut self, poll: &mut Poll, wait: Duration) {
+        if self.throttled {
+            return;
+        }
+        trace!("Throttling new connections for {:?}.", wait);
+        self.throttled = true;
+        for listener in &self.listeners {
+            if let Err(err) = poll.deregister(listener) {
+                warn!("Unable to stop polling listener: {}", err);
+            }
+        }
+        self.set_timeout(
+            wait,
+            Timeout {
+                connection: SYSTEM,
+                event: LISTENER,
+            },
+        );
+    }
+
+    fn resume_accepts(&mut self, poll: &mut Poll) {
+        self.throttled = false;
+        if !self.accepting {
+            return;
+        }
+        trace!("Accepting new connections again.");
+        for (index, listener) in self.listeners.iter().enumerate() {
+            let tok = Token(LISTENER.0 - index);
+            if let Err(err) = poll.register(listener, tok, Ready::readable(), PollOpt::level()) {
+                warn!("Unable to resume polling listener: {}", err);
+            }
+        }
+    }
+
+    // Close every connection, and stop once they have all gone or abort them at the deadline.
+    fn drain(&mut self, poll: &mut Poll, timeout: Duration, code: CloseCode, reason: &str) {
+        self.pause_accepting(poll);
+        let deadline = Instant::now() + timeout;
+        match self.deadline {
+            Some(current) => {
+                self.deadline = Some(current.min(deadline));
+                return;
+            }
+            None => self.deadline = Some(deadline),
+        }
+        debug!(
+            "Draining {} connections within {:?}.",
+            self.connections.len(),
+            timeout
+        );
+        let mut dead = Vec::new();
+        for (_, conn) in self.connections.iter_mut() {
+            conn.shutdown_with(code, reason);
+        }
+        for (_, conn) in self.connections.iter() {
+            if let Err(err) = self.schedule(poll, conn) {
+                dead.push((conn.token(), err))
+            }
+        }
+        for (token, err) in dead {
+            self.connections[token.into()].error(err)
+        }
+    }
+
+    // Drop every connection without closing it, and stop.
+    fn abort(&mut self) {
+        debug!("Aborting {} connections.", self.connections.len());
+        let tokens = self
+            .connections
+            .iter()
+            .map(|(token, _)| Token(token))
+            .collect::<Vec<_>>();
+        for token in tokens {
+            self.connections[token.into()].disconnect();
+            if let Some(timeout) = self.connections[token.into()].take_linger_timeout() {
+                self.cancel_timeout(&timeout);
+            }
+            self.leave_groups(token);
+            let handler = self.connections.remove(token.into()).consume();
+            self.factory.connection_lost(handler);
+        }
+        self.factory.on_shutdown();
+        self.state = State::Inactive;
+    }
+
     #[inline]
     fn check_active(&mut self, poll: &mut Poll, active: bool, token: Token) {
         // NOTE: Closing state only applies after a ws connection was successfully
         // established. It's possible that we may go inactive while in a connecting
         // state if the handshake fails.
         if !active {
+            if let Some(linger) = self.settings.close_linger {
+                if self.connections[token.into()].linger() {
+                    let timeout = self.set_timeout(
+                        linger,
+                        Timeout {
+                            connection: token,
+                            event: LINGER,
+                        },
+                    );
+                    self.connections[token.into()].set_linger_timeout(timeout);
+                    return self.check_active(poll, true, token);
+                }
+            }
+            if let Some(timeout) = self.connections[token.into()].take_linger_timeout() {
+                self.cancel_timeout(&timeout);
+            }
             if let Ok(addr) = self.connections[token.into()].socket().peer_addr() {
                 debug!("WebSocket connection to {} disconnected.", addr);
             } else {
                 trace!("WebSocket connection to token={:?} disconnected.", token);
             }
+            self.leave_groups(token);
             let handler = self.connections.remove(token.into()).consume();
             self.factory.connection_lost(handler);
         } else {
@@ -570,6 +1175,7 @@ where
                 .or_else(|err| {
                     // This will be an io error, so disconnect will already be called
                     self.connections[token.into()].error(err);
+                    self.leave_groups(token);
                     let handler = self.connections.remove(token.into()).consume();
                     self.factory.connection_lost(handler);
                     Ok::<(), Error>(())
@@ -578,9 +1184,132 @@ where
         }
     }
 
+    fn leave_groups(&mut self, token: Token) {
+        for group in self.groups.values_mut() {
+            group.members.remove(&token);
+        }
+    }
+
+    fn join_group(
+        &mut self,
+        poll: &mut Poll,
+        group: usize,
+        token: Token,
+        connection_id: u32,
+        since: u64,
+    ) {
+        let conn = match self.connections.get_mut(token.into()) {
+            Some(conn) if conn.connection_id() == connection_id => conn,
+            _ => {
+                trace!("Connection disconnected before it could join a group.");
+                return;
+            }
+        };
+        let state = self.groups.entry(group).or_default();
+        if state.members.insert(token, connection_id) == Some(connection_id) {
+            return;
+        }
+        if state.history.is_empty() || !conn.is_open() {
+            return;
+        }
+        // Catch the new member up on the messages it has missed.
+        let first = state.sent - state.history.len() as u64;
+        let skip = since.saturating_sub(first) as usize;
+        trace!(
+            "Replaying {} messages of group {} to {:?}.",
+            state.history.len().saturating_sub(skip),
+            group,
+            token
+        );
+        let result = state
+            .history
+            .iter()
+            .skip(skip)
+            .try_for_each(|msg| conn.send_message(msg.clone()))
+            .and_then(|_| self.schedule(poll, &self.connections[token.into()]));
+        if let Err(err) = result {
+            self.connections[token.into()].error(err)
+        }
+    }
+
+    fn send_to_group(&mut self, poll: &mut Poll, group: usize, msg: Message) {
+        let mut sent = Vec::new();
+        let mut dead = Vec::new();
+        if let Some(state) = self.groups.get_mut(&group) {
+            trace!(
+                "Sending message to {} members of group {}: {:?}",
+                state.members.len(),
+                group,
+                msg
+            );
+            let connections = &mut self.connections;
+            state.members.retain(|&token, &mut connection_id| {
+                match connections.get_mut(token.into()) {
+                    Some(ref mut conn) if conn.connection_id() == connection_id => {
+                        if conn.is_open() {
+                            if let Err(err) = conn.send_message(msg.clone()) {
+                                dead.push((token, err))
+                            }
+                            sent.push(token);
+                        }
+                        true
+                    }
+                    // The connection has gone away, and its token may have been reused.
+                    _ => false,
+                }
+            });
+            state.sent += 1;
+            if state.capacity > 0 {
+                if state.history.len() == state.capacity {
+                    state.history.pop_front();
+                }
+                state.history.push_back(msg);
+            }
+        }
+        for token in sent {
+            if let Err(err) = self.schedule(poll, &self.connections[token.into()]) {
+                dead.push((token, err))
+            }
+        }
+        for (token, err) in dead {
+            self.connections[token.into()].error(err)
+        }
+    }
+
+    // Serve a stream that was handed over through the queue.
+    fn serve(&mut self, poll: &mut Poll, stream: Option<StdTcpStream>) {
+        let stream = match stream {
+            Some(stream) => stream,
+            None => return,
+        };
+        if !self.accepting {
+            debug!("Closing tcp connection handed to the event loop while not accepting.");
+            return;
+        }
+        let res = TcpStream::from_stream(stream)
+            .map_err(Error::from)
+            .and_then(|sock| {
+                if let Ok(addr) = sock.peer_addr() {
+                    debug!(
+                        "Serving tcp connection from {} handed to the event loop.",
+                        addr
+                    );
+                }
+                self.accept(poll, sock, None)
+            });
+        if let Err(err) = res {
+            error!("Unable to serve tcp connection: {:?}", err);
+        }
+    }
+
+    // Keep running without listeners or connections, for loops that are handed their streams.
+    pub fn keep_serving(&mut self) {
+        self.serving = true;
+    }
+
     #[inline]
     fn is_client(&self) -> bool {
-        self.listener.is_none()
+        self.listeners.is_empty() && !self.serving
     }
 
     #[inline]
@@ -589,10 +1318,14 @@ where
         if self.connections.is_empty() {
             if !self.state.is_active() {
                 debug!("Shutting down websocket server.");
-            } else if self.is_client() {
+            } else if self.is_client() && self.pending_tunnels == 0 {
                 debug!("Shutting down websocket client.");
                 self.factory.on_shutdown();
                 self.state = State::Inactive;
+            } else if self.deadline.is_some() {
+                debug!("Drained all connections, shutting down websocket server.");
+                self.factory.on_shutdown();
+                self.state = State::Inactive;
             }
         }
     }
@@ -603,16 +1336,29 @@ where
                 debug_assert!(false, "System token used for io event. This is a bug!");
                 error!("System token used for io event. This is a bug!");
             }
-            ALL => {
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            HANDSHAKES => self.handle_handshakes(poll),
+            TUNNELS => self.handle_tunnels(poll),
+            token if self.listener(token).is_some() => {
                 if events.is_readable() {
-                    match self.listener
-                        .as_ref()
+                    if let Some(wait) = self.accept_delay() {
+                        return self.throttle_accepts(poll, wait);
+                    }
+                }
+                #[cfg(any(feature = "ssl", feature = "nativetls"))]
+                {
+                    if events.is_readable() && self.handshakes.is_some() {
+                        return self.offload_accept(token);
+                    }
+                }
+                if events.is_readable() {
+                    match self.listener(token)
                         .expect("No listener provided for server websocket connections")
                         .accept()
                     {
                         Ok((sock, addr)) => {
-                            info!("Accepted a new tcp connection from {}.", addr);
-                            if let Err(err) = self.accept(poll, sock) {
+                            info!(peer = addr; "Accepted a new tcp connection from {}.", addr);
+                            if let Err(err) = self.accept(poll, sock, None) {
                                 error!("Unable to build WebSocket connection {:?}", err);
                                 if self.settings.panic_on_new_connection {
                                     panic!("Unable to build WebSocket connection {:?}", err);
@@ -627,6 +1373,7 @@ where
                 }
             }
             TIMER => while let Some(t) = self.timer.poll() {
+                self.timers -= 1;
                 self.handle_timeout(poll, t);
             },
             QUEUE => {
@@ -750,6 +1497,33 @@ where
                             }
                         }
                     }
+                    Signal::Filtered(msg, filter) => {
+                        trace!("Broadcasting message to selected connections: {:?}", msg);
+                        for (_, conn) in self.connections.iter_mut() {
+                            if !conn.is_open() || !filter.matches(&conn.info()) {
+                                continue;
+                            }
+                            if let Err(err) = conn.send_message(msg.clone()) {
+                                dead.push((conn.token(), err))
+                            }
+                        }
+                    }
+                    Signal::Uncompressed(msg) => {
+                        trace!("Broadcasting uncompressed message: {:?}", msg);
+                        for (_, conn) in self.connections.iter_mut() {
+                            if let Err(err) = conn.send_uncompressed(msg.clone()) {
+                                dead.push((conn.token(), err))
+                            }
+                        }
+                    }
+                    Signal::Traced(msg, trace) => {
+                        trace!("Broadcasting message with trace {}: {:?}", trace, msg);
+                        for (_, conn) in self.connections.iter_mut() {
+                            if let Err(err) = conn.send_traced(msg.clone(), trace) {
+                                dead.push((conn.token(), err))
+                            }
+                        }
+                    }
                     Signal::Close(code, reason) => {
                         trace!("Broadcasting close: {:?} - {}", code, reason);
                         for (_, conn) in self.connections.iter_mut() {
@@ -758,6 +1532,64 @@ where
                             }
                         }
                     }
+                    Signal::SendThenClose(msg, code, reason) => {
+                        trace!("Broadcasting message and close: {:?} - {}", code, reason);
+                        for (_, conn) in self.connections.iter_mut() {
+                            let sent = conn
+                                .send_message(msg.clone())
+                                .and_then(|_| conn.send_close(code, reason.borrow()));
+                            if let Err(err) = sent {
+                                dead.push((conn.token(), err))
+                            }
+                        }
+                    }
+                    Signal::Respond(_) => {
+                        debug!("Ignoring a handshake response sent to all connections.");
+                        return;
+                    }
+                    Signal::Flush(_) => {
+                        debug!("Ignoring a flush request for all connections.");
+                        return;
+                    }
+                    Signal::Diagnostics => {
+                        debug!("Ignoring a diagnostics request for all connections.");
+                        return;
+                    }
+                    Signal::Dump(snapshot) => {
+                        let _ = snapshot.send(self.snapshot());
+                        return;
+                    }
+                    Signal::MaxConnections(max_connections) => {
+                        self.resize(max_connections);
+                        return;
+                    }
+                    Signal::Settings(settings) => {
+                        self.reconfigure(*settings);
+                        return;
+                    }
+                    Signal::Join(..) | Signal::Leave(_) => {
+                        debug!(
+                            "Ignoring a change to the membership of a group without a connection."
+                        );
+                        return;
+                    }
+                    Signal::Group(group, msg) => {
+                        self.send_to_group(poll, group, msg);
+                        return;
+                    }
+                    Signal::Disband(group) => {
+                        trace!("Disbanding group {}.", group);
+                        self.groups.remove(&group);
+                        return;
+                    }
+                    Signal::History(group, capacity) => {
+                        self.groups.entry(group).or_default().capacity = capacity;
+                        return;
+                    }
+                    Signal::Serve(stream) => {
+                        self.serve(poll, stream.take());
+                        return;
+                    }
                     Signal::Ping(data) => {
                         trace!("Broadcasting ping");
                         for (_, conn) in self.connections.iter_mut() {
@@ -774,21 +1606,62 @@ where
                             }
                         }
                     }
-                    Signal::Connect(url) => {
-                        if let Err(err) = self.connect(poll, url.clone()) {
-                            if self.settings.panic_on_new_connection {
-                                panic!("Unable to establish connection to {}: {:?}", url, err);
+                    Signal::Nodelay(nodelay) => {
+                        for (_, conn) in self.connections.iter() {
+                            if let Err(err) = conn.socket().set_nodelay(nodelay) {
+                                warn!("Unable to set TCP_NODELAY on {:?}: {}", conn.token(), err);
                             }
-                            error!("Unable to establish connection to {}: {:?}", url, err);
+                        }
+                        return;
+                    }
+                    Signal::Keepalive(keepalive) => {
+                        for (_, conn) in self.connections.iter() {
+                            if let Err(err) = conn.socket().set_keepalive(keepalive) {
+                                warn!("Unable to set TCP keepalive on {:?}: {}", conn.token(), err);
+                            }
+                        }
+                        return;
+                    }
+                    Signal::Tap(_) => {
+                        warn!("Taps can only be attached to a single connection.");
+                        return;
+                    }
+                    Signal::Priority(_) => {
+                        warn!("Priorities can only be set on a single connection.");
+                        return;
+                    }
+                    Signal::Connect(urls) => {
+                        if let Err(err) = self.dial(poll, urls.clone(), ALL, 0) {
+                            self.connect_failed(ALL, 0, &urls[0], err);
                         }
                         return;
                     }
                     Signal::Shutdown => self.shutdown(),
+                    Signal::PauseAccepting => {
+                        self.pause_accepting(poll);
+                        return;
+                    }
+                    Signal::Drain {
+                        timeout,
+                        code,
+                        retry_after,
+                    } => {
+                        let reason = match retry_after {
+                            Some(delay) => retry_reason(delay),
+                            None => "Shutting down.".into(),
+                        };
+                        self.drain(poll, timeout, code, &reason);
+                        return;
+                    }
+                    Signal::Abort => {
+                        self.abort();
+                        return;
+                    }
                     Signal::Timeout {
                         delay,
                         token: event,
                     } => {
-                        let timeout = self.timer.set_timeout(
+                        let timeout = self.set_timeout(
                             Duration::from_millis(delay),
                             Timeout {
                                 connection: ALL,
@@ -803,7 +1676,7 @@ where
                         return;
                     }
                     Signal::Cancel(timeout) => {
-                        self.timer.cancel_timeout(&timeout);
+                        self.cancel_timeout(&timeout);
                         return;
                     }
                 }
@@ -836,6 +1709,40 @@ where
                             )
                         }
                     }
+                    Signal::Filtered(..) => {
+                        debug!("Ignoring a filtered broadcast sent to a single connection.");
+                        return;
+                    }
+                    Signal::Uncompressed(msg) => {
+                        if let Some(conn) = self.connections.get_mut(token.into()) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_uncompressed(msg) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a message was waiting in the queue.")
+                            }
+                        } else {
+                            trace!(
+                                "Connection disconnected while a message was waiting in the queue."
+                            )
+                        }
+                    }
+                    Signal::Traced(msg, trace) => {
+                        if let Some(conn) = self.connections.get_mut(token.into()) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_traced(msg, trace) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a message was waiting in the queue.")
+                            }
+                        } else {
+                            trace!(
+                                "Connection disconnected while a message was waiting in the queue."
+                            )
+                        }
+                    }
                     Signal::Close(code, reason) => {
                         if let Some(conn) = self.connections.get_mut(token.into()) {
                             if conn.connection_id() == connection_id {
@@ -849,6 +1756,102 @@ where
                             trace!("Connection disconnected while close signal was waiting in the queue.")
                         }
                     }
+                    Signal::SendThenClose(msg, code, reason) => {
+                        if let Some(conn) = self.connections.get_mut(token.into()) {
+                            if conn.connection_id() == connection_id {
+                                let sent = conn
+                                    .send_message(msg)
+                                    .and_then(|_| conn.send_close(code, reason));
+                                if let Err(err) = sent {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a final message was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while a final message was waiting in the queue.")
+                        }
+                    }
+                    Signal::Respond(response) => {
+                        if let Some(conn) = self.connections.get_mut(token.into()) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.respond(*response) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a handshake response was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while a handshake response was waiting in the queue.")
+                        }
+                    }
+                    Signal::Diagnostics => {
+                        let body = self.snapshot().to_json();
+                        match self.connections.get_mut(token.into()) {
+                            Some(ref mut conn) if conn.connection_id() == connection_id => {
+                                let mut response = Response::new(200, "OK", body.into_bytes());
+                                response
+                                    .headers_mut()
+                                    .push(("Content-Type".into(), b"application/json".to_vec()));
+                                if let Err(err) = conn.respond(response) {
+                                    conn.error(err)
+                                }
+                            }
+                            _ => trace!("Connection disconnected while a diagnostics request was waiting in the queue."),
+                        }
+                    }
+                    Signal::Dump(snapshot) => {
+                        let _ = snapshot.send(self.snapshot());
+                        return;
+                    }
+                    Signal::MaxConnections(max_connections) => {
+                        self.resize(max_connections);
+                        return;
+                    }
+                    Signal::Settings(settings) => {
+                        self.reconfigure(*settings);
+                        return;
+                    }
+                    Signal::Flush(done) => {
+                        if let Some(conn) = self.connections.get_mut(token.into()) {
+                            if conn.connection_id() == connection_id {
+                                conn.flush(done)
+                            } else {
+                                trace!("Connection disconnected while a flush request was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while a flush request was waiting in the queue.")
+                        }
+                        return;
+                    }
+                    Signal::Join(group, since) => {
+                        self.join_group(poll, group, token, connection_id, since);
+                        return;
+                    }
+                    Signal::Leave(group) => {
+                        if let Some(state) = self.groups.get_mut(&group) {
+                            if state.members.get(&token) == Some(&connection_id) {
+                                state.members.remove(&token);
+                            }
+                        }
+                        return;
+                    }
+                    Signal::Group(group, msg) => {
+                        self.send_to_group(poll, group, msg);
+                        return;
+                    }
+                    Signal::Disband(group) => {
+                        self.groups.remove(&group);
+                        return;
+                    }
+                    Signal::History(group, capacity) => {
+                        self.groups.entry(group).or_default().capacity = capacity;
+                        return;
+                    }
+                    Signal::Serve(stream) => {
+                        self.serve(poll, stream.take());
+                        return;
+                    }
                     Signal::Ping(data) => {
                         if let Some(conn) = self.connections.get_mut(token.into()) {
                             if conn.connection_id() == connection_id {
@@ -875,25 +1878,80 @@ where
                             trace!("Connection disconnected while pong signal was waiting in the queue.")
                         }
                     }
-                    Signal::Connect(url) => {
-                        if let Err(err) = self.connect(poll, url.clone()) {
-                            if let Some(conn) = self.connections.get_mut(token.into()) {
-                                conn.error(err)
-                            } else {
-                                if self.settings.panic_on_new_connection {
-                                    panic!("Unable to establish connection to {}: {:?}", url, err);
+                    Signal::Nodelay(nodelay) => {
+                        match self.connections.get(token.into()) {
+                            Some(conn) if conn.connection_id() == connection_id => {
+                                if let Err(err) = conn.socket().set_nodelay(nodelay) {
+                                    warn!("Unable to set TCP_NODELAY on {:?}: {}", token, err);
+                                }
+                            }
+                            _ => trace!("Connection disconnected while nodelay signal was waiting in the queue."),
+                        }
+                        return;
+                    }
+                    Signal::Keepalive(keepalive) => {
+                        match self.connections.get(token.into()) {
+                            Some(conn) if conn.connection_id() == connection_id => {
+                                if let Err(err) = conn.socket().set_keepalive(keepalive) {
+                                    warn!("Unable to set TCP keepalive on {:?}: {}", token, err);
                                 }
-                                error!("Unable to establish connection to {}: {:?}", url, err);
                             }
+                            _ => trace!("Connection disconnected while keepalive signal was waiting in the queue."),
+                        }
+                        return;
+                    }
+                    Signal::Tap(tap) => {
+                        match self.connections.get_mut(token.into()) {
+                            Some(conn) if conn.connection_id() == connection_id => {
+                                if let Some(observer) = tap.take() {
+                                    conn.tap(observer);
+                                }
+                            }
+                            _ => trace!("Connection disconnected while tap signal was waiting in the queue."),
+                        }
+                        return;
+                    }
+                    Signal::Priority(priority) => {
+                        match self.connections.get_mut(token.into()) {
+                            Some(ref mut conn) if conn.connection_id() == connection_id => {
+                                conn.set_priority(priority)
+                            }
+                            _ => trace!("Connection disconnected while priority signal was waiting in the queue."),
+                        }
+                        return;
+                    }
+                    Signal::Connect(urls) => {
+                        if let Err(err) = self.dial(poll, urls.clone(), token, connection_id) {
+                            self.connect_failed(token, connection_id, &urls[0], err);
                         }
                         return;
                     }
                     Signal::Shutdown => self.shutdown(),
+                    Signal::PauseAccepting => {
+                        self.pause_accepting(poll);
+                        return;
+                    }
+                    Signal::Drain {
+                        timeout,
+                        code,
+                        retry_after,
+                    } => {
+                        let reason = match retry_after {
+                            Some(delay) => retry_reason(delay),
+                            None => "Shutting down.".into(),
+                        };
+                        self.drain(poll, timeout, code, &reason);
+                        return;
+                    }
+                    Signal::Abort => {
+                        self.abort();
+                        return;
+                    }
                     Signal::Timeout {
                         delay,
                         token: event,
                     } => {
-                        let timeout = self.timer.set_timeout(
+                        let timeout = self.set_timeout(
                             Duration::from_millis(delay),
                             Timeout {
                                 connection: token,
@@ -910,7 +1968,7 @@ where
                         return;
                     }
                     Signal::Cancel(timeout) => {
-                        self.timer.cancel_timeout(&timeout);
+                        self.cancel_timeout(&timeout);
                         return;
                     }
                 }
@@ -924,10 +1982,129 @@ where
         }
     }
 
+    fn report_slow_callbacks(&mut self) {
+        let slow = match self.shared.slow.lock() {
+            Ok(mut slow) if !slow.is_empty() => slow.split_off(0),
+            _ => return,
+        };
+        for (token, callback, duration) in slow {
+            self.factory.on_slow_callback(token, callback, duration);
+        }
+    }
+
+    fn set_timeout(&mut self, delay: Duration, timeout: Timeout) -> mio_extras::timer::Timeout {
+        self.timers += 1;
+        self.timer.set_timeout(delay, timeout)
+    }
+
+    fn cancel_timeout(&mut self, timeout: &mio_extras::timer::Timeout) {
+        if self.timer.cancel_timeout(timeout).is_some() {
+            self.timers -= 1;
+        }
+    }
+
+    // Shrink the idle buffers of every connection to `idle_shrink_target`.
+    fn shrink_buffers(&mut self) {
+        trace!(
+            "Shrinking the buffers of {} connections.",
+            self.connections.len()
+        );
+        let target = self.settings.idle_shrink_target;
+        for (_, conn) in self.connections.iter_mut() {
+            conn.shrink_buffers(target);
+        }
+    }
+
+    // Close the connections whose peers have not taken any outgoing data for `timeout`.
+    fn close_stalled(&mut self, poll: &mut Poll, timeout: Duration) {
+        let stalled = self
+            .connections
+            .iter_mut()
+            .filter_map(|(token, conn)| {
+                conn.check_write_stall(timeout).err().map(|err| {
+                    conn.error(err);
+                    Token(token)
+                })
+            })
+            .collect::<Vec<_>>();
+        for token in stalled {
+            let active = {
+                let conn = &self.connections[token.into()];
+                conn.events().is_readable() || conn.events().is_writable()
+            };
+            self.check_active(poll, active, token);
+        }
+    }
+
+    fn reconfigure(&mut self, settings: Settings) {
+        info!("Replacing the settings of the event loop.");
+        let max_connections = settings.max_connections;
+        self.settings = Settings {
+            max_connections: self.settings.max_connections,
+            queue_size: self.settings.queue_size,
+            ..settings
+        };
+        if max_connections != self.settings.max_connections {
+            self.resize(max_connections);
+        }
+        // The pooled buffers may no longer have the capacity that new connections start with.
+        if let Ok(mut pool) = self.shared.pool.lock() {
+            pool.clear();
+        }
+    }
+
+    // Change the connection limit. Connections keep their tokens, which are registered with the
+    // poll and held by their senders, so shrinking the slab only drops the vacant slots after
+    // the last connection.
+    fn resize(&mut self, max_connections: usize) {
+        info!(
+            "Changing the connection limit from {} to {} with {} connections.",
+            self.settings.max_connections,
+            max_connections,
+            self.connections.len()
+        );
+        self.settings.max_connections = max_connections;
+        if max_connections > self.connections.capacity() {
+            let additional = max_connections - self.connections.len();
+            self.connections.reserve_exact(additional);
+        } else {
+            self.connections.shrink_to_fit();
+        }
+    }
+
+    pub fn snapshot(&self) -> LoopStateSnapshot {
+        #[cfg(any(feature = "ssl", feature = "nativetls"))]
+        let pending_handshakes = self.pending_handshakes;
+        #[cfg(not(any(feature = "ssl", feature = "nativetls")))]
+        let pending_handshakes = 0;
+
+        LoopStateSnapshot {
+            listeners: self.local_addrs().unwrap_or_default(),
+            accepted: self.next_connection_id,
+            pending_handshakes,
+            timers: self.timers,
+            groups: self.groups.len(),
+            queue_capacity: self.queue_capacity,
+            slots: self.connections.capacity(),
+            settings: self.settings,
+            connections: self
+                .connections
+                .iter()
+                .map(|(_, conn)| conn.snapshot())
+                .collect(),
+        }
+    }
+
     fn handle_timeout(&mut self, poll: &mut Poll, Timeout { connection, event }: Timeout) {
+        if connection == SYSTEM && event == LISTENER {
+            return self.resume_accepts(poll);
+        }
         let active = {
             if let Some(conn) = self.connections.get_mut(connection.into()) {
-                if let Err(err) = conn.timeout_triggered(event) {
+                if event == LINGER && conn.is_lingering() {
+                    conn.take_linger_timeout();
+                    conn.stop_lingering();
+                } else if let Err(err) = conn.timeout_triggered(event) {
                     conn.error(err)
                 }
 
@@ -941,6 +2118,15 @@ where
     }
 }
 
+// Order events so that those of connections with a higher priority are handled first. The sort
+// is stable, so events of the same priority keep the order they arrived in.
+fn by_priority<P>(ready: &mut [(Token, Ready)], priority: P)
+where
+    P: Fn(Token) -> Priority,
+{
+    ready.sort_by_key(|&(token, _)| priority(token))
+}
+
 mod test {
     #![allow(unused_imports, unused_variables, dead_code)]
     use std::str::FromStr;
@@ -951,6 +2137,31 @@ mod test {
     use super::*;
     use result::{Error, Kind};
 
+    #[test]
+    fn events_by_priority() {
+        let mut ready: Vec<_> = (0..6).map(|i| (Token(i), Ready::readable())).collect();
+        ready.push((QUEUE, Ready::readable()));
+        by_priority(&mut ready, |token| match token.0 % 3 {
+            _ if token == QUEUE => Priority::Normal,
+            0 => Priority::Low,
+            1 => Priority::High,
+            _ => Priority::Normal,
+        });
+        let order: Vec<_> = ready.iter().map(|&(token, _)| token).collect();
+        assert_eq!(
+            order,
+            vec![
+                Token(1),
+                Token(4),
+                Token(2),
+                Token(5),
+                QUEUE,
+                Token(0),
+                Token(3)
+            ]
+        );
+    }
+
     #[test]
     fn test_url_to_addrs() {
         let ws_url = Url::from_str("ws://example.com?query=me").unwrap();
@@ -967,7 +2178,7 @@ mod test {
             Ok(_) => panic!("url_to_addrs accepts http urls."),
             Err(Error {
                 kind: Kind::Internal,
-                details: _,
+                ..
             }) => (), // pass
             err => panic!("{:?}", err),
         }
@@ -975,11 +2186,9 @@ mod test {
         match url_to_addrs(&no_resolve) {
             Ok(_) => panic!("url_to_addrs creates addresses for non-existent domains."),
             Err(Error {
-                kind: Kind::Io(_),
-                details: _,
+                kind: Kind::Io(_), ..
             }) => (), // pass
             err => panic!("{:?}", err),
         }
     }
-
 }
@@ -0,0 +1,275 @@
+//! Authorization of handshakes with JSON Web Tokens.
+//!
+//! `JwtAuthorizer` takes a token from the `Authorization: Bearer` header of the handshake
+//! request, or from a query parameter for browsers, which can not set headers on WebSocket
+//! requests. Requests without a valid token are refused with a 401 response before any handler
+//! sees them, and the claims of valid tokens are left in the context of the connection, along
+//! with their subject as the `Identity` of the connection:
+//!
+//! ```no_run
+//! use parity_ws::jwt::{Algorithm, Claims, DecodingKey, JwtAuthorizer, Validation};
+//! use parity_ws::{Builder, Sender};
+//!
+//! let mut validation = Validation::new(Algorithm::HS256);
+//! validation.set_audience(&["chat"]);
+//! let authorizer = JwtAuthorizer::new(DecodingKey::from_secret(b"secret"), validation)
+//!     .with_query_param("access_token");
+//!
+//! let mut ws = Builder::new()
+//!     .build(|out: Sender| {
+//!         let user = out
+//!             .context()
+//!             .get::<Claims>()
+//!             .and_then(|claims| claims.subject().map(String::from));
+//!         move |msg| out.send(format!("{:?}: {}", user, msg))
+//!     })
+//!     .unwrap();
+//! ws.with_authorizer(authorizer);
+//! ws.listen("127.0.0.1:3012").unwrap();
+//! ```
+use std::fmt;
+
+pub use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use jsonwebtoken::decode;
+use serde_json::{Map, Value};
+use url::form_urlencoded;
+
+use auth::{Authorization, Authorizer, Credentials};
+use handshake::Request;
+use session::Identity;
+
+/// The claims of a validated token, found in the context of the connection. See
+/// `Sender::context`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Claims(Map<String, Value>);
+
+impl Claims {
+    /// The claim with the given name.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+
+    /// The `sub` claim, which usually identifies the user.
+    pub fn subject(&self) -> Option<&str> {
+        self.get("sub").and_then(Value::as_str)
+    }
+
+    /// All claims by name.
+    pub fn into_inner(self) -> Map<String, Value> {
+        self.0
+    }
+}
+
+/// An `Authorizer` that only allows handshake requests with a valid JSON Web Token. See the
+/// module documentation.
+///
+/// The signature and the registered claims such as `exp`, `aud` and `iss` are checked
+/// according to the `Validation`. Missing tokens are answered with a `WWW-Authenticate: Bearer`
+/// challenge, and invalid ones with an `invalid_token` error in the challenge.
+pub struct JwtAuthorizer {
+    keys: Vec<DecodingKey>,
+    validation: Validation,
+    query_param: Option<String>,
+    required: Vec<(String, Value)>,
+}
+
+impl JwtAuthorizer {
+    /// Validate tokens signed with `key` according to `validation`.
+    pub fn new(key: DecodingKey, validation: Validation) -> JwtAuthorizer {
+        JwtAuthorizer {
+            keys: vec![key],
+            validation,
+            query_param: None,
+            required: Vec::new(),
+        }
+    }
+
+    /// Also accept tokens signed with `key`, such as while keys are rotated. Keys are tried in
+    /// the order they were added.
+    pub fn with_key(mut self, key: DecodingKey) -> JwtAuthorizer {
+        self.keys.push(key);
+        self
+    }
+
+    /// Take the token from the query parameter `name` of the resource when the request has no
+    /// `Authorization` header. Tokens in urls end up in logs more easily, so they should be
+    /// short-lived.
+    pub fn with_query_param<N>(mut self, name: N) -> JwtAuthorizer
+    where
+        N: Into<String>,
+    {
+        self.query_param = Some(name.into());
+        self
+    }
+
+    /// Only allow tokens whose claim `name` equals `value`, such as a role or a scope.
+    pub fn require_claim<N, V>(mut self, name: N, value: V) -> JwtAuthorizer
+    where
+        N: Into<String>,
+        V: Into<Value>,
+    {
+        self.required.push((name.into(), value.into()));
+        self
+    }
+
+    // The token of the request, from its header or its query.
+    fn token(&self, req: &Request) -> Option<String> {
+        if let Some(header) = req.header("authorization") {
+            let header = String::from_utf8_lossy(header);
+            let mut parts = header.trim().splitn(2, ' ');
+            return match (parts.next(), parts.next()) {
+                (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+                    Some(token.trim().to_owned())
+                }
+                _ => None,
+            };
+        }
+        let name = self.query_param.as_ref()?;
+        let (_, query) = req.resource().split_once('?')?;
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, token)| token.into_owned())
+    }
+
+    // The claims of a token that is valid for one of the keys.
+    fn validate(&self, token: &str) -> Option<Claims> {
+        let claims = self
+            .keys
+            .iter()
+            .filter_map(|key| decode::<Map<String, Value>>(token, key, &self.validation).ok())
+            .next()?
+            .claims;
+        if self
+            .required
+            .iter()
+            .all(|(name, value)| claims.get(name) == Some(value))
+        {
+            Some(Claims(claims))
+        } else {
+            None
+        }
+    }
+}
+
+impl Authorizer for JwtAuthorizer {
+    fn authorize(&self, credentials: &Credentials) -> Authorization {
+        let token = match self.token(credentials.request()) {
+            Some(token) => token,
+            None => {
+                return Authorization::Challenge {
+                    headers: vec![("WWW-Authenticate".into(), b"Bearer".to_vec())],
+                }
+            }
+        };
+        match self.validate(&token) {
+            Some(claims) => {
+                if let Some(subject) = claims.subject() {
+                    credentials.context().insert(Identity(subject.into()));
+                }
+                credentials.context().insert(claims);
+                Authorization::Allow
+            }
+            None => {
+                debug!("Rejecting invalid token for {}.", credentials.request().resource());
+                Authorization::Challenge {
+                    headers: vec![(
+                        "WWW-Authenticate".into(),
+                        b"Bearer error=\"invalid_token\"".to_vec(),
+                    )],
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for JwtAuthorizer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JwtAuthorizer")
+            .field("keys", &self.keys.len())
+            .field("algorithms", &self.validation.algorithms)
+            .field("query_param", &self.query_param)
+            .field("required", &self.required)
+            .finish()
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use auth::authorize;
+    use context::Context;
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use serde_json::json;
+
+    fn token(secret: &[u8], claims: Value) -> String {
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn request(resource: &str, token: Option<&str>) -> Request {
+        let mut headers = Vec::new();
+        if let Some(token) = token {
+            headers.push(("Authorization".into(), format!("Bearer {}", token).into_bytes()));
+        }
+        Request::from_parts("GET", resource, headers)
+    }
+
+    fn authorizer() -> JwtAuthorizer {
+        JwtAuthorizer::new(
+            DecodingKey::from_secret(b"secret"),
+            Validation::new(Algorithm::HS256),
+        )
+    }
+
+    #[test]
+    fn bearer_token() {
+        let exp = get_current_timestamp() + 60;
+        let valid = token(b"secret", json!({"sub": "alice", "exp": exp}));
+        let context = Context::new();
+        let refusal = authorize(&authorizer(), &request("/", Some(&valid)), None, None, &context);
+        assert!(refusal.is_none());
+        assert_eq!(context.get::<Claims>().unwrap().subject(), Some("alice"));
+        assert_eq!(context.get::<Identity>(), Some(Identity("alice".into())));
+
+        let forged = token(b"guess", json!({"sub": "alice", "exp": exp}));
+        let context = Context::new();
+        let res = authorize(&authorizer(), &request("/", Some(&forged)), None, None, &context)
+            .unwrap();
+        assert_eq!(res.status(), 401);
+        assert!(context.get::<Claims>().is_none());
+
+        let expired = token(b"secret", json!({"sub": "alice", "exp": exp - 3600}));
+        let res = authorize(&authorizer(), &request("/", Some(&expired)), None, None, &context);
+        assert!(res.is_some());
+
+        let res = authorize(&authorizer(), &request("/", None), None, None, &context).unwrap();
+        assert_eq!(res.status(), 401);
+    }
+
+    #[test]
+    fn query_param_and_claims() {
+        let exp = get_current_timestamp() + 60;
+        let valid = token(b"secret", json!({"sub": "bob", "role": "admin", "exp": exp}));
+        let resource = format!("/feed?x=1&access_token={}", valid);
+        let context = Context::new();
+
+        let res = authorize(&authorizer(), &request(&resource, None), None, None, &context);
+        assert!(res.is_some());
+
+        let authorizer = authorizer().with_query_param("access_token");
+        let res = authorize(&authorizer, &request(&resource, None), None, None, &context);
+        assert!(res.is_none());
+
+        let authorizer = authorizer.require_claim("role", "operator");
+        let res = authorize(&authorizer, &request(&resource, None), None, None, &context);
+        assert!(res.is_some());
+    }
+
+    #[test]
+    fn rotated_keys() {
+        let exp = get_current_timestamp() + 60;
+        let old = token(b"old", json!({"exp": exp}));
+        let authorizer = authorizer().with_key(DecodingKey::from_secret(b"old"));
+        let res = authorize(&authorizer, &request("/", Some(&old)), None, None, &Context::new());
+        assert!(res.is_none());
+    }
+}
@@ -6,53 +6,143 @@
 extern crate byteorder;
 extern crate bytes;
 extern crate httparse;
+extern crate iovec;
+#[cfg(target_os = "linux")]
+extern crate libc;
 extern crate mio;
 extern crate mio_extras;
+extern crate net2;
 #[cfg(feature = "ssl")]
 extern crate openssl;
 #[cfg(feature = "nativetls")]
 extern crate native_tls;
+#[cfg(feature = "otel")]
+extern crate opentelemetry;
 extern crate rand;
+#[cfg(feature = "presign")]
+extern crate hmac;
+#[cfg(feature = "jwt")]
+extern crate jsonwebtoken;
+#[cfg(any(feature = "json", feature = "jwt"))]
+extern crate serde;
+#[cfg(any(feature = "json", feature = "jwt"))]
+extern crate serde_json;
 extern crate sha1;
+#[cfg(feature = "presign")]
+extern crate sha2;
 extern crate slab;
+#[cfg(feature = "config")]
+extern crate toml;
 extern crate url;
-#[macro_use]
 extern crate log;
 
+#[macro_use]
+mod logging;
+mod audit;
+mod auth;
+mod channel;
 mod circular_buffer;
 mod communication;
+#[cfg(feature = "config")]
+mod config;
 mod connection;
+mod context;
+mod cors;
+mod event;
 mod factory;
+#[cfg(feature = "testing-hooks")]
+mod faults;
 mod frame;
 mod handler;
 mod handshake;
 mod io;
 mod message;
+#[cfg(any(feature = "ssl", feature = "nativetls"))]
+mod offload;
 mod protocol;
+mod recorder;
 mod result;
+mod selftest;
+mod session;
+mod snapshot;
+mod socks;
+mod spill;
+#[cfg(feature = "srv")]
+mod srv;
 mod stream;
+mod tap;
+#[cfg(feature = "ssl")]
+mod tls;
+mod trace;
+mod transform;
+mod watchdog;
+mod writer;
 
 #[cfg(feature = "permessage-deflate")]
 pub mod deflate;
 
+pub mod batch;
+pub mod checksum;
+pub mod cluster;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod middleware;
+#[cfg(feature = "presign")]
+pub mod presign;
+pub mod probe;
+pub mod proto;
+pub mod sync;
 pub mod util;
 
 pub use factory::Factory;
-pub use handler::Handler;
+pub use handler::{Decision, Handler};
 
-pub use communication::Sender;
-pub use frame::Frame;
-pub use handshake::{Handshake, Request, Response};
-pub use message::Message;
-pub use protocol::{CloseCode, OpCode};
+pub use audit::{AuditOutcome, AuditRecord, AuditSink};
+pub use auth::{Authorization, Authorizer, Credentials};
+pub use channel::connect_channel;
+#[doc(hidden)]
+pub use circular_buffer::CircularBuffer;
+pub use connection::{ConnectionInfo, Priority};
+pub use communication::{ControlHandle, DataSender, FlushHandle, Group, Sender, StateHandle};
+pub use context::Context;
+pub use cors::Cors;
+pub use event::{Event, EventHandler};
+#[cfg(feature = "testing-hooks")]
+pub use faults::{Fault, FaultPlan};
+pub use frame::{Frame, FrameBuilder};
+pub use handshake::{Handshake, HeaderCase, Request, Response};
+pub use logging::{LogLevel, LogRecord, WsLogger};
+pub use message::{Message, Utf8Policy};
+pub use middleware::{HandlerExt, Layer};
+#[cfg(any(feature = "ssl", feature = "nativetls"))]
+pub use offload::HandshakeAcceptor;
+pub use protocol::{CloseCode, CloseFrame, OpCode};
+pub use recorder::{StateRecorder, Transition};
 pub use result::Kind as ErrorKind;
-pub use result::{Error, Result};
+pub use result::Phase as ErrorPhase;
+pub use result::{Error, ErrorContext, Result};
+pub use selftest::{capabilities, self_test, Capabilities};
+pub use session::{Identity, SessionPolicy};
+pub use snapshot::{ConnectionPhase, ConnectionSnapshot, LoopStateSnapshot};
+pub use stream::WritePolicy;
+pub use tap::{Direction, FrameObserver};
+#[cfg(feature = "ssl")]
+pub use tls::TlsSessionCache;
+pub use trace::{TraceId, Tracer};
+pub use transform::Transform;
+pub use watchdog::Watchdog;
+pub use writer::MessageWriter;
 
 use std::borrow::Borrow;
 use std::default::Default;
 use std::fmt;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{SocketAddr, TcpStream as StdTcpStream, ToSocketAddrs};
+#[cfg(feature = "config")]
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
+use mio::tcp::TcpStream;
 use mio::Poll;
 
 /// A utility function for setting up a WebSocket server.
@@ -126,6 +216,120 @@ where
     Ok(())
 }
 
+/// A utility function for setting up a WebSocket client to a service that is discovered through
+/// DNS SRV records, such as `_ws._tcp.example.com`. The targets are tried in the order given by
+/// their priorities and weights, falling back on the next target whenever a connection is
+/// refused. Services named `_wss` are connected to with TLS.
+///
+/// # Safety
+///
+/// This function blocks until the event loop finishes running. The records are looked up
+/// synchronously before the event loop starts.
+///
+/// # Examples
+///
+/// ```no_run
+/// use parity_ws::{connect_srv, CloseCode};
+///
+/// connect_srv("_ws._tcp.example.com", |out| {
+///     out.send("Hello WebSocket").unwrap();
+///
+///     move |msg| {
+///         println!("Got message: {}", msg);
+///         out.close(CloseCode::Normal)
+///     }
+/// }).unwrap()
+/// ```
+#[cfg(feature = "srv")]
+pub fn connect_srv<F, H>(name: &str, factory: F) -> Result<()>
+where
+    F: FnMut(Sender) -> H,
+    H: Handler,
+{
+    let mut ws = WebSocket::new(factory)?;
+    ws.connect_srv(name)?;
+    ws.run()?;
+    Ok(())
+}
+
+/// A utility function for handing a single HTTP upgrade over from another HTTP server.
+///
+/// The `request` is the already parsed upgrade request, which can be built with
+/// `Request::from_parts`, and `stream` is the TCP connection it was received on. The request is
+/// validated, the handshake response is written by this library, and the connection is then
+/// driven by the handler created by the factory.
+///
+/// # Safety
+///
+/// This function blocks until the connection is closed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use parity_ws::{from_upgrade, Request};
+///
+/// let listener = TcpListener::bind("127.0.0.1:3012").unwrap();
+/// let (stream, _) = listener.accept().unwrap();
+/// // ... read and route the HTTP request using another HTTP stack ...
+/// # let headers = Vec::new();
+/// let request = Request::from_parts("GET", "/chat", headers);
+///
+/// from_upgrade(request, stream, |out: parity_ws::Sender| {
+///     move |msg| out.send(msg)
+/// }).unwrap()
+/// ```
+///
+pub fn from_upgrade<F, H>(request: Request, stream: StdTcpStream, factory: F) -> Result<()>
+where
+    F: FnMut(Sender) -> H,
+    H: Handler,
+{
+    let mut ws = WebSocket::new(factory)?;
+    ws.serve_upgrade(request, stream)?;
+    ws.run()?;
+    Ok(())
+}
+
+/// A group of leniencies for peers that are known to deviate from the protocol in the same way.
+/// See `Settings::peer_quirks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quirks {
+    /// Older versions of Safari, which send large messages as many small fragments. The number
+    /// of fragments of a message is allowed to grow beyond `fragments_capacity`, which is raised
+    /// so that such messages are reassembled without reallocating.
+    LegacySafari,
+    /// Message brokers that keep connections alive by sending pongs that were never asked for,
+    /// and that mask the frames they send as servers. Unsolicited pongs are always accepted, as
+    /// RFC 6455 allows them as heartbeats, so this turns off `masking_strict` so that the masked
+    /// frames do not fail the connection.
+    PongHeartbeats,
+    /// Gateways and proxies that match header names case sensitively, and only pass on
+    /// handshakes whose WebSocket headers are spelled as in RFC 6455. The headers of outgoing
+    /// handshakes are written with `HeaderCase::Canonical`.
+    CaseSensitiveGateway,
+}
+
+/// A starting point for the settings of a WebSocket that is tuned for a kind of workload. See
+/// `Settings::profile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Small messages that should reach the other endpoint as soon as possible, such as quotes
+    /// or game state. Nagle's algorithm is disabled and outgoing buffers are written out in full
+    /// on every writable event.
+    LowLatency,
+    /// Large messages or bulk transfers. Buffers and frames are larger, so that fewer
+    /// allocations, frame headers and system calls are needed per byte.
+    HighThroughput,
+    /// Many connections that are mostly idle, such as for push notifications. Each connection
+    /// starts out with little memory and gives any memory that a burst made it allocate back
+    /// soon after.
+    ManyIdleConnections,
+    /// Devices with little memory. Connections are few, buffers are small and bounded, and
+    /// incoming frames are limited in size.
+    Embedded,
+}
+
 /// WebSocket settings
 #[non_exhaustive]
 #[derive(Debug, Clone, Copy)]
@@ -133,9 +337,21 @@ pub struct Settings {
     /// The maximum number of connections that this WebSocket will support.
     /// The default setting is low and should be increased when expecting more
     /// connections because this is a hard limit and no new connections beyond
-    /// this limit can be made until an old connection is dropped.
+    /// this limit can be made until an old connection is dropped. The limit can be changed
+    /// while the WebSocket runs with `Sender::set_max_connections`.
     /// Default: 100
     pub max_connections: usize,
+    /// The maximum number of connections accepted from the listeners per second, to absorb a
+    /// storm of connections, such as every client reconnecting after a restart, instead of
+    /// running all of their handshakes at once. Connections over the rate wait in the backlog
+    /// of the listener until they can be accepted. Streams handed to the event loop are not
+    /// limited.
+    /// Default: None
+    pub max_accepts_per_sec: Option<u32>,
+    /// The number of connections that may be accepted in a burst on top of
+    /// `max_accepts_per_sec`, after a quiet period has saved them up.
+    /// Default: 0
+    pub accept_burst: u32,
     /// The number of events anticipated per connection. The event loop queue size will
     /// be `queue_size` * `max_connections`. In order to avoid an overflow error,
     /// `queue_size` * `max_connections` must be less than or equal to `usize::max_value()`.
@@ -160,9 +376,33 @@ pub struct Settings {
     /// The maximum length of outgoing frames. Messages longer than this will be fragmented.
     /// Default: 65,535
     pub fragment_size: usize,
+    /// Messages with payloads smaller than this many bytes are sent without compression by
+    /// extensions such as permessage-deflate, since deflating a tiny payload costs time and
+    /// often makes it larger. Individual messages can also skip compression by being sent with
+    /// `Sender::send_uncompressed`.
+    /// Default: 0
+    pub compress_min_size: usize,
     /// The maximum length of acceptable incoming frames. Messages longer than this will be rejected.
     /// Default: unlimited
     pub max_fragment_size: usize,
+    /// The maximum number of reads with data tolerated while a frame header is incomplete. A peer
+    /// that trickles a header, a few bytes per read, is treated as stalled and the connection is
+    /// closed with a Stalled error. Headers are at most 14 bytes long and are usually received
+    /// in a single read, so small limits only affect such peers.
+    /// Default: unlimited
+    pub frame_header_read_limit: usize,
+    /// The maximum number of consecutive read events tolerated that yield no data. A peer that
+    /// keeps waking the connection without sending anything is treated as stalled and the
+    /// connection is closed with a Stalled error.
+    /// Default: unlimited
+    pub empty_read_limit: usize,
+    /// The number of milliseconds that the oldest outgoing byte of a connection may wait to be
+    /// written before the peer is treated as stalled, such as one that stopped reading, and the
+    /// connection is closed with a Stalled error. This frees the buffers and the slot held by
+    /// such peers. Connections are checked a few times per period, so they are closed up to a
+    /// quarter of it late. 0 lets outgoing data wait indefinitely.
+    /// Default: 0
+    pub write_stall_timeout_ms: u64,
     /// The initial size of the incoming buffer. A larger buffer uses more memory but will allow for
     /// fewer reallocations.
     /// Default: 2048
@@ -177,6 +417,16 @@ pub struct Settings {
     /// its initial capacity once it's needed again.
     /// Default: 1,048,576
     pub in_buffer_capacity_soft_limit: usize,
+    /// Whether to tune the incoming buffer of every connection to the frames it receives, rather
+    /// than keeping `in_buffer_capacity` sized for the largest messages of any connection. After
+    /// handling what it read, a connection resizes its buffer to the 95th percentile of the sizes
+    /// of its last 64 frames, rounded up to a power of two, between `in_buffer_adaptive_min` and
+    /// `in_buffer_capacity_soft_limit`. Ignored with `strict_preallocation`.
+    /// Default: false
+    pub in_buffer_adaptive: bool,
+    /// The smallest capacity that `in_buffer_adaptive` tunes an incoming buffer to.
+    /// Default: 512
+    pub in_buffer_adaptive_min: usize,
     /// The initial size of the outgoing buffer. A larger buffer uses more memory but will allow for
     /// fewer reallocations.
     /// Default: 2048
@@ -191,6 +441,63 @@ pub struct Settings {
     /// its initial capacity once it's needed again.
     /// Default: 1,048,576
     pub out_buffer_capacity_soft_limit: usize,
+    /// How often the event loop applies `idle_shrink_target` as a soft limit to the buffers of
+    /// every connection, so that the memory taken by a burst of traffic is given back once the
+    /// connection goes quiet, instead of only when the buffers are drained while handling it.
+    /// Buffers that still hold much data are left alone. While this is set, an idle event loop
+    /// wakes up at this interval.
+    /// Default: None
+    pub idle_shrink_interval: Option<Duration>,
+    /// The capacity that buffers are shrunk to by `idle_shrink_interval`. Empty buffers that
+    /// are larger than this are freed, and reallocated with their initial capacity once they are
+    /// needed again.
+    /// Default: 0
+    pub idle_shrink_target: usize,
+    /// The number of buffers of closed connections to keep for new connections to reuse, which
+    /// saves allocating and freeing two buffers for every connection of a server that handles
+    /// many short-lived connections, and keeps the allocator from fragmenting. Only buffers that
+    /// still have their initial capacity are kept, and they are freed when the settings of the
+    /// event loop are replaced. The pool holds up to this many times `in_buffer_capacity` or
+    /// `out_buffer_capacity` bytes while no connections are open.
+    ///
+    /// The connections themselves, along with their handlers, are kept in the slots of a slab
+    /// that is sized for `max_connections` when the event loop is built, and the slot of a
+    /// closed connection is reused for the next one. Their buffers are separate allocations,
+    /// which this pool recycles; there is no arena that holds a connection and its buffers
+    /// together.
+    /// Default: 0
+    pub buffer_pool_size: usize,
+    /// The number of outgoing bytes a connection keeps in memory before it stages further
+    /// frames in a temporary file, from which they are read back as the socket drains. This
+    /// lets a slow consumer fall behind on a large backlog without growing the outgoing buffer
+    /// or hitting its hard limit. Frames are always written in the order they were sent, and
+    /// the file is removed when the connection closes. 0 keeps every outgoing byte in memory.
+    /// Default: 0
+    pub spill_threshold: usize,
+    /// Whether to allocate every buffer of a connection when it is created, and never grow or
+    /// free it afterwards. The incoming and outgoing buffers are fixed at `in_buffer_capacity`
+    /// and `out_buffer_capacity`, the soft limits are ignored, fragments are limited to
+    /// `fragments_capacity` as if `fragments_grow` were false, and handshakes must fit in the
+    /// handshake buffers. Anything that does not fit fails with a Capacity error. The messages
+    /// themselves are still allocated as they are sent and received.
+    /// Default: false
+    pub strict_preallocation: bool,
+    /// How to continue after the socket accepts only part of the outgoing buffer. Writes that
+    /// are interrupted by a signal are always retried.
+    /// Default: WritePolicy::RetainOffset
+    pub write_policy: WritePolicy,
+    /// Whether to write a message straight to the socket as it is sent, if nothing is waiting to
+    /// be written ahead of it, rather than on the next writable event. Only the part that the
+    /// socket does not take is copied into the outgoing buffer, which cuts the latency and the
+    /// copying of request/response workloads.
+    /// Default: false
+    pub write_direct: bool,
+    /// What to do with a received text message that is not valid UTF-8. Connections to peers
+    /// that occasionally send invalid UTF-8 can be kept open by replacing the invalid sequences
+    /// or by delivering such messages as binary ones. `panic_on_encoding` only applies to the
+    /// strict policy.
+    /// Default: Utf8Policy::Strict
+    pub utf8_policy: Utf8Policy,
     /// Whether to panic when an Internal error is encountered. Internal errors should generally
     /// not occur, so this setting defaults to true as a debug measure, whereas production
     /// applications should consider setting it to false.
@@ -235,6 +542,20 @@ pub struct Settings {
     /// requirement that handshakes begin with a GET method, set this to true.
     /// Default: false
     pub method_strict: bool,
+    /// The value of the `User-Agent` header added to handshake requests sent by client
+    /// connections, so that servers can tell which library and version connected to them. A
+    /// `User-Agent` header set by `Handler::build_request` takes precedence over this setting.
+    /// Default: None
+    pub user_agent: Option<&'static str>,
+    /// The value of the `Server` header added to handshake responses sent by server
+    /// connections. A `Server` header set by `Handler::on_request` takes precedence over this
+    /// setting.
+    /// Default: None
+    pub server_ident: Option<&'static str>,
+    /// How the names of the headers of outgoing handshakes are written, including the ones set
+    /// by handlers. See `HeaderCase`.
+    /// Default: HeaderCase::Preserve
+    pub header_case: HeaderCase,
     /// Indicate whether server connections should use ssl encryption when accepting connections.
     /// Setting this to true means that clients should use the `wss` scheme to connect to this
     /// server. Note that using this flag will in general necessitate overriding the
@@ -250,25 +571,138 @@ pub struct Settings {
     ///
     /// Default: false
     pub tcp_nodelay: bool,
+    /// The priority of connections, which decides the order in which connections that are ready
+    /// at the same time are handled. This can be changed for a single connection with
+    /// `Factory::connection_settings` or `Sender::set_priority`.
+    /// Default: Priority::Normal
+    pub priority: Priority,
+    /// Whether listening sockets bound to IPv6 addresses accept only IPv6 connections. When
+    /// false, such sockets are dual-stack and also accept IPv4 connections as IPv4-mapped
+    /// addresses. This is always set explicitly on the socket, because the operating system
+    /// defaults differ: Linux is dual-stack by default, while Windows is IPv6 only. To listen on
+    /// the same port for both `0.0.0.0` and `[::]` with `bind_all`, set this to true.
+    /// Default: false
+    pub ipv6_only: bool,
+    /// How long to linger on a connection after the closing handshake completes. When set, the
+    /// endpoint that drops the connection first shuts down the write half of its TCP socket and
+    /// discards incoming data until the peer closes its side or this period elapses. Dropping a
+    /// socket with unread data makes the kernel send a reset, which many peers report as an
+    /// abnormal closure (1006) even though the WebSocket close was clean.
+    /// Default: None
+    pub close_linger: Option<Duration>,
+    /// The address of a SOCKS5 proxy through which client connections should be made.
+    /// When set, the host of each url is passed to the proxy unresolved, so names that only the
+    /// proxy can resolve, such as onion services, can be reached. TLS for `wss` urls and the
+    /// WebSocket handshake are then performed through the tunnel. The proxy negotiation runs on a
+    /// thread of its own, trying the urls of a connection in turn, and the connection is made once
+    /// the tunnel is open. Connections through a proxy do not fall back on other urls later.
+    /// Default: None
+    pub socks5_proxy: Option<SocketAddr>,
+    /// The local address that client connections are bound to before they connect, to choose
+    /// the interface that they go out through on hosts with several, such as a VPN interface.
+    /// The port is usually 0, to let the operating system pick one. Addresses of the other IP
+    /// version can not be connected to. Connections to a `socks5_proxy` are not bound.
+    /// Default: None
+    pub bind_local_addr: Option<SocketAddr>,
+    /// The name of a network device that client connections are bound to with
+    /// `SO_BINDTODEVICE`, so that they only go out through it whatever the routing table says.
+    /// This is only supported on Linux, and usually needs the `CAP_NET_RAW` capability.
+    /// Elsewhere, connecting fails. Connections to a `socks5_proxy` are not bound.
+    /// Default: None
+    pub bind_device: Option<&'static str>,
+    /// Answer preflight requests from browsers and allow the origins of upgrade requests
+    /// according to this policy, for endpoints that also receive cross-origin requests from
+    /// scripts. Preflight requests are answered without calling `Handler::on_request`, and
+    /// handshake responses get the `Access-Control-*` headers unless the handler set them.
+    /// See `Cors`.
+    /// Default: None
+    pub cors: Option<&'static Cors>,
+    /// Decides whether each handshake request may open a connection before it is passed to
+    /// `Handler::on_request`. Preflight requests answered for `cors` are not authorized. See
+    /// `Authorizer`.
+    /// Default: None
+    pub authorizer: Option<&'static dyn Authorizer>,
+    /// Allow only one open connection for each `Identity` that the authorizer leaves in the
+    /// context of a connection, either by refusing the handshakes of further connections with a
+    /// 409 response or by closing the connection that a new one replaces. Connections without
+    /// an identity are not limited. Sessions are tracked by each event loop, so the loops of a
+    /// `Cluster` each allow a connection for the same identity. See `SessionPolicy`.
+    /// Default: None
+    pub single_session: Option<SessionPolicy>,
+    /// Receives a structured record of every connection attempt as it ends, whether it was
+    /// rejected, failed or closed. See `AuditSink`.
+    /// Default: None
+    pub audit: Option<&'static dyn AuditSink>,
+    /// A transformation applied to the payload of every message sent and received, such as
+    /// application level encryption or an envelope format. See `Transform`.
+    /// Default: None
+    pub transform: Option<&'static dyn Transform>,
+    /// Whether every data message carries an optional `TraceId` in an envelope around its
+    /// payload. Both endpoints must agree on this setting. See `Sender::send_traced`.
+    /// Default: false
+    pub trace_ids: bool,
+    /// Hooks called as traced messages are queued, written to the socket and delivered to the
+    /// handler. See `Tracer`.
+    /// Default: None
+    pub tracer: Option<&'static dyn Tracer>,
+    /// Failures injected into the reads and writes of every connection, for testing how an
+    /// application copes with short reads, spurious `WouldBlock`s, slow writes and dropped
+    /// connections. Each connection follows the plan independently. See `FaultPlan`.
+    /// Default: None
+    #[cfg(feature = "testing-hooks")]
+    pub faults: Option<&'static FaultPlan>,
+    /// How long a single call of a `Handler` method may take before the factory is told about
+    /// it through `Factory::on_slow_callback`. Every callback blocks the event loop and all other
+    /// connections while it runs, so this helps to find the handlers that stall it.
+    /// Default: None
+    pub slow_callback_threshold: Option<Duration>,
+    /// Where the WebSocket logs to instead of the global logger of the `log` crate. See
+    /// `WsLogger`.
+    /// Default: None
+    pub logger: Option<&'static dyn WsLogger>,
+    /// A callback run on a separate thread when the event loop stops ticking for
+    /// `watchdog_timeout_ms`, such as when a handler blocks or deadlocks. See `Watchdog`.
+    /// Default: None
+    pub watchdog: Option<&'static dyn Watchdog>,
+    /// How long the event loop may go without ticking before the watchdog is called. While a
+    /// watchdog is set, an idle event loop wakes up twice per period to tick.
+    /// Default: 5000
+    pub watchdog_timeout_ms: u64,
 }
 
 impl Default for Settings {
     fn default() -> Settings {
         Settings {
             max_connections: 100,
+            max_accepts_per_sec: None,
+            accept_burst: 0,
             queue_size: 5,
             panic_on_new_connection: false,
             panic_on_shutdown: false,
             fragments_capacity: 10,
             fragments_grow: true,
             fragment_size: u16::max_value() as usize,
+            compress_min_size: 0,
             max_fragment_size: usize::max_value(),
+            frame_header_read_limit: usize::max_value(),
+            empty_read_limit: usize::max_value(),
+            write_stall_timeout_ms: 0,
             in_buffer_capacity: 2048,
             in_buffer_capacity_hard_limit: 10 * 1024 * 1024,
             in_buffer_capacity_soft_limit: 1024 * 1024,
+            in_buffer_adaptive: false,
+            in_buffer_adaptive_min: 512,
             out_buffer_capacity: 2048,
             out_buffer_capacity_hard_limit: 10 * 1024 * 1024,
             out_buffer_capacity_soft_limit: 1024 * 1024,
+            idle_shrink_interval: None,
+            idle_shrink_target: 0,
+            buffer_pool_size: 0,
+            spill_threshold: 0,
+            strict_preallocation: false,
+            write_policy: WritePolicy::RetainOffset,
+            write_direct: false,
+            utf8_policy: Utf8Policy::Strict,
             panic_on_internal: true,
             panic_on_capacity: false,
             panic_on_protocol: false,
@@ -280,10 +714,143 @@ impl Default for Settings {
             masking_strict: false,
             key_strict: false,
             method_strict: false,
+            user_agent: None,
+            server_ident: None,
+            header_case: HeaderCase::Preserve,
             encrypt_server: false,
             tcp_nodelay: false,
+            priority: Priority::Normal,
+            ipv6_only: false,
+            close_linger: None,
+            socks5_proxy: None,
+            bind_local_addr: None,
+            bind_device: None,
+            cors: None,
+            authorizer: None,
+            single_session: None,
+            audit: None,
+            transform: None,
+            trace_ids: false,
+            tracer: None,
+            #[cfg(feature = "testing-hooks")]
+            faults: None,
+            slow_callback_threshold: None,
+            logger: None,
+            watchdog: None,
+            watchdog_timeout_ms: 5000,
+        }
+    }
+}
+
+impl Settings {
+    /// The default settings tuned for a kind of workload. The settings of a profile can be
+    /// changed further like any others, such as to raise `max_connections`.
+    ///
+    /// ```
+    /// use parity_ws::{Builder, Profile, Settings};
+    ///
+    /// let mut settings = Settings::profile(Profile::ManyIdleConnections);
+    /// settings.max_connections = 50_000;
+    /// let ws = Builder::new().with_settings(settings).build(|_| |_| Ok(())).unwrap();
+    /// ```
+    pub fn profile(profile: Profile) -> Settings {
+        let defaults = Settings::default();
+        match profile {
+            Profile::LowLatency => Settings {
+                tcp_nodelay: true,
+                write_policy: WritePolicy::Requeue,
+                write_direct: true,
+                queue_size: 16,
+                in_buffer_capacity: 16_384,
+                out_buffer_capacity: 16_384,
+                ..defaults
+            },
+            Profile::HighThroughput => Settings {
+                queue_size: 64,
+                write_policy: WritePolicy::Requeue,
+                fragments_capacity: 64,
+                fragment_size: 1_048_576,
+                in_buffer_capacity: 65_536,
+                in_buffer_capacity_soft_limit: 8_388_608,
+                in_buffer_capacity_hard_limit: 67_108_864,
+                out_buffer_capacity: 65_536,
+                out_buffer_capacity_soft_limit: 8_388_608,
+                out_buffer_capacity_hard_limit: 67_108_864,
+                ..defaults
+            },
+            Profile::ManyIdleConnections => Settings {
+                max_connections: 10_000,
+                queue_size: 2,
+                fragments_capacity: 2,
+                in_buffer_capacity: 512,
+                in_buffer_capacity_soft_limit: 16_384,
+                out_buffer_capacity: 512,
+                out_buffer_capacity_soft_limit: 16_384,
+                ..defaults
+            },
+            Profile::Embedded => Settings {
+                max_connections: 8,
+                queue_size: 4,
+                fragments_capacity: 4,
+                fragment_size: 4096,
+                max_fragment_size: 65_536,
+                in_buffer_capacity: 512,
+                in_buffer_capacity_soft_limit: 4096,
+                in_buffer_capacity_hard_limit: 131_072,
+                out_buffer_capacity: 512,
+                out_buffer_capacity_soft_limit: 4096,
+                out_buffer_capacity_hard_limit: 131_072,
+                ..defaults
+            },
+        }
+    }
+
+    /// Relax the settings for peers with known quirks. Only the settings that the quirks
+    /// concern are changed, so this can be applied to a profile, or several times for
+    /// different quirks.
+    ///
+    /// ```
+    /// use parity_ws::{Profile, Quirks, Settings};
+    ///
+    /// let settings = Settings::profile(Profile::LowLatency)
+    ///     .peer_quirks(Quirks::LegacySafari)
+    ///     .peer_quirks(Quirks::CaseSensitiveGateway);
+    /// assert!(settings.tcp_nodelay);
+    /// assert!(settings.fragments_grow);
+    /// ```
+    pub fn peer_quirks(self, quirks: Quirks) -> Settings {
+        match quirks {
+            Quirks::LegacySafari => Settings {
+                fragments_grow: true,
+                fragments_capacity: self.fragments_capacity.max(64),
+                ..self
+            },
+            Quirks::PongHeartbeats => Settings {
+                masking_strict: false,
+                ..self
+            },
+            Quirks::CaseSensitiveGateway => Settings {
+                header_case: HeaderCase::Canonical,
+                ..self
+            },
         }
     }
+
+    /// Load settings from a TOML file, starting from the defaults. The keys are the names of the
+    /// numeric and boolean settings, along with `socks5_proxy` and `bind_local_addr` as strings,
+    /// and `close_linger`, `slow_callback_threshold` and `idle_shrink_interval` in milliseconds,
+    /// where 0 turns them off, as it does `max_accepts_per_sec`. Other settings, and tables, can
+    /// not be configured from a file, and unknown keys are an error.
+    ///
+    /// ```toml
+    /// max_connections = 10_000
+    /// max_fragment_size = 1_048_576
+    /// close_linger = 2000
+    /// ```
+    #[cfg(feature = "config")]
+    pub fn from_toml<P: AsRef<Path>>(path: P) -> Result<Settings> {
+        config::load(path.as_ref(), Settings::default(), false)
+    }
 }
 
 /// The WebSocket struct. A WebSocket can support multiple incoming and outgoing connections.
@@ -293,6 +860,8 @@ where
 {
     poll: Poll,
     handler: io::Handler<F>,
+    #[cfg(feature = "config")]
+    config: Option<Arc<()>>,
 }
 
 impl<F> WebSocket<F>
@@ -307,12 +876,13 @@ where
     /// Consume the WebSocket and bind to the specified address.
     /// If the `addr_spec` yields multiple addresses this will return after the
     /// first successful bind. `local_addr` can be called to determine which
-    /// address it ended up binding to.
+    /// address it ended up binding to. Binding again adds another listening address.
     /// After the server is successfully bound you should start it using `run`.
     pub fn bind<A>(mut self, addr_spec: A) -> Result<WebSocket<F>>
     where
         A: ToSocketAddrs,
     {
+        let _logger = self.handler.log_scope();
         let mut last_error = Error::new(ErrorKind::Internal, "No address given");
 
         for addr in addr_spec.to_socket_addrs()? {
@@ -329,6 +899,33 @@ where
         Err(last_error)
     }
 
+    /// Consume the WebSocket and bind to every address yielded by `addr_spec`, for example both
+    /// the IPv4 and IPv6 addresses of a host name, or an explicit list of interfaces. Fails if any
+    /// of the addresses cannot be bound. This may be combined with previous calls to `bind`, and
+    /// `local_addrs` can be called to determine which addresses the WebSocket is listening on.
+    /// After the server is successfully bound you should start it using `run`.
+    pub fn bind_all<A>(mut self, addr_spec: A) -> Result<WebSocket<F>>
+    where
+        A: ToSocketAddrs,
+    {
+        let _logger = self.handler.log_scope();
+        let mut bound = false;
+        for addr in addr_spec.to_socket_addrs()? {
+            if let Err(e) = self.handler.listen(&mut self.poll, &addr) {
+                error!("Unable to listen on {}", addr);
+                return Err(e);
+            }
+            info!("Listening for new connections on {}.", addr);
+            bound = true;
+        }
+
+        if bound {
+            Ok(self)
+        } else {
+            Err(Error::new(ErrorKind::Internal, "No address given"))
+        }
+    }
+
     /// Consume the WebSocket and listen for new connections on the specified address.
     ///
     /// # Safety
@@ -344,12 +941,151 @@ where
     /// Queue an outgoing connection on this WebSocket. This method may be called multiple times,
     /// but the actual connections will not be established until `run` is called.
     pub fn connect(&mut self, url: url::Url) -> Result<&mut WebSocket<F>> {
+        let _logger = self.handler.log_scope();
         let sender = self.handler.sender();
         info!("Queuing connection to {}", url);
         sender.connect(url)?;
         Ok(self)
     }
 
+    /// Queue an outgoing connection to the first of a prioritized list of urls that accepts it,
+    /// falling back on the next url whenever one cannot be resolved or refuses the connection.
+    /// See `Sender::connect_any`.
+    pub fn connect_any(&mut self, urls: Vec<url::Url>) -> Result<&mut WebSocket<F>> {
+        let _logger = self.handler.log_scope();
+        let sender = self.handler.sender();
+        info!("Queuing connection to one of {} urls", urls.len());
+        sender.connect_any(urls)?;
+        Ok(self)
+    }
+
+    /// Queue an outgoing connection to the url of a `Probe` with the fastest opening handshake,
+    /// falling back on the slower ones as with `connect_any`. The urls are probed first, which
+    /// blocks, unless they were probed within the probe's interval.
+    pub fn connect_fastest(&mut self, probe: &mut probe::Probe) -> Result<&mut WebSocket<F>> {
+        let _logger = self.handler.log_scope();
+        let urls = probe.urls()?;
+        info!("Queuing connection to the fastest of {} urls", urls.len());
+        self.handler.sender().connect_any(urls)?;
+        Ok(self)
+    }
+
+    /// Queue an outgoing connection to a service that is discovered through DNS SRV records,
+    /// such as `_ws._tcp.example.com`. The records are looked up before this method returns, and
+    /// their targets are then tried in the order given by their priorities and weights, as with
+    /// `connect_any`. Services named `_wss` are connected to with TLS.
+    ///
+    /// The lookup blocks for up to 5 seconds for each name server that does not answer. The name
+    /// servers are read from `/etc/resolv.conf`, so elsewhere, such as on Windows, only a
+    /// resolver on the local host is queried.
+    #[cfg(feature = "srv")]
+    pub fn connect_srv(&mut self, name: &str) -> Result<&mut WebSocket<F>> {
+        let _logger = self.handler.log_scope();
+        let urls = srv::resolve(name)?;
+        info!("Queuing connection to {} through SRV records", name);
+        self.handler.sender().connect_any(urls)?;
+        Ok(self)
+    }
+
+    /// Apply the settings in a TOML file, in the format of `Settings::from_toml`, on top of the
+    /// current settings, and apply them again whenever the file changes, such as to adjust limits
+    /// on a live server. The file is checked for changes twice per second until the WebSocket is
+    /// dropped or another file is watched.
+    ///
+    /// Only connections that are made after a reload pick up the reloaded settings. Open
+    /// connections keep the settings they were made with, such as their buffer and frame size
+    /// limits, while the connection limit applies to the whole WebSocket at once. See
+    /// `Sender::reconfigure`.
+    ///
+    /// Only limits, such as timeouts, quotas and buffer sizes, can be changed this way. Settings
+    /// that decide how the server works, such as `encrypt_server`, `ipv6_only`, `socks5_proxy`,
+    /// the strictness and the `panic_on_` settings, can only be loaded with
+    /// `Settings::from_toml`, and are an error in a watched file.
+    ///
+    /// An invalid file is an error here, but once the file is watched, a change that can not be
+    /// applied is logged and the settings stay as they were.
+    #[cfg(feature = "config")]
+    pub fn watch_config<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut WebSocket<F>> {
+        let _logger = self.handler.log_scope();
+        let path = path.as_ref().to_path_buf();
+        info!("Watching {} for settings.", path.display());
+        self.config = Some(config::watch(
+            path,
+            self.handler.settings(),
+            self.handler.sender(),
+        )?);
+        Ok(self)
+    }
+
+    /// Queue an already accepted TCP stream as a server connection on this WebSocket. The server
+    /// side of the opening handshake will be performed on the stream once `run` is called.
+    ///
+    /// This allows the WebSocket to be driven by an external accept loop, or to serve a single
+    /// socket handed to the process by a supervisor such as inetd or launchd. If the WebSocket is
+    /// not also listening on an address, the event loop will finish once all of the queued
+    /// connections have closed.
+    pub fn serve_stream(&mut self, stream: StdTcpStream) -> Result<&mut WebSocket<F>> {
+        let _logger = self.handler.log_scope();
+        let sock = TcpStream::from_stream(stream)?;
+        if let Ok(addr) = sock.peer_addr() {
+            info!("Serving pre-accepted tcp connection from {}.", addr);
+        }
+        self.handler.accept(&mut self.poll, sock, None)?;
+        Ok(self)
+    }
+
+    /// Queue a TCP stream whose opening handshake request has already been read by another HTTP
+    /// server. The request is validated and passed to `Handler::on_request`, and the handshake
+    /// response is written to the stream once `run` is called.
+    ///
+    /// The stream must be the raw TCP connection with no unread data buffered elsewhere, and no
+    /// response may have been written to it yet.
+    pub fn serve_upgrade(
+        &mut self,
+        request: Request,
+        stream: StdTcpStream,
+    ) -> Result<&mut WebSocket<F>> {
+        let _logger = self.handler.log_scope();
+        let sock = TcpStream::from_stream(stream)?;
+        if let Ok(addr) = sock.peer_addr() {
+            info!("Serving upgraded tcp connection from {}.", addr);
+        }
+        self.handler.accept(&mut self.poll, sock, Some(request))?;
+        Ok(self)
+    }
+
+    /// Authorize every handshake request with `authorizer` before it reaches a handler. Unlike
+    /// `Builder::with_authorizer`, this takes ownership of the authorizer, which is dropped along
+    /// with the WebSocket, and takes precedence over `Settings::authorizer`. See `Authorizer`.
+    pub fn with_authorizer<A>(&mut self, authorizer: A) -> &mut WebSocket<F>
+    where
+        A: Authorizer + Send + 'static,
+    {
+        self.handler.set_authorizer(Arc::new(authorizer));
+        self
+    }
+
+    /// Run the TLS handshakes of connections accepted by the listeners of this WebSocket on a pool
+    /// of `threads` worker threads, instead of on the event loop thread, so that a burst of new
+    /// connections does not delay traffic on established ones. Once a handshake completes the
+    /// encrypted stream is handed back to the event loop and the WebSocket handshake continues as
+    /// usual. `Handler::upgrade_ssl_server` is not called for these connections.
+    ///
+    /// This requires `Settings::encrypt_server`, and is only supported on Unix, because on
+    /// Windows mio can not read from a stream before the stream is registered with the event loop.
+    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    pub fn offload_tls_handshakes<A>(
+        &mut self,
+        acceptor: A,
+        threads: usize,
+    ) -> Result<&mut WebSocket<F>>
+    where
+        A: HandshakeAcceptor,
+    {
+        self.handler.offload_tls_handshakes(acceptor, threads)?;
+        Ok(self)
+    }
+
     /// Run the WebSocket. This will run the encapsulated event loop blocking the calling thread until
     /// the WebSocket is shutdown.
     pub fn run(mut self) -> Result<WebSocket<F>> {
@@ -366,12 +1102,56 @@ where
         self.handler.sender()
     }
 
+    /// Create a new, empty group of connections on this WebSocket. See `Group`.
+    #[inline]
+    pub fn group(&self) -> Group {
+        self.handler.sender().group()
+    }
+
+    /// Create a new, empty group that keeps the last `capacity` messages sent to it. See
+    /// `Sender::group_with_history`.
+    #[inline]
+    pub fn group_with_history(&self, capacity: usize) -> Result<Group> {
+        self.handler.sender().group_with_history(capacity)
+    }
+
+    /// Queue a message for the open connections selected by `filter`. The message is sent once
+    /// the WebSocket is running. Use the `broadcaster` to do this from other threads while it
+    /// runs. See `Sender::broadcast_filtered`.
+    #[inline]
+    pub fn broadcast_filtered<M, P>(&self, msg: M, filter: P) -> Result<()>
+    where
+        M: Into<Message>,
+        P: Fn(&ConnectionInfo) -> bool + Send + Sync + 'static,
+    {
+        self.handler.sender().broadcast_filtered(msg, filter)
+    }
+
     /// Get the local socket address this socket is bound to. Will return an error
     /// if the backend returns an error. Will return a `NotFound` error if
     /// this WebSocket is not a listening socket.
     pub fn local_addr(&self) -> ::std::io::Result<SocketAddr> {
         self.handler.local_addr()
     }
+
+    /// Get the local socket addresses of every listening socket, in the order they were bound.
+    pub fn local_addrs(&self) -> ::std::io::Result<Vec<SocketAddr>> {
+        self.handler.local_addrs()
+    }
+
+    /// Look up a connection of this WebSocket by its token, such as one created by
+    /// `serve_stream`, to get its peer address and the resource it requested without keeping
+    /// its `Handshake`. The address is known as soon as the connection is made, and the resource
+    /// once it is open. From a handler, use `Sender::peer_addr` instead.
+    pub fn connection_info(&self, token: util::Token) -> Option<ConnectionInfo<'_>> {
+        self.handler.connection_info(token)
+    }
+
+    /// Capture the state of this WebSocket and all of its connections. Once the WebSocket is
+    /// running, use `Sender::dump_state` on its broadcaster instead.
+    pub fn dump_state(&self) -> LoopStateSnapshot {
+        self.handler.snapshot()
+    }
 }
 
 /// Utility for constructing a WebSocket from various settings.
@@ -396,6 +1176,8 @@ impl Builder {
         Ok(WebSocket {
             poll: Poll::new()?,
             handler: io::Handler::new(factory, self.settings),
+            #[cfg(feature = "config")]
+            config: None,
         })
     }
 
@@ -404,4 +1186,39 @@ impl Builder {
         self.settings = settings;
         self
     }
+
+    /// Use the settings of a profile. See `Settings::profile`.
+    pub fn with_profile(&mut self, profile: Profile) -> &mut Builder {
+        self.settings = Settings::profile(profile);
+        self
+    }
+
+    /// Relax the settings for peers with known quirks. This changes the settings given so far,
+    /// so it must come after `with_settings` or `with_profile`. See `Settings::peer_quirks`.
+    pub fn with_peer_quirks(&mut self, quirks: Quirks) -> &mut Builder {
+        self.settings = self.settings.peer_quirks(quirks);
+        self
+    }
+
+    /// Log to `logger` instead of the global logger of the `log` crate. This sets
+    /// `Settings::logger`, so it must come after `with_settings`. See `WsLogger`.
+    pub fn with_logger(&mut self, logger: &'static dyn WsLogger) -> &mut Builder {
+        self.settings.logger = Some(logger);
+        self
+    }
+
+    /// Authorize every handshake request with `authorizer` before it reaches a handler. This
+    /// sets `Settings::authorizer`, so it must come after `with_settings`. An authorizer that is
+    /// not a static can be given to `WebSocket::with_authorizer` instead. See `Authorizer`.
+    pub fn with_authorizer(&mut self, authorizer: &'static dyn Authorizer) -> &mut Builder {
+        self.settings.authorizer = Some(authorizer);
+        self
+    }
+
+    /// Send a record of every connection attempt to `sink`. This sets `Settings::audit`, so it
+    /// must come after `with_settings`. See `AuditSink`.
+    pub fn with_audit(&mut self, sink: &'static dyn AuditSink) -> &mut Builder {
+        self.settings.audit = Some(sink);
+        self
+    }
 }
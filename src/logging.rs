@@ -0,0 +1,333 @@
+use std::cell::Cell;
+use std::fmt;
+
+use log;
+
+// The logging macros of the crate. They have the names and syntax of the macros of the `log`
+// crate, and also take structured fields before the message, separated from it by a semicolon:
+//
+//     info!(peer = addr; "Accepted a new tcp connection from {}.", addr);
+macro_rules! ws_log {
+    ($level:expr, [$($key:ident = $value:expr),*], $($arg:tt)+) => {{
+        let level = $level;
+        if $crate::logging::enabled(level, module_path!()) {
+            $crate::logging::log(
+                level,
+                module_path!(),
+                file!(),
+                line!(),
+                format_args!($($arg)+),
+                &[$($crate::logging::field(stringify!($key), &$value)),*],
+            );
+        }
+    }};
+}
+
+macro_rules! error {
+    ($($key:ident = $value:expr),+; $($arg:tt)+) => {
+        ws_log!($crate::LogLevel::Error, [$($key = $value),+], $($arg)+)
+    };
+    ($($arg:tt)+) => { ws_log!($crate::LogLevel::Error, [], $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($key:ident = $value:expr),+; $($arg:tt)+) => {
+        ws_log!($crate::LogLevel::Warn, [$($key = $value),+], $($arg)+)
+    };
+    ($($arg:tt)+) => { ws_log!($crate::LogLevel::Warn, [], $($arg)+) };
+}
+
+macro_rules! info {
+    ($($key:ident = $value:expr),+; $($arg:tt)+) => {
+        ws_log!($crate::LogLevel::Info, [$($key = $value),+], $($arg)+)
+    };
+    ($($arg:tt)+) => { ws_log!($crate::LogLevel::Info, [], $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($key:ident = $value:expr),+; $($arg:tt)+) => {
+        ws_log!($crate::LogLevel::Debug, [$($key = $value),+], $($arg)+)
+    };
+    ($($arg:tt)+) => { ws_log!($crate::LogLevel::Debug, [], $($arg)+) };
+}
+
+macro_rules! trace {
+    ($($key:ident = $value:expr),+; $($arg:tt)+) => {
+        ws_log!($crate::LogLevel::Trace, [$($key = $value),+], $($arg)+)
+    };
+    ($($arg:tt)+) => { ws_log!($crate::LogLevel::Trace, [], $($arg)+) };
+}
+
+/// The severity of a log event, from the most to the least severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    /// A failure, such as a connection that could not be accepted.
+    Error = 1,
+    /// A problem that the WebSocket recovered from.
+    Warn,
+    /// A change to the WebSocket, such as a new listener or connection.
+    Info,
+    /// A change to a connection, such as the start of the closing handshake.
+    Debug,
+    /// The details of the work on a connection, such as every frame that is sent.
+    Trace,
+}
+
+impl LogLevel {
+    /// The name of the level in upper case, such as `"WARN"`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        }
+    }
+
+    fn to_log(self) -> log::Level {
+        match self {
+            LogLevel::Error => log::Level::Error,
+            LogLevel::Warn => log::Level::Warn,
+            LogLevel::Info => log::Level::Info,
+            LogLevel::Debug => log::Level::Debug,
+            LogLevel::Trace => log::Level::Trace,
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A log event of a WebSocket, passed to a `WsLogger`.
+pub struct LogRecord<'a> {
+    level: LogLevel,
+    target: &'a str,
+    file: &'a str,
+    line: u32,
+    args: fmt::Arguments<'a>,
+    fields: &'a [(&'static str, &'a dyn fmt::Display)],
+}
+
+impl<'a> LogRecord<'a> {
+    /// The severity of the event.
+    pub fn level(&self) -> LogLevel {
+        self.level
+    }
+
+    /// The module of the crate that logged the event, such as `parity_ws::io`.
+    pub fn target(&self) -> &'a str {
+        self.target
+    }
+
+    /// The source file that logged the event.
+    pub fn file(&self) -> &'a str {
+        self.file
+    }
+
+    /// The line in the source file that logged the event.
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// The message, to be formatted with `Display` or `to_string`.
+    pub fn args(&self) -> &fmt::Arguments<'a> {
+        &self.args
+    }
+
+    /// The structured fields of the event by name, such as the `token` of the connection or the
+    /// `peer` address.
+    pub fn fields(&self) -> &'a [(&'static str, &'a dyn fmt::Display)] {
+        self.fields
+    }
+}
+
+impl<'a> fmt::Debug for LogRecord<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {}{}",
+            self.level,
+            self.target,
+            self.args,
+            Fields(self.fields)
+        )
+    }
+}
+
+// Structured fields rendered as ` key=value` pairs.
+struct Fields<'a>(&'a [(&'static str, &'a dyn fmt::Display)]);
+
+impl<'a> fmt::Display for Fields<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(key, value) in self.0 {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A destination for the log events of a WebSocket other than the global logger of the `log`
+/// crate, such as when several WebSockets in one process need separate logs or when the crate is
+/// embedded through FFI. Set it with `Builder::with_logger` or `Settings::logger`.
+///
+/// The logger receives every event logged while the WebSocket runs its event loop, and while it
+/// binds, connects or serves streams. It runs on the thread of the event loop, so it should not
+/// block. Any function taking a `LogRecord` can be used as a logger:
+///
+/// ```
+/// # use parity_ws::{Builder, LogLevel, LogRecord, Sender};
+/// fn log(record: &LogRecord) {
+///     if record.level() <= LogLevel::Info {
+///         eprintln!("[ws {}] {}", record.level(), record.args());
+///     }
+/// }
+///
+/// static LOG: fn(&LogRecord) = log;
+///
+/// let ws = Builder::new()
+///     .with_logger(&LOG)
+///     .build(|out: Sender| move |msg| out.send(msg))
+///     .unwrap();
+/// ```
+pub trait WsLogger: Sync {
+    /// Whether the logger wants events of the given level from the given module of the crate.
+    /// Disabled events are not formatted. All events are enabled by default.
+    fn enabled(&self, _level: LogLevel, _target: &str) -> bool {
+        true
+    }
+
+    /// Handle a log event.
+    fn log(&self, record: &LogRecord);
+}
+
+impl<F> WsLogger for F
+where
+    F: Fn(&LogRecord) + Sync,
+{
+    fn log(&self, record: &LogRecord) {
+        self(record)
+    }
+}
+
+impl fmt::Debug for dyn WsLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WsLogger")
+    }
+}
+
+thread_local! {
+    // The logger of the WebSocket that the current thread works for.
+    static LOGGER: Cell<Option<&'static dyn WsLogger>> = Cell::new(None);
+}
+
+// Send the log events of the current thread to `logger`, or to the `log` crate if it is `None`,
+// until the returned guard is dropped.
+pub fn scope(logger: Option<&'static dyn WsLogger>) -> Scope {
+    Scope {
+        previous: LOGGER.with(|current| current.replace(logger)),
+    }
+}
+
+pub struct Scope {
+    previous: Option<&'static dyn WsLogger>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        LOGGER.with(|current| current.set(previous))
+    }
+}
+
+pub fn field<'a, T: fmt::Display>(
+    key: &'static str,
+    value: &'a T,
+) -> (&'static str, &'a dyn fmt::Display) {
+    (key, value)
+}
+
+#[inline]
+pub fn enabled(level: LogLevel, target: &str) -> bool {
+    match LOGGER.with(Cell::get) {
+        Some(logger) => logger.enabled(level, target),
+        None => level.to_log() <= log::STATIC_MAX_LEVEL && level.to_log() <= log::max_level(),
+    }
+}
+
+pub fn log(
+    level: LogLevel,
+    target: &str,
+    file: &str,
+    line: u32,
+    args: fmt::Arguments,
+    fields: &[(&'static str, &dyn fmt::Display)],
+) {
+    match LOGGER.with(Cell::get) {
+        Some(logger) => logger.log(&LogRecord {
+            level,
+            target,
+            file,
+            line,
+            args,
+            fields,
+        }),
+        None => log::logger().log(
+            &log::Record::builder()
+                .level(level.to_log())
+                .target(target)
+                .module_path(Some(target))
+                .file(Some(file))
+                .line(Some(line))
+                .args(format_args!("{}{}", args, Fields(fields)))
+                .build(),
+        ),
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::sync::Mutex;
+
+    struct Collect(Mutex<Vec<String>>);
+
+    impl WsLogger for Collect {
+        fn enabled(&self, level: LogLevel, _: &str) -> bool {
+            level <= LogLevel::Debug
+        }
+
+        fn log(&self, record: &LogRecord) {
+            self.0.lock().unwrap().push(format!("{:?}", record));
+        }
+    }
+
+    static COLLECT: Collect = Collect(Mutex::new(Vec::new()));
+
+    #[test]
+    fn scoped_logger() {
+        {
+            let _logger = scope(Some(&COLLECT));
+            let token = 7;
+            info!(token = token, peer = "127.0.0.1:3012"; "Accepted {}.", "a connection");
+            trace!("Disabled.");
+            {
+                let _inner = scope(None);
+                warn!("Sent to the log crate.");
+            }
+            error!("Failed.");
+        }
+        warn!("Sent to the log crate as well.");
+        assert_eq!(
+            *COLLECT.0.lock().unwrap(),
+            vec![
+                "INFO parity_ws::logging::test: Accepted a connection. token=7 \
+                 peer=127.0.0.1:3012",
+                "ERROR parity_ws::logging::test: Failed.",
+            ]
+        );
+    }
+}
@@ -101,6 +101,43 @@ impl Message {
     }
 }
 
+/// What to do with a received text message that is not valid UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Fail the connection with an Invalid Frame Payload Data (1007) close code, as the protocol
+    /// requires.
+    Strict,
+    /// Deliver the message as text, with each invalid sequence replaced by U+FFFD.
+    ReplaceLossy,
+    /// Deliver the received bytes as a binary message.
+    DeliverBytes,
+}
+
+// Turn the payload of a received text message into a message according to `policy`.
+pub fn decode_text(data: Vec<u8>, policy: Utf8Policy) -> Result<Message> {
+    let err = match String::from_utf8(data) {
+        Ok(string) => return Ok(Text(string)),
+        Err(err) => err,
+    };
+    match policy {
+        Utf8Policy::Strict => Err(err.utf8_error().into()),
+        Utf8Policy::ReplaceLossy => {
+            debug!(
+                "Replacing invalid UTF-8 in text message: {}",
+                err.utf8_error()
+            );
+            Ok(Text(String::from_utf8_lossy(err.as_bytes()).into_owned()))
+        }
+        Utf8Policy::DeliverBytes => {
+            debug!(
+                "Delivering text message with invalid UTF-8 as binary: {}",
+                err.utf8_error()
+            );
+            Ok(Binary(err.into_bytes()))
+        }
+    }
+}
+
 impl From<String> for Message {
     fn from(string: String) -> Message {
         Message::text(string)
@@ -139,6 +176,30 @@ mod test {
     #![allow(unused_imports, unused_variables, dead_code)]
     use super::*;
 
+    #[test]
+    fn utf8_policy() {
+        let invalid = b"caf\xC3 au lait".to_vec();
+        assert!(decode_text(invalid.clone(), Utf8Policy::Strict).is_err());
+        assert_eq!(
+            decode_text(invalid.clone(), Utf8Policy::ReplaceLossy).unwrap(),
+            Message::text("caf\u{FFFD} au lait")
+        );
+        assert_eq!(
+            decode_text(invalid.clone(), Utf8Policy::DeliverBytes).unwrap(),
+            Message::binary(invalid)
+        );
+        for &policy in &[
+            Utf8Policy::Strict,
+            Utf8Policy::ReplaceLossy,
+            Utf8Policy::DeliverBytes,
+        ] {
+            assert_eq!(
+                decode_text("café".into(), policy).unwrap(),
+                Message::text("café")
+            );
+        }
+    }
+
     #[test]
     fn display() {
         let t = Message::text(format!("test"));
@@ -0,0 +1,2478 @@
+//! Reusable layers that wrap a `Handler` to add cross-cutting behaviour.
+//!
+//! Every layer is a `Handler` itself, which forwards everything it does not deal with to the
+//! handler it wraps, so layers compose by chaining the methods of `HandlerExt`:
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use parity_ws::{listen, HandlerExt};
+//! use parity_ws::middleware::RateLimit;
+//!
+//! listen("127.0.0.1:3012", |out| {
+//!     (move |msg| out.send(msg))
+//!         .with_rate_limit(RateLimit::new(100, Duration::from_secs(1)))
+//!         .with_auth(|req| req.header("authorization").is_some())
+//!         .with_logging()
+//! }).unwrap()
+//! ```
+//!
+//! The outermost layer sees events first, so in this example unauthorized requests are logged
+//! before they are rejected. Reusable layers can also be written by implementing `Layer`.
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::io::Write;
+#[cfg(feature = "json")]
+use std::marker::PhantomData;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "nativetls")]
+use native_tls::TlsStream as SslStream;
+#[cfg(feature = "otel")]
+use opentelemetry::global;
+#[cfg(feature = "otel")]
+use opentelemetry::global::BoxedSpan;
+#[cfg(feature = "otel")]
+use opentelemetry::metrics::{Histogram, Unit, UpDownCounter};
+#[cfg(feature = "otel")]
+use opentelemetry::trace::{Span, SpanKind, Status, Tracer as _};
+#[cfg(feature = "otel")]
+use opentelemetry::KeyValue;
+#[cfg(feature = "ssl")]
+use openssl::ssl::SslStream;
+#[cfg(feature = "json")]
+use serde::de::DeserializeOwned;
+#[cfg(feature = "json")]
+use serde_json;
+use rand;
+use url;
+
+use communication::Sender;
+use frame::Frame;
+use handler::{Decision, Handler};
+use handshake::{Handshake, Request, Response};
+use message::Message;
+use protocol::{CloseCode, CloseFrame, OpCode};
+use result::{Error, Kind, Result};
+#[cfg(any(feature = "ssl", feature = "nativetls"))]
+use util::TcpStream;
+use util::{Timeout, Token};
+
+// Implement the given `Handler` methods by passing them on to `self.inner`.
+macro_rules! forward {
+    (on_shutdown) => {
+        #[inline]
+        fn on_shutdown(&mut self) {
+            self.inner.on_shutdown()
+        }
+    };
+    (on_open) => {
+        #[inline]
+        fn on_open(&mut self, shake: Handshake) -> Result<()> {
+            self.inner.on_open(shake)
+        }
+    };
+    (on_message) => {
+        #[inline]
+        fn on_message(&mut self, msg: Message) -> Result<()> {
+            self.inner.on_message(msg)
+        }
+    };
+    (on_close) => {
+        #[inline]
+        fn on_close(&mut self, code: CloseCode, reason: &str) {
+            self.inner.on_close(code, reason)
+        }
+    };
+    (on_close_frame) => {
+        #[inline]
+        fn on_close_frame(&mut self, frame: &CloseFrame) {
+            self.inner.on_close_frame(frame)
+        }
+    };
+    (on_error) => {
+        #[inline]
+        fn on_error(&mut self, err: Error) {
+            self.inner.on_error(err)
+        }
+    };
+    (on_request) => {
+        #[inline]
+        fn on_request(&mut self, req: &Request) -> Result<Response> {
+            self.inner.on_request(req)
+        }
+    };
+    (on_response) => {
+        #[inline]
+        fn on_response(&mut self, res: &Response) -> Result<()> {
+            self.inner.on_response(res)
+        }
+    };
+    (on_timeout) => {
+        #[inline]
+        fn on_timeout(&mut self, event: Token) -> Result<()> {
+            self.inner.on_timeout(event)
+        }
+    };
+    (on_new_timeout) => {
+        #[inline]
+        fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
+            self.inner.on_new_timeout(event, timeout)
+        }
+    };
+    (on_ack_timeout) => {
+        #[inline]
+        fn on_ack_timeout(&mut self, id: u64, msg: Message) -> Result<()> {
+            self.inner.on_ack_timeout(id, msg)
+        }
+    };
+    (on_replay_detected) => {
+        #[inline]
+        fn on_replay_detected(&mut self, id: u64, msg: Message) -> Result<()> {
+            self.inner.on_replay_detected(id, msg)
+        }
+    };
+    (on_flush_after_reconnect) => {
+        #[inline]
+        fn on_flush_after_reconnect(&mut self, count: usize) -> Result<()> {
+            self.inner.on_flush_after_reconnect(count)
+        }
+    };
+    (on_frame) => {
+        #[inline]
+        fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+            self.inner.on_frame(frame)
+        }
+    };
+    (on_unknown_frame) => {
+        #[inline]
+        fn on_unknown_frame(&mut self, frame: &Frame) -> Decision {
+            self.inner.on_unknown_frame(frame)
+        }
+    };
+    (on_large_frame) => {
+        #[inline]
+        fn on_large_frame_start(
+            &mut self,
+            opcode: OpCode,
+            len: u64,
+        ) -> Option<Box<dyn Write + Send>> {
+            self.inner.on_large_frame_start(opcode, len)
+        }
+
+        #[inline]
+        fn on_large_frame_end(&mut self, len: u64) -> Result<()> {
+            self.inner.on_large_frame_end(len)
+        }
+    };
+    (on_peer_half_close) => {
+        #[inline]
+        fn on_peer_half_close(&mut self) -> Result<()> {
+            self.inner.on_peer_half_close()
+        }
+    };
+    (on_send_frame) => {
+        #[inline]
+        fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+            self.inner.on_send_frame(frame)
+        }
+    };
+    (build_request) => {
+        #[inline]
+        fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+            self.inner.build_request(url)
+        }
+    };
+    (ssl) => {
+        #[inline]
+        #[cfg(any(feature = "ssl", feature = "nativetls"))]
+        fn upgrade_ssl_client(
+            &mut self,
+            stream: TcpStream,
+            url: &url::Url,
+        ) -> Result<SslStream<TcpStream>> {
+            self.inner.upgrade_ssl_client(stream, url)
+        }
+
+        #[inline]
+        #[cfg(any(feature = "ssl", feature = "nativetls"))]
+        fn upgrade_ssl_server(&mut self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+            self.inner.upgrade_ssl_server(stream)
+        }
+    };
+    ($($method:ident),+) => {
+        $(forward!($method);)+
+    };
+}
+
+/// A reusable piece of middleware that wraps a handler in another handler.
+///
+/// Closures that take a handler and return a new one are layers too.
+pub trait Layer<H: Handler> {
+    /// The handler produced by this layer.
+    type Handler: Handler;
+
+    /// Wrap `inner` in this layer.
+    fn layer(self, inner: H) -> Self::Handler;
+}
+
+impl<H, F, O> Layer<H> for F
+where
+    H: Handler,
+    F: FnOnce(H) -> O,
+    O: Handler,
+{
+    type Handler = O;
+
+    fn layer(self, inner: H) -> O {
+        self(inner)
+    }
+}
+
+/// Methods for wrapping any `Handler` in middleware.
+pub trait HandlerExt: Handler + Sized {
+    /// Wrap this handler in a layer.
+    fn layer<L>(self, layer: L) -> L::Handler
+    where
+        L: Layer<Self>,
+    {
+        layer.layer(self)
+    }
+
+    /// Log the lifecycle of the connection and every message it receives.
+    fn with_logging(self) -> Logging<Self> {
+        Logging { inner: self }
+    }
+
+    /// Reject handshake requests for which `check` returns false with a 401 response.
+    fn with_auth<C>(self, check: C) -> Auth<Self, C>
+    where
+        C: FnMut(&Request) -> bool,
+    {
+        Auth { inner: self, check }
+    }
+
+    /// Answer requests for `path` with a JSON snapshot of the WebSocket instead of a handshake.
+    /// See `Diagnostics`.
+    fn with_diagnostics<P>(self, out: &Sender, path: P) -> Diagnostics<Self>
+    where
+        P: Into<String>,
+    {
+        Diagnostics {
+            inner: self,
+            out: out.clone(),
+            path: path.into(),
+        }
+    }
+
+    /// Limit the rate of incoming messages. See `RateLimit`.
+    fn with_rate_limit(self, limit: RateLimit) -> RateLimited<Self> {
+        RateLimited {
+            inner: self,
+            tokens: f64::from(limit.messages),
+            last: Instant::now(),
+            limit,
+        }
+    }
+
+    /// Acknowledge the messages that the other endpoint sends with `Sender::send_reliable`, and
+    /// retry the ones sent through `out` according to `policy`. See `Acked`.
+    fn with_acks(self, out: &Sender, policy: RetryPolicy) -> Acked<Self> {
+        out.context().insert(Outbox {
+            policy,
+            key: rand::random(),
+            next: 0,
+            received: 0,
+            floor: 0,
+            pending: BTreeMap::new(),
+            armed: false,
+        });
+        Acked {
+            inner: self,
+            out: out.clone(),
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            replay: None,
+        }
+    }
+
+    /// Ping the other endpoint while the connection is idle, and close the connection when it
+    /// stops answering or reaches its maximum age. See `KeptAlive`.
+    fn with_keepalive(self, out: &Sender, policy: KeepAlive) -> KeptAlive<Self> {
+        let now = Instant::now();
+        KeptAlive {
+            inner: self,
+            out: out.clone(),
+            policy,
+            ping: 0,
+            awaiting: None,
+            active: now,
+            retire: None,
+        }
+    }
+
+    /// Pass incoming messages to `process` on one of `workers` instead of this handler's
+    /// `on_message`, so that slow messages do not hold up the event loop. See `Offloaded`.
+    fn with_workers<F>(self, out: &Sender, workers: &Workers, process: F) -> Offloaded<Self, F>
+    where
+        F: Fn(&Sender, Message) -> Result<()> + Send + Sync + 'static,
+    {
+        Offloaded {
+            inner: self,
+            out: out.clone(),
+            workers: workers.clone(),
+            process: Arc::new(process),
+            queue: Arc::new(Mutex::new(Queue {
+                messages: VecDeque::new(),
+                running: false,
+                started: None,
+                overran: false,
+            })),
+            deadline: None,
+        }
+    }
+
+    /// Send the messages of `queue` over this connection once it is open, and the ones sent
+    /// while it is open straight to it. See `EarlyQueue`.
+    fn with_early_queue(self, out: &Sender, queue: &EarlyQueue) -> EarlyFlushed<Self> {
+        EarlyFlushed {
+            inner: self,
+            out: out.clone(),
+            queue: queue.clone(),
+            connection: None,
+        }
+    }
+
+    /// Report the connection to OpenTelemetry, through the global meter and tracer providers.
+    /// See `Telemetry`.
+    #[cfg(feature = "otel")]
+    fn with_telemetry(self) -> Telemetry<Self> {
+        Telemetry::new(self)
+    }
+
+    /// Route incoming messages to methods of this handler by the discriminant that `key`
+    /// extracts from them. See `Router`.
+    fn with_router<K, F>(self, key: F) -> Router<Self, K, F>
+    where
+        K: Hash + Eq,
+        F: FnMut(&Message) -> Option<K>,
+    {
+        Router {
+            inner: self,
+            key,
+            routes: HashMap::new(),
+        }
+    }
+
+    /// Parse every incoming message as JSON and pass the value to `JsonHandler::on_json`.
+    #[cfg(feature = "json")]
+    fn with_json<T>(self) -> Json<Self, T>
+    where
+        Self: JsonHandler<T>,
+        T: DeserializeOwned,
+    {
+        Json {
+            inner: self,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<H: Handler> HandlerExt for H {}
+
+/// Logs connection events. See `HandlerExt::with_logging`.
+pub struct Logging<H> {
+    inner: H,
+}
+
+impl<H: Handler> Handler for Logging<H> {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        info!(
+            "Connection for {} from {} is open.",
+            shake.request.resource(),
+            shake
+                .peer_addr
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|| "UNKNOWN".into())
+        );
+        self.inner.on_open(shake)
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        debug!("Received {} byte message.", msg.len());
+        self.inner.on_message(msg)
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        info!("Connection closing due to ({:?}) {}", code, reason);
+        self.inner.on_close(code, reason)
+    }
+
+    fn on_close_frame(&mut self, frame: &CloseFrame) {
+        info!("Connection closing due to ({:?}) {}", frame.code, frame.reason);
+        self.inner.on_close_frame(frame)
+    }
+
+    fn on_error(&mut self, err: Error) {
+        warn!("Connection error: {}", err);
+        self.inner.on_error(err)
+    }
+
+    forward!(
+        on_shutdown,
+        on_request,
+        on_response,
+        on_timeout,
+        on_new_timeout,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_frame,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+/// Rejects unauthorized handshake requests. See `HandlerExt::with_auth`.
+pub struct Auth<H, C> {
+    inner: H,
+    check: C,
+}
+
+impl<H, C> Handler for Auth<H, C>
+where
+    H: Handler,
+    C: FnMut(&Request) -> bool,
+{
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        if (self.check)(req) {
+            self.inner.on_request(req)
+        } else {
+            debug!("Rejecting unauthorized request for {}.", req.resource());
+            Ok(Response::new(401, "Unauthorized", Vec::new()))
+        }
+    }
+
+    forward!(
+        on_shutdown,
+        on_open,
+        on_message,
+        on_close,
+        on_close_frame,
+        on_error,
+        on_response,
+        on_timeout,
+        on_new_timeout,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_frame,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+/// Serves a snapshot of the WebSocket on a path. See `HandlerExt::with_diagnostics`.
+///
+/// Requests for the path, ignoring any query, are answered with the JSON of
+/// `Sender::respond_with_diagnostics` and the connection is closed, so the snapshot can be
+/// fetched with a browser or `curl` during an incident. Other requests are passed on to the
+/// wrapped handler. The snapshot includes the addresses of all peers, so wrap this layer in
+/// `with_auth` or another check when the server is reachable from untrusted networks:
+///
+/// ```no_run
+/// use parity_ws::{listen, HandlerExt, Sender};
+///
+/// listen("127.0.0.1:3012", |out: Sender| {
+///     let echo = out.clone();
+///     (move |msg| echo.send(msg))
+///         .with_diagnostics(&out, "/__ws_debug")
+///         .with_auth(|req| req.header("x-operator-token").map_or(false, |token| token == b"secret"))
+/// }).unwrap()
+/// ```
+pub struct Diagnostics<H> {
+    inner: H,
+    out: Sender,
+    path: String,
+}
+
+impl<H> Handler for Diagnostics<H>
+where
+    H: Handler,
+{
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let resource = req.resource();
+        let path = resource.split('?').next().unwrap_or(resource);
+        if path == self.path {
+            debug!("Serving diagnostics for {}.", resource);
+            self.out.respond_with_diagnostics()?;
+            Ok(Response::pending())
+        } else {
+            self.inner.on_request(req)
+        }
+    }
+
+    forward!(
+        on_shutdown,
+        on_open,
+        on_message,
+        on_close,
+        on_close_frame,
+        on_error,
+        on_response,
+        on_timeout,
+        on_new_timeout,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_frame,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+/// The number of messages that a connection may receive per period of time.
+///
+/// The limit is enforced with a token bucket that starts full and refills continuously, so
+/// short bursts of up to `messages` are allowed. Messages over the limit are dropped, and the
+/// wrapped handler's `on_error` receives a `Custom` error for each of them. The handler may
+/// close the connection in response.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    /// The number of messages allowed per `period`.
+    pub messages: u32,
+    /// The period over which `messages` are allowed.
+    pub period: Duration,
+}
+
+impl RateLimit {
+    /// Allow `messages` messages every `period`.
+    pub fn new(messages: u32, period: Duration) -> RateLimit {
+        RateLimit { messages, period }
+    }
+}
+
+impl<H: Handler> Layer<H> for RateLimit {
+    type Handler = RateLimited<H>;
+
+    fn layer(self, inner: H) -> RateLimited<H> {
+        inner.with_rate_limit(self)
+    }
+}
+
+/// Drops messages over a rate limit. See `HandlerExt::with_rate_limit`.
+pub struct RateLimited<H> {
+    inner: H,
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl<H: Handler> Handler for RateLimited<H> {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+
+        let period = self.limit.period.as_secs() as f64
+            + f64::from(self.limit.period.subsec_nanos()) / 1e9;
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let capacity = f64::from(self.limit.messages);
+        if period > 0.0 {
+            self.tokens = (self.tokens + elapsed * capacity / period).min(capacity);
+        } else {
+            self.tokens = capacity;
+        }
+
+        if self.tokens < 1.0 {
+            return Err(Error::new(
+                Kind::Custom("Message rate limit exceeded.".into()),
+                "Dropped a message over the rate limit.",
+            ));
+        }
+        self.tokens -= 1.0;
+        self.inner.on_message(msg)
+    }
+
+    forward!(
+        on_shutdown,
+        on_open,
+        on_close,
+        on_close_frame,
+        on_error,
+        on_request,
+        on_response,
+        on_timeout,
+        on_new_timeout,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_frame,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+/// The token of the timeout that `Acked` uses to retry messages. Handlers wrapped with
+/// `HandlerExt::with_acks` must not schedule timeouts with it.
+pub const RETRY: Token = Token(usize::MAX);
+
+// The number of message ids remembered to detect redelivered messages.
+const SEEN: usize = 1024;
+// The prefixes of reliable messages and their acknowledgements.
+const DATA: &str = "\0rel:";
+const ACK: &str = "\0ack:";
+
+/// How messages sent with `Sender::send_reliable` are retried.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// How long to wait for an acknowledgement before sending a message again.
+    pub interval: Duration,
+    /// The number of times a message is sent before giving up on it.
+    pub attempts: u32,
+    /// The factor by which the interval grows after each retry.
+    pub backoff: u32,
+}
+
+impl RetryPolicy {
+    /// Send each message up to `attempts` times, `interval` apart.
+    pub fn new(interval: Duration, attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            interval,
+            attempts,
+            backoff: 1,
+        }
+    }
+
+    /// Multiply the interval by `factor` after each retry.
+    pub fn backoff(mut self, factor: u32) -> RetryPolicy {
+        self.backoff = factor;
+        self
+    }
+}
+
+// The unacknowledged messages of a connection, kept in its context so that any sender can add
+// to them.
+#[doc(hidden)]
+pub struct Outbox {
+    policy: RetryPolicy,
+    // The key of the logical session, which is kept when it is resumed on another connection.
+    key: [u8; 16],
+    next: u64,
+    // The id after the highest one received.
+    received: u64,
+    // Ids below this were received before the session was resumed on this connection.
+    floor: u64,
+    pending: BTreeMap<u64, Pending>,
+    // Whether the retry timeout is scheduled.
+    armed: bool,
+}
+
+struct Pending {
+    msg: Message,
+    sent: u32,
+    due: Instant,
+}
+
+// The messages to send again or give up on, and when to check again.
+struct Due {
+    resend: Vec<(u64, Message)>,
+    expired: Vec<(u64, Message)>,
+    next: Option<Duration>,
+}
+
+impl Outbox {
+    fn retry(&mut self, now: Instant) -> Due {
+        let mut due = Due {
+            resend: Vec::new(),
+            expired: Vec::new(),
+            next: None,
+        };
+        let policy = self.policy;
+        let mut expired = Vec::new();
+        for (&id, pending) in self.pending.iter_mut() {
+            if pending.due > now {
+                continue;
+            }
+            if pending.sent >= policy.attempts {
+                expired.push(id);
+            } else {
+                pending.due = now + policy.interval * policy.backoff.saturating_pow(pending.sent);
+                pending.sent += 1;
+                due.resend.push((id, pending.msg.clone()));
+            }
+        }
+        for id in expired {
+            if let Some(pending) = self.pending.remove(&id) {
+                due.expired.push((id, pending.msg))
+            }
+        }
+        due.next = self.pending.values().map(|pending| pending.due - now).min();
+        self.armed = due.next.is_some();
+        due
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+fn envelope(id: u64, msg: &Message) -> Message {
+    let header = format!("{}{}\n", DATA, id);
+    match *msg {
+        Message::Text(ref text) => Message::text(header + text),
+        Message::Binary(ref data) => {
+            let mut payload = header.into_bytes();
+            payload.extend_from_slice(data);
+            Message::binary(payload)
+        }
+    }
+}
+
+enum Envelope {
+    Data(u64, Message),
+    Ack(u64),
+    Plain(Message),
+}
+
+fn open(msg: Message) -> Result<Envelope> {
+    let (id, start) = {
+        let data = match msg {
+            Message::Text(ref text) => text.as_bytes(),
+            Message::Binary(ref data) => &data[..],
+        };
+        let parse = |digits: &[u8]| {
+            ::std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| digits.parse::<u64>().ok())
+                .ok_or_else(|| Error::new(Kind::Protocol, "Invalid reliable message id."))
+        };
+        if data.starts_with(ACK.as_bytes()) {
+            return Ok(Envelope::Ack(parse(&data[ACK.len()..])?));
+        } else if !data.starts_with(DATA.as_bytes()) {
+            return Ok(Envelope::Plain(msg));
+        }
+        let end = data
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .ok_or_else(|| Error::new(Kind::Protocol, "Unterminated reliable message header."))?;
+        (parse(&data[DATA.len()..end])?, end + 1)
+    };
+    Ok(Envelope::Data(
+        id,
+        match msg {
+            Message::Text(mut text) => {
+                text.drain(..start);
+                Message::Text(text)
+            }
+            Message::Binary(mut data) => {
+                data.drain(..start);
+                Message::Binary(data)
+            }
+        },
+    ))
+}
+
+#[doc(hidden)]
+pub fn send_reliable(out: &Sender, msg: Message) -> Result<u64> {
+    let now = Instant::now();
+    let (id, envelope, delay) = out
+        .context()
+        .with(|outbox: &mut Outbox| {
+            let id = outbox.next;
+            outbox.next += 1;
+            let envelope = envelope(id, &msg);
+            outbox.pending.insert(
+                id,
+                Pending {
+                    msg,
+                    sent: 1,
+                    due: now + outbox.policy.interval,
+                },
+            );
+            let delay = if outbox.armed {
+                None
+            } else {
+                outbox.armed = true;
+                Some(outbox.policy.interval)
+            };
+            (id, envelope, delay)
+        })
+        .ok_or_else(without_acks)?;
+    out.send(envelope)?;
+    if let Some(delay) = delay {
+        out.timeout(millis(delay), RETRY)?;
+    }
+    Ok(id)
+}
+
+// The version of the encoding of `Resumption`, followed by the key and both positions.
+const RESUMPTION_VERSION: u8 = 1;
+const RESUMPTION_LEN: usize = 1 + 16 + 8 + 8;
+
+/// The position of a session of reliable messages, which lets it continue on another
+/// connection, possibly on another server. See `Sender::resumption`.
+///
+/// When clients are spread over several servers without sticky sessions, a client that
+/// reconnects may end up on a server that has never seen it. The server it was connected to
+/// stores the encoded resumption under its key, in a store shared by all servers, and the
+/// client presents the key when it reconnects, for instance in a header. The new server looks
+/// the resumption up and passes it to `Sender::resume`, so that it continues to number its
+/// messages where the old one stopped and drops the messages that the old one already handled.
+///
+/// The encoding is not signed, so it should not be handed to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resumption {
+    key: [u8; 16],
+    sent: u64,
+    received: u64,
+}
+
+impl Resumption {
+    /// The random key identifying the session.
+    #[inline]
+    pub fn key(&self) -> [u8; 16] {
+        self.key
+    }
+
+    /// The id of the next reliable message to send.
+    #[inline]
+    pub fn sent(&self) -> u64 {
+        self.sent
+    }
+
+    /// The id after the highest one received.
+    #[inline]
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Encode the resumption into 33 bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut blob = Vec::with_capacity(RESUMPTION_LEN);
+        blob.push(RESUMPTION_VERSION);
+        blob.extend_from_slice(&self.key);
+        blob.extend_from_slice(&self.sent.to_be_bytes());
+        blob.extend_from_slice(&self.received.to_be_bytes());
+        blob
+    }
+
+    /// Decode a resumption encoded with `encode`.
+    pub fn decode(blob: &[u8]) -> Result<Resumption> {
+        if blob.len() != RESUMPTION_LEN || blob[0] != RESUMPTION_VERSION {
+            return Err(Error::new(Kind::Protocol, "Invalid session resumption."));
+        }
+        let mut key = [0u8; 16];
+        key.copy_from_slice(&blob[1..17]);
+        let mut sent = [0u8; 8];
+        sent.copy_from_slice(&blob[17..25]);
+        let mut received = [0u8; 8];
+        received.copy_from_slice(&blob[25..33]);
+        Ok(Resumption {
+            key,
+            sent: u64::from_be_bytes(sent),
+            received: u64::from_be_bytes(received),
+        })
+    }
+}
+
+fn without_acks() -> Error {
+    Error::new(
+        Kind::Internal,
+        "Reliable messages require a handler wrapped with HandlerExt::with_acks.",
+    )
+}
+
+#[doc(hidden)]
+pub fn resumption(out: &Sender) -> Result<Resumption> {
+    out.context()
+        .with(|outbox: &mut Outbox| Resumption {
+            key: outbox.key,
+            sent: outbox.next,
+            received: outbox.received,
+        })
+        .ok_or_else(without_acks)
+}
+
+#[doc(hidden)]
+pub fn resume(out: &Sender, resumption: &Resumption) -> Result<()> {
+    out.context()
+        .with(|outbox: &mut Outbox| {
+            outbox.key = resumption.key;
+            outbox.next = outbox.next.max(resumption.sent);
+            outbox.received = outbox.received.max(resumption.received);
+            outbox.floor = resumption.received;
+        })
+        .ok_or_else(without_acks)
+}
+
+// The ids of reliable messages received most recently, for rejecting replayed messages.
+struct ReplayWindow {
+    size: usize,
+    // The id after the highest one received.
+    next: u64,
+    // Whether each of the ids just before `next` has been received, oldest first.
+    received: VecDeque<bool>,
+}
+
+impl ReplayWindow {
+    fn new(size: usize) -> ReplayWindow {
+        ReplayWindow {
+            size,
+            next: 0,
+            received: VecDeque::with_capacity(size),
+        }
+    }
+
+    // Record the id, returning whether it is new. Ids older than the window can not be told
+    // apart from replayed ones, so they are not new either.
+    fn accept(&mut self, id: u64) -> bool {
+        if id >= self.next {
+            let gap = id - self.next;
+            if gap >= self.size as u64 {
+                self.received.clear();
+            } else {
+                self.received.extend((0..gap).map(|_| false));
+            }
+            self.received.push_back(true);
+            while self.received.len() > self.size {
+                self.received.pop_front();
+            }
+            self.next = id + 1;
+            return true;
+        }
+        let age = self.next - id;
+        if age > self.received.len() as u64 {
+            return false;
+        }
+        let index = self.received.len() - age as usize;
+        !::std::mem::replace(&mut self.received[index], true)
+    }
+}
+
+/// Acknowledges reliable messages and retries unacknowledged ones. See `HandlerExt::with_acks`.
+///
+/// Messages sent with `Sender::send_reliable` carry an id, and are sent again according to the
+/// `RetryPolicy` until the other endpoint acknowledges them. Once every attempt has failed, the
+/// wrapped handler's `on_ack_timeout` is called with the message. Redelivered messages are
+/// acknowledged again but only passed on to the wrapped handler once, and other messages are
+/// passed on unchanged.
+///
+/// Only the ids of recent messages are remembered to recognise redelivered ones. Where a
+/// message that is sent again much later must never be handled twice, such as on a channel for
+/// financial commands, enable a replay window with `replay_window`.
+///
+/// Reliable messages and acknowledgements are ordinary data messages that start with a NUL
+/// byte, so both endpoints must use this layer. Messages that are still unacknowledged when
+/// the connection closes are dropped. The numbering of the messages can be carried over to
+/// another connection with `Sender::resumption` and `Sender::resume`.
+pub struct Acked<H> {
+    inner: H,
+    out: Sender,
+    seen: HashSet<u64>,
+    order: VecDeque<u64>,
+    replay: Option<ReplayWindow>,
+}
+
+impl<H> Acked<H> {
+    /// Reject reliable messages whose ids were already received, or that are more than `size`
+    /// ids older than the newest one received, and pass them to the wrapped handler's
+    /// `on_replay_detected` instead of `on_message`. Rejected messages are still acknowledged.
+    ///
+    /// Reliable messages are numbered in the order they are sent, so the window must be large
+    /// enough to cover the messages that may be retried or arrive out of order at any time.
+    pub fn replay_window(mut self, size: usize) -> Acked<H> {
+        self.replay = Some(ReplayWindow::new(size));
+        self
+    }
+}
+
+impl<H: Handler> Handler for Acked<H> {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        match open(msg)? {
+            Envelope::Plain(msg) => self.inner.on_message(msg),
+            Envelope::Ack(id) => {
+                trace!("Message {} was acknowledged.", id);
+                self.out
+                    .context()
+                    .with(|outbox: &mut Outbox| outbox.pending.remove(&id));
+                Ok(())
+            }
+            Envelope::Data(id, msg) => {
+                self.out.send(format!("{}{}", ACK, id))?;
+                let floor = self
+                    .out
+                    .context()
+                    .with(|outbox: &mut Outbox| {
+                        outbox.received = outbox.received.max(id + 1);
+                        outbox.floor
+                    })
+                    .unwrap_or(0);
+                if id < floor {
+                    trace!("Dropping message {} handled before the session was resumed.", id);
+                    return Ok(());
+                }
+                if let Some(ref mut replay) = self.replay {
+                    if replay.accept(id) {
+                        return self.inner.on_message(msg);
+                    }
+                    warn!("Rejecting replayed message {}.", id);
+                    return self.inner.on_replay_detected(id, msg);
+                }
+                if !self.seen.insert(id) {
+                    trace!("Dropping redelivered message {}.", id);
+                    return Ok(());
+                }
+                self.order.push_back(id);
+                if self.order.len() > SEEN {
+                    if let Some(id) = self.order.pop_front() {
+                        self.seen.remove(&id);
+                    }
+                }
+                self.inner.on_message(msg)
+            }
+        }
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        if event != RETRY {
+            return self.inner.on_timeout(event);
+        }
+        let due = match self
+            .out
+            .context()
+            .with(|outbox: &mut Outbox| outbox.retry(Instant::now()))
+        {
+            Some(due) => due,
+            None => return Ok(()),
+        };
+        for (id, msg) in due.resend {
+            trace!("Retrying message {}.", id);
+            self.out.send(envelope(id, &msg))?;
+        }
+        if let Some(delay) = due.next {
+            self.out.timeout(millis(delay), RETRY)?;
+        }
+        for (id, msg) in due.expired {
+            self.inner.on_ack_timeout(id, msg)?;
+        }
+        Ok(())
+    }
+
+    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
+        if event == RETRY {
+            Ok(())
+        } else {
+            self.inner.on_new_timeout(event, timeout)
+        }
+    }
+
+    forward!(
+        on_shutdown,
+        on_open,
+        on_close,
+        on_close_frame,
+        on_error,
+        on_request,
+        on_response,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_frame,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+/// The token of the timeout that `KeptAlive` uses to ping the other endpoint. Handlers wrapped
+/// with `HandlerExt::with_keepalive` must not schedule timeouts with it.
+pub const KEEPALIVE: Token = Token(usize::MAX - 1);
+
+/// How `KeptAlive` checks the health of a connection and when it retires it.
+///
+/// Long-lived connections that are mostly idle, such as those kept in a client's pool, can be
+/// dropped silently by NAT gateways and load balancers, and are then only found to be dead when
+/// a message is sent. Pinging them regularly keeps the mappings of such devices alive and
+/// finds dead connections early. Retiring connections after a maximum age spreads them over
+/// new server instances after a deployment.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    /// How long the connection may be idle before it is pinged.
+    pub interval: Duration,
+    /// How long to wait for the pong before the connection is considered dead.
+    pub timeout: Duration,
+    /// The age at which the connection is closed, so that it can be replaced.
+    pub max_age: Option<Duration>,
+    /// The fraction of `max_age` by which the age of each connection is shortened at random, so
+    /// that connections opened together are not all retired at once. Between 0 and 1.
+    pub jitter: f64,
+}
+
+impl KeepAlive {
+    /// Ping the connection every `interval`, and fail it if a pong does not arrive within
+    /// `timeout`.
+    pub fn new(interval: Duration, timeout: Duration) -> KeepAlive {
+        KeepAlive {
+            interval,
+            timeout,
+            max_age: None,
+            jitter: 0.0,
+        }
+    }
+
+    /// Close the connection once it is `age` old, minus a random fraction of up to `jitter` of
+    /// it.
+    pub fn max_age(mut self, age: Duration, jitter: f64) -> KeepAlive {
+        self.max_age = Some(age);
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+}
+
+/// Pings the other endpoint while the connection is idle, and closes the connection when it
+/// fails a health check or reaches its maximum age. See `HandlerExt::with_keepalive`.
+///
+/// A connection that does not answer a ping in time fails with a `Stalled` error, which the
+/// wrapped handler's `on_error` receives, and is dropped without waiting for a closing
+/// handshake. A connection that reaches its maximum age is closed with a Going Away (1001)
+/// close code. Either way the wrapped handler's `on_close` is called, where a client can
+/// connect again to replace the connection.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use parity_ws::middleware::KeepAlive;
+/// use parity_ws::{connect, HandlerExt};
+///
+/// let policy = KeepAlive::new(Duration::from_secs(30), Duration::from_secs(10))
+///     .max_age(Duration::from_secs(3600), 0.2);
+/// connect("ws://127.0.0.1:3012", |out| {
+///     let handler = |msg| Ok(println!("{}", msg));
+///     handler.with_keepalive(&out, policy)
+/// }).unwrap()
+/// ```
+pub struct KeptAlive<H> {
+    inner: H,
+    out: Sender,
+    policy: KeepAlive,
+    // The number of the ping that is waiting for its pong, and when it was sent.
+    ping: u64,
+    awaiting: Option<Instant>,
+    // When the connection last received anything, which shows it is alive as well as a pong.
+    active: Instant,
+    retire: Option<Instant>,
+}
+
+impl<H> KeptAlive<H> {
+    fn schedule(&self, now: Instant) -> Result<()> {
+        let mut due = match self.awaiting {
+            Some(sent) => {
+                // Check again after the interval too, so that pinging goes on at the interval
+                // once the pong has arrived, even if the timeout is longer.
+                let deadline = sent + self.policy.timeout;
+                let next = sent + self.policy.interval;
+                if next > now {
+                    next.min(deadline)
+                } else {
+                    deadline
+                }
+            }
+            None => self.active + self.policy.interval,
+        };
+        if let Some(retire) = self.retire {
+            due = due.min(retire);
+        }
+        self.out
+            .timeout(millis(due.saturating_duration_since(now)).max(1), KEEPALIVE)
+    }
+}
+
+impl<H: Handler> Handler for KeptAlive<H> {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        let now = Instant::now();
+        self.active = now;
+        if let Some(age) = self.policy.max_age {
+            let shortened = age.as_secs() as f64 + f64::from(age.subsec_nanos()) / 1e9;
+            let shortened = shortened * (1.0 - self.policy.jitter * rand::random::<f64>());
+            self.retire = Some(now + Duration::from_millis((shortened * 1000.0) as u64));
+        }
+        self.schedule(now)?;
+        self.inner.on_open(shake)
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.active = Instant::now();
+        if frame.opcode() == OpCode::Pong && frame.payload()[..] == self.ping.to_be_bytes() {
+            trace!("Connection answered keepalive ping {}.", self.ping);
+            self.awaiting = None;
+        }
+        self.inner.on_frame(frame)
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        if event != KEEPALIVE {
+            return self.inner.on_timeout(event);
+        }
+        let now = Instant::now();
+        if let Some(retire) = self.retire {
+            if now >= retire {
+                debug!("Retiring connection after reaching its maximum age.");
+                return self
+                    .out
+                    .close_with_reason(CloseCode::Away, "Connection reached its maximum age.");
+            }
+        }
+        match self.awaiting {
+            Some(sent) if now >= sent + self.policy.timeout => {
+                return Err(Error::new(
+                    Kind::Stalled,
+                    format!(
+                        "No pong received within {}ms of keepalive ping {}.",
+                        millis(self.policy.timeout),
+                        self.ping
+                    ),
+                ))
+            }
+            Some(_) => (),
+            None if now >= self.active + self.policy.interval => {
+                self.ping += 1;
+                self.awaiting = Some(now);
+                self.out.ping(self.ping.to_be_bytes().to_vec())?;
+            }
+            None => (),
+        }
+        self.schedule(now)
+    }
+
+    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
+        if event == KEEPALIVE {
+            Ok(())
+        } else {
+            self.inner.on_new_timeout(event, timeout)
+        }
+    }
+
+    forward!(
+        on_shutdown,
+        on_message,
+        on_close,
+        on_close_frame,
+        on_error,
+        on_request,
+        on_response,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+/// The token of the timeout that `Offloaded` uses to check the deadline of the message being
+/// processed. Handlers wrapped with `HandlerExt::with_workers` must not schedule timeouts with
+/// it.
+pub const DEADLINE: Token = Token(usize::MAX - 2);
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A pool of threads that process the messages of connections off the event loop. See
+/// `HandlerExt::with_workers`.
+///
+/// Clones share the same threads, so one pool can serve every connection of a WebSocket. The
+/// threads stop once every clone has been dropped.
+#[derive(Clone)]
+pub struct Workers {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Workers {
+    /// Start a pool of `threads` threads.
+    pub fn new(threads: usize) -> Result<Workers> {
+        let (jobs, queued) = mpsc::channel::<Job>();
+        let queued = Arc::new(Mutex::new(queued));
+        for n in 0..threads.max(1) {
+            let queued = queued.clone();
+            thread::Builder::new()
+                .name(format!("ws-worker-{}", n))
+                .spawn(move || loop {
+                    let job = match queued.lock() {
+                        Ok(queued) => queued.recv(),
+                        Err(_) => return,
+                    };
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })?;
+        }
+        Ok(Workers { jobs })
+    }
+
+    fn submit(&self, job: Job) -> Result<()> {
+        self.jobs
+            .send(job)
+            .map_err(|_| Error::new(Kind::Internal, "Message workers have stopped."))
+    }
+}
+
+/// What `Offloaded` does when processing a message takes longer than its deadline.
+#[derive(Debug, Clone)]
+pub enum Overrun {
+    /// Send the given message to the other endpoint, such as a busy indication, and answer
+    /// every message that arrives with it too, instead of queueing the message, until the
+    /// worker has finished.
+    Notify(Message),
+    /// Close the connection with a Try Again Later (1013) close code.
+    Close,
+}
+
+// The messages of a connection waiting for a worker, and the one being processed.
+struct Queue {
+    messages: VecDeque<Message>,
+    // Whether a worker is taking messages from the queue.
+    running: bool,
+    started: Option<Instant>,
+    overran: bool,
+}
+
+fn lock(queue: &Mutex<Queue>) -> MutexGuard<'_, Queue> {
+    // The lock is never held while a message is processed, so it can not be poisoned by it.
+    queue.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Processes incoming messages on `Workers`. See `HandlerExt::with_workers`.
+///
+/// The messages of a connection are processed one at a time and in order, by whichever worker
+/// is free, while every other event goes to the wrapped handler on the event loop. An error
+/// returned by the processing function, or a panic in it, closes the connection with an
+/// Internal Error (1011) close code.
+///
+/// A message that never finishes, such as one that triggers a bug, would hold up the
+/// connection forever. With a `deadline`, the connection is told about it instead, by closing
+/// it or by answering with a busy indication. A worker can not be interrupted, so the message
+/// goes on to be processed in the background.
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use parity_ws::middleware::{Overrun, Workers};
+/// use parity_ws::{listen, HandlerExt};
+///
+/// let workers = Workers::new(8).unwrap();
+/// listen("127.0.0.1:3012", |out| {
+///     let handler = |_| Ok(());
+///     handler
+///         .with_workers(&out, &workers, |out, msg| out.send(msg))
+///         .deadline(Duration::from_secs(5), Overrun::Notify("busy".into()))
+/// }).unwrap()
+/// ```
+pub struct Offloaded<H, F> {
+    inner: H,
+    out: Sender,
+    workers: Workers,
+    process: Arc<F>,
+    queue: Arc<Mutex<Queue>>,
+    deadline: Option<(Duration, Overrun)>,
+}
+
+impl<H, F> Offloaded<H, F>
+where
+    F: Fn(&Sender, Message) -> Result<()> + Send + Sync + 'static,
+{
+    /// Apply `overrun` when processing a message takes longer than `limit`.
+    pub fn deadline(mut self, limit: Duration, overrun: Overrun) -> Offloaded<H, F> {
+        self.deadline = Some((limit, overrun));
+        self
+    }
+
+    // Start a worker on the queue, which takes messages until it is empty.
+    fn dispatch(&self) -> Result<()> {
+        let queue = self.queue.clone();
+        let process = self.process.clone();
+        let out = self.out.clone();
+        let limit = self.deadline.as_ref().map(|&(limit, _)| limit);
+        self.workers.submit(Box::new(move || loop {
+            let msg = {
+                let mut queue = lock(&queue);
+                match queue.messages.pop_front() {
+                    Some(msg) => {
+                        queue.started = Some(Instant::now());
+                        msg
+                    }
+                    None => {
+                        queue.running = false;
+                        return;
+                    }
+                }
+            };
+            if let Some(limit) = limit {
+                let _ = out.timeout(millis(limit).max(1), DEADLINE);
+            }
+
+            let res = catch_unwind(AssertUnwindSafe(|| process(&out, msg)));
+            {
+                let mut queue = lock(&queue);
+                queue.started = None;
+                queue.overran = false;
+            }
+            let reason = match res {
+                Ok(Ok(())) => continue,
+                Ok(Err(err)) => format!("Unable to process message: {}", err),
+                Err(_) => "Processing of a message panicked.".into(),
+            };
+            error!("{}", reason);
+            let _ = out.close_with_reason(CloseCode::Error, "Unable to process message.");
+        }))
+    }
+}
+
+impl<H, F> Handler for Offloaded<H, F>
+where
+    H: Handler,
+    F: Fn(&Sender, Message) -> Result<()> + Send + Sync + 'static,
+{
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let start = {
+            let mut queue = lock(&self.queue);
+            if queue.overran {
+                if let Some((_, Overrun::Notify(ref busy))) = self.deadline {
+                    trace!("Answering a message while the worker is over its deadline.");
+                    return self.out.send(busy.clone());
+                }
+            }
+            queue.messages.push_back(msg);
+            !std::mem::replace(&mut queue.running, true)
+        };
+        if start {
+            self.dispatch()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        if event != DEADLINE {
+            return self.inner.on_timeout(event);
+        }
+        let (limit, overrun) = match self.deadline {
+            Some((limit, ref overrun)) => (limit, overrun),
+            None => return Ok(()),
+        };
+        {
+            let mut queue = lock(&self.queue);
+            // The timeout may have been scheduled for a message that has since finished.
+            match queue.started {
+                Some(started) if !queue.overran && started.elapsed() >= limit => {
+                    queue.overran = true
+                }
+                _ => return Ok(()),
+            }
+        }
+        warn!(
+            "Processing of a message exceeded its deadline of {}ms.",
+            millis(limit)
+        );
+        match *overrun {
+            Overrun::Notify(ref busy) => self.out.send(busy.clone()),
+            Overrun::Close => self.out.close_with_reason(
+                CloseCode::Again,
+                "Processing of a message exceeded its deadline.",
+            ),
+        }
+    }
+
+    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
+        if event == DEADLINE {
+            Ok(())
+        } else {
+            self.inner.on_new_timeout(event, timeout)
+        }
+    }
+
+    forward!(
+        on_shutdown,
+        on_open,
+        on_close,
+        on_close_frame,
+        on_error,
+        on_request,
+        on_response,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_frame,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+/// A method of a handler that a `Router` passes messages to.
+pub type Route<H> = fn(&mut H, Message) -> Result<()>;
+
+/// Passes each incoming message to the method of the wrapped handler that was registered for
+/// its discriminant, such as a message type, instead of one `on_message` that matches on it.
+/// Messages without a discriminant, or with one that has no route, go to `on_message`.
+///
+/// The discriminant is extracted by a function given to `HandlerExt::with_router`, such as
+/// `first_byte` for binary protocols, `first_word` for text commands, or `json_type` for JSON
+/// messages with a `"type"` field.
+///
+/// ```no_run
+/// use parity_ws::middleware::first_word;
+/// use parity_ws::{listen, Handler, HandlerExt, Message, Result, Sender};
+///
+/// struct Server {
+///     out: Sender,
+/// }
+///
+/// impl Server {
+///     fn on_subscribe(&mut self, msg: Message) -> Result<()> {
+///         self.out.send("subscribed")
+///     }
+///
+///     fn on_unsubscribe(&mut self, msg: Message) -> Result<()> {
+///         self.out.send("unsubscribed")
+///     }
+/// }
+///
+/// impl Handler for Server {
+///     fn on_message(&mut self, msg: Message) -> Result<()> {
+///         self.out.send("unknown command")
+///     }
+/// }
+///
+/// listen("127.0.0.1:3012", |out| {
+///     Server { out }
+///         .with_router(first_word)
+///         .route("subscribe", Server::on_subscribe)
+///         .route("unsubscribe", Server::on_unsubscribe)
+/// }).unwrap()
+/// ```
+pub struct Router<H, K, F> {
+    inner: H,
+    key: F,
+    routes: HashMap<K, Route<H>>,
+}
+
+impl<H, K, F> Router<H, K, F>
+where
+    H: Handler,
+    K: Hash + Eq,
+    F: FnMut(&Message) -> Option<K>,
+{
+    /// Pass messages with the discriminant `key` to `method`, replacing any method that was
+    /// registered for it before.
+    pub fn route<Q: Into<K>>(mut self, key: Q, method: Route<H>) -> Router<H, K, F> {
+        self.routes.insert(key.into(), method);
+        self
+    }
+}
+
+impl<H, K, F> Handler for Router<H, K, F>
+where
+    H: Handler,
+    K: Hash + Eq,
+    F: FnMut(&Message) -> Option<K>,
+{
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let route = (self.key)(&msg).and_then(|key| self.routes.get(&key).cloned());
+        match route {
+            Some(method) => method(&mut self.inner, msg),
+            None => self.inner.on_message(msg),
+        }
+    }
+
+    forward!(
+        on_shutdown,
+        on_open,
+        on_close,
+        on_close_frame,
+        on_error,
+        on_request,
+        on_response,
+        on_timeout,
+        on_new_timeout,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_frame,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+/// The first byte of a message, for binary protocols that begin every message with its type.
+pub fn first_byte(msg: &Message) -> Option<u8> {
+    match *msg {
+        Message::Text(ref text) => text.as_bytes().first().cloned(),
+        Message::Binary(ref data) => data.first().cloned(),
+    }
+}
+
+/// The first word of a text message, for protocols of text commands such as `subscribe prices`.
+pub fn first_word(msg: &Message) -> Option<String> {
+    match *msg {
+        Message::Text(ref text) => text.split_whitespace().next().map(String::from),
+        Message::Binary(_) => None,
+    }
+}
+
+/// The `"type"` field of a message that is a JSON object, such as `{"type": "subscribe"}`.
+/// Available with the `json` feature.
+#[cfg(feature = "json")]
+pub fn json_type(msg: &Message) -> Option<String> {
+    let value: serde_json::Value = match *msg {
+        Message::Text(ref text) => serde_json::from_str(text).ok()?,
+        Message::Binary(ref data) => serde_json::from_slice(data).ok()?,
+    };
+    value.get("type")?.as_str().map(String::from)
+}
+
+/// A handler that receives messages as values deserialized from JSON. See
+/// `HandlerExt::with_json`.
+#[cfg(feature = "json")]
+pub trait JsonHandler<T>: Handler {
+    /// Called with each incoming message after it has been parsed.
+    fn on_json(&mut self, value: T) -> Result<()>;
+}
+
+/// Parses incoming messages as JSON. Messages that fail to parse are dropped, and the wrapped
+/// handler's `on_error` receives a `Custom` error holding the parse error.
+#[cfg(feature = "json")]
+pub struct Json<H, T> {
+    inner: H,
+    value: PhantomData<fn() -> T>,
+}
+
+#[cfg(feature = "json")]
+impl<H, T> Handler for Json<H, T>
+where
+    H: JsonHandler<T>,
+    T: DeserializeOwned,
+{
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let value = serde_json::from_slice(&msg.into_data()).map_err(|err| {
+            Error::new(
+                Kind::Custom(Box::new(err)),
+                "Unable to parse message as JSON.",
+            )
+        })?;
+        self.inner.on_json(value)
+    }
+
+    forward!(
+        on_shutdown,
+        on_open,
+        on_close,
+        on_close_frame,
+        on_error,
+        on_request,
+        on_response,
+        on_timeout,
+        on_new_timeout,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_frame,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+// The name of the instrumentation scope of the meter and tracer.
+#[cfg(feature = "otel")]
+const INSTRUMENTATION: &str = "parity-ws";
+
+/// Records the connection as an OpenTelemetry span and its traffic as metrics, available with
+/// the `otel` feature. See `HandlerExt::with_telemetry`.
+///
+/// Everything is reported through the global providers of the `opentelemetry` crate, so it is
+/// exported by whatever SDK the application installs there. Attributes use the names of the
+/// semantic conventions where they exist.
+///
+/// The span is named `websocket.connection`, has the `Server` or `Client` kind, lasts from the
+/// end of the handshake until the connection closes, and carries the
+/// `network.protocol.name`, `network.peer.address`, `network.peer.port` and `url.path`
+/// attributes, the `websocket.close.code` of the closing handshake, and an `error.type` and
+/// error status if the connection failed.
+///
+/// The metrics are:
+///
+/// * `websocket.connection.active`, the number of open connections.
+/// * `websocket.connection.duration`, a histogram of the lifetime of connections in seconds.
+/// * `websocket.message.received.size` and `websocket.message.sent.size`, histograms of the
+///   size of the payloads of data messages as they appear on the wire, in bytes. Their counts
+///   are the numbers of messages.
+///
+/// ```no_run
+/// use parity_ws::{listen, HandlerExt};
+///
+/// listen("127.0.0.1:3012", |out| (move |msg| out.send(msg)).with_telemetry()).unwrap()
+/// ```
+#[cfg(feature = "otel")]
+pub struct Telemetry<H> {
+    inner: H,
+    client: bool,
+    attributes: Vec<KeyValue>,
+    active: UpDownCounter<i64>,
+    duration: Histogram<f64>,
+    received: Histogram<u64>,
+    sent: Histogram<u64>,
+    span: Option<BoxedSpan>,
+    opened: Option<Instant>,
+    // The payload received so far of a fragmented message.
+    receiving: u64,
+    sending: u64,
+}
+
+#[cfg(feature = "otel")]
+impl<H> Telemetry<H> {
+    fn new(inner: H) -> Telemetry<H> {
+        let meter = global::meter(INSTRUMENTATION);
+        Telemetry {
+            inner,
+            client: false,
+            attributes: vec![KeyValue::new("network.protocol.name", "websocket")],
+            active: meter
+                .i64_up_down_counter("websocket.connection.active")
+                .with_description("The number of open WebSocket connections.")
+                .with_unit(Unit::new("{connection}"))
+                .init(),
+            duration: meter
+                .f64_histogram("websocket.connection.duration")
+                .with_description("The time that WebSocket connections stay open.")
+                .with_unit(Unit::new("s"))
+                .init(),
+            received: meter
+                .u64_histogram("websocket.message.received.size")
+                .with_description("The size of the payloads of received WebSocket messages.")
+                .with_unit(Unit::new("By"))
+                .init(),
+            sent: meter
+                .u64_histogram("websocket.message.sent.size")
+                .with_description("The size of the payloads of sent WebSocket messages.")
+                .with_unit(Unit::new("By"))
+                .init(),
+            span: None,
+            opened: None,
+            receiving: 0,
+            sending: 0,
+        }
+    }
+
+    // Add the payload of a data frame to the message it belongs to, returning the size of the
+    // message once its final frame arrives.
+    fn count(total: &mut u64, frame: &Frame) -> Option<u64> {
+        if frame.is_control() {
+            return None;
+        }
+        *total += frame.payload().len() as u64;
+        if frame.is_final() {
+            Some(::std::mem::replace(total, 0))
+        } else {
+            None
+        }
+    }
+
+    // Record the close code and finish.
+    fn closed(&mut self, code: CloseCode) {
+        if let Some(ref mut span) = self.span {
+            let value: u16 = code.into();
+            span.set_attribute(KeyValue::new("websocket.close.code", i64::from(value)));
+        }
+        self.finish();
+    }
+
+    // End the span and stop counting the connection as open, once.
+    fn finish(&mut self) {
+        if let Some(opened) = self.opened.take() {
+            self.active.add(-1, &self.attributes);
+            let duration = opened.elapsed().as_secs_f64();
+            self.duration.record(duration, &self.attributes);
+        }
+        if let Some(mut span) = self.span.take() {
+            span.end();
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+impl<H: Handler> Handler for Telemetry<H> {
+    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+        self.client = true;
+        self.inner.build_request(url)
+    }
+
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        let resource = shake.request.resource();
+        let mut attributes = self.attributes.clone();
+        attributes.push(KeyValue::new(
+            "url.path",
+            resource.split('?').next().unwrap_or(resource).to_string(),
+        ));
+        if let Some(peer) = shake.peer_addr {
+            attributes.push(KeyValue::new("network.peer.address", peer.ip().to_string()));
+            attributes.push(KeyValue::new("network.peer.port", i64::from(peer.port())));
+        }
+        let tracer = global::tracer(INSTRUMENTATION);
+        let kind = if self.client {
+            SpanKind::Client
+        } else {
+            SpanKind::Server
+        };
+        self.span = Some(
+            tracer
+                .span_builder("websocket.connection")
+                .with_kind(kind)
+                .with_attributes(attributes)
+                .start(&tracer),
+        );
+        self.opened = Some(Instant::now());
+        self.active.add(1, &self.attributes);
+        self.inner.on_open(shake)
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.closed(code);
+        self.inner.on_close(code, reason)
+    }
+
+    fn on_close_frame(&mut self, frame: &CloseFrame) {
+        self.closed(frame.code);
+        self.inner.on_close_frame(frame)
+    }
+
+    fn on_error(&mut self, err: Error) {
+        if let Some(ref mut span) = self.span {
+            span.set_attribute(KeyValue::new("error.type", error_type(&err.kind)));
+            span.set_status(Status::error(err.to_string()));
+        }
+        self.inner.on_error(err)
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        if let Some(size) = Self::count(&mut self.receiving, &frame) {
+            self.received.record(size, &self.attributes);
+        }
+        self.inner.on_frame(frame)
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        let frame = self.inner.on_send_frame(frame)?;
+        if let Some(ref frame) = frame {
+            if let Some(size) = Self::count(&mut self.sending, frame) {
+                self.sent.record(size, &self.attributes);
+            }
+        }
+        Ok(frame)
+    }
+
+    forward!(
+        on_shutdown,
+        on_message,
+        on_request,
+        on_response,
+        on_timeout,
+        on_new_timeout,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        ssl
+    );
+}
+
+#[cfg(feature = "otel")]
+impl<H> Drop for Telemetry<H> {
+    fn drop(&mut self) {
+        self.finish()
+    }
+}
+
+// The value of the `error.type` attribute for an error.
+#[cfg(feature = "otel")]
+fn error_type(kind: &Kind) -> &'static str {
+    match *kind {
+        Kind::Internal => "internal",
+        Kind::Capacity => "capacity",
+        Kind::Protocol => "protocol",
+        Kind::Encoding(_) => "encoding",
+        Kind::Io(_) => "io",
+        Kind::Http(_) => "http",
+        Kind::Queue(_) => "queue",
+        Kind::Stalled => "stalled",
+        #[cfg(any(feature = "ssl", feature = "nativetls"))]
+        Kind::Ssl(_) => "ssl",
+        #[cfg(any(feature = "ssl", feature = "nativetls"))]
+        Kind::SslHandshake(_) => "ssl_handshake",
+        Kind::Custom(_) => "custom",
+    }
+}
+
+/// What `EarlyQueue::send` does with a message while the client is disconnected and the queue
+/// is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the oldest queued message to make room for the new one.
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Fail to send the new message with a `Capacity` error.
+    Reject,
+}
+
+struct EarlyState {
+    capacity: usize,
+    overflow: Overflow,
+    messages: VecDeque<Message>,
+    // The sender of the open connection, along with its number among the connections that the
+    // queue was flushed to.
+    out: Option<(u64, Sender)>,
+    connections: u64,
+}
+
+/// Messages for a client to send, which are held while it is disconnected and sent as soon as
+/// it has reconnected, so that the code producing them does not have to know whether there is
+/// a connection. See `HandlerExt::with_early_queue`.
+///
+/// Clones share the same queue, which outlives the connections, and the WebSockets, that it is
+/// used with. A client creates one queue and wraps the handler of every connection that it
+/// makes with it:
+///
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+/// use parity_ws::middleware::{EarlyQueue, Overflow};
+/// use parity_ws::{connect, HandlerExt, Message, Result};
+///
+/// let queue = EarlyQueue::new(100, Overflow::DropOldest);
+/// let positions = queue.clone();
+/// thread::spawn(move || loop {
+///     positions.send("position").unwrap();
+///     thread::sleep(Duration::from_secs(1));
+/// });
+///
+/// loop {
+///     let _ = connect("ws://127.0.0.1:3012", |out| {
+///         (|msg: Message| -> Result<()> { Ok(println!("{}", msg)) }).with_early_queue(&out, &queue)
+///     });
+///     thread::sleep(Duration::from_secs(5));
+/// }
+/// ```
+///
+/// While a connection is open, messages are handed straight to it, and those that it has not
+/// written when it drops are lost, so use `Sender::send_reliable` where every message must
+/// arrive. The queued messages are flushed through the queue of the event loop, so the
+/// capacity should not exceed `Settings::queue_size` times `Settings::max_connections`.
+#[derive(Clone)]
+pub struct EarlyQueue {
+    state: Arc<Mutex<EarlyState>>,
+}
+
+impl EarlyQueue {
+    /// A queue that holds up to `capacity` messages while disconnected, following `overflow`
+    /// once it is full.
+    pub fn new(capacity: usize, overflow: Overflow) -> EarlyQueue {
+        EarlyQueue {
+            state: Arc::new(Mutex::new(EarlyState {
+                capacity,
+                overflow,
+                messages: VecDeque::new(),
+                out: None,
+                connections: 0,
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, EarlyState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Send a message over the open connection, or queue it until there is one.
+    pub fn send<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        let mut state = self.lock();
+        if let Some((_, ref out)) = state.out {
+            return out.send(msg);
+        }
+        if state.messages.len() >= state.capacity {
+            match state.overflow {
+                Overflow::DropOldest if state.capacity > 0 => {
+                    trace!("Dropping the oldest message queued while disconnected.");
+                    state.messages.pop_front();
+                }
+                Overflow::DropOldest | Overflow::DropNewest => {
+                    trace!("Dropping a message sent while disconnected.");
+                    return Ok(());
+                }
+                Overflow::Reject => {
+                    return Err(Error::new(
+                        Kind::Capacity,
+                        "The queue of messages sent while disconnected is full.",
+                    ))
+                }
+            }
+        }
+        state.messages.push_back(msg.into());
+        Ok(())
+    }
+
+    /// The number of messages waiting for a connection.
+    pub fn len(&self) -> usize {
+        self.lock().messages.len()
+    }
+
+    /// Whether no messages are waiting for a connection.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether messages are sent straight to an open connection.
+    pub fn is_connected(&self) -> bool {
+        self.lock().out.is_some()
+    }
+
+    // Hand the queued messages to a connection that has just opened, and send later ones
+    // straight to it. Returns the number of the connection and how many messages were sent.
+    fn attach(&self, out: &Sender) -> Result<(u64, usize)> {
+        let mut state = self.lock();
+        let mut count = 0;
+        while let Some(msg) = state.messages.pop_front() {
+            out.send(msg)?;
+            count += 1;
+        }
+        state.connections += 1;
+        let connection = state.connections;
+        state.out = Some((connection, out.clone()));
+        Ok((connection, count))
+    }
+
+    // Queue messages again once the connection has gone, unless another has replaced it.
+    fn detach(&self, connection: u64) {
+        let mut state = self.lock();
+        if state.out.as_ref().map(|&(number, _)| number) == Some(connection) {
+            state.out = None;
+        }
+    }
+}
+
+impl fmt::Debug for EarlyQueue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.lock();
+        f.debug_struct("EarlyQueue")
+            .field("capacity", &state.capacity)
+            .field("overflow", &state.overflow)
+            .field("queued", &state.messages.len())
+            .field("connected", &state.out.is_some())
+            .finish()
+    }
+}
+
+/// Sends the messages of an `EarlyQueue` over the connection while it is open. See
+/// `HandlerExt::with_early_queue`.
+pub struct EarlyFlushed<H> {
+    inner: H,
+    out: Sender,
+    queue: EarlyQueue,
+    connection: Option<u64>,
+}
+
+impl<H> EarlyFlushed<H> {
+    fn detach(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.queue.detach(connection);
+        }
+    }
+}
+
+impl<H: Handler> Handler for EarlyFlushed<H> {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        // Whatever the handler sends on opening, such as a login, goes before the queue.
+        self.inner.on_open(shake)?;
+        let (connection, count) = self.queue.attach(&self.out)?;
+        self.connection = Some(connection);
+        if count > 0 {
+            debug!("Flushed {} messages queued while disconnected.", count);
+            self.inner.on_flush_after_reconnect(count)?;
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.detach();
+        self.inner.on_close(code, reason)
+    }
+
+    forward!(
+        on_shutdown,
+        on_message,
+        on_close_frame,
+        on_error,
+        on_request,
+        on_response,
+        on_timeout,
+        on_new_timeout,
+        on_ack_timeout,
+        on_replay_detected,
+        on_flush_after_reconnect,
+        on_frame,
+        on_unknown_frame,
+        on_large_frame,
+        on_peer_half_close,
+        on_send_frame,
+        build_request,
+        ssl
+    );
+}
+
+impl<H> Drop for EarlyFlushed<H> {
+    fn drop(&mut self) {
+        self.detach()
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn counter() -> (Rc<RefCell<usize>>, impl Handler) {
+        let count = Rc::new(RefCell::new(0));
+        let inner = count.clone();
+        (count, move |_: Message| {
+            *inner.borrow_mut() += 1;
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn rate_limit() {
+        let (count, handler) = counter();
+        let mut handler = handler.with_rate_limit(RateLimit::new(3, Duration::from_secs(3600)));
+        for _ in 0..3 {
+            handler.on_message(Message::text("ok")).unwrap();
+        }
+        match handler.on_message(Message::text("too many")) {
+            Err(Error {
+                kind: Kind::Custom(_),
+                ..
+            }) => (),
+            _ => panic!("expected the message to be rate limited"),
+        }
+        assert_eq!(*count.borrow(), 3);
+    }
+
+    #[test]
+    fn auth() {
+        let (_, handler) = counter();
+        let mut handler = handler.with_auth(|req| req.resource() == "/allowed");
+
+        let allowed = Request::from_url(&url::Url::parse("ws://localhost/allowed").unwrap());
+        assert_eq!(handler.on_request(&allowed.unwrap()).unwrap().status(), 101);
+        let denied = Request::from_url(&url::Url::parse("ws://localhost/denied").unwrap());
+        assert_eq!(handler.on_request(&denied.unwrap()).unwrap().status(), 401);
+    }
+
+    #[test]
+    fn layers_compose() {
+        let (count, handler) = counter();
+        let mut handler = handler
+            .layer(RateLimit::new(1, Duration::from_secs(3600)))
+            .layer(|inner| Logging { inner });
+        handler.on_message(Message::text("ok")).unwrap();
+        assert!(handler.on_message(Message::text("dropped")).is_err());
+        assert_eq!(*count.borrow(), 1);
+    }
+
+    // Records which of its methods each message was passed to.
+    struct Commands(Vec<&'static str>);
+
+    impl Commands {
+        fn on_subscribe(&mut self, _: Message) -> Result<()> {
+            self.0.push("subscribe");
+            Ok(())
+        }
+
+        fn on_ping(&mut self, _: Message) -> Result<()> {
+            self.0.push("ping");
+            Ok(())
+        }
+    }
+
+    impl Handler for Commands {
+        fn on_message(&mut self, _: Message) -> Result<()> {
+            self.0.push("other");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn router() {
+        let mut handler = Commands(Vec::new())
+            .with_router(first_word)
+            .route("subscribe", Commands::on_subscribe);
+        handler.on_message(Message::text("subscribe prices")).unwrap();
+        handler.on_message(Message::text("unsubscribe prices")).unwrap();
+        handler.on_message(Message::binary(b"subscribe".to_vec())).unwrap();
+        assert_eq!(handler.inner.0, vec!["subscribe", "other", "other"]);
+
+        let mut handler = Commands(Vec::new())
+            .with_router(first_byte)
+            .route(1, Commands::on_ping);
+        handler.on_message(Message::binary(vec![1, 0])).unwrap();
+        handler.on_message(Message::binary(vec![2, 0])).unwrap();
+        handler.on_message(Message::binary(vec![])).unwrap();
+        assert_eq!(handler.inner.0, vec!["ping", "other", "other"]);
+    }
+
+    #[test]
+    fn envelopes() {
+        match open(envelope(7, &Message::text("hi"))).unwrap() {
+            Envelope::Data(7, msg) => assert_eq!(msg, Message::text("hi")),
+            _ => panic!("expected a reliable message"),
+        }
+        match open(envelope(8, &Message::binary(vec![1, 2]))).unwrap() {
+            Envelope::Data(8, msg) => assert_eq!(msg, Message::binary(vec![1, 2])),
+            _ => panic!("expected a reliable message"),
+        }
+        match open(Message::text(format!("{}9", ACK))).unwrap() {
+            Envelope::Ack(9) => (),
+            _ => panic!("expected an acknowledgement"),
+        }
+        match open(Message::text("plain")).unwrap() {
+            Envelope::Plain(msg) => assert_eq!(msg, Message::text("plain")),
+            _ => panic!("expected a plain message"),
+        }
+        assert!(open(Message::text(format!("{}x\nhi", DATA))).is_err());
+    }
+
+    #[test]
+    fn retry_policy() {
+        let (tx, rx) = ::mio::channel::sync_channel(10);
+        let out = Sender::new(Token(1), tx, 0);
+        assert!(out.send_reliable("lost").is_err());
+
+        let (_, handler) = counter();
+        let handler = handler.with_acks(
+            &out,
+            RetryPolicy::new(Duration::from_secs(1), 3).backoff(2),
+        );
+        assert_eq!(out.send_reliable("lost").unwrap(), 0);
+        let mut now = Instant::now() + Duration::from_secs(1);
+        let mut waits = Vec::new();
+        loop {
+            let due = out
+                .context()
+                .with(|outbox: &mut Outbox| outbox.retry(now))
+                .unwrap();
+            if !due.expired.is_empty() {
+                assert!(due.resend.is_empty() && due.next.is_none());
+                break;
+            }
+            assert_eq!(due.resend.len(), 1);
+            waits.push(due.next.unwrap());
+            now += due.next.unwrap();
+        }
+        // The message is sent three times, waiting twice as long for each retry.
+        assert_eq!(waits, vec![Duration::from_secs(2), Duration::from_secs(4)]);
+    }
+
+    #[test]
+    fn keepalive() {
+        use communication::Signal;
+
+        let (tx, rx) = ::mio::channel::sync_channel(10);
+        let out = Sender::new(Token(1), tx, 0);
+        let (_, handler) = counter();
+        let policy = KeepAlive::new(Duration::from_secs(30), Duration::from_secs(10));
+        let mut handler = handler.with_keepalive(&out, policy);
+        let drain = || {
+            let mut signals = Vec::new();
+            while let Ok(command) = rx.try_recv() {
+                signals.push(command.into_signal());
+            }
+            signals
+        };
+
+        // A connection that has been idle for the interval is pinged.
+        handler.active = Instant::now() - Duration::from_secs(31);
+        handler.on_timeout(KEEPALIVE).unwrap();
+        let signals = drain();
+        match signals[0] {
+            Signal::Ping(ref data) => assert_eq!(data[..], 1u64.to_be_bytes()),
+            ref signal => panic!("expected a ping, got {:?}", signal),
+        }
+        match signals[1] {
+            Signal::Timeout { delay, token } => {
+                assert_eq!(token, KEEPALIVE);
+                assert!(delay > 9_000 && delay <= 10_000);
+            }
+            ref signal => panic!("expected a timeout, got {:?}", signal),
+        }
+
+        // A pong for an earlier ping does not count, the one for the last ping does.
+        let mut pong = Frame::pong(0u64.to_be_bytes().to_vec());
+        handler.on_frame(pong).unwrap();
+        assert!(handler.awaiting.is_some());
+        pong = Frame::pong(1u64.to_be_bytes().to_vec());
+        handler.on_frame(pong).unwrap();
+        assert!(handler.awaiting.is_none());
+
+        // A connection that does not answer in time fails.
+        handler.awaiting = Some(Instant::now() - Duration::from_secs(11));
+        match handler.on_timeout(KEEPALIVE) {
+            Err(Error {
+                kind: Kind::Stalled,
+                ..
+            }) => (),
+            _ => panic!("expected the connection to stall"),
+        }
+
+        // An old connection is retired, and timeouts of the wrapped handler pass through.
+        handler.retire = Some(Instant::now());
+        handler.on_timeout(KEEPALIVE).unwrap();
+        match drain()[0] {
+            Signal::Close(CloseCode::Away, _) => (),
+            ref signal => panic!("expected a close, got {:?}", signal),
+        }
+        handler.on_timeout(Token(7)).unwrap();
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::new(4);
+        assert!(window.accept(0));
+        assert!(window.accept(2));
+        assert!(!window.accept(2));
+        // Ids may arrive out of order within the window.
+        assert!(window.accept(1));
+        assert!(!window.accept(0));
+        assert!(window.accept(5));
+        // 1 has left the window, but 3 and 4 have not been received yet.
+        assert!(!window.accept(1));
+        assert!(window.accept(4));
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+        // A jump past the window forgets everything older.
+        assert!(window.accept(100));
+        assert!(!window.accept(99));
+        assert!(!window.accept(5));
+    }
+
+    #[test]
+    fn resumption_encoding() {
+        let resumption = Resumption {
+            key: [7; 16],
+            sent: 3,
+            received: 1 << 40,
+        };
+        let blob = resumption.encode();
+        assert_eq!(blob.len(), 33);
+        assert_eq!(Resumption::decode(&blob).unwrap(), resumption);
+        assert!(Resumption::decode(&blob[..32]).is_err());
+        let mut future = blob.clone();
+        future[0] = 2;
+        assert!(Resumption::decode(&future).is_err());
+    }
+
+    #[test]
+    fn early_queue() {
+        let queue = EarlyQueue::new(2, Overflow::DropOldest);
+        for msg in &["one", "two", "three"] {
+            queue.send(*msg).unwrap();
+        }
+        assert_eq!(queue.len(), 2);
+
+        let (tx, rx) = ::mio::channel::sync_channel(10);
+        let out = Sender::new(Token(1), tx, 0);
+        let (first, count) = queue.attach(&out).unwrap();
+        assert_eq!(count, 2);
+        queue.send("four").unwrap();
+        assert!(queue.is_empty() && queue.is_connected());
+        let mut sent = Vec::new();
+        while let Ok(command) = rx.try_recv() {
+            match command.into_signal() {
+                ::communication::Signal::Message(msg) => sent.push(msg.into_text().unwrap()),
+                _ => panic!("expected a message"),
+            }
+        }
+        assert_eq!(sent, vec!["two", "three", "four"]);
+
+        // A connection that has been replaced does not stop the queue from sending.
+        let (second, _) = queue.attach(&out).unwrap();
+        queue.detach(first);
+        assert!(queue.is_connected());
+        queue.detach(second);
+        assert!(!queue.is_connected());
+
+        let queue = EarlyQueue::new(1, Overflow::Reject);
+        queue.send("one").unwrap();
+        assert!(queue.send("two").is_err());
+        let queue = EarlyQueue::new(1, Overflow::DropNewest);
+        queue.send("one").unwrap();
+        queue.send("two").unwrap();
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "otel")]
+    fn telemetry() {
+        use protocol::OpCode;
+
+        // Without an SDK installed, the global providers do nothing.
+        let (count, handler) = counter();
+        let mut handler = handler.with_telemetry();
+        let first = Frame::message(b"he".to_vec(), OpCode::Text, false);
+        assert!(handler.on_frame(first).unwrap().is_some());
+        assert_eq!(handler.receiving, 2);
+        let last = Frame::message(b"llo".to_vec(), OpCode::Continue, true);
+        assert_eq!(handler.on_frame(last).unwrap().unwrap().payload(), b"llo");
+        assert_eq!(handler.receiving, 0);
+        handler.on_message(Message::text("hello")).unwrap();
+        assert_eq!(*count.borrow(), 1);
+        handler.on_close(CloseCode::Normal, "");
+    }
+}
@@ -0,0 +1,117 @@
+use std::net::{SocketAddr, TcpStream as StdTcpStream};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use mio;
+use mio::tcp::TcpStream;
+#[cfg(feature = "nativetls")]
+use native_tls::{TlsAcceptor, TlsStream as SslStream};
+#[cfg(feature = "ssl")]
+use openssl::ssl::{SslAcceptor, SslStream};
+
+use result::{Error, Kind, Result};
+
+const HANDSHAKE_TIMEOUT_MILLIS: u64 = 10_000;
+
+/// Performs the server side of TLS handshakes on the worker threads of a handshake offload pool.
+///
+/// Unlike `Handler::upgrade_ssl_server`, one acceptor is shared by all of the workers, so it
+/// must be `Send` and `Sync`. The stream is in blocking mode for the duration of `accept`, and
+/// the handshake fails if the peer stalls for more than ten seconds.
+pub trait HandshakeAcceptor: Send + Sync + 'static {
+    /// Complete a TLS handshake on the stream.
+    fn accept(&self, stream: TcpStream) -> Result<SslStream<TcpStream>>;
+}
+
+#[cfg(feature = "ssl")]
+impl HandshakeAcceptor for SslAcceptor {
+    fn accept(&self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        SslAcceptor::accept(self, stream).map_err(Error::from)
+    }
+}
+
+#[cfg(feature = "nativetls")]
+impl HandshakeAcceptor for TlsAcceptor {
+    fn accept(&self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        TlsAcceptor::accept(self, stream).map_err(Error::from)
+    }
+}
+
+pub type Completed = (SocketAddr, Result<SslStream<TcpStream>>);
+
+/// A pool of threads that run TLS handshakes for accepted connections and hand the encrypted
+/// streams back to the event loop through `completed`.
+pub struct HandshakePool {
+    jobs: mpsc::Sender<(StdTcpStream, SocketAddr)>,
+    completed: mio::channel::Receiver<Completed>,
+}
+
+impl HandshakePool {
+    pub fn new<A>(acceptor: A, threads: usize) -> Result<HandshakePool>
+    where
+        A: HandshakeAcceptor,
+    {
+        let (jobs, queued) = mpsc::channel::<(StdTcpStream, SocketAddr)>();
+        let (done, completed) = mio::channel::channel();
+        let queued = Arc::new(Mutex::new(queued));
+        let acceptor = Arc::new(acceptor);
+
+        for n in 0..threads.max(1) {
+            let queued = queued.clone();
+            let acceptor = acceptor.clone();
+            let done = done.clone();
+            thread::Builder::new()
+                .name(format!("ws-tls-handshake-{}", n))
+                .spawn(move || loop {
+                    let job = match queued.lock() {
+                        Ok(queued) => queued.recv(),
+                        Err(_) => return,
+                    };
+                    // The pool has been dropped along with the event loop.
+                    let (stream, addr) = match job {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    trace!("Performing TLS handshake with {} on a worker.", addr);
+                    if done.send((addr, handshake(&*acceptor, stream))).is_err() {
+                        return;
+                    }
+                })?;
+        }
+
+        Ok(HandshakePool { jobs, completed })
+    }
+
+    pub fn offload(&self, stream: StdTcpStream, addr: SocketAddr) -> Result<()> {
+        self.jobs
+            .send((stream, addr))
+            .map_err(|_| Error::new(Kind::Internal, "TLS handshake workers have stopped."))
+    }
+
+    pub fn completed(&self) -> &mio::channel::Receiver<Completed> {
+        &self.completed
+    }
+}
+
+fn handshake<A>(acceptor: &A, stream: StdTcpStream) -> Result<SslStream<TcpStream>>
+where
+    A: HandshakeAcceptor,
+{
+    // The clone shares the socket with the mio stream, which lets us switch it to blocking mode
+    // for the handshake and back again before the event loop takes it over.
+    let control = stream.try_clone()?;
+    let sock = TcpStream::from_stream(stream)?;
+    let timeout = Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MILLIS));
+    control.set_nonblocking(false)?;
+    control.set_read_timeout(timeout)?;
+    control.set_write_timeout(timeout)?;
+
+    let res = acceptor.accept(sock);
+
+    control.set_read_timeout(None)?;
+    control.set_write_timeout(None)?;
+    control.set_nonblocking(true)?;
+    res
+}
@@ -0,0 +1,248 @@
+//! Urls that grant short-lived access to a WebSocket.
+//!
+//! A web backend that shares a key with the WebSocket server can hand out urls that expire,
+//! without the server keeping track of them. `UrlSigner::sign` adds an `expires` parameter with
+//! the unix time of the expiry and a `signature` parameter with the HMAC-SHA256 of the path and
+//! the query to the url. The server checks both before the handshake, by setting the same
+//! `UrlSigner` as the `Authorizer` of the WebSocket:
+//!
+//! ```no_run
+//! extern crate parity_ws;
+//! extern crate url;
+//!
+//! use std::time::Duration;
+//!
+//! use parity_ws::presign::UrlSigner;
+//! use parity_ws::Builder;
+//! use url::Url;
+//!
+//! # fn main() {
+//! let signer = UrlSigner::new("shared key");
+//!
+//! // In the backend, for a client that may connect within the next minute.
+//! let url = Url::parse("ws://127.0.0.1:3012/rooms/7?user=alice").unwrap();
+//! let presigned = signer.sign(&url, Duration::from_secs(60));
+//!
+//! // In the WebSocket server.
+//! let mut ws = Builder::new()
+//!     .build(|out: parity_ws::Sender| move |msg| out.send(msg))
+//!     .unwrap();
+//! ws.with_authorizer(signer);
+//! ws.listen("127.0.0.1:3012").unwrap();
+//! # }
+//! ```
+//!
+//! Only the path and the query are signed, so the url stays valid behind proxies that rewrite
+//! the host. Anyone holding the url can use it until it expires, so the expiry should be short.
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use url::{form_urlencoded, Url};
+
+use auth::{Authorization, Authorizer, Credentials};
+use result::{Error, Kind, Result};
+
+/// The query parameter with the unix time at which a presigned url expires.
+pub const EXPIRES: &str = "expires";
+/// The query parameter with the hex encoded signature of a presigned url, always the last one.
+pub const SIGNATURE: &str = "signature";
+
+/// Signs urls and verifies the signatures of handshake requests with shared keys. See the module
+/// documentation.
+///
+/// As an `Authorizer`, requests for resources without a valid signature, or whose signature has
+/// expired, are refused with a 403 response.
+pub struct UrlSigner {
+    keys: Vec<Vec<u8>>,
+}
+
+impl UrlSigner {
+    /// Sign and verify urls with `key`.
+    pub fn new<K>(key: K) -> UrlSigner
+    where
+        K: Into<Vec<u8>>,
+    {
+        UrlSigner {
+            keys: vec![key.into()],
+        }
+    }
+
+    /// Also accept urls signed with `key`, such as a previous key, so urls that were handed
+    /// out before the key was rotated stay valid until they expire. Urls are always signed with
+    /// the first key.
+    pub fn with_key<K>(mut self, key: K) -> UrlSigner
+    where
+        K: Into<Vec<u8>>,
+    {
+        self.keys.push(key.into());
+        self
+    }
+
+    /// Presign `url` so it is valid for `ttl` from now.
+    pub fn sign(&self, url: &Url, ttl: Duration) -> Url {
+        self.sign_until(url, SystemTime::now() + ttl)
+    }
+
+    /// Presign `url` so it is valid until `expires`.
+    pub fn sign_until(&self, url: &Url, expires: SystemTime) -> Url {
+        let expires = expires
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or(0);
+        let mut url = url.clone();
+        url.query_pairs_mut()
+            .append_pair(EXPIRES, &expires.to_string());
+        let signature = encode_hex(&mac(&self.keys[0], &resource(&url)).result().code());
+        url.query_pairs_mut().append_pair(SIGNATURE, &signature);
+        url
+    }
+
+    /// Verify the signature of `resource`, the path and the query of a handshake request, as
+    /// returned by `Request::resource`.
+    pub fn verify(&self, resource: &str) -> Result<()> {
+        self.verify_at(resource, SystemTime::now())
+    }
+
+    /// Verify the signature of `resource` as if the time was `now`.
+    pub fn verify_at(&self, resource: &str, now: SystemTime) -> Result<()> {
+        let marker = format!("&{}=", SIGNATURE);
+        let at = resource
+            .rfind(&marker)
+            .ok_or_else(|| Error::new(Kind::Protocol, "Url is not presigned."))?;
+        let (signed, signature) = (&resource[..at], &resource[at + marker.len()..]);
+        let signature = decode_hex(signature)
+            .ok_or_else(|| Error::new(Kind::Protocol, "Malformed signature of presigned url."))?;
+
+        if !self
+            .keys
+            .iter()
+            .any(|key| mac(key, signed).verify(&signature).is_ok())
+        {
+            return Err(Error::new(
+                Kind::Protocol,
+                "Invalid signature of presigned url.",
+            ));
+        }
+
+        let query = signed.split_once('?').map(|(_, query)| query).unwrap_or("");
+        let expires = form_urlencoded::parse(query.as_bytes())
+            .filter(|(name, _)| name == EXPIRES)
+            .last()
+            .and_then(|(_, expires)| expires.parse::<u64>().ok())
+            .ok_or_else(|| Error::new(Kind::Protocol, "Presigned url has no expiry."))?;
+        if UNIX_EPOCH + Duration::from_secs(expires) <= now {
+            return Err(Error::new(Kind::Protocol, "Presigned url has expired."));
+        }
+        Ok(())
+    }
+}
+
+impl Authorizer for UrlSigner {
+    fn authorize(&self, credentials: &Credentials) -> Authorization {
+        match self.verify(credentials.request().resource()) {
+            Ok(()) => Authorization::Allow,
+            Err(err) => {
+                debug!("{}", err);
+                Authorization::Deny { status: 403 }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for UrlSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UrlSigner")
+            .field("keys", &self.keys.len())
+            .finish()
+    }
+}
+
+// The signed part of a url, as it appears in the handshake request.
+fn resource(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().into(),
+    }
+}
+
+fn mac(key: &[u8], data: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any length.");
+    mac.input(data.as_bytes());
+    mac
+}
+
+fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use auth::authorize;
+    use context::Context;
+    use handshake::Request;
+
+    fn presigned(signer: &UrlSigner, ttl: Duration) -> String {
+        let url = Url::parse("ws://example.com/rooms/7?user=alice").unwrap();
+        resource(&signer.sign(&url, ttl))
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let signer = UrlSigner::new("key");
+        let resource = presigned(&signer, Duration::from_secs(60));
+        assert!(resource.starts_with("/rooms/7?user=alice&expires="));
+        assert!(signer.verify(&resource).is_ok());
+
+        let later = SystemTime::now() + Duration::from_secs(120);
+        assert!(signer.verify_at(&resource, later).is_err());
+        assert!(UrlSigner::new("other").verify(&resource).is_err());
+        assert!(signer.verify("/rooms/7?user=alice").is_err());
+    }
+
+    #[test]
+    fn tampering() {
+        let signer = UrlSigner::new("key");
+        let resource = presigned(&signer, Duration::from_secs(60));
+        let tampered = resource.replace("user=alice", "user=bob");
+        assert!(signer.verify(&tampered).is_err());
+
+        let (signed, signature) = resource.split_at(resource.rfind('&').unwrap());
+        let extended = format!("{}&expires=99999999999{}", signed, signature);
+        assert!(signer.verify(&extended).is_err());
+
+        let truncated = &resource[..resource.len() - 1];
+        assert!(signer.verify(truncated).is_err());
+    }
+
+    #[test]
+    fn rotated_keys() {
+        let resource = presigned(&UrlSigner::new("key"), Duration::from_secs(60));
+        let rotated = UrlSigner::new("new key").with_key("key");
+        assert!(rotated.verify(&resource).is_ok());
+    }
+
+    #[test]
+    fn authorizer() {
+        let signer = UrlSigner::new("key");
+        let resource = presigned(&signer, Duration::from_secs(60));
+        let req = Request::from_parts("GET", &resource, Vec::new());
+        assert!(authorize(&signer, &req, None, None, &Context::new()).is_none());
+
+        let req = Request::from_parts("GET", "/rooms/7", Vec::new());
+        let res = authorize(&signer, &req, None, None, &Context::new()).unwrap();
+        assert_eq!(res.status(), 403);
+    }
+}
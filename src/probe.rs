@@ -0,0 +1,205 @@
+//! The probe module chooses among several endpoints of a service by their latency.
+//!
+//! A `Probe` opens a WebSocket connection to every url at once and measures how long each
+//! opening handshake takes, which covers the TCP connection, a TLS handshake if any, and the
+//! upgrade request. The urls are then connected to fastest first, with the slower ones as
+//! fallbacks, as with `WebSocket::connect_any`. Since the fastest endpoint can change, such as
+//! when a regional gateway comes under load, the measurements are repeated once they are older
+//! than the probe's interval.
+//!
+//! ```no_run
+//! use parity_ws::probe::Probe;
+//! use parity_ws::WebSocket;
+//!
+//! let urls = vec![
+//!     "wss://eu.example.com/feed".parse().unwrap(),
+//!     "wss://us.example.com/feed".parse().unwrap(),
+//! ];
+//! let mut probe = Probe::new(urls);
+//!
+//! // Reconnect to whichever endpoint is fastest whenever the connection is lost.
+//! loop {
+//!     let mut ws = WebSocket::new(|out| move |msg| Ok(println!("{}", msg))).unwrap();
+//!     ws.connect_fastest(&mut probe).unwrap();
+//!     ws.run().unwrap();
+//! }
+//! ```
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use url;
+
+use communication::Sender;
+use handler::Handler;
+use handshake::Handshake;
+use protocol::CloseCode;
+use result::Result;
+
+use super::{Builder, Settings};
+
+// Measures the opening handshake of one connection, then closes it.
+struct Prober {
+    out: Sender,
+    started: Instant,
+    results: mpsc::Sender<(String, Duration)>,
+}
+
+impl Handler for Prober {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        let elapsed = self.started.elapsed();
+        if let Some(url) = shake.url {
+            let _ = self.results.send((url, elapsed));
+        }
+        self.out.close(CloseCode::Away)
+    }
+}
+
+/// Measure the handshake round trip time to each of the urls, waiting at most `timeout` for
+/// them, and return the urls that could be connected to, fastest first.
+///
+/// The connections are made concurrently on an event loop of their own, and are closed again as
+/// soon as they open. This blocks the calling thread, so it should not be called from a handler.
+pub fn measure(urls: &[url::Url], timeout: Duration) -> Result<Vec<(url::Url, Duration)>> {
+    let settings = Settings {
+        max_connections: urls.len().max(1),
+        ..Settings::default()
+    };
+    let (tx, rx) = mpsc::channel();
+    let mut ws = Builder::new()
+        .with_settings(settings)
+        .build(move |out| Prober {
+            out,
+            started: Instant::now(),
+            results: tx.clone(),
+        })?;
+    for url in urls {
+        ws.connect(url.clone())?;
+    }
+    let broadcaster = ws.broadcaster();
+    let deadline = Instant::now() + timeout;
+    let thread = thread::Builder::new()
+        .name("ws-probe".into())
+        .spawn(move || {
+            if let Err(err) = ws.run() {
+                debug!("Probe event loop failed: {}", err);
+            }
+        })?;
+
+    let mut measured = Vec::with_capacity(urls.len());
+    while measured.len() < urls.len() {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        match rx.recv_timeout(deadline - now) {
+            Ok((address, rtt)) => {
+                if let Some(url) = urls.iter().find(|url| url.as_str() == address) {
+                    trace!("Handshake with {} took {:?}.", url, rtt);
+                    measured.push((url.clone(), rtt));
+                }
+            }
+            // Either the time is up, or every connection has failed or closed.
+            Err(_) => break,
+        }
+    }
+    let _ = broadcaster.shutdown();
+    let _ = thread.join();
+
+    measured.sort_by_key(|&(_, rtt)| rtt);
+    Ok(measured)
+}
+
+/// Keeps a ranking of the urls of a service by handshake latency. See the module documentation.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    urls: Vec<url::Url>,
+    timeout: Duration,
+    interval: Duration,
+    latencies: Vec<(url::Url, Duration)>,
+    probed: Option<Instant>,
+}
+
+impl Probe {
+    /// Create a probe for the urls, which are given in order of preference. Urls that can not be
+    /// connected to while probing are ranked last, in this order.
+    ///
+    /// By default, each probe waits up to 5 seconds for the handshakes, and the urls are probed
+    /// again once the measurements are a minute old.
+    pub fn new(urls: Vec<url::Url>) -> Probe {
+        Probe {
+            urls,
+            timeout: Duration::from_secs(5),
+            interval: Duration::from_secs(60),
+            latencies: Vec::new(),
+            probed: None,
+        }
+    }
+
+    /// Set how long a probe waits for the handshakes to complete.
+    pub fn timeout(mut self, timeout: Duration) -> Probe {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set how old the measurements may become before the urls are probed again.
+    pub fn interval(mut self, interval: Duration) -> Probe {
+        self.interval = interval;
+        self
+    }
+
+    /// Measure the latency of every url now, regardless of the age of the last measurements.
+    pub fn probe(&mut self) -> Result<()> {
+        self.latencies = measure(&self.urls, self.timeout)?;
+        self.probed = Some(Instant::now());
+        Ok(())
+    }
+
+    /// The urls ranked fastest first, probing them first if they have not been probed within
+    /// the interval.
+    pub fn urls(&mut self) -> Result<Vec<url::Url>> {
+        let stale = match self.probed {
+            Some(probed) => probed.elapsed() >= self.interval,
+            None => true,
+        };
+        if stale {
+            self.probe()?;
+        }
+        Ok(self.ranked())
+    }
+
+    /// The urls that answered the last probe with their handshake round trip times, fastest
+    /// first.
+    pub fn latencies(&self) -> &[(url::Url, Duration)] {
+        &self.latencies
+    }
+
+    fn ranked(&self) -> Vec<url::Url> {
+        let mut ranked = self
+            .latencies
+            .iter()
+            .map(|&(ref url, _)| url.clone())
+            .collect::<Vec<_>>();
+        for url in &self.urls {
+            if !ranked.contains(url) {
+                ranked.push(url.clone());
+            }
+        }
+        ranked
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn unreachable_ranked_last() {
+        let a = url::Url::parse("ws://a.invalid/").unwrap();
+        let b = url::Url::parse("ws://b.invalid/").unwrap();
+        let c = url::Url::parse("ws://c.invalid/").unwrap();
+        let mut probe = Probe::new(vec![a.clone(), b.clone(), c.clone()]);
+        probe.latencies = vec![(c.clone(), Duration::from_millis(3))];
+        assert_eq!(probe.ranked(), vec![c, a, b]);
+    }
+}
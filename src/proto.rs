@@ -0,0 +1,939 @@
+//! The WebSocket protocol without any IO.
+//!
+//! The event loop of this crate frames messages and computes handshake keys with these
+//! functions, which only deal with byte slices. They can be used to speak the protocol over
+//! other transports, or from another event loop or an async runtime, without a `WebSocket`.
+//!
+//! Apart from the conversion of `HeaderError` into this crate's `Error`, the header functions
+//! only rely on `core`, and the handshake keys additionally on an allocator and the `sha1` and
+//! `rand` crates, so they can be lifted into a `no_std` crate with few changes.
+//!
+//! ```
+//! use parity_ws::OpCode;
+//! use parity_ws::proto::{decode_header, encode_header, Header, MAX_HEADER_LEN};
+//!
+//! let header = Header::new(OpCode::Text, 5);
+//! let mut buf = [0u8; MAX_HEADER_LEN];
+//! let len = encode_header(&header, &mut buf);
+//! assert_eq!(decode_header(&buf[..len], u64::MAX), Ok(Some((header, len))));
+//! // A header that has not been received completely yet decodes to nothing.
+//! assert_eq!(decode_header(&buf[..1], u64::MAX), Ok(None));
+//! ```
+//!
+//! `ProtocolMachine` puts these together into a whole connection, once its handshake is done.
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+
+use rand;
+use sha1::{self, Digest};
+
+use circular_buffer::CircularBuffer;
+use frame::Frame;
+use message::Message;
+use protocol::{CloseCode, OpCode};
+use result::{Error, Kind, Result};
+
+static WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+static BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// The length of the longest frame header: two bytes, a 64 bit payload length and a mask.
+pub const MAX_HEADER_LEN: usize = 14;
+
+/// Encode a close code into the first two bytes of a close frame payload, in network order.
+pub fn encode_close_code(code: u16) -> [u8; 2] {
+    code.to_be_bytes()
+}
+
+/// Decode the close code from the first two bytes of a close frame payload, or `None` if the
+/// payload is too short to hold one.
+pub fn decode_close_code(payload: &[u8]) -> Option<u16> {
+    if payload.len() < 2 {
+        return None;
+    }
+    Some(u16::from_be_bytes([payload[0], payload[1]]))
+}
+
+// Write `len` into an extended payload length field of 2 or 8 bytes, in network order.
+fn encode_extended_length(len: u64, field: &mut [u8]) {
+    match field.len() {
+        2 => {
+            debug_assert!(len <= u64::from(u16::MAX));
+            field.copy_from_slice(&(len as u16).to_be_bytes())
+        }
+        8 => field.copy_from_slice(&len.to_be_bytes()),
+        other => unreachable!("Extended payload length of {} bytes.", other),
+    }
+}
+
+// Read an extended payload length field of 2 or 8 bytes, in network order.
+fn decode_extended_length(field: &[u8]) -> u64 {
+    match field.len() {
+        2 => u64::from(u16::from_be_bytes([field[0], field[1]])),
+        8 => {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(field);
+            u64::from_be_bytes(bytes)
+        }
+        other => unreachable!("Extended payload length of {} bytes.", other),
+    }
+}
+
+/// The header of a WebSocket frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// Whether this is the final frame of a message.
+    pub finished: bool,
+    /// The first reserved bit, used by extensions such as permessage-deflate.
+    pub rsv1: bool,
+    /// The second reserved bit.
+    pub rsv2: bool,
+    /// The third reserved bit.
+    pub rsv3: bool,
+    /// The opcode of the frame.
+    pub opcode: OpCode,
+    /// The key that the payload is masked with, which clients must always set.
+    pub mask: Option<[u8; 4]>,
+    /// The length of the payload that follows the header.
+    pub payload_len: u64,
+}
+
+impl Header {
+    /// The header of an unmasked final frame with no reserved bits set.
+    pub fn new(opcode: OpCode, payload_len: u64) -> Header {
+        Header {
+            finished: true,
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            opcode,
+            mask: None,
+            payload_len,
+        }
+    }
+
+    /// The number of bytes that this header takes up once encoded.
+    pub fn encoded_len(&self) -> usize {
+        let length_len = match self.payload_len {
+            len if len < 126 => 0,
+            len if len <= 65535 => 2,
+            _ => 8,
+        };
+        let mask_len = if self.mask.is_some() { 4 } else { 0 };
+        2 + length_len + mask_len
+    }
+
+    /// The number of bytes of the whole frame, header and payload.
+    pub fn frame_len(&self) -> Option<u64> {
+        self.payload_len.checked_add(self.encoded_len() as u64)
+    }
+
+    /// The reserved bits that are set, in their positions within the first byte of the header:
+    /// `0x40` for RSV1, `0x20` for RSV2 and `0x10` for RSV3.
+    pub fn rsv_bits(&self) -> u8 {
+        (self.rsv1 as u8) << 6 | (self.rsv2 as u8) << 5 | (self.rsv3 as u8) << 4
+    }
+}
+
+// The constraints that come with an opcode. Control frames must be final and fit in 125 bytes.
+const CONTROL: u8 = 0x01;
+// The 125 byte limit is checked as ping and pong headers are decoded. Close frames are left to
+// the caller, see `decode_header`.
+const SHORT: u8 = 0x02;
+
+// Every opcode that fits in the low nibble of the first header byte along with its rules, so
+// that a header is classified and checked with one lookup rather than a chain of matches.
+static OPCODES: [(OpCode, u8); 16] = [
+    (OpCode::Continue, 0),
+    (OpCode::Text, 0),
+    (OpCode::Binary, 0),
+    (OpCode::Reserved(0x3), 0),
+    (OpCode::Reserved(0x4), 0),
+    (OpCode::Reserved(0x5), 0),
+    (OpCode::Reserved(0x6), 0),
+    (OpCode::Reserved(0x7), 0),
+    (OpCode::Close, CONTROL),
+    (OpCode::Ping, CONTROL | SHORT),
+    (OpCode::Pong, CONTROL | SHORT),
+    (OpCode::Reserved(0xB), CONTROL),
+    (OpCode::Reserved(0xC), CONTROL),
+    (OpCode::Reserved(0xD), CONTROL),
+    (OpCode::Reserved(0xE), CONTROL),
+    (OpCode::Reserved(0xF), CONTROL),
+];
+
+#[doc(hidden)]
+pub fn opcode(nibble: u8) -> OpCode {
+    OPCODES[usize::from(nibble & 0x0F)].0
+}
+
+fn rules(opcode: OpCode) -> u8 {
+    match opcode {
+        OpCode::Bad => 0,
+        opcode => OPCODES[usize::from(Into::<u8>::into(opcode) & 0x0F)].1,
+    }
+}
+
+/// A frame header that breaks the protocol.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The opcode is reserved.
+    BadOpcode(u8),
+    /// The payload is longer than the maximum that was allowed, which is included.
+    TooLong(u64),
+    /// A ping or pong has a payload longer than 125 bytes, the length of which is included.
+    ControlTooLong(u64),
+    /// Reserved bits that no extension allowed are set, in their positions within the first
+    /// byte of the header.
+    ReservedBits(u8),
+    /// A control frame is not final.
+    FragmentedControl,
+    /// A 64-bit payload length has its most significant bit set, which RFC 6455 forbids.
+    BadLength(u64),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HeaderError::BadOpcode(code) => write!(f, "Encountered invalid opcode: {}", code),
+            HeaderError::TooLong(max) => write!(
+                f,
+                "Rejected frame with payload length exceeding defined max: {}.",
+                max
+            ),
+            HeaderError::ControlTooLong(len) => write!(
+                f,
+                "Rejected WebSocket handshake.Received control frame with length: {}.",
+                len
+            ),
+            HeaderError::ReservedBits(bits) => write!(
+                f,
+                "Encountered frame with reserved bits set: {:#04x}.",
+                bits
+            ),
+            HeaderError::FragmentedControl => write!(f, "Encountered fragmented control frame."),
+            HeaderError::BadLength(len) => {
+                write!(f, "Encountered frame with invalid payload length: {}.", len)
+            }
+        }
+    }
+}
+
+impl From<HeaderError> for Error {
+    fn from(err: HeaderError) -> Error {
+        Error::new(Kind::Protocol, err.to_string())
+    }
+}
+
+/// Decode the frame header at the start of `buf` along with the number of bytes it takes up, or
+/// `None` if `buf` does not hold all of it yet. Payloads longer than `max_payload_length` are
+/// rejected.
+///
+/// A payload length may be encoded with more bytes than it needs, so the number of bytes taken
+/// up can be larger than `Header::encoded_len`.
+///
+/// Close frames with payloads longer than 125 bytes are not rejected, so that the caller can
+/// fail the connection with a close frame of its own. Neither are reserved opcodes, so that the
+/// caller can decide what to do with such frames.
+pub fn decode_header(
+    buf: &[u8],
+    max_payload_length: u64,
+) -> ::std::result::Result<Option<(Header, usize)>, HeaderError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let first = buf[0];
+    let second = buf[1];
+
+    let (opcode, rules) = OPCODES[usize::from(first & 0x0F)];
+
+    let mut pos = 2;
+    let mut payload_len = u64::from(second & 0x7F);
+    let length_len = match payload_len {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    if length_len > 0 {
+        if buf.len() < pos + length_len {
+            return Ok(None);
+        }
+        payload_len = decode_extended_length(&buf[pos..pos + length_len]);
+        pos += length_len;
+    }
+    if payload_len >> 63 != 0 {
+        return Err(HeaderError::BadLength(payload_len));
+    }
+
+    if payload_len > max_payload_length {
+        return Err(HeaderError::TooLong(max_payload_length));
+    }
+    if rules & SHORT != 0 && payload_len > 125 {
+        return Err(HeaderError::ControlTooLong(payload_len));
+    }
+
+    let mask = if second & 0x80 != 0 {
+        if buf.len() < pos + 4 {
+            return Ok(None);
+        }
+        let mut mask = [0u8; 4];
+        mask.copy_from_slice(&buf[pos..pos + 4]);
+        pos += 4;
+        Some(mask)
+    } else {
+        None
+    };
+
+    let header = Header {
+        finished: first & 0x80 != 0,
+        rsv1: first & 0x40 != 0,
+        rsv2: first & 0x20 != 0,
+        rsv3: first & 0x10 != 0,
+        opcode,
+        mask,
+        payload_len,
+    };
+    Ok(Some((header, pos)))
+}
+
+/// Check a decoded header against the rules of its opcode in one step: reserved bits outside of
+/// `allowed_rsv`, which holds the bits that negotiated extensions use in the positions of
+/// `Header::rsv_bits`, fragmented control frames, and control frames with payloads longer than
+/// 125 bytes, including close frames. Reserved opcodes are not rejected.
+///
+/// ```
+/// use parity_ws::OpCode;
+/// use parity_ws::proto::{check_header, Header, HeaderError};
+///
+/// let mut header = Header::new(OpCode::Text, 5);
+/// header.rsv1 = true;
+/// // Compressed frames are allowed once permessage-deflate is negotiated.
+/// assert_eq!(check_header(&header, 0x40), Ok(()));
+/// assert_eq!(check_header(&header, 0), Err(HeaderError::ReservedBits(0x40)));
+/// ```
+pub fn check_header(header: &Header, allowed_rsv: u8) -> ::std::result::Result<(), HeaderError> {
+    let control = rules(header.opcode) & CONTROL != 0;
+    let rsv = header.rsv_bits() & !allowed_rsv;
+    // Fold every violation into one value, so that valid headers take a single branch.
+    let fragmented = (control & !header.finished) as u8;
+    let long = (control & (header.payload_len > 125)) as u8;
+    if rsv | fragmented | long == 0 {
+        return Ok(());
+    }
+    if rsv != 0 {
+        Err(HeaderError::ReservedBits(rsv))
+    } else if fragmented != 0 {
+        Err(HeaderError::FragmentedControl)
+    } else {
+        Err(HeaderError::ControlTooLong(header.payload_len))
+    }
+}
+
+/// Encode `header` into `buf`, returning the number of bytes written.
+pub fn encode_header(header: &Header, buf: &mut [u8; MAX_HEADER_LEN]) -> usize {
+    let mut first: u8 = header.opcode.into();
+    if header.finished {
+        first |= 0x80;
+    }
+    if header.rsv1 {
+        first |= 0x40;
+    }
+    if header.rsv2 {
+        first |= 0x20;
+    }
+    if header.rsv3 {
+        first |= 0x10;
+    }
+
+    let mut second = 0u8;
+    if header.mask.is_some() {
+        second |= 0x80;
+    }
+    let length_len = match header.payload_len {
+        len if len < 126 => {
+            second |= len as u8;
+            0
+        }
+        len if len <= 65535 => {
+            second |= 126;
+            2
+        }
+        _ => {
+            second |= 127;
+            8
+        }
+    };
+    buf[0] = first;
+    buf[1] = second;
+
+    let mut pos = 2;
+    if length_len > 0 {
+        encode_extended_length(header.payload_len, &mut buf[pos..pos + length_len]);
+        pos += length_len;
+    }
+    if let Some(mask) = header.mask {
+        buf[pos..pos + 4].copy_from_slice(&mask);
+        pos += 4;
+    }
+    pos
+}
+
+/// Mask or unmask `buf` with `mask`, which are the same operation.
+pub fn apply_mask(buf: &mut [u8], mask: &[u8; 4]) {
+    let iter = buf.iter_mut().zip(mask.iter().cycle());
+    for (byte, &key) in iter {
+        *byte ^= key
+    }
+}
+
+/// A random `Sec-WebSocket-Key` for a client handshake request.
+pub fn generate_key() -> String {
+    let key: [u8; 16] = rand::random();
+    encode_base64(&key)
+}
+
+/// The `Sec-WebSocket-Accept` value that a server responds with to the given
+/// `Sec-WebSocket-Key`.
+pub fn accept_key(key: &[u8]) -> String {
+    let mut hasher = sha1::Sha1::new();
+
+    hasher.input(key);
+    hasher.input(WS_GUID.as_bytes());
+
+    encode_base64(&hasher.result())
+}
+
+// This code is based on rustc_serialize base64 STANDARD
+#[doc(hidden)]
+pub fn encode_base64(data: &[u8]) -> String {
+    let len = data.len();
+    let mod_len = len % 3;
+
+    let mut encoded = vec![b'='; len.div_ceil(3) * 4];
+    {
+        let mut in_iter = data[..len - mod_len].iter().map(|&c| u32::from(c));
+        let mut out_iter = encoded.iter_mut();
+
+        let enc = |val| BASE64[val as usize];
+        let mut write = |val| *out_iter.next().unwrap() = val;
+
+        while let (Some(one), Some(two), Some(three)) =
+            (in_iter.next(), in_iter.next(), in_iter.next())
+        {
+            let g24 = one << 16 | two << 8 | three;
+            write(enc((g24 >> 18) & 63));
+            write(enc((g24 >> 12) & 63));
+            write(enc((g24 >> 6) & 63));
+            write(enc(g24 & 63));
+        }
+
+        match mod_len {
+            1 => {
+                let pad = (u32::from(data[len - 1])) << 16;
+                write(enc((pad >> 18) & 63));
+                write(enc((pad >> 12) & 63));
+            }
+            2 => {
+                let pad = (u32::from(data[len - 2])) << 16 | (u32::from(data[len - 1])) << 8;
+                write(enc((pad >> 18) & 63));
+                write(enc((pad >> 12) & 63));
+                write(enc((pad >> 6) & 63));
+            }
+            _ => (),
+        }
+    }
+
+    String::from_utf8(encoded).unwrap()
+}
+
+/// Which end of a connection a `ProtocolMachine` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// The endpoint that sent the handshake request, which masks the frames it sends.
+    Client,
+    /// The endpoint that accepted the handshake request.
+    Server,
+}
+
+/// Something that happened on a connection driven by a `ProtocolMachine`.
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum ProtocolEvent {
+    /// A whole message was received.
+    Message(Message),
+    /// A ping was received, and a pong has been queued in reply.
+    Ping(Vec<u8>),
+    /// A pong was received.
+    Pong(Vec<u8>),
+    /// A close frame was received. If this endpoint had not sent one yet, a close frame with
+    /// the same code has been queued in reply.
+    Close(CloseCode, String),
+    /// The other endpoint broke the protocol. A close frame has been queued, and any further
+    /// input is ignored.
+    Error(Error),
+}
+
+/// The RFC 6455 state machine of an open connection, without any IO.
+///
+/// Hand the bytes that arrive from the other endpoint to `feed_bytes`, and write out whatever
+/// `next_outbound` returns, over any transport. The machine frames, masks, reassembles and
+/// validates messages, answers pings and takes part in the closing handshake in the same way
+/// as the connections of a `WebSocket`. The opening handshake is left to the caller, see
+/// `accept_key` and `generate_key`.
+///
+/// ```
+/// use parity_ws::Message;
+/// use parity_ws::proto::{ProtocolEvent, ProtocolMachine, Role};
+///
+/// let mut client = ProtocolMachine::new(Role::Client);
+/// let mut server = ProtocolMachine::new(Role::Server);
+/// client.send("hello").unwrap();
+/// while let Some(bytes) = client.next_outbound() {
+///     for event in server.feed_bytes(bytes) {
+///         match event {
+///             ProtocolEvent::Message(msg) => assert_eq!(msg, Message::text("hello")),
+///             event => panic!("unexpected event {:?}", event),
+///         }
+///     }
+/// }
+/// ```
+pub struct ProtocolMachine {
+    role: Role,
+    max_payload_length: u64,
+    inbound: CircularBuffer,
+    // The opcode and payload of a fragmented message that is being received.
+    fragments: Option<(OpCode, Vec<u8>)>,
+    outbound: VecDeque<Vec<u8>>,
+    // The bytes last returned by `next_outbound`.
+    current: Vec<u8>,
+    sent_close: bool,
+    received_close: bool,
+    failed: bool,
+}
+
+impl ProtocolMachine {
+    /// A machine for an endpoint with the given role, on a connection that has just opened.
+    pub fn new(role: Role) -> ProtocolMachine {
+        ProtocolMachine {
+            role,
+            max_payload_length: u64::MAX,
+            inbound: CircularBuffer::new(2048, usize::MAX),
+            fragments: None,
+            outbound: VecDeque::new(),
+            current: Vec::new(),
+            sent_close: false,
+            received_close: false,
+            failed: false,
+        }
+    }
+
+    /// Fail the connection when a frame or message with a longer payload is received.
+    pub fn with_max_payload_length(mut self, max_payload_length: u64) -> ProtocolMachine {
+        self.max_payload_length = max_payload_length;
+        self
+    }
+
+    /// Whether the closing handshake has completed or the connection has failed. The transport
+    /// can be closed once `next_outbound` returns `None`.
+    pub fn is_closed(&self) -> bool {
+        self.failed || (self.sent_close && self.received_close)
+    }
+
+    /// Process bytes received from the other endpoint, returning what they amounted to. Frames
+    /// may be split across calls in any way.
+    pub fn feed_bytes(&mut self, bytes: &[u8]) -> Vec<ProtocolEvent> {
+        let mut events = Vec::new();
+        if self.failed || self.received_close {
+            return events;
+        }
+        if let Err(err) = self.inbound.write_all(bytes) {
+            self.fail(Error::from(err), &mut events);
+            return events;
+        }
+        while !self.received_close {
+            match Frame::parse(&mut self.inbound, self.max_payload_length) {
+                Ok(Some(frame)) => {
+                    if let Err(err) = self.receive(frame, &mut events) {
+                        self.fail(err, &mut events);
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    self.fail(err, &mut events);
+                    break;
+                }
+            }
+        }
+        events
+    }
+
+    /// The next bytes to send to the other endpoint, if any. Each call moves on to the next
+    /// bytes, so all of the returned slice must be written before calling this again.
+    pub fn next_outbound(&mut self) -> Option<&[u8]> {
+        self.current = self.outbound.pop_front()?;
+        Some(&self.current)
+    }
+
+    /// Queue a message.
+    pub fn send<M>(&mut self, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        let msg = msg.into();
+        let opcode = msg.opcode();
+        self.queue(Frame::message(msg.into_data(), opcode, true))
+    }
+
+    /// Queue a ping.
+    pub fn ping(&mut self, data: Vec<u8>) -> Result<()> {
+        self.queue(Frame::ping(data))
+    }
+
+    /// Queue a pong.
+    pub fn pong(&mut self, data: Vec<u8>) -> Result<()> {
+        self.queue(Frame::pong(data))
+    }
+
+    /// Queue a close frame to start the closing handshake. Nothing can be sent after it.
+    pub fn close<R>(&mut self, code: CloseCode, reason: R) -> Result<()>
+    where
+        R: AsRef<str>,
+    {
+        self.queue(Frame::close(code, reason.as_ref()))?;
+        self.sent_close = true;
+        Ok(())
+    }
+
+    fn queue(&mut self, mut frame: Frame) -> Result<()> {
+        if self.sent_close {
+            return Err(Error::new(
+                Kind::Internal,
+                "Unable to send a frame after the close frame.",
+            ));
+        }
+        if self.role == Role::Client {
+            frame.set_mask();
+        }
+        let mut bytes = Vec::with_capacity(frame.len());
+        frame.format(&mut bytes)?;
+        self.outbound.push_back(bytes);
+        Ok(())
+    }
+
+    fn fail(&mut self, err: Error, events: &mut Vec<ProtocolEvent>) {
+        self.failed = true;
+        self.fragments = None;
+        if !self.sent_close {
+            let code = match err.kind {
+                Kind::Encoding(_) => CloseCode::Invalid,
+                Kind::Capacity => CloseCode::Size,
+                _ => CloseCode::Protocol,
+            };
+            if let Err(err) = self.close(code, "") {
+                debug!("Unable to queue a close frame: {}", err);
+            }
+        }
+        events.push(ProtocolEvent::Error(err));
+    }
+
+    fn receive(&mut self, mut frame: Frame, events: &mut Vec<ProtocolEvent>) -> Result<()> {
+        match self.role {
+            Role::Server if !frame.is_masked() => {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    "Received unmasked frame from client.",
+                ))
+            }
+            Role::Client if frame.is_masked() => {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    "Received masked frame from server.",
+                ))
+            }
+            _ => (),
+        }
+        frame.remove_mask();
+        frame.check(0)?;
+
+        let finished = frame.is_final();
+        match frame.opcode() {
+            OpCode::Text | OpCode::Binary => {
+                if self.fragments.is_some() {
+                    return Err(Error::new(
+                        Kind::Protocol,
+                        "Received a new message while processing fragmented message.",
+                    ));
+                }
+                let opcode = frame.opcode();
+                let data = frame.into_data();
+                if finished {
+                    events.push(ProtocolEvent::Message(message(opcode, data)?));
+                } else {
+                    self.fragments = Some((opcode, data));
+                }
+            }
+            OpCode::Continue => {
+                let (opcode, mut data) = self.fragments.take().ok_or_else(|| {
+                    Error::new(
+                        Kind::Protocol,
+                        "Received continuation frame without a message to continue.",
+                    )
+                })?;
+                data.extend(frame.into_data());
+                if data.len() as u64 > self.max_payload_length {
+                    return Err(Error::new(
+                        Kind::Capacity,
+                        "Received fragmented message exceeding the max payload length.",
+                    ));
+                }
+                if finished {
+                    events.push(ProtocolEvent::Message(message(opcode, data)?));
+                } else {
+                    self.fragments = Some((opcode, data));
+                }
+            }
+            OpCode::Ping => {
+                let data = frame.into_data();
+                if !self.sent_close {
+                    self.pong(data.clone())?;
+                }
+                events.push(ProtocolEvent::Ping(data));
+            }
+            OpCode::Pong => events.push(ProtocolEvent::Pong(frame.into_data())),
+            OpCode::Close => {
+                let data = frame.into_data();
+                let (code, reason) = match decode_close_code(&data) {
+                    None if data.is_empty() => (CloseCode::Status, String::new()),
+                    None => {
+                        return Err(Error::new(
+                            Kind::Protocol,
+                            "Received close frame with a truncated close code.",
+                        ))
+                    }
+                    Some(code) => {
+                        let code = CloseCode::from(code);
+                        let reason = String::from_utf8(data[2..].to_vec())
+                            .map_err(|err| err.utf8_error())?;
+                        (code, reason)
+                    }
+                };
+                self.received_close = true;
+                if !self.sent_close {
+                    let reply = match code {
+                        CloseCode::Status => CloseCode::Empty,
+                        code => code,
+                    };
+                    self.close(reply, "")?;
+                }
+                events.push(ProtocolEvent::Close(code, reason));
+            }
+            OpCode::Reserved(code) => return Err(HeaderError::BadOpcode(code).into()),
+            OpCode::Bad => unreachable!("invalid opcodes are rejected by the parser"),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ProtocolMachine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ProtocolMachine {{ role: {:?}, outbound: {}, sent_close: {:?}, received_close: {:?}, failed: {:?} }}",
+            self.role,
+            self.outbound.len(),
+            self.sent_close,
+            self.received_close,
+            self.failed
+        )
+    }
+}
+
+fn message(opcode: OpCode, data: Vec<u8>) -> Result<Message> {
+    if opcode == OpCode::Text {
+        Ok(Message::Text(
+            String::from_utf8(data).map_err(|err| err.utf8_error())?,
+        ))
+    } else {
+        Ok(Message::Binary(data))
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn header_lengths() {
+        let mut buf = [0u8; MAX_HEADER_LEN];
+        for &len in &[0, 125, 126, 65535, 65536, u64::MAX >> 1] {
+            let mut header = Header::new(OpCode::Binary, len);
+            header.mask = Some([1, 2, 3, 4]);
+            header.rsv1 = true;
+            let written = encode_header(&header, &mut buf);
+            assert_eq!(written, header.encoded_len());
+            assert_eq!(decode_header(&buf[..written], u64::MAX), Ok(Some((header, written))));
+            assert_eq!(decode_header(&buf[..written - 1], u64::MAX), Ok(None));
+        }
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let (header, _) = decode_header(&[0x83, 0], 10).unwrap().unwrap();
+        assert_eq!(header.opcode, OpCode::Reserved(3));
+        assert_eq!(decode_header(&[0x82, 11], 10), Err(HeaderError::TooLong(10)));
+        assert_eq!(decode_header(&[0x89, 126, 0, 126], 1000), Err(HeaderError::ControlTooLong(126)));
+        assert!(decode_header(&[0x88, 126, 0, 126], 1000).unwrap().is_some());
+        assert_eq!(
+            decode_header(&[0x81, 127, 0x80, 0, 0, 0, 0, 0, 0, 0], u64::MAX),
+            Err(HeaderError::BadLength(1 << 63))
+        );
+        // An oversized length field is accepted, and counted as part of the header.
+        let (header, len) = decode_header(&[0x82, 127, 0, 0, 0, 0, 0, 0, 0, 5], 10).unwrap().unwrap();
+        assert_eq!((header.payload_len, header.encoded_len(), len), (5, 2, 10));
+    }
+
+    #[test]
+    fn header_rules() {
+        for nibble in 0..16u8 {
+            let (header, _) = decode_header(&[0x80 | nibble, 0], 10).unwrap().unwrap();
+            assert_eq!(Into::<u8>::into(header.opcode), nibble);
+            assert_eq!(header.opcode, OpCode::from(nibble));
+        }
+        let mut header = Header::new(OpCode::Binary, 200);
+        assert_eq!(check_header(&header, 0), Ok(()));
+        header.rsv2 = true;
+        header.rsv3 = true;
+        assert_eq!(check_header(&header, 0x40), Err(HeaderError::ReservedBits(0x30)));
+        assert_eq!(check_header(&header, 0x70), Ok(()));
+
+        let mut close = Header::new(OpCode::Close, 126);
+        assert_eq!(check_header(&close, 0), Err(HeaderError::ControlTooLong(126)));
+        close.payload_len = 2;
+        close.finished = false;
+        assert_eq!(check_header(&close, 0), Err(HeaderError::FragmentedControl));
+        let reserved = Header {
+            finished: false,
+            ..Header::new(OpCode::Reserved(0xB), 0)
+        };
+        assert_eq!(check_header(&reserved, 0), Err(HeaderError::FragmentedControl));
+        let reserved = Header {
+            finished: false,
+            ..Header::new(OpCode::Reserved(0x3), 0)
+        };
+        assert_eq!(check_header(&reserved, 0), Ok(()));
+    }
+
+    #[test]
+    fn extended_lengths() {
+        let mut field = [0u8; 2];
+        encode_extended_length(0x1234, &mut field);
+        assert_eq!(field, [0x12, 0x34]);
+        assert_eq!(decode_extended_length(&field), 0x1234);
+        assert_eq!(decode_extended_length(&[0xFF, 0xFF]), 65535);
+
+        let mut field = [0u8; 8];
+        encode_extended_length(0x0102_0304_0506_0708, &mut field);
+        assert_eq!(field, [1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(decode_extended_length(&field), 0x0102_0304_0506_0708);
+        encode_extended_length(65536, &mut field);
+        assert_eq!(field, [0, 0, 0, 0, 0, 1, 0, 0]);
+
+        let mut buf = [0u8; MAX_HEADER_LEN];
+        assert_eq!(encode_header(&Header::new(OpCode::Binary, 300), &mut buf), 4);
+        assert_eq!(buf[..4], [0x82, 126, 0x01, 0x2C]);
+        assert_eq!(encode_header(&Header::new(OpCode::Binary, 1 << 32), &mut buf), 10);
+        assert_eq!(buf[..10], [0x82, 127, 0, 0, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn close_codes() {
+        assert_eq!(encode_close_code(1000), [0x03, 0xE8]);
+        assert_eq!(encode_close_code(4999), [0x13, 0x87]);
+        assert_eq!(decode_close_code(&[0x03, 0xE8, b'b', b'y', b'e']), Some(1000));
+        assert_eq!(decode_close_code(&[0xFF, 0xFF]), Some(65535));
+        assert_eq!(decode_close_code(&[0x03]), None);
+        assert_eq!(decode_close_code(&[]), None);
+    }
+
+    // Move everything that `from` has queued over to `to`.
+    fn deliver(from: &mut ProtocolMachine, to: &mut ProtocolMachine) -> Vec<ProtocolEvent> {
+        let mut events = Vec::new();
+        while let Some(bytes) = from.next_outbound() {
+            // Split the bytes up to check that frames are reassembled.
+            for byte in bytes {
+                events.extend(to.feed_bytes(&[*byte]));
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn machine_messages() {
+        let mut client = ProtocolMachine::new(Role::Client);
+        let mut server = ProtocolMachine::new(Role::Server);
+        client.send("hello").unwrap();
+        client.ping(vec![1]).unwrap();
+        match &deliver(&mut client, &mut server)[..] {
+            [ProtocolEvent::Message(msg), ProtocolEvent::Ping(data)] => {
+                assert_eq!(msg, &Message::text("hello"));
+                assert_eq!(data, &vec![1]);
+            }
+            events => panic!("unexpected events {:?}", events),
+        }
+        match &deliver(&mut server, &mut client)[..] {
+            [ProtocolEvent::Pong(data)] => assert_eq!(data, &vec![1]),
+            events => panic!("unexpected events {:?}", events),
+        }
+
+        // A fragmented message from the server.
+        for (opcode, finished, data) in [(OpCode::Binary, false, 1), (OpCode::Continue, true, 2)] {
+            let mut frame = Frame::message(vec![data], opcode, finished);
+            let mut bytes = Vec::new();
+            frame.format(&mut bytes).unwrap();
+            server.outbound.push_back(bytes);
+        }
+        match &deliver(&mut server, &mut client)[..] {
+            [ProtocolEvent::Message(msg)] => assert_eq!(msg, &Message::binary(vec![1, 2])),
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
+
+    #[test]
+    fn machine_close() {
+        let mut client = ProtocolMachine::new(Role::Client);
+        let mut server = ProtocolMachine::new(Role::Server);
+        server.close(CloseCode::Away, "bye").unwrap();
+        assert!(server.send("late").is_err());
+        match &deliver(&mut server, &mut client)[..] {
+            [ProtocolEvent::Close(CloseCode::Away, reason)] => assert_eq!(reason, "bye"),
+            events => panic!("unexpected events {:?}", events),
+        }
+        assert!(client.is_closed());
+        match &deliver(&mut client, &mut server)[..] {
+            [ProtocolEvent::Close(CloseCode::Away, _)] => (),
+            events => panic!("unexpected events {:?}", events),
+        }
+        assert!(server.is_closed());
+        assert!(server.next_outbound().is_none());
+    }
+
+    #[test]
+    fn machine_failure() {
+        let mut server = ProtocolMachine::new(Role::Server);
+        // An unmasked frame from a client.
+        match &server.feed_bytes(&[0x81, 0x01, b'a'])[..] {
+            [ProtocolEvent::Error(_)] => (),
+            events => panic!("unexpected events {:?}", events),
+        }
+        assert!(server.is_closed());
+        assert_eq!(server.next_outbound(), Some(&[0x88, 0x02, 0x03, 0xea][..]));
+        assert!(server.feed_bytes(&[0x81, 0x01, b'a']).is_empty());
+    }
+
+    #[test]
+    fn accept() {
+        assert_eq!(accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+}
@@ -1,5 +1,10 @@
 use std::convert::{From, Into};
 use std::fmt;
+use std::str::from_utf8;
+use std::time::Duration;
+
+use proto::{decode_close_code, encode_close_code, opcode};
+use result;
 
 use self::OpCode::*;
 /// Operation codes as part of rfc6455.
@@ -17,6 +22,9 @@ pub enum OpCode {
     Ping,
     /// Indicates a pong control frame.
     Pong,
+    /// Indicates an opcode that is reserved for further frame types, 0x3 to 0x7 for data frames
+    /// and 0xB to 0xF for control frames. See `Handler::on_unknown_frame`.
+    Reserved(u8),
     /// Indicates an invalid opcode was received.
     Bad,
 }
@@ -26,6 +34,7 @@ impl OpCode {
     pub fn is_control(&self) -> bool {
         match *self {
             Text | Binary | Continue => false,
+            Reserved(code) => code >= 8,
             _ => true,
         }
     }
@@ -40,6 +49,7 @@ impl fmt::Display for OpCode {
             Close => write!(f, "CLOSE"),
             Ping => write!(f, "PING"),
             Pong => write!(f, "PONG"),
+            Reserved(code) => write!(f, "RESERVED({:#X})", code),
             Bad => write!(f, "BAD"),
         }
     }
@@ -54,6 +64,7 @@ impl Into<u8> for OpCode {
             Close => 8,
             Ping => 9,
             Pong => 10,
+            Reserved(code) => code,
             Bad => {
                 debug_assert!(
                     false,
@@ -67,14 +78,10 @@ impl Into<u8> for OpCode {
 
 impl From<u8> for OpCode {
     fn from(byte: u8) -> OpCode {
-        match byte {
-            0 => Continue,
-            1 => Text,
-            2 => Binary,
-            8 => Close,
-            9 => Ping,
-            10 => Pong,
-            _ => Bad,
+        if byte < 0x10 {
+            opcode(byte)
+        } else {
+            Bad
         }
     }
 }
@@ -149,6 +156,14 @@ pub enum CloseCode {
     Other(u16),
 }
 
+impl CloseCode {
+    /// Whether the code asks the other endpoint to connect again later, as `Restart` (1012) and
+    /// `Again` (1013) do. See `CloseFrame::retry_after`.
+    pub fn is_retryable(self) -> bool {
+        matches!(self, Restart | Again)
+    }
+}
+
 impl Into<u16> for CloseCode {
     fn into(self) -> u16 {
         match self {
@@ -195,10 +210,150 @@ impl From<u16> for CloseCode {
     }
 }
 
+/// The status code and reason of a close frame, along with its payload. See
+/// `Handler::on_close_frame`. It converts into a `Frame` for handlers that send frames of their
+/// own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    /// The status code, which is `CloseCode::Status` (1005) if the payload is empty.
+    pub code: CloseCode,
+    /// The reason for closing, which may be empty.
+    pub reason: String,
+    /// The payload of the frame, holding the code and the reason as they are sent.
+    pub payload: Vec<u8>,
+}
+
+impl CloseFrame {
+    /// A close frame to send with the given code and reason.
+    ///
+    /// `CloseCode::Status` and `CloseCode::Empty` produce a frame with an empty payload, which
+    /// the other endpoint receives as `CloseCode::Status` (1005), so the reason must be empty as
+    /// well. Codes that may not be sent, such as `CloseCode::Abnormal`, and reasons longer than
+    /// the 123 bytes that fit into a control frame are rejected with a Protocol error.
+    pub fn new<R>(code: CloseCode, reason: R) -> result::Result<CloseFrame>
+    where
+        R: Into<String>,
+    {
+        let reason = reason.into();
+        let invalid = |details: String| Err(result::Error::new(result::Kind::Protocol, details));
+        match code {
+            Status | Empty if reason.is_empty() => {
+                return Ok(CloseFrame {
+                    code: Status,
+                    reason,
+                    payload: Vec::new(),
+                })
+            }
+            Status | Empty => return invalid("A close frame without a code has no reason.".into()),
+            Abnormal | Tls => return invalid(format!("Close code {:?} may not be sent.", code)),
+            Other(raw) if !(3000..=4999).contains(&raw) => {
+                return invalid(format!("Close code {} may not be sent.", raw))
+            }
+            _ => (),
+        }
+        if reason.len() > 123 {
+            return invalid(format!(
+                "Close reason of {} bytes is longer than 123 bytes.",
+                reason.len()
+            ));
+        }
+        let raw: u16 = code.into();
+        let mut payload = Vec::with_capacity(2 + reason.len());
+        payload.extend_from_slice(&encode_close_code(raw));
+        payload.extend_from_slice(reason.as_bytes());
+        Ok(CloseFrame {
+            code,
+            reason,
+            payload,
+        })
+    }
+
+    /// A close frame with the given code whose reason tells the other endpoint how long to wait
+    /// before it connects again, as `Retry-After: <seconds>` like the HTTP header. Meant for
+    /// `CloseCode::Restart` and `CloseCode::Again`.
+    pub fn retry(code: CloseCode, delay: Duration) -> result::Result<CloseFrame> {
+        CloseFrame::new(code, retry_reason(delay))
+    }
+
+    /// The delay from a reason in the form of `CloseFrame::retry`, rounded to seconds.
+    pub fn retry_after(&self) -> Option<Duration> {
+        let seconds = self.reason.strip_prefix(RETRY_AFTER)?;
+        seconds.trim().parse().ok().map(Duration::from_secs)
+    }
+
+    /// Parse the payload of a received close frame. An empty payload is parsed as
+    /// `CloseCode::Status` (1005) with an empty reason, while a payload of a single byte is a
+    /// Protocol error and a reason that is not valid UTF-8 an Encoding error.
+    pub fn parse(payload: Vec<u8>) -> result::Result<CloseFrame> {
+        let code = match decode_close_code(&payload) {
+            Some(code) => CloseCode::from(code),
+            None if payload.is_empty() => Status,
+            None => {
+                return Err(result::Error::new(
+                    result::Kind::Protocol,
+                    "Close frame payload has a truncated close code.",
+                ))
+            }
+        };
+        let reason = if payload.len() > 2 {
+            from_utf8(&payload[2..])?.to_owned()
+        } else {
+            String::new()
+        };
+        Ok(CloseFrame {
+            code,
+            reason,
+            payload,
+        })
+    }
+}
+
+const RETRY_AFTER: &str = "Retry-After: ";
+
+// The reason of a close frame that asks the other endpoint to connect again after `delay`,
+// rounded up to whole seconds.
+#[doc(hidden)]
+pub fn retry_reason(delay: Duration) -> String {
+    let seconds = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+    format!("{}{}", RETRY_AFTER, seconds)
+}
+
 mod test {
     #![allow(unused_imports, unused_variables, dead_code)]
     use super::*;
 
+    #[test]
+    fn close_frame() {
+        let frame = CloseFrame::new(Away, "restarting").unwrap();
+        assert_eq!(frame.payload, b"\x03\xe9restarting");
+        assert_eq!(CloseFrame::parse(frame.payload.clone()).unwrap(), frame);
+
+        let empty = CloseFrame::new(Empty, "").unwrap();
+        assert_eq!((empty.code, empty.payload.len()), (Status, 0));
+        assert_eq!(CloseFrame::parse(Vec::new()).unwrap(), empty);
+        assert_eq!(CloseFrame::parse(vec![3, 232]).unwrap().code, Normal);
+
+        assert!(CloseFrame::new(Status, "why").is_err());
+        assert!(CloseFrame::new(Abnormal, "").is_err());
+        assert!(CloseFrame::new(Other(1004), "").is_err());
+        assert!(CloseFrame::new(Other(4000), "application").is_ok());
+        assert!(CloseFrame::new(Normal, "x".repeat(124)).is_err());
+        assert!(CloseFrame::parse(vec![3]).is_err());
+        assert!(CloseFrame::parse(vec![3, 232, 0xff]).is_err());
+    }
+
+    #[test]
+    fn retry_after() {
+        let frame = CloseFrame::retry(Restart, Duration::from_millis(2500)).unwrap();
+        assert_eq!(frame.reason, "Retry-After: 3");
+        let parsed = CloseFrame::parse(frame.payload).unwrap();
+        assert!(parsed.code.is_retryable());
+        assert_eq!(parsed.retry_after(), Some(Duration::from_secs(3)));
+
+        assert!(!Away.is_retryable());
+        assert_eq!(CloseFrame::new(Again, "busy").unwrap().retry_after(), None);
+    }
+
     #[test]
     fn opcode_from_u8() {
         let byte = 2u8;
@@ -212,6 +367,18 @@ mod test {
         assert_eq!(byte, 1u8);
     }
 
+    #[test]
+    fn reserved_opcode() {
+        assert_eq!(OpCode::from(3u8), OpCode::Reserved(3));
+        assert_eq!(OpCode::from(0xFu8), OpCode::Reserved(0xF));
+        assert_eq!(OpCode::from(0x10u8), OpCode::Bad);
+        assert!(!OpCode::Reserved(7).is_control());
+        assert!(OpCode::Reserved(0xB).is_control());
+        let byte: u8 = OpCode::Reserved(5).into();
+        assert_eq!(byte, 5u8);
+        assert_eq!(OpCode::Reserved(0xB).to_string(), "RESERVED(0xB)");
+    }
+
     #[test]
     fn closecode_from_u16() {
         let byte = 1008u16;
@@ -0,0 +1,234 @@
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use audit::rfc3339;
+use frame::Frame;
+use proto::decode_close_code;
+use protocol::{CloseCode, OpCode};
+use snapshot::ConnectionPhase;
+use tap::{Direction, FrameObserver};
+
+/// A change of phase of the state machine of a connection, as recorded by a `StateRecorder`.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    /// When the connection entered the new phase.
+    pub time: SystemTime,
+    /// The phase that the connection left.
+    pub from: ConnectionPhase,
+    /// The phase that the connection entered.
+    pub to: ConnectionPhase,
+    /// The frame that caused the transition, which is `None` for the opening handshake.
+    pub cause: Option<(Direction, OpCode)>,
+    /// The close code of the close frame that caused the transition, if it had one.
+    pub close_code: Option<CloseCode>,
+}
+
+#[derive(Debug, Default)]
+struct Recording {
+    transitions: Vec<Transition>,
+    // The code of the last close frame seen in each direction, inbound first.
+    close_codes: [Option<CloseCode>; 2],
+}
+
+/// Records the transitions of the state machine of a connection, to be exported as a Graphviz
+/// DOT graph or as a plain text trace, such as to find out why a connection is stuck closing.
+///
+/// The recorder is attached to the connection of a `Sender` as a `FrameObserver`, and shares
+/// what it records with its clones:
+///
+/// ```no_run
+/// use parity_ws::{listen, CloseCode, Handler, Handshake, Result, Sender, StateRecorder};
+///
+/// struct Server {
+///     out: Sender,
+///     recorder: StateRecorder,
+/// }
+///
+/// impl Handler for Server {
+///     fn on_open(&mut self, _: Handshake) -> Result<()> {
+///         self.out.tap(Box::new(self.recorder.clone()))
+///     }
+///
+///     fn on_close(&mut self, _: CloseCode, _: &str) {
+///         self.recorder.write_dot("connection.dot").unwrap();
+///     }
+/// }
+///
+/// listen("127.0.0.1:3012", |out| Server { out, recorder: StateRecorder::new() }).unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct StateRecorder {
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl StateRecorder {
+    /// A recorder that has not recorded anything yet.
+    pub fn new() -> StateRecorder {
+        StateRecorder::default()
+    }
+
+    /// The transitions recorded so far, oldest first.
+    pub fn transitions(&self) -> Vec<Transition> {
+        match self.recording.lock() {
+            Ok(recording) => recording.transitions.clone(),
+            Err(poisoned) => poisoned.into_inner().transitions.clone(),
+        }
+    }
+
+    /// Render the transitions as a directed graph in the DOT language of Graphviz, with an
+    /// edge for every transition labelled with its number, cause and time since the first
+    /// one. The phase that the connection is in is drawn in bold.
+    pub fn to_dot(&self) -> String {
+        let transitions = self.transitions();
+        let mut dot = String::from("digraph connection {\n    rankdir=LR;\n    node [shape=box];\n");
+        if let Some(last) = transitions.last() {
+            let _ = writeln!(dot, "    \"{}\" [style=bold];", last.to.as_str());
+        }
+        let start = transitions.first().map(|transition| transition.time);
+        for (number, transition) in transitions.iter().enumerate() {
+            let _ = writeln!(
+                dot,
+                "    \"{}\" -> \"{}\" [label=\"{}: {}\\n+{}ms\"];",
+                transition.from.as_str(),
+                transition.to.as_str(),
+                number + 1,
+                cause(transition),
+                since(start, transition.time).as_millis(),
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render the transitions as lines of text, each with the time in RFC 3339 format, the time
+    /// since the first transition, the phases and the cause.
+    pub fn to_trace(&self) -> String {
+        let transitions = self.transitions();
+        let start = transitions.first().map(|transition| transition.time);
+        let mut trace = String::new();
+        for transition in &transitions {
+            let _ = writeln!(
+                trace,
+                "{} +{}ms {} -> {} ({})",
+                rfc3339(transition.time),
+                since(start, transition.time).as_millis(),
+                transition.from.as_str(),
+                transition.to.as_str(),
+                cause(transition),
+            );
+        }
+        trace
+    }
+
+    /// Write the DOT graph of `to_dot` to a file.
+    pub fn write_dot<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_dot())
+    }
+
+    /// Write the trace of `to_trace` to a file.
+    pub fn write_trace<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_trace())
+    }
+
+    fn with_recording<F: FnOnce(&mut Recording)>(&self, f: F) {
+        match self.recording.lock() {
+            Ok(mut recording) => f(&mut recording),
+            Err(poisoned) => f(&mut poisoned.into_inner()),
+        }
+    }
+}
+
+impl FrameObserver for StateRecorder {
+    fn on_frame(&mut self, direction: Direction, frame: &Frame) {
+        if frame.opcode() == OpCode::Close {
+            let code = decode_close_code(frame.payload()).map(CloseCode::from);
+            self.with_recording(|recording| {
+                recording.close_codes[(direction == Direction::Outbound) as usize] = code
+            });
+        }
+    }
+
+    fn on_transition(
+        &mut self,
+        from: ConnectionPhase,
+        to: ConnectionPhase,
+        cause: Option<(Direction, OpCode)>,
+    ) {
+        let time = SystemTime::now();
+        self.with_recording(|recording| {
+            let close_code = match cause {
+                Some((direction, OpCode::Close)) => {
+                    recording.close_codes[(direction == Direction::Outbound) as usize]
+                }
+                _ => None,
+            };
+            recording.transitions.push(Transition {
+                time,
+                from,
+                to,
+                cause,
+                close_code,
+            })
+        });
+    }
+}
+
+// What caused a transition, such as `received CLOSE 1000`.
+fn cause(transition: &Transition) -> String {
+    let (direction, opcode) = match transition.cause {
+        Some(cause) => cause,
+        None => return "handshake".into(),
+    };
+    let mut cause = match direction {
+        Direction::Inbound => format!("received {}", opcode),
+        Direction::Outbound => format!("sent {}", opcode),
+    };
+    if let Some(code) = transition.close_code {
+        let _ = write!(cause, " {}", Into::<u16>::into(code));
+    }
+    cause
+}
+
+fn since(start: Option<SystemTime>, time: SystemTime) -> Duration {
+    start
+        .and_then(|start| time.duration_since(start).ok())
+        .unwrap_or_default()
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn export() {
+        let mut recorder = StateRecorder::new();
+        recorder.on_transition(ConnectionPhase::Connecting, ConnectionPhase::Open, None);
+        recorder.on_frame(Direction::Outbound, &Frame::close(CloseCode::Away, "bye"));
+        recorder.on_transition(
+            ConnectionPhase::Open,
+            ConnectionPhase::AwaitingClose,
+            Some((Direction::Outbound, OpCode::Close)),
+        );
+
+        let transitions = recorder.transitions();
+        assert_eq!(transitions.len(), 2);
+        assert_eq!(transitions[1].close_code, Some(CloseCode::Away));
+
+        let dot = recorder.to_dot();
+        assert!(dot.starts_with("digraph connection {\n"));
+        assert!(dot.contains("    \"awaiting_close\" [style=bold];\n"));
+        assert!(dot.contains("    \"connecting\" -> \"open\" [label=\"1: handshake\\n+"));
+        assert!(dot.contains("    \"open\" -> \"awaiting_close\" [label=\"2: sent CLOSE 1001\\n+"));
+
+        let trace = recorder.to_trace();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" connecting -> open (handshake)"));
+        assert!(lines[1].ends_with(" open -> awaiting_close (sent CLOSE 1001)"));
+    }
+}
@@ -3,6 +3,7 @@ use std::convert::{From, Into};
 use std::error::Error as StdError;
 use std::fmt;
 use std::io;
+use std::net::SocketAddr;
 use std::result::Result as StdResult;
 use std::str::Utf8Error;
 
@@ -16,6 +17,8 @@ use native_tls::{Error as SslError, HandshakeError as SslHandshakeError};
 type HandshakeError = SslHandshakeError<mio::tcp::TcpStream>;
 
 use communication::Command;
+use protocol::OpCode;
+use util::Token;
 
 pub type Result<T> = StdResult<T, Error>;
 
@@ -52,6 +55,11 @@ pub enum Kind {
     /// If encountered, retuning from a handler method and waiting for the EventLoop to consume
     /// the queue may relieve the situation.
     Queue(mio::channel::SendError<Command>),
+    /// Indicates that the other endpoint stopped making progress, for example by trickling a
+    /// frame header or waking the connection without sending any data, as limited by
+    /// `Settings::frame_header_read_limit` and `Settings::empty_read_limit`.
+    /// The WebSocket will attempt to send a Policy (1008) close code and then disconnect.
+    Stalled,
     /// Indicates a failure to perform SSL encryption.
     #[cfg(any(feature = "ssl", feature = "nativetls"))]
     Ssl(SslError),
@@ -65,9 +73,61 @@ pub enum Kind {
 }
 
 /// A struct indicating the kind of error that has occurred and any precise details of that error.
+///
+/// Errors also carry the connection on which they occurred in a private field, so they can not
+/// be built with a struct literal. Use `Error::new` instead.
 pub struct Error {
     pub kind: Kind,
     pub details: Cow<'static, str>,
+    // Boxed, because errors are returned everywhere and the context is rarely set.
+    context: Option<Box<ErrorContext>>,
+}
+
+/// Where an error occurred: the connection and the stage it had reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// The token of the connection.
+    pub token: Token,
+    /// The address of the other endpoint, if the socket still knows it.
+    pub peer_addr: Option<SocketAddr>,
+    /// The stage of the connection.
+    pub phase: Phase,
+    /// The opcode of the received frame that was being processed, if any.
+    pub opcode: Option<OpCode>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "token={} phase={}", self.token.0, self.phase)?;
+        if let Some(addr) = self.peer_addr {
+            write!(f, " peer={}", addr)?;
+        }
+        if let Some(opcode) = self.opcode {
+            write!(f, " opcode={:?}", opcode)?;
+        }
+        Ok(())
+    }
+}
+
+/// The stage of a connection, as part of an `ErrorContext`. Exported as `ErrorPhase`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// The opening handshake has not completed.
+    Handshake,
+    /// The connection is open for messages.
+    Open,
+    /// The closing handshake has started.
+    Closing,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Phase::Handshake => write!(f, "handshake"),
+            Phase::Open => write!(f, "open"),
+            Phase::Closing => write!(f, "closing"),
+        }
+    }
 }
 
 impl Error {
@@ -78,7 +138,23 @@ impl Error {
         Error {
             kind,
             details: details.into(),
+            context: None,
+        }
+    }
+
+    /// Attach the connection on which the error occurred, unless the error already has a
+    /// context, which is then kept as the more precise one.
+    pub fn with_context(mut self, context: ErrorContext) -> Error {
+        if self.context.is_none() {
+            self.context = Some(Box::new(context));
         }
+        self
+    }
+
+    /// The connection on which the error occurred, if any. The WebSocket sets it before passing
+    /// the error to `Handler::on_error`, and it is included in the `Debug` output of the error.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        self.context.as_deref()
     }
 
     pub fn into_box(self) -> Box<dyn StdError> {
@@ -92,10 +168,14 @@ impl Error {
 impl fmt::Debug for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.details.len() > 0 {
-            write!(f, "WS Error <{:?}>: {}", self.kind, self.details)
+            write!(f, "WS Error <{:?}>: {}", self.kind, self.details)?;
         } else {
-            write!(f, "WS Error <{:?}>", self.kind)
+            write!(f, "WS Error <{:?}>", self.kind)?;
+        }
+        if let Some(ref context) = self.context {
+            write!(f, " ({})", context)?;
         }
+        Ok(())
     }
 }
 
@@ -123,6 +203,7 @@ impl StdError for Error {
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Kind::SslHandshake(ref err) => err.description(),
             Kind::Queue(_) => "Unable to send signal on event loop",
+            Kind::Stalled => "WebSocket Peer Stalled",
             Kind::Custom(ref err) => err.description(),
         }
     }
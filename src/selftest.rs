@@ -0,0 +1,215 @@
+use std::fmt;
+
+use message::Message;
+use proto::{accept_key, apply_mask, ProtocolEvent, ProtocolMachine, Role};
+use protocol::CloseCode;
+use result::{Error, Kind, Result};
+
+/// What this build of the library supports, as returned by `capabilities`.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The version of the library.
+    pub version: &'static str,
+    /// The TLS backend that `wss` urls are served and connected with, either "openssl" or
+    /// "native-tls", if any.
+    pub tls: Option<&'static str>,
+    /// Whether the permessage-deflate extension is available.
+    pub permessage_deflate: bool,
+    /// Whether messages can be (de)serialized as JSON.
+    pub json: bool,
+    /// Whether traces can be exported through OpenTelemetry.
+    pub opentelemetry: bool,
+    /// Whether endpoints can be discovered through DNS SRV records.
+    pub srv: bool,
+    /// The transports connections can be made over.
+    pub transports: Vec<&'static str>,
+    /// Whether frames are masked with SIMD instructions. Masking is done one byte at a time.
+    pub simd_masking: bool,
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let yes_no = |enabled| if enabled { "yes" } else { "no" };
+        writeln!(f, "ws {}", self.version)?;
+        writeln!(f, "tls: {}", self.tls.unwrap_or("none"))?;
+        writeln!(f, "permessage-deflate: {}", yes_no(self.permessage_deflate))?;
+        writeln!(f, "json: {}", yes_no(self.json))?;
+        writeln!(f, "opentelemetry: {}", yes_no(self.opentelemetry))?;
+        writeln!(f, "srv: {}", yes_no(self.srv))?;
+        writeln!(f, "transports: {}", self.transports.join(", "))?;
+        write!(f, "simd masking: {}", yes_no(self.simd_masking))
+    }
+}
+
+/// Report what was compiled into this build of the library, such as to log it at startup or
+/// to check that a deployment was built with the features it needs.
+///
+/// ```
+/// let capabilities = parity_ws::capabilities();
+/// println!("{}", capabilities);
+/// assert!(capabilities.transports.contains(&"tcp"));
+/// ```
+pub fn capabilities() -> Capabilities {
+    let tls = if cfg!(feature = "ssl") {
+        Some("openssl")
+    } else if cfg!(feature = "nativetls") {
+        Some("native-tls")
+    } else {
+        None
+    };
+    let mut transports = vec!["tcp"];
+    if tls.is_some() {
+        transports.push("tls");
+    }
+    transports.push("socks5");
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        tls,
+        permessage_deflate: cfg!(feature = "permessage-deflate"),
+        json: cfg!(feature = "json"),
+        opentelemetry: cfg!(feature = "otel"),
+        srv: cfg!(feature = "srv"),
+        transports,
+        simd_masking: false,
+    }
+}
+
+fn failed<T>(details: &str) -> Result<T> {
+    Err(Error::new(
+        Kind::Internal,
+        format!("Self-test failed: {}", details),
+    ))
+}
+
+// Hand the bytes queued by one endpoint to the other, returning the events they caused.
+fn deliver(from: &mut ProtocolMachine, to: &mut ProtocolMachine) -> Vec<ProtocolEvent> {
+    let mut events = Vec::new();
+    while let Some(bytes) = from.next_outbound() {
+        events.extend(to.feed_bytes(bytes));
+    }
+    events
+}
+
+// Echo messages between a client and a server in memory, through the same framing, masking
+// and validation as real connections, then close the connection.
+fn loopback() -> Result<()> {
+    let mut client = ProtocolMachine::new(Role::Client);
+    let mut server = ProtocolMachine::new(Role::Server);
+    let messages = vec![
+        Message::text("self-test"),
+        Message::binary(vec![0xA5; 200]),
+        // Long enough for the 64 bit payload length.
+        Message::binary((0..70_000).map(|i| i as u8).collect::<Vec<u8>>()),
+    ];
+    for msg in &messages {
+        client.send(msg.clone())?;
+    }
+    client.ping(b"self-test".to_vec())?;
+
+    for event in deliver(&mut client, &mut server) {
+        match event {
+            ProtocolEvent::Message(msg) => server.send(msg)?,
+            ProtocolEvent::Ping(_) => (),
+            event => return failed(&format!("the server got {:?}", event)),
+        }
+    }
+    let mut echoed = Vec::new();
+    let mut ponged = false;
+    for event in deliver(&mut server, &mut client) {
+        match event {
+            ProtocolEvent::Message(msg) => echoed.push(msg),
+            ProtocolEvent::Pong(ref data) if data == b"self-test" => ponged = true,
+            event => return failed(&format!("the client got {:?}", event)),
+        }
+    }
+    if echoed != messages {
+        return failed("the echoed messages differ from the ones sent");
+    }
+    if !ponged {
+        return failed("the ping was not answered");
+    }
+
+    client.close(CloseCode::Normal, "")?;
+    deliver(&mut client, &mut server);
+    deliver(&mut server, &mut client);
+    if !client.is_closed() || !server.is_closed() {
+        return failed("the closing handshake did not complete");
+    }
+    Ok(())
+}
+
+/// Check that this build of the library works, without using the network.
+///
+/// This computes a handshake key and masks data against known values, echoes text, binary and
+/// ping messages between a client and a server in memory, and closes that connection. When
+/// the features are enabled, it also compresses and decompresses a message with zlib, and
+/// initializes the TLS library. The first check that fails is returned as an Internal error.
+///
+/// This is meant to be run at startup or from a health check, to catch builds that are broken
+/// by their environment, such as a system zlib or TLS library that is missing or incompatible,
+/// before any connection is accepted.
+///
+/// ```
+/// parity_ws::self_test().unwrap();
+/// ```
+pub fn self_test() -> Result<()> {
+    // The example from RFC 6455, section 1.3.
+    if accept_key(b"dGhlIHNhbXBsZSBub25jZQ==") != "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=" {
+        return failed("the handshake key is wrong");
+    }
+
+    let mask = [0x37, 0xFA, 0x21, 0x3D];
+    let mut data = *b"Hello";
+    apply_mask(&mut data, &mask);
+    if data != [0x7F, 0x9F, 0x4D, 0x51, 0x58] {
+        return failed("masking is wrong");
+    }
+    apply_mask(&mut data, &mask);
+    if &data != b"Hello" {
+        return failed("unmasking is wrong");
+    }
+
+    loopback()?;
+
+    #[cfg(feature = "permessage-deflate")]
+    ::deflate::self_test()?;
+
+    #[cfg(feature = "ssl")]
+    {
+        use openssl::ssl::{SslConnector, SslMethod};
+        if let Err(err) = SslConnector::builder(SslMethod::tls()) {
+            return failed(&format!("unable to initialize OpenSSL: {}", err));
+        }
+    }
+    #[cfg(feature = "nativetls")]
+    {
+        use native_tls::TlsConnector;
+        if let Err(err) = TlsConnector::new() {
+            return failed(&format!("unable to initialize the TLS library: {}", err));
+        }
+    }
+    Ok(())
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn passes() {
+        self_test().unwrap();
+    }
+
+    #[test]
+    fn report() {
+        let capabilities = capabilities();
+        assert_eq!(
+            capabilities.permessage_deflate,
+            cfg!(feature = "permessage-deflate")
+        );
+        let report = capabilities.to_string();
+        assert!(report.starts_with("ws "));
+        assert!(report.contains("transports: tcp"));
+    }
+}
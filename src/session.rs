@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use mio::channel::SyncSender;
+use mio::Token;
+
+use communication::{Command, Sender};
+use context::Context;
+use handshake::{reason_phrase, Response};
+use protocol::CloseCode;
+
+/// Who the other endpoint of a connection is, such as a user id. An `Authorizer` leaves it in
+/// the context of the connection to limit the number of connections of each identity. See
+/// `Settings::single_session`.
+///
+/// ```
+/// use parity_ws::{Authorization, Credentials, Identity};
+///
+/// fn authorize(credentials: &Credentials) -> Authorization {
+///     match credentials.request().header("x-user") {
+///         Some(user) => {
+///             let user = String::from_utf8_lossy(user).into_owned();
+///             credentials.context().insert(Identity(user));
+///             Authorization::Allow
+///         }
+///         None => Authorization::Deny { status: 403 },
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity(pub String);
+
+/// What to do when a connection opens for an identity that already has an open connection.
+/// See `Settings::single_session`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionPolicy {
+    /// Refuse the handshake of the new connection with a 409 response.
+    RejectNew,
+    /// Close the old connection with the given code once the new one is open.
+    CloseOld(CloseCode),
+}
+
+struct Registry {
+    // The token and id of the open connection of each identity.
+    open: HashMap<String, (Token, u32)>,
+    channel: SyncSender<Command>,
+}
+
+// The identities with an open connection on a WebSocket, shared by its connections.
+pub struct Sessions {
+    registry: Mutex<Registry>,
+}
+
+impl Sessions {
+    pub fn new(channel: SyncSender<Command>) -> Sessions {
+        Sessions {
+            registry: Mutex::new(Registry {
+                open: HashMap::new(),
+                channel,
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // The response that refuses the handshake of a connection whose identity already has an
+    // open connection, if the policy is to reject new connections.
+    pub fn refusal(&self, policy: Option<SessionPolicy>, context: &Context) -> Option<Response> {
+        if policy != Some(SessionPolicy::RejectNew) {
+            return None;
+        }
+        let identity = context.get::<Identity>()?;
+        if !self.lock().open.contains_key(&identity.0) {
+            return None;
+        }
+        debug!("Refusing another connection for {}.", identity.0);
+        Some(Response::new(409, reason_phrase(409), Vec::new()))
+    }
+
+    // Record the open connection of an identity, closing the one it replaces if the policy says
+    // so. Returns false if the identity already has an open connection that stays open.
+    pub fn claim(
+        &self,
+        identity: &str,
+        token: Token,
+        connection_id: u32,
+        policy: SessionPolicy,
+    ) -> bool {
+        let mut registry = self.lock();
+        let old = match registry.open.get(identity) {
+            Some(&old) => old,
+            None => {
+                registry.open.insert(identity.into(), (token, connection_id));
+                return true;
+            }
+        };
+        match policy {
+            SessionPolicy::RejectNew => false,
+            SessionPolicy::CloseOld(code) => {
+                debug!("Closing the previous connection for {}.", identity);
+                let (old_token, old_id) = old;
+                let out = Sender::new(old_token, registry.channel.clone(), old_id);
+                if let Err(err) = out.close_with_reason(code, "Replaced by a new connection.") {
+                    error!("Unable to close the previous connection for {}: {}", identity, err);
+                }
+                registry.open.insert(identity.into(), (token, connection_id));
+                true
+            }
+        }
+    }
+
+    // Forget the open connection of an identity, unless it has been replaced by another.
+    pub fn release(&self, identity: &str, token: Token, connection_id: u32) {
+        let mut registry = self.lock();
+        if registry.open.get(identity) == Some(&(token, connection_id)) {
+            registry.open.remove(identity);
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn claims() {
+        let (tx, rx) = mio::channel::sync_channel(4);
+        let sessions = Sessions::new(tx);
+        let context = Context::new();
+        context.insert(Identity("alice".into()));
+
+        assert!(sessions.claim("alice", Token(1), 1, SessionPolicy::RejectNew));
+        assert!(!sessions.claim("alice", Token(2), 2, SessionPolicy::RejectNew));
+        let res = sessions
+            .refusal(Some(SessionPolicy::RejectNew), &context)
+            .unwrap();
+        assert_eq!(res.status(), 409);
+        assert!(sessions.refusal(None, &context).is_none());
+
+        assert!(sessions.claim("alice", Token(2), 2, SessionPolicy::CloseOld(CloseCode::Policy)));
+        let command = rx.try_recv().unwrap();
+        assert_eq!((command.token(), command.connection_id()), (Token(1), 1));
+
+        // The replaced connection no longer holds the identity when it goes away.
+        sessions.release("alice", Token(1), 1);
+        assert!(sessions
+            .refusal(Some(SessionPolicy::RejectNew), &context)
+            .is_some());
+        sessions.release("alice", Token(2), 2);
+        assert!(sessions
+            .refusal(Some(SessionPolicy::RejectNew), &context)
+            .is_none());
+    }
+}
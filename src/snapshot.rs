@@ -0,0 +1,250 @@
+use std::net::SocketAddr;
+
+use connection::Priority;
+use util::Token;
+use Settings;
+
+/// The phase of the state machine of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionPhase {
+    /// The opening handshake is in progress.
+    Connecting,
+    /// The handshake is complete, and messages can be sent and received.
+    Open,
+    /// A close frame was sent, and the connection waits for the other endpoint to answer it.
+    AwaitingClose,
+    /// A close frame was received, and the answer is being sent.
+    RespondingClose,
+    /// The closing handshake is complete, and the socket is about to be dropped.
+    FinishedClose,
+}
+
+impl ConnectionPhase {
+    /// The name of the phase in snake case, as it appears in the JSON of a snapshot.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ConnectionPhase::Connecting => "connecting",
+            ConnectionPhase::Open => "open",
+            ConnectionPhase::AwaitingClose => "awaiting_close",
+            ConnectionPhase::RespondingClose => "responding_close",
+            ConnectionPhase::FinishedClose => "finished_close",
+        }
+    }
+}
+
+/// The state of a single connection at the time of a snapshot. See `LoopStateSnapshot`.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct ConnectionSnapshot {
+    /// The token identifying the connection within the WebSocket.
+    pub token: Token,
+    /// The connection_id identifying the connection within the WebSocket.
+    pub connection_id: u32,
+    /// The phase of the state machine of the connection.
+    pub phase: ConnectionPhase,
+    /// Whether this is the client end of the connection.
+    pub client: bool,
+    /// The priority with which the events of the connection are handled.
+    pub priority: Priority,
+    /// The address of the other endpoint.
+    pub peer_addr: Option<SocketAddr>,
+    /// The local address of the connection.
+    pub local_addr: Option<SocketAddr>,
+    /// The resource that was requested in the handshake, once the connection is open.
+    pub resource: Option<String>,
+    /// The number of bytes that were received but not handled yet.
+    pub in_buffered: usize,
+    /// The number of bytes waiting to be written to the socket.
+    pub out_buffered: usize,
+    /// The memory allocated for the incoming buffer, in bytes.
+    pub in_capacity: usize,
+    /// The memory allocated for the outgoing buffer, in bytes.
+    pub out_capacity: usize,
+    /// The number of frames of a fragmented message that is still being received.
+    pub fragments: usize,
+    /// Whether the handler deferred the handshake response and has not completed it yet.
+    pub handshake_deferred: bool,
+    /// Whether the connection is draining the socket after the closing handshake.
+    pub lingering: bool,
+    /// The number of `Sender::flush` requests waiting for the outgoing buffer to be written.
+    pub flushes: usize,
+}
+
+/// The state of a WebSocket and all of its connections at one point in time, for postmortem
+/// analysis of a server that stopped making progress. See `WebSocket::dump_state` and
+/// `Sender::dump_state`.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct LoopStateSnapshot {
+    /// The addresses that the WebSocket is listening on.
+    pub listeners: Vec<SocketAddr>,
+    /// The number of connections that have been made so far, which wraps around.
+    pub accepted: u32,
+    /// The number of accepted connections that are waiting for a TLS handshake on another
+    /// thread.
+    pub pending_handshakes: usize,
+    /// The number of timeouts that are scheduled and have not fired yet.
+    pub timers: usize,
+    /// The number of groups that have members or history.
+    pub groups: usize,
+    /// The number of commands that the queue of the event loop can hold.
+    pub queue_capacity: usize,
+    /// The number of connections that the event loop has room for without allocating, which
+    /// exceeds the number of connections by the slots left by closed ones.
+    pub slots: usize,
+    /// The settings of the WebSocket.
+    pub settings: Settings,
+    /// Every connection of the WebSocket.
+    pub connections: Vec<ConnectionSnapshot>,
+}
+
+impl LoopStateSnapshot {
+    /// Render the snapshot as a JSON object with the `loop`, `settings` and `connections` keys,
+    /// without depending on a serialization framework. This is what
+    /// `Sender::respond_with_diagnostics` serves.
+    pub fn to_json(&self) -> String {
+        let settings = &self.settings;
+        let listeners: Vec<String> = self
+            .listeners
+            .iter()
+            .map(|addr| json_string(&addr.to_string()))
+            .collect();
+
+        let mut json = format!(
+            "{{\"loop\":{{\"connections\":{},\"accepted\":{},\"pending_handshakes\":{},\
+             \"timers\":{},\"groups\":{},\"queue_capacity\":{},\"slots\":{},\"listeners\":[{}]}},",
+            self.connections.len(),
+            self.accepted,
+            self.pending_handshakes,
+            self.timers,
+            self.groups,
+            self.queue_capacity,
+            self.slots,
+            listeners.join(","),
+        );
+        json.push_str(&format!(
+            "\"settings\":{{\"max_connections\":{},\"queue_size\":{},\"fragment_size\":{},\
+             \"max_fragment_size\":{},\"in_buffer_capacity\":{},\
+             \"in_buffer_capacity_hard_limit\":{},\"out_buffer_capacity\":{},\
+             \"out_buffer_capacity_hard_limit\":{},\"strict_preallocation\":{},\
+             \"tcp_nodelay\":{},\"encrypt_server\":{},\"close_linger_ms\":{}}},",
+            settings.max_connections,
+            settings.queue_size,
+            settings.fragment_size,
+            settings.max_fragment_size,
+            settings.in_buffer_capacity,
+            settings.in_buffer_capacity_hard_limit,
+            settings.out_buffer_capacity,
+            settings.out_buffer_capacity_hard_limit,
+            settings.strict_preallocation,
+            settings.tcp_nodelay,
+            settings.encrypt_server,
+            settings
+                .close_linger
+                .map(|linger| (linger.as_millis() as u64).to_string())
+                .unwrap_or_else(|| "null".into()),
+        ));
+        let connections: Vec<String> = self.connections.iter().map(connection_json).collect();
+        json.push_str(&format!("\"connections\":[{}]}}", connections.join(",")));
+        json
+    }
+}
+
+fn connection_json(conn: &ConnectionSnapshot) -> String {
+    format!(
+        "{{\"token\":{},\"connection_id\":{},\"state\":\"{}\",\"client\":{},\
+         \"priority\":\"{}\",\"peer\":{},\"resource\":{},\"in_buffered\":{},\
+         \"out_buffered\":{},\"in_capacity\":{},\"out_capacity\":{},\"fragments\":{},\
+         \"handshake_deferred\":{},\"lingering\":{},\"flushes\":{}}}",
+        conn.token.0,
+        conn.connection_id,
+        conn.phase.as_str(),
+        conn.client,
+        format!("{:?}", conn.priority).to_lowercase(),
+        conn.peer_addr
+            .map(|addr| json_string(&addr.to_string()))
+            .unwrap_or_else(|| "null".into()),
+        conn.resource
+            .as_ref()
+            .map(|resource| json_string(resource))
+            .unwrap_or_else(|| "null".into()),
+        conn.in_buffered,
+        conn.out_buffered,
+        conn.in_capacity,
+        conn.out_capacity,
+        conn.fragments,
+        conn.handshake_deferred,
+        conn.lingering,
+        conn.flushes,
+    )
+}
+
+// Quote and escape a string for JSON.
+pub fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn escape_json() {
+        assert_eq!(json_string("/a\"b\\c\n"), "\"/a\\\"b\\\\c\\u000a\"");
+    }
+
+    #[test]
+    fn snapshot_json() {
+        let snapshot = LoopStateSnapshot {
+            listeners: vec!["127.0.0.1:3012".parse().unwrap()],
+            accepted: 1,
+            pending_handshakes: 0,
+            timers: 2,
+            groups: 0,
+            queue_capacity: 500,
+            slots: 100,
+            settings: Settings::default(),
+            connections: vec![ConnectionSnapshot {
+                token: Token(0),
+                connection_id: 0,
+                phase: ConnectionPhase::AwaitingClose,
+                client: false,
+                priority: Priority::High,
+                peer_addr: None,
+                local_addr: None,
+                resource: Some("/feed".into()),
+                in_buffered: 0,
+                out_buffered: 6,
+                in_capacity: 2048,
+                out_capacity: 2048,
+                fragments: 0,
+                handshake_deferred: false,
+                lingering: false,
+                flushes: 1,
+            }],
+        };
+        let json = snapshot.to_json();
+        assert!(json.starts_with(
+            "{\"loop\":{\"connections\":1,\"accepted\":1,\"pending_handshakes\":0,\"timers\":2,"
+        ));
+        assert!(json.contains("\"slots\":100,\"listeners\":[\"127.0.0.1:3012\"]}"));
+        assert!(json.contains("\"close_linger_ms\":null}"));
+        assert!(json.ends_with(
+            "\"connections\":[{\"token\":0,\"connection_id\":0,\"state\":\"awaiting_close\",\
+             \"client\":false,\"priority\":\"high\",\"peer\":null,\"resource\":\"/feed\",\
+             \"in_buffered\":0,\"out_buffered\":6,\"in_capacity\":2048,\"out_capacity\":2048,\
+             \"fragments\":0,\"handshake_deferred\":false,\"lingering\":false,\"flushes\":1}]}"
+        ));
+    }
+}
@@ -0,0 +1,232 @@
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use mio;
+use mio::Token;
+use url::{Host, Url};
+
+use result::{Error, Kind, Result};
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const NEGOTIATION_TIMEOUT_MILLIS: u64 = 10_000;
+
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        1 => "General SOCKS server failure.",
+        2 => "Connection not allowed by ruleset.",
+        3 => "Network unreachable.",
+        4 => "Host unreachable.",
+        5 => "Connection refused.",
+        6 => "TTL expired.",
+        7 => "Command not supported.",
+        8 => "Address type not supported.",
+        _ => "Unknown SOCKS error.",
+    }
+}
+
+// Build the CONNECT request for the url. Host names are passed to the proxy unresolved so that
+// names only the proxy can resolve, such as onion services, can be reached.
+fn connect_request(url: &Url) -> Result<Vec<u8>> {
+    if url.scheme() != "ws" && url.scheme() != "wss" {
+        return Err(Error::new(
+            Kind::Internal,
+            format!("Not a valid websocket url: {}", url),
+        ));
+    }
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut request = vec![SOCKS_VERSION, CONNECT, 0];
+
+    match url.host() {
+        Some(Host::Ipv4(ip)) => {
+            request.push(ATYP_IPV4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Some(Host::Ipv6(ip)) => {
+            request.push(ATYP_IPV6);
+            request.extend_from_slice(&ip.octets());
+        }
+        Some(Host::Domain(domain)) => {
+            if domain.len() > 255 {
+                return Err(Error::new(
+                    Kind::Internal,
+                    format!("Host name is too long for a SOCKS proxy: {}", domain),
+                ));
+            }
+            request.push(ATYP_DOMAIN);
+            request.push(domain.len() as u8);
+            request.extend_from_slice(domain.as_bytes());
+        }
+        None => {
+            return Err(Error::new(
+                Kind::Internal,
+                format!("Not a valid websocket url: {}", url),
+            ))
+        }
+    }
+
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+    Ok(request)
+}
+
+/// Open a TCP connection to the url through a SOCKS5 proxy.
+///
+/// The negotiation is performed before the stream is handed to the event loop, so any TLS and
+/// WebSocket handshakes will run inside the tunnel.
+pub fn connect(proxy: &SocketAddr, url: &Url) -> Result<TcpStream> {
+    let request = connect_request(url)?;
+    let timeout = Duration::from_millis(NEGOTIATION_TIMEOUT_MILLIS);
+
+    let mut stream = TcpStream::connect_timeout(proxy, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    stream.write_all(&[SOCKS_VERSION, 1, NO_AUTHENTICATION])?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != SOCKS_VERSION || choice[1] != NO_AUTHENTICATION {
+        return Err(Error::new(
+            Kind::Protocol,
+            "SOCKS proxy does not accept unauthenticated connections.",
+        ));
+    }
+
+    stream.write_all(&request)?;
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(Error::new(Kind::Protocol, "Invalid reply from SOCKS proxy."));
+    }
+    if reply[1] != 0 {
+        return Err(Error::new(Kind::Protocol, reply_error(reply[1])));
+    }
+
+    // Discard the address the proxy bound for this connection.
+    let remaining = match reply[3] {
+        ATYP_IPV4 => 4 + 2,
+        ATYP_IPV6 => 16 + 2,
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            len[0] as usize + 2
+        }
+        _ => {
+            return Err(Error::from(IoError::new(
+                ErrorKind::InvalidData,
+                "SOCKS proxy replied with an unknown address type.",
+            )))
+        }
+    };
+    let mut bound = vec![0u8; remaining];
+    stream.read_exact(&mut bound)?;
+
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    debug!("Connected to {} through SOCKS proxy at {}.", url, proxy);
+    Ok(stream)
+}
+
+// Open a tunnel to the first of the urls that the proxy can reach, returning its index.
+fn connect_any(proxy: &SocketAddr, urls: &[Url]) -> Result<(usize, TcpStream)> {
+    let mut last = Error::new(Kind::Internal, "No url to connect to.");
+    for (i, url) in urls.iter().enumerate() {
+        match connect(proxy, url) {
+            Ok(stream) => return Ok((i, stream)),
+            Err(err) => {
+                debug!(
+                    "Unable to connect to {} through SOCKS proxy at {}: {}",
+                    url, proxy, err
+                );
+                last = err;
+            }
+        }
+    }
+    Err(last)
+}
+
+/// A tunnel through a SOCKS proxy to the url at its index, or the error of the last url that was
+/// tried, along with the connection that asked for it.
+pub struct Tunnel {
+    pub origin: Token,
+    pub connection_id: u32,
+    pub urls: Vec<Url>,
+    pub result: Result<(usize, TcpStream)>,
+}
+
+/// Opens tunnels through SOCKS proxies on threads of their own, since the negotiation blocks,
+/// and hands them to the event loop through `completed`.
+pub struct Dialer {
+    done: mio::channel::Sender<Tunnel>,
+    completed: mio::channel::Receiver<Tunnel>,
+}
+
+impl Default for Dialer {
+    fn default() -> Dialer {
+        let (done, completed) = mio::channel::channel();
+        Dialer { done, completed }
+    }
+}
+
+impl Dialer {
+    pub fn dial(
+        &self,
+        proxy: SocketAddr,
+        urls: Vec<Url>,
+        origin: Token,
+        connection_id: u32,
+    ) -> Result<()> {
+        let done = self.done.clone();
+        thread::Builder::new()
+            .name("ws-socks".into())
+            .spawn(move || {
+                let result = connect_any(&proxy, &urls);
+                // The event loop is gone when the tunnel can not be handed to it.
+                let _ = done.send(Tunnel {
+                    origin,
+                    connection_id,
+                    urls,
+                    result,
+                });
+            })?;
+        Ok(())
+    }
+
+    pub fn completed(&self) -> &mio::channel::Receiver<Tunnel> {
+        &self.completed
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn domain_is_not_resolved() {
+        let url = Url::parse("ws://exampleonionaddress.onion:8080/").unwrap();
+        let req = connect_request(&url).unwrap();
+        let mut expected = vec![5, 1, 0, 3, 25];
+        expected.extend_from_slice(b"exampleonionaddress.onion");
+        expected.extend_from_slice(&[0x1f, 0x90]);
+        assert_eq!(req, expected);
+    }
+
+    #[test]
+    fn ip_address_request() {
+        let url = Url::parse("wss://127.0.0.1/").unwrap();
+        let req = connect_request(&url).unwrap();
+        assert_eq!(req, vec![5, 1, 0, 1, 127, 0, 0, 1, 1, 187]);
+    }
+
+    #[test]
+    fn websocket_urls_only() {
+        let url = Url::parse("http://example.com/").unwrap();
+        assert!(connect_request(&url).is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use circular_buffer::CircularBuffer;
+use rand;
+
+/// Outgoing bytes that did not fit in memory, staged in a temporary file until the socket has
+/// room for them. The file is removed when the spill is dropped.
+pub struct Spill {
+    file: File,
+    path: PathBuf,
+    // The bytes before this position have been read back already.
+    read: u64,
+    written: u64,
+}
+
+impl Spill {
+    pub fn new() -> io::Result<Spill> {
+        // The temporary directory is shared, so the name must not be guessable and the file
+        // must only be readable by this user. `create_new` refuses files planted in advance.
+        let path = env::temp_dir().join(format!("ws-spill-{:032x}", rand::random::<u128>()));
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options.open(&path)?;
+        trace!("Spilling outgoing data to {}.", path.display());
+        Ok(Spill {
+            file,
+            path,
+            read: 0,
+            written: 0,
+        })
+    }
+
+    /// The number of bytes waiting in the file.
+    pub fn pending(&self) -> usize {
+        (self.written - self.read) as usize
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(self.written))?;
+        self.file.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    /// Move up to `max` pending bytes into `buffer`, returning how many were moved.
+    pub fn refill(&mut self, buffer: &mut CircularBuffer, max: usize) -> io::Result<usize> {
+        let max = ::std::cmp::min(max, self.pending());
+        if max == 0 {
+            return Ok(0);
+        }
+        self.file.seek(SeekFrom::Start(self.read))?;
+        let moved = buffer.write_from(&mut (&self.file).take(max as u64), max)?;
+        self.read += moved as u64;
+        if self.read == self.written {
+            // Start over at the beginning of the file rather than let it grow.
+            self.file.set_len(0)?;
+            self.read = 0;
+            self.written = 0;
+        }
+        Ok(moved)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            debug!("Unable to remove {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use bytes::Buf;
+
+    #[test]
+    fn spill() {
+        let mut spill = Spill::new().unwrap();
+        let path = spill.path.clone();
+        spill.push(b"hello ").unwrap();
+        spill.push(b"world").unwrap();
+        assert_eq!(spill.pending(), 11);
+
+        let mut buffer = CircularBuffer::new(8, 8);
+        assert_eq!(spill.refill(&mut buffer, 8).unwrap(), 8);
+        assert_eq!(buffer.read_exact_into_vec(8), b"hello wo");
+        spill.push(b"!").unwrap();
+        assert_eq!(spill.refill(&mut buffer, 8).unwrap(), 4);
+        assert_eq!(buffer.read_exact_into_vec(4), b"rld!");
+        assert_eq!(spill.pending(), 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        drop(spill);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let spill = Spill::new().unwrap();
+        let other = Spill::new().unwrap();
+        assert_ne!(spill.path, other.path);
+        let mode = fs::metadata(&spill.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
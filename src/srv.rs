@@ -0,0 +1,457 @@
+//! Discovery of WebSocket endpoints through DNS SRV records, as described in RFC 2782.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use rand::{self, Rng};
+use url::Url;
+
+use result::{Error, Kind, Result};
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_NAME_ERROR: u16 = 3;
+const MAX_MESSAGE: usize = 512;
+const QUERY_TIMEOUT_MILLIS: u64 = 5_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+fn invalid() -> Error {
+    Error::new(Kind::Protocol, "Invalid DNS response.")
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16> {
+    msg.get(pos..pos + 2)
+        .map(|bytes| u16::from(bytes[0]) << 8 | u16::from(bytes[1]))
+        .ok_or_else(invalid)
+}
+
+// Build a recursive query for the SRV records of the name.
+fn query(id: u16, name: &str) -> Result<Vec<u8>> {
+    let mut msg = Vec::with_capacity(MAX_MESSAGE);
+    msg.extend_from_slice(&[(id >> 8) as u8, id as u8]);
+    msg.extend_from_slice(&[(FLAG_RECURSION_DESIRED >> 8) as u8, 0]);
+    // One question, and no answer, authority or additional records.
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(Error::new(
+                Kind::Internal,
+                format!("Not a valid SRV name: {}", name),
+            ));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&[0, TYPE_SRV as u8, 0, CLASS_IN as u8]);
+    Ok(msg)
+}
+
+// Read the possibly compressed domain name at `pos`, returning it along with the position of
+// whatever follows it.
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Every pointer must lead further back than the last, so that loops are impossible.
+    let mut limit = pos;
+    loop {
+        let len = *msg.get(pos).ok_or_else(invalid)? as usize;
+        if len & 0xC0 == 0xC0 {
+            let target = (read_u16(msg, pos)? & 0x3FFF) as usize;
+            if target >= limit {
+                return Err(invalid());
+            }
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            limit = target;
+            pos = target;
+        } else if len == 0 {
+            return Ok((name, end.unwrap_or(pos + 1)));
+        } else {
+            let label = msg.get(pos + 1..pos + 1 + len).ok_or_else(invalid)?;
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.push_str(&String::from_utf8_lossy(label));
+            pos += 1 + len;
+        }
+    }
+}
+
+// Parse the SRV records answering the query with the id. A name that does not exist has no
+// records.
+fn parse(msg: &[u8], id: u16) -> Result<Vec<Record>> {
+    let flags = read_u16(msg, 2)?;
+    if read_u16(msg, 0)? != id || flags & FLAG_RESPONSE == 0 {
+        return Err(invalid());
+    }
+    if flags & FLAG_TRUNCATED != 0 {
+        return Err(Error::new(Kind::Protocol, "Truncated DNS response."));
+    }
+    match flags & 0xF {
+        0 => (),
+        RCODE_NAME_ERROR => return Ok(Vec::new()),
+        code => {
+            return Err(Error::new(
+                Kind::Protocol,
+                format!("DNS query failed with response code {}.", code),
+            ))
+        }
+    }
+
+    let questions = read_u16(msg, 4)?;
+    let answers = read_u16(msg, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(msg, pos)?.1 + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        let (_, next) = read_name(msg, pos)?;
+        let kind = read_u16(msg, next)?;
+        let data = next + 10;
+        let len = read_u16(msg, next + 8)? as usize;
+        if msg.len() < data + len {
+            return Err(invalid());
+        }
+        if kind == TYPE_SRV {
+            records.push(Record {
+                priority: read_u16(msg, data)?,
+                weight: read_u16(msg, data + 2)?,
+                port: read_u16(msg, data + 4)?,
+                target: read_name(msg, data + 6)?.0,
+            });
+        }
+        pos = data + len;
+    }
+    Ok(records)
+}
+
+// Order the records the way that RFC 2782 asks clients to try them: by ascending priority, and
+// within a priority at random, with the chance of each record going next in proportion to its
+// weight.
+fn order<R: Rng>(mut records: Vec<Record>, rng: &mut R) -> Vec<Record> {
+    // Records without weight go first within their priority, so that they are only picked when
+    // the draw is zero.
+    records.sort_by_key(|record| (record.priority, record.weight != 0));
+    let mut ordered = Vec::with_capacity(records.len());
+    while !records.is_empty() {
+        let priority = records[0].priority;
+        let group = records
+            .iter()
+            .take_while(|record| record.priority == priority)
+            .count();
+        let total: u32 = records[..group]
+            .iter()
+            .map(|record| u32::from(record.weight))
+            .sum();
+        let draw = rng.gen_range(0, total + 1);
+        let mut sum = 0;
+        let index = records[..group]
+            .iter()
+            .position(|record| {
+                sum += u32::from(record.weight);
+                sum >= draw
+            })
+            .unwrap_or(0);
+        ordered.push(records.remove(index));
+    }
+    ordered
+}
+
+// The name servers configured for the system, or the local host if there are none.
+fn nameservers() -> Vec<SocketAddr> {
+    let conf = fs::read_to_string(RESOLV_CONF).unwrap_or_default();
+    let mut servers = conf
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next() == Some("nameserver") {
+                words.next().and_then(|addr| addr.parse::<IpAddr>().ok())
+            } else {
+                None
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .collect::<Vec<SocketAddr>>();
+    if servers.is_empty() {
+        servers.push(SocketAddr::from(([127, 0, 0, 1], DNS_PORT)));
+    }
+    servers
+}
+
+fn exchange(server: &SocketAddr, request: &[u8], id: u16) -> Result<Vec<Record>> {
+    let local = if server.is_ipv4() {
+        SocketAddr::from(([0, 0, 0, 0], 0))
+    } else {
+        SocketAddr::from(([0u16; 8], 0))
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.set_read_timeout(Some(Duration::from_millis(QUERY_TIMEOUT_MILLIS)))?;
+    socket.connect(server)?;
+    socket.send(request)?;
+
+    let mut buf = [0u8; MAX_MESSAGE];
+    loop {
+        let len = socket.recv(&mut buf)?;
+        // Stray datagrams that do not answer this query are ignored.
+        if read_u16(&buf[..len], 0).ok() == Some(id) {
+            // Answers that do not fit in a datagram are asked for again over TCP.
+            if read_u16(&buf[..len], 2)? & FLAG_TRUNCATED != 0 {
+                debug!("Truncated DNS response from {}, retrying over TCP.", server);
+                return exchange_tcp(server, request, id);
+            }
+            return parse(&buf[..len], id);
+        }
+    }
+}
+
+// Send the query over TCP, where messages are prefixed with their length and not limited in size.
+fn exchange_tcp(server: &SocketAddr, request: &[u8], id: u16) -> Result<Vec<Record>> {
+    let timeout = Duration::from_millis(QUERY_TIMEOUT_MILLIS);
+    let mut stream = TcpStream::connect_timeout(server, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut msg = Vec::with_capacity(request.len() + 2);
+    msg.extend_from_slice(&[(request.len() >> 8) as u8, request.len() as u8]);
+    msg.extend_from_slice(request);
+    stream.write_all(&msg)?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut response = vec![0u8; read_u16(&len, 0)? as usize];
+    stream.read_exact(&mut response)?;
+    parse(&response, id)
+}
+
+// Query the servers in turn until one of them answers.
+fn lookup(name: &str, servers: &[SocketAddr]) -> Result<Vec<Record>> {
+    let id = rand::random();
+    let request = query(id, name)?;
+    let mut last = Error::new(Kind::Internal, "No DNS servers to query.");
+    for server in servers {
+        match exchange(server, &request, id) {
+            Ok(records) => return Ok(records),
+            Err(err) => {
+                debug!("Unable to look up {} with {}: {}", name, server, err);
+                last = err;
+            }
+        }
+    }
+    Err(last)
+}
+
+// The urls of the targets in the order that they should be tried. Services named `_wss` are
+// reached with `wss` urls and all others with `ws` urls.
+fn urls<R: Rng>(name: &str, records: Vec<Record>, rng: &mut R) -> Result<Vec<Url>> {
+    let scheme = if name.starts_with("_wss.") { "wss" } else { "ws" };
+    // A single target of "." means that the service is not available at this name.
+    let urls = order(records, rng)
+        .into_iter()
+        .filter(|record| !record.target.is_empty())
+        .map(|record| {
+            Url::parse(&format!("{}://{}:{}/", scheme, record.target, record.port)).map_err(
+                |err| {
+                    Error::new(
+                        Kind::Protocol,
+                        format!("Invalid SRV target {}: {}", record.target, err),
+                    )
+                },
+            )
+        })
+        .collect::<Result<Vec<Url>>>()?;
+    if urls.is_empty() {
+        return Err(Error::new(
+            Kind::Internal,
+            format!("No SRV records for {}", name),
+        ));
+    }
+    Ok(urls)
+}
+
+/// Resolve the SRV records of a service name such as `_ws._tcp.example.com` with the name
+/// servers of the system, and return the urls of the targets in the order they should be tried.
+///
+/// The name servers are read from `/etc/resolv.conf`, and a resolver on the local host is
+/// queried where that file does not exist, such as on Windows. The servers are queried in turn,
+/// and each of them that does not answer blocks the caller for up to 5 seconds.
+pub fn resolve(name: &str) -> Result<Vec<Url>> {
+    let records = lookup(name, &nameservers())?;
+    debug!("Found {} SRV records for {}.", records.len(), name);
+    urls(name, records, &mut rand::thread_rng())
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn record(priority: u16, weight: u16, port: u16, target: &str) -> Record {
+        Record {
+            priority,
+            weight,
+            port,
+            target: target.into(),
+        }
+    }
+
+    // A response to the query with two records, whose targets are compressed against the name
+    // in the question.
+    fn response(request: &[u8]) -> Vec<u8> {
+        let mut msg = request.to_vec();
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[7] = 2;
+        // `_ws._tcp.example.com` starts right after the header, and `example.com` 9 bytes on.
+        let answers: [(u16, u16, u16, &[u8]); 2] = [
+            (20, 0, 9000, b"\x05spare\xc0\x15"),
+            (10, 5, 8080, b"\x04main\xc0\x15"),
+        ];
+        for &(priority, weight, port, target) in &answers {
+            msg.extend_from_slice(&[0xc0, 12, 0, 33, 0, 1, 0, 0, 0, 60]);
+            msg.extend_from_slice(&[0, 6 + target.len() as u8]);
+            for value in &[priority, weight, port] {
+                msg.extend_from_slice(&[(value >> 8) as u8, *value as u8]);
+            }
+            msg.extend_from_slice(target);
+        }
+        msg
+    }
+
+    #[test]
+    fn query_name() {
+        let msg = query(0x1234, "_ws._tcp.example.com.").unwrap();
+        assert_eq!(&msg[..12], &[0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&msg[12..], &b"\x03_ws\x04_tcp\x07example\x03com\x00\x00\x21\x00\x01"[..]);
+        assert!(query(1, "_ws.._tcp").is_err());
+    }
+
+    #[test]
+    fn parse_compressed() {
+        let request = query(7, "_ws._tcp.example.com").unwrap();
+        let records = parse(&response(&request), 7).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                record(20, 0, 9000, "spare.example.com"),
+                record(10, 5, 8080, "main.example.com"),
+            ]
+        );
+        assert!(parse(&response(&request), 8).is_err());
+
+        let mut looped = response(&request);
+        looped.extend_from_slice(&[0xc0, 0xff]);
+        let at = looped.len() - 2;
+        looped[at + 1] = at as u8;
+        assert!(read_name(&looped, at).is_err());
+    }
+
+    #[test]
+    fn missing_name() {
+        let mut msg = query(7, "_ws._tcp.example.com").unwrap();
+        msg[2] = 0x81;
+        msg[3] = 0x83;
+        assert_eq!(parse(&msg, 7).unwrap(), Vec::new());
+        msg[3] = 0x82;
+        assert!(parse(&msg, 7).is_err());
+    }
+
+    #[test]
+    fn priority_then_weight() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let ordered = order(
+                vec![
+                    record(2, 1, 3, "c"),
+                    record(1, 0, 2, "b"),
+                    record(1, 65535, 1, "a"),
+                    record(3, 0, 4, "d"),
+                ],
+                &mut rng,
+            );
+            let priorities = ordered.iter().map(|r| r.priority).collect::<Vec<_>>();
+            assert_eq!(priorities, vec![1, 1, 2, 3]);
+        }
+
+        // A record without weight only goes first when the draw is zero.
+        let first = (0..100)
+            .filter(|_| {
+                order(vec![record(1, 0, 1, "a"), record(1, 65535, 2, "b")], &mut rng)[0].target
+                    == "b"
+            })
+            .count();
+        assert!(first > 90);
+    }
+
+    #[test]
+    fn target_urls() {
+        let mut rng = rand::thread_rng();
+        let urls = urls(
+            "_wss._tcp.example.com",
+            vec![record(1, 0, 8443, "main.example.com")],
+            &mut rng,
+        )
+        .unwrap();
+        assert_eq!(urls[0].as_str(), "wss://main.example.com:8443/");
+        assert!(super::urls("_ws._tcp.example.com", vec![record(0, 0, 0, "")], &mut rng).is_err());
+    }
+
+    #[test]
+    fn lookup_truncated() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let udp = UdpSocket::bind(addr).unwrap();
+        let thread = thread::spawn(move || {
+            let mut buf = [0u8; MAX_MESSAGE];
+            let (len, from) = udp.recv_from(&mut buf).unwrap();
+            let mut truncated = buf[..len].to_vec();
+            truncated[2] = 0x83;
+            truncated[3] = 0x80;
+            udp.send_to(&truncated, from).unwrap();
+
+            let (mut stream, _) = tcp.accept().unwrap();
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut request = vec![0u8; read_u16(&len, 0).unwrap() as usize];
+            stream.read_exact(&mut request).unwrap();
+            let msg = response(&request);
+            let len = [(msg.len() >> 8) as u8, msg.len() as u8];
+            stream.write_all(&len).unwrap();
+            stream.write_all(&msg).unwrap();
+        });
+        let records = lookup("_ws._tcp.example.com", &[addr]).unwrap();
+        assert_eq!(records.len(), 2);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn lookup_with_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let thread = thread::spawn(move || {
+            let mut buf = [0u8; MAX_MESSAGE];
+            let (len, from) = server.recv_from(&mut buf).unwrap();
+            server.send_to(&response(&buf[..len]), from).unwrap();
+        });
+        let records = lookup("_ws._tcp.example.com", &[addr]).unwrap();
+        assert_eq!(records.len(), 2);
+        thread.join().unwrap();
+    }
+}
@@ -1,10 +1,11 @@
 use std::io;
-use std::io::ErrorKind::WouldBlock;
+use std::io::ErrorKind::{Interrupted, WouldBlock};
 #[cfg(any(feature = "ssl", feature = "nativetls"))]
 use std::mem::replace;
 use std::net::SocketAddr;
 
 use bytes::{Buf, BufMut};
+use iovec::IoVec;
 use mio::tcp::TcpStream;
 #[cfg(feature = "nativetls")]
 use native_tls::{
@@ -13,6 +14,7 @@ use native_tls::{
 #[cfg(feature = "ssl")]
 use openssl::ssl::{ErrorCode as SslErrorCode, HandshakeError, MidHandshakeSslStream, SslStream};
 
+use circular_buffer::CircularBuffer;
 use result::{Error, Kind, Result};
 
 fn map_non_block<T>(res: io::Result<T>) -> io::Result<Option<T>> {
@@ -28,6 +30,36 @@ fn map_non_block<T>(res: io::Result<T>) -> io::Result<Option<T>> {
     }
 }
 
+/// How the remainder of a partial write is handled.
+///
+/// Neither policy ever drops or reorders bytes: the unwritten remainder always stays at the
+/// front of the outgoing buffer, and writes resume from exactly where the socket stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Write once per writable event. After a short write the connection waits for the socket
+    /// to become writable again before writing the remainder.
+    RetainOffset,
+    /// After a short write, immediately re-queue the remainder and keep writing until the
+    /// buffer is empty or the socket would block. This also writes both halves of a wrapped
+    /// buffer in a single event.
+    Requeue,
+}
+
+// Retry an IO operation for as long as it is interrupted by a signal.
+fn retry_interrupted<T, F>(mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    loop {
+        match op() {
+            Err(ref err) if err.kind() == Interrupted => {
+                trace!("IO operation interrupted, retrying.");
+            }
+            res => return res,
+        }
+    }
+}
+
 pub trait TryReadBuf: io::Read {
     fn try_read_buf<B: BufMut>(&mut self, buf: &mut B) -> io::Result<Option<usize>>
     where
@@ -38,7 +70,7 @@ pub trait TryReadBuf: io::Read {
         // If your protocol is msg based (instead of continuous stream) you should
         // ensure that your buffer is large enough to hold an entire segment (1532 bytes if not jumbo
         // frames)
-        let res = map_non_block(self.read(unsafe { buf.bytes_mut() }));
+        let res = map_non_block(retry_interrupted(|| self.read(unsafe { buf.bytes_mut() })));
 
         if let Ok(Some(cnt)) = res {
             unsafe {
@@ -48,6 +80,14 @@ pub trait TryReadBuf: io::Read {
 
         res
     }
+
+    // Read into a circular buffer, filling both parts of its free space when it wraps around.
+    fn try_read_circular(&mut self, buf: &mut CircularBuffer) -> io::Result<Option<usize>>
+    where
+        Self: Sized,
+    {
+        map_non_block(retry_interrupted(|| buf.write_from(self, usize::max_value())))
+    }
 }
 
 pub trait TryWriteBuf: io::Write {
@@ -55,7 +95,7 @@ pub trait TryWriteBuf: io::Write {
     where
         Self: Sized,
     {
-        let res = map_non_block(self.write(buf.bytes()));
+        let res = map_non_block(retry_interrupted(|| self.write(buf.bytes())));
 
         if let Ok(Some(cnt)) = res {
             buf.advance(cnt);
@@ -63,6 +103,40 @@ pub trait TryWriteBuf: io::Write {
 
         res
     }
+
+    // Write `bufs` in one go where the writer supports it. Returns None if it would block.
+    fn try_write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<Option<usize>>
+    where
+        Self: Sized,
+    {
+        map_non_block(retry_interrupted(|| self.write_vectored(bufs)))
+    }
+
+    /// Write from `buf` following the partial write `policy`. Returns the total number of bytes
+    /// written, or None if the writer would block before anything was written.
+    fn try_write_buf_with<B: Buf>(
+        &mut self,
+        buf: &mut B,
+        policy: WritePolicy,
+    ) -> io::Result<Option<usize>>
+    where
+        Self: Sized,
+    {
+        let mut total = None;
+        loop {
+            match self.try_write_buf(buf)? {
+                Some(0) => return Ok(Some(total.unwrap_or(0))),
+                Some(cnt) => {
+                    total = Some(total.unwrap_or(0) + cnt);
+                    if policy == WritePolicy::RetainOffset || !buf.has_remaining() {
+                        return Ok(total);
+                    }
+                    trace!("Partial write of {} bytes, re-queuing the remainder.", cnt);
+                }
+                None => return Ok(total),
+            }
+        }
+    }
 }
 
 impl<T: io::Read> TryReadBuf for T {}
@@ -143,6 +217,40 @@ impl Stream {
             Tls(ref inner) => inner.local_addr(),
         }
     }
+
+    // The DER encoded certificate of the other endpoint, once the TLS handshake is done.
+    pub fn peer_certificate(&self) -> Option<Vec<u8>> {
+        match *self {
+            Tcp(_) => None,
+            #[cfg(feature = "ssl")]
+            Tls(TlsStream::Live(ref sock)) => sock
+                .ssl()
+                .peer_certificate()
+                .and_then(|cert| cert.to_der().ok()),
+            #[cfg(feature = "nativetls")]
+            Tls(TlsStream::Live(ref sock)) => sock
+                .peer_certificate()
+                .ok()
+                .and_then(|cert| cert)
+                .and_then(|cert| cert.to_der().ok()),
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            Tls(_) => None,
+        }
+    }
+
+    // Send a TLS closing alert, without which OpenSSL does not resume the session of the
+    // connection. The connection is about to be dropped, so failures are ignored.
+    pub fn close_notify(&mut self) {
+        match *self {
+            Tcp(_) => (),
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            Tls(TlsStream::Live(ref mut sock)) => {
+                let _ = sock.shutdown();
+            }
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            Tls(_) => (),
+        }
+    }
 }
 
 impl io::Read for Stream {
@@ -282,6 +390,33 @@ impl io::Write for Stream {
         }
     }
 
+    // The header and the payload of a frame that is written straight from a message go out in
+    // a single system call, and so in a single segment with `tcp_nodelay`.
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        match *self {
+            Tcp(ref mut sock) => {
+                // An `IoVec` can't be empty, so the slots start out pointing at a placeholder.
+                let mut slots: [&IoVec; 4] = [(&b"\0"[..]).into(); 4];
+                let mut count = 0;
+                for buf in bufs.iter().filter(|buf| !buf.is_empty()).take(slots.len()) {
+                    slots[count] = (&buf[..]).into();
+                    count += 1;
+                }
+                if count == 0 {
+                    return Ok(0);
+                }
+                sock.write_bufs(&slots[..count])
+            }
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            Tls(TlsStream::Live(ref mut sock)) => sock.write_vectored(bufs),
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            Tls(_) => {
+                let buf = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &buf[..]);
+                self.write(buf)
+            }
+        }
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             Tcp(ref mut sock) => sock.flush(),
@@ -356,3 +491,153 @@ impl TlsStream {
         }
     }
 }
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use circular_buffer::CircularBuffer;
+    use frame::Frame;
+    use protocol::OpCode;
+    use std::io::Write;
+
+    // A writer behind a flaky link: every other call is interrupted, each write accepts at most
+    // `window` bytes and the link blocks once `budget` bytes have gone through.
+    struct FlakyWriter {
+        written: Vec<u8>,
+        window: usize,
+        budget: usize,
+        interrupt: bool,
+    }
+
+    impl FlakyWriter {
+        fn new(window: usize) -> FlakyWriter {
+            FlakyWriter {
+                written: Vec::new(),
+                window,
+                budget: 0,
+                interrupt: false,
+            }
+        }
+    }
+
+    impl io::Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::Error::new(Interrupted, "interrupted"));
+            }
+            if self.budget == 0 {
+                return Err(io::Error::new(WouldBlock, "would block"));
+            }
+            let len = buf.len().min(self.window).min(self.budget);
+            self.budget -= len;
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl io::Read for FlakyWriter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::Error::new(Interrupted, "interrupted"));
+            }
+            let len = buf.len().min(self.window).min(self.written.len());
+            buf[..len].copy_from_slice(&self.written[..len]);
+            self.written.drain(..len);
+            Ok(len)
+        }
+    }
+
+    fn frames() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for i in 0..20u8 {
+            Frame::message(vec![i; 10 + i as usize * 7], OpCode::Binary, true)
+                .format(&mut bytes)
+                .unwrap();
+        }
+        bytes
+    }
+
+    // Push `input` through a small wrapping buffer into `writer`, letting a few bytes through at
+    // a time, the way the event loop would on successive writable events.
+    fn drain(input: &[u8], writer: &mut FlakyWriter, policy: WritePolicy) -> usize {
+        let mut buffer = CircularBuffer::new(64, 64);
+        let mut pending = input;
+        let mut events = 0;
+        while !pending.is_empty() || buffer.has_remaining() {
+            let len = buffer.write(pending).unwrap();
+            pending = &pending[len..];
+            writer.budget = 23;
+            writer.try_write_buf_with(&mut buffer, policy).unwrap();
+            events += 1;
+        }
+        events
+    }
+
+    #[test]
+    fn interrupted_io_is_retried() {
+        let mut flaky = FlakyWriter::new(16);
+        flaky.budget = 16;
+        let mut out = io::Cursor::new(&b"hello"[..]);
+        assert_eq!(flaky.try_write_buf(&mut out).unwrap(), Some(5));
+
+        let mut buffer = CircularBuffer::new(16, 16);
+        assert_eq!(flaky.try_read_buf(&mut buffer).unwrap(), Some(5));
+        assert_eq!(buffer.read_exact_into_vec(5), b"hello");
+    }
+
+    #[test]
+    fn short_writes_do_not_corrupt_frames() {
+        let input = frames();
+        for &policy in &[WritePolicy::RetainOffset, WritePolicy::Requeue] {
+            let mut flaky = FlakyWriter::new(3);
+            drain(&input, &mut flaky, policy);
+            assert_eq!(flaky.written, input);
+
+            let mut parsed = CircularBuffer::new(input.len(), input.len());
+            parsed.write_all(&flaky.written).unwrap();
+            for i in 0..20u8 {
+                let frame = Frame::parse(&mut parsed, u64::max_value())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(frame.payload(), &vec![i; 10 + i as usize * 7]);
+            }
+        }
+    }
+
+    #[test]
+    fn requeue_writes_until_blocked() {
+        let input = frames();
+        let retain = drain(&input, &mut FlakyWriter::new(3), WritePolicy::RetainOffset);
+        let requeue = drain(&input, &mut FlakyWriter::new(3), WritePolicy::Requeue);
+        assert!(requeue < retain);
+
+        let mut flaky = FlakyWriter::new(3);
+        flaky.budget = 10;
+        let mut out = io::Cursor::new(&b"0123456789abcdef"[..]);
+        assert_eq!(
+            flaky
+                .try_write_buf_with(&mut out, WritePolicy::RetainOffset)
+                .unwrap(),
+            Some(3)
+        );
+        assert_eq!(
+            flaky
+                .try_write_buf_with(&mut out, WritePolicy::Requeue)
+                .unwrap(),
+            Some(7)
+        );
+        assert_eq!(
+            flaky
+                .try_write_buf_with(&mut out, WritePolicy::Requeue)
+                .unwrap(),
+            None
+        );
+        assert_eq!(out.bytes(), b"abcdef");
+    }
+}
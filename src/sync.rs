@@ -0,0 +1,154 @@
+//! A blocking client for exchanging a few messages without implementing a `Handler`.
+//!
+//! The client runs a WebSocket event loop on a background thread and hands messages over to the
+//! calling thread, so it is meant for scripts and tests rather than for many connections.
+//!
+//! ```no_run
+//! use parity_ws::sync::Client;
+//!
+//! let mut client = Client::connect("ws://127.0.0.1:3012").unwrap();
+//! client.send("Hello WebSocket").unwrap();
+//! println!("Got message: {}", client.recv().unwrap());
+//! client.close(parity_ws::CloseCode::Normal).unwrap();
+//! ```
+use std::borrow::Borrow;
+use std::io;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use channel::connect_channel;
+use communication::Sender;
+use event::Event;
+use message::Message;
+use protocol::CloseCode;
+use result::{Error, Kind, Result};
+
+// The number of events that may be waiting for `recv` before the connection stops reading.
+const CAPACITY: usize = 1024;
+
+fn timed_out(details: &'static str) -> Error {
+    Error::new(
+        Kind::Io(io::Error::new(io::ErrorKind::TimedOut, details)),
+        details,
+    )
+}
+
+fn closed(details: String) -> Error {
+    Error::new(
+        Kind::Io(io::Error::new(io::ErrorKind::ConnectionAborted, details.clone())),
+        details,
+    )
+}
+
+/// A blocking WebSocket client.
+///
+/// Dropping the client without closing it shuts the connection down without a closing
+/// handshake.
+pub struct Client {
+    out: Sender,
+    events: mpsc::Receiver<Event>,
+    read_timeout: Option<Duration>,
+    closed: Option<(CloseCode, String)>,
+}
+
+impl Client {
+    /// Connect to a WebSocket server, blocking until the handshake completes.
+    pub fn connect<U: Borrow<str>>(url: U) -> Result<Client> {
+        Client::open(url.borrow(), None)
+    }
+
+    /// Connect to a WebSocket server, failing with a `TimedOut` IO error if the handshake does
+    /// not complete within `timeout`.
+    pub fn connect_timeout<U: Borrow<str>>(url: U, timeout: Duration) -> Result<Client> {
+        Client::open(url.borrow(), Some(timeout))
+    }
+
+    fn open(url: &str, timeout: Option<Duration>) -> Result<Client> {
+        let (out, events) = connect_channel(url, CAPACITY)?;
+        let mut client = Client {
+            out,
+            events,
+            read_timeout: None,
+            closed: None,
+        };
+        match client.next(timeout, "Timed out waiting for the WebSocket handshake.")? {
+            Event::Open(_) => Ok(client),
+            Event::Error(err) => Err(err),
+            _ => Err(Error::new(Kind::Internal, "Connection did not open.")),
+        }
+    }
+
+    fn next(&mut self, timeout: Option<Duration>, details: &'static str) -> Result<Event> {
+        if let Some((code, ref reason)) = self.closed {
+            return Err(closed(format!("Connection closed with {:?} {}", code, reason)));
+        }
+        let next = match timeout {
+            Some(timeout) => self.events.recv_timeout(timeout).map_err(|err| match err {
+                mpsc::RecvTimeoutError::Timeout => timed_out(details),
+                mpsc::RecvTimeoutError::Disconnected => closed("Connection closed.".into()),
+            }),
+            None => self.events
+                .recv()
+                .map_err(|_| closed("Connection closed.".into())),
+        };
+        match next {
+            Ok(Event::Close { code, reason }) => {
+                self.closed = Some((code, reason));
+                self.next(timeout, details)
+            }
+            next => next,
+        }
+    }
+
+    /// Set how long `recv` waits for a message before failing with a `TimedOut` IO error.
+    /// `None`, the default, waits indefinitely.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// The sender for this connection, for use from other threads.
+    pub fn sender(&self) -> &Sender {
+        &self.out
+    }
+
+    /// Send a message to the server.
+    pub fn send<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        self.out.send(msg)
+    }
+
+    /// Block until the next message arrives.
+    ///
+    /// Once the connection has closed, this fails with a `ConnectionAborted` IO error.
+    pub fn recv(&mut self) -> Result<Message> {
+        // The read timeout covers the events that are skipped on the way to the message.
+        let deadline = self.read_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let timeout =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match self.next(timeout, "Timed out waiting for a message.")? {
+                Event::Message(msg) => return Ok(msg),
+                Event::Error(err) => return Err(err),
+                // Closes are turned into errors by `next`, and other events are skipped.
+                _ => (),
+            }
+        }
+    }
+
+    /// Start the closing handshake and block until the connection has closed. Messages that
+    /// arrive in the meantime are discarded.
+    pub fn close(self, code: CloseCode) -> Result<()> {
+        self.out.close(code)?;
+        // The channel disconnects once the event loop has finished.
+        for _ in self.events.iter() {}
+        Ok(())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self.out.shutdown();
+    }
+}
@@ -0,0 +1,71 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use frame::Frame;
+use protocol::OpCode;
+use snapshot::ConnectionPhase;
+
+/// The way that a frame seen by a `FrameObserver` was travelling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The frame was received from the other endpoint.
+    Inbound,
+    /// The frame is being sent to the other endpoint.
+    Outbound,
+}
+
+/// A read-only view of the frames of a connection, attached with `Sender::tap`, such as for an
+/// audit log or a live debugging console.
+///
+/// Inbound frames are observed once they are unmasked, before `Handler::on_frame` runs, and
+/// outbound frames after `Handler::on_send_frame` has run, before they are masked. Frames that a
+/// handler drops are therefore seen inbound but not outbound. Observers run on the event loop, so
+/// they should hand anything slow off to another thread.
+pub trait FrameObserver: Send {
+    /// Called with every frame that passes through the connection.
+    fn on_frame(&mut self, direction: Direction, frame: &Frame);
+
+    /// Called when the state machine of the connection moves to another phase, with the frame
+    /// that caused it, which is `None` for the opening handshake. See `StateRecorder`.
+    fn on_transition(
+        &mut self,
+        from: ConnectionPhase,
+        to: ConnectionPhase,
+        cause: Option<(Direction, OpCode)>,
+    ) {
+        trace!("Connection moved from {:?} to {:?} on {:?}.", from, to, cause);
+    }
+}
+
+impl<F> FrameObserver for F
+where
+    F: FnMut(Direction, &Frame) + Send,
+{
+    fn on_frame(&mut self, direction: Direction, frame: &Frame) {
+        self(direction, frame)
+    }
+}
+
+/// An observer on its way to a connection. Signals must be `Clone`, so the observer is shared
+/// and taken out by whoever handles the signal first.
+#[derive(Clone)]
+pub struct Tap(Arc<Mutex<Option<Box<dyn FrameObserver>>>>);
+
+impl Tap {
+    pub fn new(observer: Box<dyn FrameObserver>) -> Tap {
+        Tap(Arc::new(Mutex::new(Some(observer))))
+    }
+
+    pub fn take(&self) -> Option<Box<dyn FrameObserver>> {
+        match self.0.lock() {
+            Ok(mut observer) => observer.take(),
+            Err(_) => None,
+        }
+    }
+}
+
+impl fmt::Debug for Tap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tap")
+    }
+}
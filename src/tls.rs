@@ -0,0 +1,166 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use openssl::ssl::{
+    ConnectConfiguration, NameType, SslConnector, SslConnectorBuilder, SslSession,
+    SslSessionCacheMode, SslStream,
+};
+
+use result::{Error, Kind, Result};
+use util::TcpStream;
+
+fn upgrade_error<E: fmt::Display>(err: E) -> Error {
+    Error::new(
+        Kind::Internal,
+        format!("Failed to upgrade client to SSL: {}", err),
+    )
+}
+
+#[derive(Default)]
+struct Sessions {
+    by_name: HashMap<String, SslSession>,
+    // The server names in the order that their sessions were cached, oldest first.
+    order: VecDeque<String>,
+    capacity: usize,
+}
+
+impl Sessions {
+    fn insert(&mut self, name: &str, session: SslSession) {
+        if self.by_name.insert(name.into(), session).is_some() {
+            self.order.retain(|cached| cached != name);
+        }
+        self.order.push_back(name.into());
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.by_name.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// A TLS connector for clients that caches the sessions it negotiates, so that reconnecting to
+/// a server resumes an earlier session with a session ticket or id instead of a full
+/// handshake. This saves a round trip and the key exchange on every reconnect, which matters
+/// most to mobile clients on flaky networks.
+///
+/// Sessions are cached by the server name sent with SNI, so connections made without SNI are
+/// not resumed. Clones share the connector and the cache.
+///
+/// OpenSSL guards against truncation attacks by not resuming the session of a connection that
+/// ended without a TLS closing alert, so only the sessions of connections that completed the
+/// WebSocket closing handshake are resumed. Sessions are only cached for handlers that use a
+/// cache to encrypt their connections:
+///
+/// ```no_run
+/// # extern crate openssl;
+/// # extern crate parity_ws;
+/// # extern crate url;
+/// use openssl::ssl::{SslConnector, SslMethod, SslStream};
+/// use parity_ws::util::TcpStream;
+/// use parity_ws::{Handler, Result, TlsSessionCache};
+///
+/// struct Client {
+///     tls: TlsSessionCache,
+/// }
+///
+/// impl Handler for Client {
+///     fn upgrade_ssl_client(
+///         &mut self,
+///         stream: TcpStream,
+///         url: &url::Url,
+///     ) -> Result<SslStream<TcpStream>> {
+///         self.tls.connect(url.domain().unwrap_or_default(), stream)
+///     }
+/// }
+///
+/// # fn main() {
+/// let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+/// builder.set_ca_file("ca.pem").unwrap();
+/// let tls = TlsSessionCache::new(builder, 16);
+/// parity_ws::connect("wss://example.com", |_| Client { tls: tls.clone() }).unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct TlsSessionCache {
+    connector: SslConnector,
+    sessions: Arc<Mutex<Sessions>>,
+}
+
+impl TlsSessionCache {
+    /// Build a connector from `builder` that caches the sessions of up to `capacity` servers,
+    /// forgetting the oldest ones first.
+    pub fn new(mut builder: SslConnectorBuilder, capacity: usize) -> TlsSessionCache {
+        let sessions = Arc::new(Mutex::new(Sessions {
+            capacity,
+            ..Sessions::default()
+        }));
+        let cached = sessions.clone();
+        builder.set_session_cache_mode(SslSessionCacheMode::CLIENT);
+        // With TLS 1.3, tickets arrive after the handshake, whenever the connection is read.
+        builder.set_new_session_callback(move |ssl, session| {
+            let name = match ssl.servername(NameType::HOST_NAME) {
+                Some(name) => name,
+                None => return,
+            };
+            trace!("Caching TLS session for {}.", name);
+            lock(&cached).insert(name, session);
+        });
+        TlsSessionCache {
+            connector: builder.build(),
+            sessions,
+        }
+    }
+
+    /// The number of servers that a session is cached for.
+    pub fn len(&self) -> usize {
+        lock(&self.sessions).by_name.len()
+    }
+
+    /// Whether no session is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget every cached session, such as after the trusted certificates change.
+    pub fn clear(&self) {
+        let mut sessions = lock(&self.sessions);
+        sessions.by_name.clear();
+        sessions.order.clear();
+    }
+
+    /// The configuration of a connection to `domain`, which resumes the cached session of the
+    /// server if there is one. Use this to change the configuration of a single connection
+    /// before connecting.
+    pub fn configure(&self, domain: &str) -> Result<ConnectConfiguration> {
+        let mut config = self.connector.configure().map_err(upgrade_error)?;
+        let session = lock(&self.sessions).by_name.get(domain).cloned();
+        if let Some(session) = session {
+            debug!("Resuming TLS session with {}.", domain);
+            // The session was negotiated with the context of this connector.
+            unsafe { config.set_session(&session) }.map_err(upgrade_error)?;
+        }
+        Ok(config)
+    }
+
+    /// Encrypt a connection to `domain`, resuming the cached session of the server if there
+    /// is one.
+    pub fn connect(&self, domain: &str, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        self.configure(domain)?
+            .connect(domain, stream)
+            .map_err(Error::from)
+    }
+}
+
+impl fmt::Debug for TlsSessionCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsSessionCache")
+            .field("sessions", &self.len())
+            .finish()
+    }
+}
+
+fn lock(sessions: &Mutex<Sessions>) -> MutexGuard<'_, Sessions> {
+    // A panic while holding the lock can not leave the map itself inconsistent.
+    sessions.lock().unwrap_or_else(|err| err.into_inner())
+}
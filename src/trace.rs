@@ -0,0 +1,133 @@
+use std::fmt;
+
+use result::{Error, Kind, Result};
+use util::Token;
+
+// The envelope of a message without a trace ID is a single zero byte, and that of a traced
+// message is a one followed by the ID in network byte order.
+const UNTRACED: u8 = 0;
+const TRACED: u8 = 1;
+const ID_LEN: usize = 16;
+
+/// An identifier that follows a message from `Sender::send_traced` through the event loops of
+/// both endpoints, such as the trace ID of a distributed trace.
+///
+/// With `Settings::trace_ids` enabled on both endpoints, the ID travels in a small envelope
+/// around the payload of every data message. While the handler of the receiving endpoint runs
+/// `on_message` for a traced message, the ID is stored in the context of the connection, so
+/// that it can be logged or passed on with the replies:
+///
+/// ```
+/// # use parity_ws::{Handler, Message, Result, Sender, TraceId};
+/// struct Relay {
+///     out: Sender,
+/// }
+///
+/// impl Handler for Relay {
+///     fn on_message(&mut self, msg: Message) -> Result<()> {
+///         match self.out.context().get::<TraceId>() {
+///             Some(trace) => self.out.send_traced(msg, trace),
+///             None => self.out.send(msg),
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceId(pub u128);
+
+// 32 lowercase hexadecimal digits, like the trace ID of a W3C trace context.
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+/// Hooks called on the event loop as traced messages pass through it. See `TraceId`.
+///
+/// The enqueue and flush hooks run for every message sent with `Sender::send_traced`, even when
+/// `Settings::trace_ids` is disabled and the ID stays on this endpoint. The deliver hook runs for
+/// every received message that carries a trace ID. All hooks do nothing by default.
+pub trait Tracer: Sync {
+    /// The message was framed and added to the outgoing buffer of the connection.
+    fn on_enqueue(&self, _token: Token, _trace: TraceId) {}
+
+    /// The last byte of the message was written to the socket of the connection.
+    fn on_flush(&self, _token: Token, _trace: TraceId) {}
+
+    /// The message was received and is about to be passed to `Handler::on_message`.
+    fn on_deliver(&self, _token: Token, _trace: TraceId) {}
+}
+
+impl fmt::Debug for dyn Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tracer")
+    }
+}
+
+// Put the envelope around an outgoing payload.
+pub fn wrap(payload: &mut Vec<u8>, trace: Option<TraceId>) {
+    match trace {
+        Some(TraceId(id)) => {
+            let mut head = [TRACED; 1 + ID_LEN];
+            head[1..].copy_from_slice(&id.to_be_bytes());
+            payload.splice(0..0, head.iter().cloned());
+        }
+        None => payload.insert(0, UNTRACED),
+    }
+}
+
+// Take the envelope off an incoming payload.
+pub fn unwrap(payload: &mut Vec<u8>) -> Result<Option<TraceId>> {
+    match payload.first() {
+        Some(&UNTRACED) => {
+            payload.remove(0);
+            Ok(None)
+        }
+        Some(&TRACED) if payload.len() > ID_LEN => {
+            let mut id = [0; ID_LEN];
+            id.copy_from_slice(&payload[1..=ID_LEN]);
+            payload.drain(..=ID_LEN);
+            Ok(Some(TraceId(u128::from_be_bytes(id))))
+        }
+        _ => Err(Error::new(
+            Kind::Protocol,
+            "Received a message without a valid trace envelope.",
+        )),
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    #[test]
+    fn envelope() {
+        let mut payload = b"hello".to_vec();
+        wrap(&mut payload, None);
+        assert_eq!(payload, b"\x00hello");
+        assert_eq!(unwrap(&mut payload).unwrap(), None);
+        assert_eq!(payload, b"hello");
+
+        wrap(&mut payload, Some(TraceId(0x0102)));
+        assert_eq!(payload.len(), 22);
+        assert_eq!(&payload[15..], b"\x01\x02hello");
+        assert_eq!(unwrap(&mut payload).unwrap(), Some(TraceId(0x0102)));
+        assert_eq!(payload, b"hello");
+    }
+
+    #[test]
+    fn invalid_envelope() {
+        assert!(unwrap(&mut Vec::new()).is_err());
+        assert!(unwrap(&mut vec![2, 0]).is_err());
+        assert!(unwrap(&mut vec![1; 16]).is_err());
+        assert_eq!(
+            unwrap(&mut vec![1; 17]).unwrap(),
+            Some(TraceId(0x0101_0101_0101_0101_0101_0101_0101_0101))
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(TraceId(0xab).to_string(), "000000000000000000000000000000ab");
+    }
+}
@@ -0,0 +1,77 @@
+use std::fmt;
+
+use protocol::OpCode;
+use result::Result;
+
+/// A transformation applied to the payload of every data message on a connection.
+///
+/// Outgoing payloads are encoded before they are framed and handed to extensions such as
+/// permessage-deflate, and incoming payloads are decoded once the whole message has been
+/// received, before text is validated as UTF-8. Both run on the event loop and work on the
+/// payload in place, so a transformation that keeps the length of the data never allocates.
+/// Both endpoints must use the same transformation.
+///
+/// A pair of closures can be used as a transformation, the first encoding and the second
+/// decoding:
+///
+/// ```
+/// # use parity_ws::{Result, Settings, Transform};
+/// fn flip(payload: &mut Vec<u8>) -> Result<()> {
+///     payload.iter_mut().for_each(|byte| *byte = !*byte);
+///     Ok(())
+/// }
+///
+/// static FLIP: (fn(&mut Vec<u8>) -> Result<()>, fn(&mut Vec<u8>) -> Result<()>) = (flip, flip);
+///
+/// let mut settings = Settings::default();
+/// settings.transform = Some(&FLIP);
+/// ```
+pub trait Transform: Sync {
+    /// Encode the payload of an outgoing message with the given opcode.
+    fn encode(&self, opcode: OpCode, payload: &mut Vec<u8>) -> Result<()>;
+
+    /// Decode the payload of an incoming message with the given opcode. An error closes the
+    /// connection.
+    fn decode(&self, opcode: OpCode, payload: &mut Vec<u8>) -> Result<()>;
+}
+
+impl<E, D> Transform for (E, D)
+where
+    E: Fn(&mut Vec<u8>) -> Result<()> + Sync,
+    D: Fn(&mut Vec<u8>) -> Result<()> + Sync,
+{
+    fn encode(&self, _: OpCode, payload: &mut Vec<u8>) -> Result<()> {
+        (self.0)(payload)
+    }
+
+    fn decode(&self, _: OpCode, payload: &mut Vec<u8>) -> Result<()> {
+        (self.1)(payload)
+    }
+}
+
+impl fmt::Debug for dyn Transform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Transform")
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use result::{Error, Kind};
+
+    #[test]
+    fn closure_pair() {
+        let transform = (
+            |payload: &mut Vec<u8>| {
+                payload.reverse();
+                Ok(())
+            },
+            |_: &mut Vec<u8>| Err(Error::new(Kind::Protocol, "Bad envelope.")),
+        );
+        let mut payload = b"abc".to_vec();
+        transform.encode(OpCode::Text, &mut payload).unwrap();
+        assert_eq!(payload, b"cba");
+        assert!(transform.decode(OpCode::Text, &mut payload).is_err());
+    }
+}
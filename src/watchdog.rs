@@ -0,0 +1,122 @@
+use std::cmp::max;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use logging::{self, WsLogger};
+
+/// Called from a separate thread when the event loop stops making progress.
+///
+/// The event loop ticks after every batch of events and at least twice per
+/// `Settings::watchdog_timeout_ms` while it is idle. When it has not ticked for that long, most
+/// likely because a handler blocks or deadlocks, the watchdog is called with the time since the
+/// last tick. It is called once per stall, and again only after the loop has ticked in between.
+///
+/// Any function taking the duration of the stall can be used as a watchdog:
+///
+/// ```
+/// # use std::time::Duration;
+/// # use parity_ws::Settings;
+/// fn alert(stalled: Duration) {
+///     eprintln!("The event loop has been stuck for {:?}.", stalled);
+/// }
+///
+/// static ALERT: fn(Duration) = alert;
+///
+/// let mut settings = Settings::default();
+/// settings.watchdog = Some(&ALERT);
+/// settings.watchdog_timeout_ms = 1000;
+/// ```
+pub trait Watchdog: Sync {
+    /// The event loop has not ticked for `stalled`.
+    fn on_stall(&self, stalled: Duration);
+}
+
+impl<F> Watchdog for F
+where
+    F: Fn(Duration) + Sync,
+{
+    fn on_stall(&self, stalled: Duration) {
+        self(stalled)
+    }
+}
+
+impl fmt::Debug for dyn Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Watchdog")
+    }
+}
+
+// The event loop's end of a watchdog thread, which stops the thread when dropped.
+pub struct Monitor {
+    ticks: Arc<AtomicUsize>,
+    _stop: mpsc::Sender<()>,
+}
+
+impl Monitor {
+    pub fn start(
+        watchdog: &'static dyn Watchdog,
+        timeout: Duration,
+        logger: Option<&'static dyn WsLogger>,
+    ) -> Monitor {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let (stop, stopped) = mpsc::channel::<()>();
+        let seen = ticks.clone();
+        let check = max(timeout / 4, Duration::from_millis(1));
+        thread::spawn(move || {
+            let _logger = logging::scope(logger);
+            let mut last = seen.load(Ordering::Relaxed);
+            let mut since = Instant::now();
+            let mut fired = false;
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(check) {
+                let now = seen.load(Ordering::Relaxed);
+                if now != last {
+                    last = now;
+                    since = Instant::now();
+                    fired = false;
+                } else if !fired && since.elapsed() >= timeout {
+                    error!("Event loop has not ticked for {:?}.", since.elapsed());
+                    watchdog.on_stall(since.elapsed());
+                    fired = true;
+                }
+            }
+        });
+        Monitor { ticks, _stop: stop }
+    }
+
+    #[inline]
+    pub fn tick(&self) {
+        self.ticks.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    static STALLS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(_: Duration) {
+        STALLS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    static COUNT: fn(Duration) = count;
+
+    #[test]
+    fn fires_once_per_stall() {
+        let monitor = Monitor::start(&COUNT, Duration::from_millis(50), None);
+        for _ in 0..10 {
+            monitor.tick();
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(STALLS.load(Ordering::SeqCst), 0);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(STALLS.load(Ordering::SeqCst), 1);
+        monitor.tick();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(STALLS.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,153 @@
+use std::io;
+use std::mem::take;
+
+use communication::DataSender;
+use result::Kind;
+
+/// Adapts a connection to `std::io::Write`, so that code which produces its output through a
+/// writer, such as a log shipper or an encoder, can send it over a WebSocket.
+///
+/// Every message is sent as a binary message. By default each call to `write` sends its buffer
+/// as one message. A writer created with `MessageWriter::buffered` instead collects the written
+/// bytes until `flush` is called, which marks the end of a message. A buffered writer sends
+/// anything left over when it is dropped.
+///
+/// ```
+/// # use parity_ws::{MessageWriter, Sender};
+/// # use std::io::Write;
+/// # fn ship(out: &Sender) -> std::io::Result<()> {
+/// let mut writer = MessageWriter::buffered(out.data());
+/// write!(writer, "{} + {} = ", 1, 2)?;
+/// write!(writer, "{}", 3)?;
+/// // "1 + 2 = 3" is sent as a single message
+/// writer.flush()
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MessageWriter {
+    sender: DataSender,
+    buffer: Option<Vec<u8>>,
+}
+
+impl MessageWriter {
+    /// Create a writer that sends each write as a separate message.
+    pub fn new<S>(sender: S) -> MessageWriter
+    where
+        S: Into<DataSender>,
+    {
+        MessageWriter {
+            sender: sender.into(),
+            buffer: None,
+        }
+    }
+
+    /// Create a writer that sends everything written between calls to `flush` as one message.
+    pub fn buffered<S>(sender: S) -> MessageWriter
+    where
+        S: Into<DataSender>,
+    {
+        MessageWriter {
+            sender: sender.into(),
+            buffer: Some(Vec::new()),
+        }
+    }
+
+    /// The sender that messages are sent through.
+    pub fn sender(&self) -> &DataSender {
+        &self.sender
+    }
+
+    fn send(&self, data: Vec<u8>) -> io::Result<()> {
+        self.sender.send(data).map_err(|err| match err.kind {
+            Kind::Io(err) => err,
+            _ => io::Error::other(err.to_string()),
+        })
+    }
+}
+
+impl io::Write for MessageWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref mut buffer) = self.buffer {
+            buffer.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+        self.send(buf.to_vec())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let data = match self.buffer {
+            Some(ref mut buffer) if !buffer.is_empty() => take(buffer),
+            _ => return Ok(()),
+        };
+        self.send(data)
+    }
+}
+
+impl Drop for MessageWriter {
+    fn drop(&mut self) {
+        if let Err(err) = io::Write::flush(self) {
+            debug!("Unable to send buffered message: {}", err);
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use std::io::Write;
+
+    use mio;
+    use communication::{Command, Sender, Signal};
+    use message::Message;
+    use util::Token;
+
+    fn writer(buffered: bool) -> (MessageWriter, mio::channel::Receiver<Command>) {
+        let (tx, rx) = mio::channel::sync_channel(10);
+        let sender = Sender::new(Token(1), tx, 0);
+        let writer = if buffered {
+            MessageWriter::buffered(sender)
+        } else {
+            MessageWriter::new(sender)
+        };
+        (writer, rx)
+    }
+
+    fn messages(rx: &mio::channel::Receiver<Command>) -> Vec<Message> {
+        let mut messages = Vec::new();
+        while let Ok(cmd) = rx.try_recv() {
+            match cmd.into_signal() {
+                Signal::Message(msg) => messages.push(msg),
+                signal => panic!("unexpected signal {:?}", signal),
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn message_per_write() {
+        let (mut writer, rx) = writer(false);
+        writer.write_all(b"one").unwrap();
+        writer.write_all(b"two").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(
+            messages(&rx),
+            vec![Message::binary("one"), Message::binary("two")]
+        );
+    }
+
+    #[test]
+    fn message_per_flush() {
+        let (mut writer, rx) = writer(true);
+        write!(writer, "{}-{}", 1, 2).unwrap();
+        assert!(messages(&rx).is_empty());
+        writer.flush().unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"rest").unwrap();
+        drop(writer);
+        assert_eq!(
+            messages(&rx),
+            vec![Message::binary("1-2"), Message::binary("rest")]
+        );
+    }
+}
@@ -0,0 +1,46 @@
+extern crate parity_ws as ws;
+
+use std::net::TcpStream;
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, Settings};
+
+#[test]
+fn throttles_accepts() {
+    let (tx, accepted) = channel();
+    let tx = Mutex::new(tx);
+    let mut settings = Settings::default();
+    settings.max_accepts_per_sec = Some(10);
+    settings.accept_burst = 5;
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(move |_| {
+            tx.lock().unwrap().send(Instant::now()).unwrap();
+            |_| Ok(())
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+
+    // The connections wait in the backlog until they are accepted.
+    let start = Instant::now();
+    let _streams: Vec<_> = (0..25)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+    let times: Vec<Duration> = (0..25)
+        .map(|_| accepted.recv_timeout(Duration::from_secs(10)).unwrap() - start)
+        .collect();
+
+    // A second's worth and the burst are accepted at once, and the rest at the rate.
+    let early = times
+        .iter()
+        .filter(|&&time| time < Duration::from_millis(300))
+        .count();
+    assert!((15..=18).contains(&early), "{:?}", times);
+    assert!(times[24] >= Duration::from_millis(800), "{:?}", times);
+}
@@ -0,0 +1,204 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use ws::middleware::{Resumption, RetryPolicy};
+use ws::sync::Client;
+use ws::{CloseCode, Handler, HandlerExt, Handshake, Message, Result, Sender, WebSocket};
+
+struct Commander {
+    out: Sender,
+    events: Channel<String>,
+}
+
+impl Handler for Commander {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send_reliable("first").map(|_| ())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(msg.to_string()).unwrap();
+        self.out.send_reliable("second").map(|_| ())
+    }
+
+    fn on_ack_timeout(&mut self, id: u64, msg: Message) -> Result<()> {
+        self.events.send(format!("timeout {} {}", id, msg)).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn reliable_messages() {
+    let (events_tx, events) = channel();
+    let server = WebSocket::new(move |out: Sender| {
+        Commander {
+            out: out.clone(),
+            events: events_tx.clone(),
+        }.with_acks(&out, RetryPolicy::new(Duration::from_millis(100), 2))
+    }).unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    // The client speaks the envelope format by hand, so that it can lose messages.
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    for _ in 0..2 {
+        assert_eq!(client.recv().unwrap(), Message::text("\0rel:0\nfirst"));
+    }
+    assert_eq!(events.recv().unwrap(), "timeout 0 first");
+
+    // A redelivered message is acknowledged again, but only handled once.
+    client.send("\0rel:7\nhello").unwrap();
+    client.send("\0rel:7\nhello").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("\0ack:7"));
+    assert_eq!(client.recv().unwrap(), Message::text("\0rel:1\nsecond"));
+    assert_eq!(client.recv().unwrap(), Message::text("\0ack:7"));
+    assert_eq!(events.recv().unwrap(), "hello");
+
+    // An acknowledged message is not sent again.
+    client.send("\0ack:1").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(500)));
+    assert!(client.recv().is_err());
+    assert!(events.try_recv().is_err());
+
+    client.close(CloseCode::Normal).unwrap();
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+struct Ledger {
+    events: Channel<String>,
+}
+
+impl Handler for Ledger {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(format!("execute {}", msg)).unwrap();
+        Ok(())
+    }
+
+    fn on_replay_detected(&mut self, id: u64, msg: Message) -> Result<()> {
+        self.events.send(format!("replay {} {}", id, msg)).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn replay_window() {
+    let (events_tx, events) = channel();
+    let server = WebSocket::new(move |out: Sender| {
+        Ledger {
+            events: events_tx.clone(),
+        }.with_acks(&out, RetryPolicy::new(Duration::from_millis(100), 2))
+            .replay_window(2)
+    }).unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    for command in &["0\npay 10", "1\npay 20", "0\npay 10", "2\npay 30", "0\npay 10"] {
+        client.send(format!("\0rel:{}", command)).unwrap();
+    }
+    for _ in 0..5 {
+        assert!(client.recv().unwrap().as_text().unwrap().starts_with("\0ack:"));
+    }
+    assert_eq!(events.recv().unwrap(), "execute pay 10");
+    assert_eq!(events.recv().unwrap(), "execute pay 20");
+    assert_eq!(events.recv().unwrap(), "replay 0 pay 10");
+    assert_eq!(events.recv().unwrap(), "execute pay 30");
+    // Once it is out of the window, the message is rejected without having to be remembered.
+    assert_eq!(events.recv().unwrap(), "replay 0 pay 10");
+
+    client.close(CloseCode::Normal).unwrap();
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+// Stores the position of its session when the connection closes, and resumes the stored session
+// when a connection opens.
+struct Resumable {
+    out: Sender,
+    store: Arc<Mutex<Option<Vec<u8>>>>,
+    events: Channel<String>,
+}
+
+impl Handler for Resumable {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        if let Some(ref blob) = *self.store.lock().unwrap() {
+            self.out.resume(&Resumption::decode(blob)?)?;
+        }
+        self.out.send_reliable("hello").map(|_| ())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(msg.to_string()).unwrap();
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        let blob = self.out.resumption().unwrap().encode();
+        *self.store.lock().unwrap() = Some(blob);
+        self.events.send("stored".into()).unwrap();
+    }
+}
+
+#[test]
+fn resumed_session() {
+    let (events_tx, events) = channel();
+    let store = Arc::new(Mutex::new(None));
+    let mut addrs = Vec::new();
+    let mut servers = Vec::new();
+    for _ in 0..2 {
+        let events_tx = events_tx.clone();
+        let store = store.clone();
+        let server = WebSocket::new(move |out: Sender| {
+            Resumable {
+                out: out.clone(),
+                store: store.clone(),
+                events: events_tx.clone(),
+            }.with_acks(&out, RetryPolicy::new(Duration::from_secs(10), 2))
+        }).unwrap()
+            .bind("127.0.0.1:0")
+            .unwrap();
+        addrs.push(server.local_addr().unwrap());
+        let broadcaster = server.broadcaster();
+        servers.push((broadcaster, thread::spawn(move || server.run().unwrap())));
+    }
+
+    let mut client = Client::connect(format!("ws://{}", addrs[0])).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("\0rel:0\nhello"));
+    client.send("\0ack:0").unwrap();
+    client.send("\0rel:0\nfirst").unwrap();
+    client.send("\0rel:1\nsecond").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("\0ack:0"));
+    assert_eq!(client.recv().unwrap(), Message::text("\0ack:1"));
+    assert_eq!(events.recv().unwrap(), "first");
+    assert_eq!(events.recv().unwrap(), "second");
+    client.close(CloseCode::Normal).unwrap();
+    assert_eq!(events.recv().unwrap(), "stored");
+
+    // The other server numbers its messages on from the first one, and does not handle the
+    // message that the first one already handled again.
+    let mut client = Client::connect(format!("ws://{}", addrs[1])).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("\0rel:1\nhello"));
+    client.send("\0ack:1").unwrap();
+    client.send("\0rel:1\nsecond").unwrap();
+    client.send("\0rel:2\nthird").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("\0ack:1"));
+    assert_eq!(client.recv().unwrap(), Message::text("\0ack:2"));
+    assert_eq!(events.recv().unwrap(), "third");
+    client.close(CloseCode::Normal).unwrap();
+    assert_eq!(events.recv().unwrap(), "stored");
+
+    for (broadcaster, server) in servers {
+        broadcaster.shutdown().unwrap();
+        server.join().unwrap();
+    }
+}
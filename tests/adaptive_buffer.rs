@@ -0,0 +1,54 @@
+extern crate parity_ws as ws;
+
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{Builder, Sender, Settings};
+
+fn in_capacity(broadcaster: &Sender) -> usize {
+    let state = broadcaster
+        .dump_state()
+        .unwrap()
+        .wait_timeout(Duration::from_secs(5))
+        .unwrap();
+    state.connections[0].in_capacity
+}
+
+#[test]
+fn tunes_to_received_frames() {
+    let mut settings = Settings::default();
+    settings.in_buffer_capacity = 65_536;
+    settings.in_buffer_adaptive = true;
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    thread::spawn(move || server.run().unwrap());
+
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    assert_eq!(in_capacity(&broadcaster), 65_536);
+
+    // Small messages shrink the buffer down to the minimum.
+    for _ in 0..20 {
+        client.send(vec![0u8; 100]).unwrap();
+        client.recv().unwrap();
+    }
+    assert_eq!(in_capacity(&broadcaster), 512);
+
+    // Once enough of the recent frames are larger, the buffer follows them.
+    for _ in 0..20 {
+        client.send(vec![0u8; 3000]).unwrap();
+        client.recv().unwrap();
+    }
+    assert_eq!(in_capacity(&broadcaster), 4096);
+
+    // A single large message is not enough to keep a large buffer.
+    client.send(vec![0u8; 20_000]).unwrap();
+    assert_eq!(client.recv().unwrap().len(), 20_000);
+    assert_eq!(in_capacity(&broadcaster), 4096);
+}
@@ -0,0 +1,68 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{AuditOutcome, AuditRecord, Authorization, Builder, CloseCode, Credentials};
+
+fn authorize(credentials: &Credentials) -> Authorization {
+    if credentials.request().resource() == "/private" {
+        Authorization::Deny { status: 403 }
+    } else {
+        Authorization::Allow
+    }
+}
+
+static AUTHORIZER: fn(&Credentials) -> Authorization = authorize;
+
+fn serve() -> (String, Receiver<AuditRecord>) {
+    let (tx, rx) = channel();
+    let tx = Mutex::new(tx);
+    let sink = move |record: &AuditRecord| tx.lock().unwrap().send(record.clone()).unwrap();
+    let server = Builder::new()
+        .with_authorizer(&AUTHORIZER)
+        .with_audit(Box::leak(Box::new(sink)))
+        .build(|out: ws::Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+    (format!("ws://{}", addr), rx)
+}
+
+#[test]
+fn closed_connection() {
+    let (url, records) = serve();
+    let mut client = Client::connect(format!("{}/feed", url)).unwrap();
+    client.send("hello").unwrap();
+    client.recv().unwrap();
+    client.close(CloseCode::Normal).unwrap();
+
+    let record = records.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(record.outcome, AuditOutcome::Closed);
+    assert_eq!(record.resource.as_ref().map(|r| &r[..]), Some("/feed"));
+    assert_eq!(record.status, Some(101));
+    assert_eq!(record.close_code, Some(CloseCode::Normal));
+    assert!(!record.client);
+    assert!(record.peer_addr.is_some());
+    // The handshake and the frames of the message and the close are counted.
+    assert!(record.bytes_received > 150, "{}", record.bytes_received);
+    assert!(record.bytes_sent > 130, "{}", record.bytes_sent);
+    assert!(record.to_json().contains("\"outcome\":\"closed\""));
+}
+
+#[test]
+fn rejected_connection() {
+    let (url, records) = serve();
+    assert!(Client::connect(format!("{}/private", url)).is_err());
+
+    let record = records.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(record.outcome, AuditOutcome::Rejected);
+    assert_eq!(record.status, Some(403));
+    assert_eq!(record.resource, None);
+    assert_eq!(record.close_code, None);
+}
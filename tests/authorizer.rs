@@ -0,0 +1,137 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{Authorization, Builder, Credentials, Handler, Request, Response, Result, Sender};
+
+#[derive(Debug, Clone, PartialEq)]
+struct User(String);
+
+fn authorize(credentials: &Credentials) -> Authorization {
+    match credentials.request().header("authorization") {
+        Some(token) if token == b"Bearer alice" => {
+            credentials.context().insert(User("alice".into()));
+            Authorization::Allow
+        }
+        Some(_) => Authorization::Deny { status: 403 },
+        None => Authorization::Challenge {
+            headers: vec![("WWW-Authenticate".into(), b"Bearer".to_vec())],
+        },
+    }
+}
+
+static AUTHORIZER: fn(&Credentials) -> Authorization = authorize;
+
+struct Server {
+    out: Sender,
+    users: mpsc::Sender<Option<User>>,
+}
+
+impl Handler for Server {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        self.users.send(self.out.context().get::<User>()).unwrap();
+        Response::from_request(req)
+    }
+}
+
+// Send a handshake request with the given extra headers and read the head of the response.
+fn handshake(addr: std::net::SocketAddr, headers: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\
+         {}\r\n",
+        headers
+    )
+    .unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+#[test]
+fn authorized_handshakes() {
+    let (users, seen) = mpsc::channel();
+    let server = Builder::new()
+        .with_authorizer(&AUTHORIZER)
+        .build(move |out| Server {
+            out,
+            users: users.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let head = handshake(addr, "");
+    assert!(head.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    assert!(head.contains("WWW-Authenticate: Bearer\r\n"));
+
+    let head = handshake(addr, "Authorization: Bearer mallory\r\n");
+    assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+    // Only the allowed request reaches the handler, which finds what the authorizer left.
+    let head = handshake(addr, "Authorization: Bearer alice\r\n");
+    assert!(head.starts_with("HTTP/1.1 101 "));
+    assert_eq!(seen.recv().unwrap(), Some(User("alice".into())));
+    assert!(seen.try_recv().is_err());
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+// An authorizer that owns its state, which does not have to live for the rest of the process.
+struct Token(String);
+
+impl ws::Authorizer for Token {
+    fn authorize(&self, credentials: &Credentials) -> Authorization {
+        match credentials.request().header("authorization") {
+            Some(token) if token == self.0.as_bytes() => Authorization::Allow,
+            _ => Authorization::Deny { status: 403 },
+        }
+    }
+}
+
+#[test]
+fn owned_authorizer() {
+    let (users, seen) = mpsc::channel();
+    let mut server = Builder::new()
+        .build(move |out| Server {
+            out,
+            users: users.clone(),
+        })
+        .unwrap();
+    server.with_authorizer(Token(String::from("Bearer bob")));
+    let server = server.bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let head = handshake(addr, "Authorization: Bearer alice\r\n");
+    assert!(head.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+    let head = handshake(addr, "Authorization: Bearer bob\r\n");
+    assert!(head.starts_with("HTTP/1.1 101 "));
+    assert_eq!(seen.recv().unwrap(), None);
+    assert!(seen.try_recv().is_err());
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
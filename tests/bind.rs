@@ -1,10 +1,20 @@
 extern crate parity_ws as ws;
 
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener as StdTcpListener, TcpStream};
+use std::thread;
 
 struct Handler;
 impl ws::Handler for Handler {}
 
+struct Client {
+    out: ws::Sender,
+}
+impl ws::Handler for Client {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        self.out.close(ws::CloseCode::Normal)
+    }
+}
+
 #[test]
 fn bind_port_zero() {
     let ws = ws::WebSocket::new(|_sender| Handler).unwrap();
@@ -29,3 +39,76 @@ fn bind_try_multiple_addrs() {
     let local_addr = ws.local_addr().unwrap();
     assert_eq!(valid_addr, local_addr);
 }
+
+#[test]
+fn bind_all_addrs() {
+    let addrs: Vec<SocketAddr> = vec![
+        "127.0.0.1:0".parse().unwrap(),
+        "127.0.0.1:0".parse().unwrap(),
+    ];
+
+    let ws = ws::WebSocket::new(|_sender| Handler).unwrap();
+    let ws = ws.bind_all(&addrs[..]).unwrap();
+
+    let local_addrs = ws.local_addrs().unwrap();
+    assert_eq!(local_addrs.len(), 2);
+    assert_ne!(local_addrs[0].port(), local_addrs[1].port());
+    assert_eq!(ws.local_addr().unwrap(), local_addrs[0]);
+
+    let shutdown = ws.broadcaster();
+    let server = thread::spawn(move || {
+        ws.run().unwrap();
+    });
+    for addr in &local_addrs {
+        ws::connect(format!("ws://{}", addr), |out| Client { out }).unwrap();
+    }
+    shutdown.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn bind_all_fails_on_any_error() {
+    let valid_addr = "127.0.0.1:0".parse().unwrap();
+    let invalid_addr = "99.99.99.99:0".parse().unwrap();
+    let addrs: Vec<SocketAddr> = vec![valid_addr, invalid_addr];
+
+    let ws = ws::WebSocket::new(|_sender| Handler).unwrap();
+    assert!(ws.bind_all(&addrs[..]).is_err());
+}
+
+// Skipped on hosts without IPv6.
+#[test]
+fn ipv6_dual_stack() {
+    if StdTcpListener::bind("[::1]:0").is_err() {
+        return;
+    }
+
+    let ws = ws::WebSocket::new(|_sender| Handler)
+        .unwrap()
+        .bind("[::]:0")
+        .unwrap();
+    let port = ws.local_addr().unwrap().port();
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+}
+
+#[test]
+fn ipv6_only() {
+    if StdTcpListener::bind("[::1]:0").is_err() {
+        return;
+    }
+
+    let mut settings = ws::Settings::default();
+    settings.ipv6_only = true;
+    let ws = ws::Builder::new()
+        .with_settings(settings)
+        .build(|_sender| Handler)
+        .unwrap()
+        .bind("[::]:0")
+        .unwrap();
+    let port = ws.local_addr().unwrap().port();
+    assert!(TcpStream::connect(("127.0.0.1", port)).is_err());
+
+    // The IPv4 side of the port is still free.
+    let ws = ws.bind(("0.0.0.0", port)).unwrap();
+    assert_eq!(ws.local_addrs().unwrap().len(), 2);
+}
@@ -0,0 +1,52 @@
+extern crate parity_ws as ws;
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Result, Sender, Settings};
+
+struct Reporter {
+    out: Sender,
+    peers: Channel<Option<IpAddr>>,
+}
+
+impl Handler for Reporter {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.peers.send(shake.peer_addr.map(|addr| addr.ip())).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+// The whole of 127.0.0.0/8 is routed to the loopback interface on Linux.
+#[cfg(target_os = "linux")]
+#[test]
+fn binds_client_to_local_address() {
+    let (tx, peers) = channel();
+    let server = Builder::new()
+        .build(move |out| Reporter {
+            out,
+            peers: tx.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+
+    let mut settings = Settings::default();
+    settings.bind_local_addr = Some("127.0.0.2:0".parse().unwrap());
+    let mut client = Builder::new()
+        .with_settings(settings)
+        .build(|_| |_| Ok(()))
+        .unwrap();
+    client
+        .connect(format!("ws://{}", addr).parse().unwrap())
+        .unwrap();
+    let client = thread::spawn(move || client.run().unwrap());
+
+    let peer = peers.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(peer, Some(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))));
+    client.join().unwrap();
+}
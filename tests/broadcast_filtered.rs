@@ -0,0 +1,53 @@
+extern crate parity_ws as ws;
+
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{CloseCode, Message, Sender, WebSocket};
+
+struct Authenticated;
+
+#[test]
+fn broadcast_to_selected_connections() {
+    let server = WebSocket::new(|out: Sender| {
+        move |msg: Message| {
+            // Any message authenticates the connection.
+            out.context().insert(Authenticated);
+            out.send(msg)
+        }
+    }).unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let connect = |resource: &str| {
+        let mut client = Client::connect(format!("ws://{}{}", addr, resource)).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(500)));
+        client
+    };
+    let mut feed = connect("/feed");
+    let mut anonymous = connect("/feed");
+    let mut other = connect("/other");
+    for client in [&mut feed, &mut other].iter_mut() {
+        client.send("login").unwrap();
+        assert_eq!(client.recv().unwrap(), Message::text("login"));
+    }
+
+    broadcaster
+        .broadcast_filtered("update", |conn| {
+            conn.resource() == Some("/feed") && conn.context().contains::<Authenticated>()
+        })
+        .unwrap();
+    assert_eq!(feed.recv().unwrap(), Message::text("update"));
+    assert!(anonymous.recv().is_err());
+    assert!(other.recv().is_err());
+
+    feed.close(CloseCode::Normal).unwrap();
+    anonymous.close(CloseCode::Normal).unwrap();
+    other.close(CloseCode::Normal).unwrap();
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
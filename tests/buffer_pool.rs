@@ -0,0 +1,40 @@
+extern crate parity_ws as ws;
+
+use std::thread;
+
+use ws::sync::Client;
+use ws::{Builder, CloseCode, Message, Sender, Settings};
+
+#[test]
+fn reused_buffers_start_empty() {
+    let mut settings = Settings::default();
+    settings.buffer_pool_size = 4;
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // Every connection after the first gets the buffers of one that closed before it, except
+    // after the large message, whose buffers grew and are not kept.
+    for i in 0..20 {
+        let mut client = Client::connect(url.as_str()).unwrap();
+        let msg = if i == 10 {
+            Message::binary(vec![7; 100_000])
+        } else {
+            Message::text(format!("message {}", i))
+        };
+        client.send(msg.clone()).unwrap();
+        assert_eq!(client.recv().unwrap(), msg);
+        client.close(CloseCode::Normal).unwrap();
+    }
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
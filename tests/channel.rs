@@ -0,0 +1,91 @@
+extern crate parity_ws as ws;
+
+use std::net::TcpListener;
+use std::thread;
+
+use ws::{connect_channel, CloseCode, Event, Handler, Handshake, Message, Result, WebSocket};
+
+#[test]
+fn receive_events() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut ws = WebSocket::new(|out: ws::Sender| {
+            move |msg| {
+                out.send(msg)?;
+                out.close_with_reason(CloseCode::Away, "done")
+            }
+        }).unwrap();
+        ws.serve_stream(stream).unwrap();
+        ws.run().unwrap();
+    });
+
+    let (out, events) = connect_channel(url, 1).unwrap();
+    let mut seen = Vec::new();
+    for event in events {
+        match event {
+            Event::Open(shake) => {
+                assert_eq!(shake.request.resource(), "/");
+                out.send("echo").unwrap();
+                seen.push("open");
+            }
+            Event::Message(msg) => {
+                assert_eq!(msg, Message::text("echo"));
+                seen.push("message");
+            }
+            Event::Close { code, reason } => {
+                assert_eq!((code, reason.as_str()), (CloseCode::Away, "done"));
+                seen.push("close");
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    assert_eq!(seen, vec!["open", "message", "close"]);
+    server.join().unwrap();
+}
+
+struct Farewell {
+    out: ws::Sender,
+}
+
+impl Handler for Farewell {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send_then_close("bye", CloseCode::Away, "done")
+    }
+}
+
+#[test]
+fn final_message() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut ws = WebSocket::new(|out| Farewell { out }).unwrap();
+        ws.serve_stream(stream).unwrap();
+        ws.run().unwrap();
+    });
+
+    let (_, events) = connect_channel(url, 1).unwrap();
+    let events = events
+        .into_iter()
+        .filter_map(|event| match event {
+            Event::Open(_) => None,
+            Event::Message(msg) => Some(msg.to_string()),
+            Event::Close { code, reason } => Some(format!("{:?} {}", code, reason)),
+            event => panic!("unexpected event {:?}", event),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(events, vec!["bye", "Away done"]);
+    server.join().unwrap();
+}
+
+#[test]
+fn connection_refused() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (_, events) = connect_channel(format!("ws://{}", addr), 1).unwrap();
+    match events.recv().unwrap() {
+        Event::Error(_) => (),
+        event => panic!("unexpected event {:?}", event),
+    }
+}
@@ -0,0 +1,116 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+
+use ws::checksum::ChecksumHandler;
+use ws::{
+    CloseCode, Frame, Handler, Handshake, Message, Request, Response, Result, Sender, WebSocket,
+};
+
+fn echo_server() -> (String, Sender, thread::JoinHandle<()>) {
+    let server = WebSocket::new(|out: Sender| ChecksumHandler::new(move |msg| out.send(msg)))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    (url, broadcaster, thread)
+}
+
+struct Client {
+    out: Sender,
+    events: mpsc::Sender<String>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("hello")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(msg.to_string()).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.events.send(format!("{:?}", code)).unwrap();
+    }
+}
+
+// Agrees on the extension, but appends a checksum that does not match.
+struct Corrupt(Sender);
+
+impl Handler for Corrupt {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let mut res = Response::from_request(req)?;
+        res.add_extension("permessage-checksum; algorithm=crc32c");
+        Ok(res)
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.0.send(msg)
+    }
+
+    fn on_frame(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
+        if !frame.is_control() {
+            let len = frame.payload().len() - 4;
+            frame.payload_mut().truncate(len);
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_send_frame(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
+        if !frame.is_control() {
+            frame.payload_mut().extend_from_slice(&[0, 0, 0, 0]);
+        }
+        Ok(Some(frame))
+    }
+}
+
+#[test]
+fn echo() {
+    let (url, broadcaster, server) = echo_server();
+    let (events, received) = mpsc::channel();
+    ws::connect(url, |out| {
+        ChecksumHandler::new(Client {
+            out,
+            events: events.clone(),
+        })
+    })
+    .unwrap();
+    assert_eq!(received.recv().unwrap(), "hello");
+    assert_eq!(received.recv().unwrap(), "Normal");
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn corrupted() {
+    let server = WebSocket::new(Corrupt)
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (events, received) = mpsc::channel();
+    ws::connect(url, |out| {
+        ChecksumHandler::new(Client {
+            out,
+            events: events.clone(),
+        })
+    })
+    .unwrap();
+    assert_eq!(received.recv().unwrap(), "Protocol");
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
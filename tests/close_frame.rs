@@ -0,0 +1,58 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+
+use ws::middleware::HandlerExt;
+use ws::{CloseCode, CloseFrame, Handler, Handshake, Result, Sender, WebSocket};
+
+struct Server(mpsc::Sender<CloseFrame>);
+
+impl Handler for Server {
+    fn on_close_frame(&mut self, frame: &CloseFrame) {
+        self.0.send(frame.clone()).unwrap();
+    }
+}
+
+struct Client(Sender, CloseCode);
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.0.close_with_reason(self.1, "bye")
+    }
+}
+
+fn close(code: CloseCode) -> CloseFrame {
+    let (frames, received) = mpsc::channel();
+    let server = WebSocket::new(move |_| Server(frames.clone()).with_logging())
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    ws::connect(url, |out| Client(out, code)).unwrap();
+    let frame = received.recv().unwrap();
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    frame
+}
+
+#[test]
+fn reason() {
+    assert_eq!(
+        close(CloseCode::Away),
+        CloseFrame::new(CloseCode::Away, "bye").unwrap()
+    );
+}
+
+#[test]
+fn empty() {
+    let frame = close(CloseCode::Empty);
+    assert_eq!(frame.code, CloseCode::Status);
+    assert_eq!(frame.reason, "");
+    assert!(frame.payload.is_empty());
+}
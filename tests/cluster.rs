@@ -0,0 +1,185 @@
+extern crate parity_ws as ws;
+
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::cluster::{Address, Bus, Cluster, ClusterHandle, Placement};
+use ws::sync::Client;
+use ws::{CloseCode, Message, Sender};
+
+// Start a two loop echo cluster on a free port, returning its url.
+fn echo_cluster(placement: Placement) -> (String, ClusterHandle, thread::JoinHandle<()>) {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let cluster = Cluster::new(2, |_| |out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .with_placement(placement);
+    let handle = cluster.handle();
+    let server = thread::spawn(move || cluster.listen(addr).unwrap());
+    (format!("ws://{}", addr), handle, server)
+}
+
+fn connections(handle: &ClusterHandle) -> Vec<usize> {
+    handle
+        .metrics()
+        .iter()
+        .map(|metrics| metrics.connections)
+        .collect()
+}
+
+// Wait for the loops to register their connections.
+fn wait_for(handle: &ClusterHandle, expected: Vec<usize>) {
+    let start = Instant::now();
+    while connections(handle) != expected {
+        assert!(start.elapsed() < Duration::from_secs(5), "{:?}", connections(handle));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn connect(url: &str) -> Client {
+    let start = Instant::now();
+    loop {
+        match Client::connect(url) {
+            Ok(client) => return client,
+            // The cluster may not be listening yet.
+            Err(_) if start.elapsed() < Duration::from_secs(5) => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(err) => panic!("{:?}", err),
+        }
+    }
+}
+
+#[test]
+fn round_robin() {
+    let (url, handle, server) = echo_cluster(Placement::RoundRobin);
+    let mut clients = (0..4).map(|_| connect(&url)).collect::<Vec<_>>();
+    wait_for(&handle, vec![2, 2]);
+
+    for client in clients.iter_mut() {
+        client.send("echo").unwrap();
+        assert_eq!(client.recv().unwrap(), Message::text("echo"));
+    }
+    handle.broadcast("all").unwrap();
+    for client in clients.iter_mut() {
+        assert_eq!(client.recv().unwrap(), Message::text("all"));
+    }
+
+    for client in clients {
+        client.close(CloseCode::Normal).unwrap();
+    }
+    wait_for(&handle, vec![0, 0]);
+    assert!(handle.metrics().iter().all(|metrics| metrics.served == 2));
+    handle.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn peer_addr() {
+    let (url, handle, server) = echo_cluster(Placement::PeerAddr);
+    let clients = (0..3).map(|_| connect(&url)).collect::<Vec<_>>();
+    let mut counts = connections(&handle);
+    let start = Instant::now();
+    while counts.iter().sum::<usize>() < 3 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+        counts = connections(&handle);
+    }
+    // Every connection comes from the same address.
+    counts.sort();
+    assert_eq!(counts, vec![0, 3]);
+
+    drop(clients);
+    handle.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn bus_between_loops() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let peers = Arc::new(Mutex::new(Vec::new()));
+    let cluster = {
+        let peers = peers.clone();
+        Cluster::new(2, move |_| {
+            let peers = peers.clone();
+            move |out: Sender| {
+                let own = out.context().get::<Address>().unwrap();
+                peers.lock().unwrap().push(own);
+                let peers = peers.clone();
+                // Relay every message to all of the other connections, wherever they live.
+                move |msg: Message| {
+                    let bus = out.context().get::<Bus>().unwrap();
+                    for &peer in peers.lock().unwrap().iter().filter(|&&peer| peer != own) {
+                        bus.send(peer, msg.clone())?;
+                    }
+                    Ok(())
+                }
+            }
+        }).unwrap()
+    };
+    let handle = cluster.handle();
+    let server = thread::spawn(move || cluster.listen(addr).unwrap());
+
+    let url = format!("ws://{}", addr);
+    let mut first = connect(&url);
+    let mut second = connect(&url);
+    wait_for(&handle, vec![1, 1]);
+    let peers = peers.lock().unwrap().clone();
+    assert_ne!(peers[0].shard(), peers[1].shard());
+
+    first.send("hello").unwrap();
+    assert_eq!(second.recv().unwrap(), Message::text("hello"));
+
+    second.close(CloseCode::Normal).unwrap();
+    wait_for(&handle, vec![1, 0]);
+    assert!(handle.bus().send(peers[0], "direct").is_ok());
+    assert_eq!(first.recv().unwrap(), Message::text("direct"));
+
+    first.close(CloseCode::Normal).unwrap();
+    handle.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn bus_forgets_closed_connections() {
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let addresses = Arc::new(Mutex::new(Vec::new()));
+    let cluster = {
+        let addresses = addresses.clone();
+        Cluster::new(2, move |_| {
+            let addresses = addresses.clone();
+            move |out: Sender| {
+                addresses
+                    .lock()
+                    .unwrap()
+                    .push(out.context().get::<Address>().unwrap());
+                move |msg| out.send(msg)
+            }
+        }).unwrap()
+    };
+    let handle = cluster.handle();
+    let server = thread::spawn(move || cluster.listen(addr).unwrap());
+
+    let client = connect(&format!("ws://{}", addr));
+    wait_for(&handle, vec![1, 0]);
+    let address = addresses.lock().unwrap()[0];
+    assert!(handle.bus().sender(address).is_some());
+
+    client.close(CloseCode::Normal).unwrap();
+    wait_for(&handle, vec![0, 0]);
+    assert!(handle.bus().sender(address).is_none());
+    assert!(handle.bus().send(address, "gone").is_err());
+
+    handle.shutdown().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,63 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{Builder, CloseCode, Frame, Handler, Handshake, OpCode, Result, Sender, Settings};
+
+// Sends a small, a large and an uncompressed large message, and reports whether each frame may
+// be compressed.
+struct Client {
+    out: Sender,
+    frames: mpsc::Sender<(usize, bool)>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send(vec![0; 10])?;
+        self.out.send(vec![0; 100])?;
+        self.out.send_uncompressed(vec![0; 100])?;
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        if frame.opcode() == OpCode::Binary {
+            self.frames
+                .send((frame.payload().len(), frame.is_compressible()))
+                .unwrap();
+        }
+        Ok(Some(frame))
+    }
+}
+
+#[test]
+fn compress_min_size() {
+    let server = Builder::new()
+        .build(|_| |_| Ok(()))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let mut settings = Settings::default();
+    settings.compress_min_size = 64;
+    let (frames, reported) = mpsc::channel();
+    let mut client = Builder::new()
+        .with_settings(settings)
+        .build(move |out| Client {
+            out,
+            frames: frames.clone(),
+        })
+        .unwrap();
+    client.connect(url.parse().unwrap()).unwrap();
+    client.run().unwrap();
+
+    assert_eq!(
+        reported.iter().collect::<Vec<_>>(),
+        vec![(10, false), (100, true), (100, false)]
+    );
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
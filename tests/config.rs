@@ -0,0 +1,87 @@
+#![cfg(feature = "config")]
+extern crate parity_ws as ws;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Sender, Settings, WebSocket};
+
+fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("ws-{}-{}.toml", name, std::process::id()));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn max_fragment_size(broadcaster: &Sender) -> usize {
+    broadcaster
+        .dump_state()
+        .unwrap()
+        .wait_timeout(Duration::from_secs(5))
+        .unwrap()
+        .settings
+        .max_fragment_size
+}
+
+#[test]
+fn from_toml() {
+    let path = config_file(
+        "from-toml",
+        "# limits\nmax_connections = 5\nclose_linger = 250\n",
+    );
+    let settings = Settings::from_toml(&path).unwrap();
+    assert_eq!(settings.max_connections, 5);
+    assert_eq!(settings.close_linger, Some(Duration::from_millis(250)));
+    assert_eq!(settings.queue_size, Settings::default().queue_size);
+
+    fs::write(&path, "max_connection = 5\n").unwrap();
+    assert!(Settings::from_toml(&path).is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn reload_on_change() {
+    let path = config_file("reload", "max_fragment_size = 1000\n");
+    let mut server = WebSocket::new(|_| |_| Ok(()))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    server.watch_config(&path).unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    assert_eq!(max_fragment_size(&broadcaster), 1000);
+
+    // An invalid change is ignored.
+    fs::write(&path, "max_fragment_size = \"large\"\n").unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(max_fragment_size(&broadcaster), 1000);
+
+    fs::write(&path, "max_fragment_size = 2000\n").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while max_fragment_size(&broadcaster) != 2000 {
+        assert!(Instant::now() < deadline, "settings were not reloaded");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn invalid_file() {
+    let path = config_file("invalid", "[server]\n");
+    let mut server = WebSocket::new(|_| |_| Ok(())).unwrap();
+    assert!(server.watch_config(&path).is_err());
+
+    // Settings other than limits can not be changed on a running server.
+    fs::write(&path, "encrypt_server = true
+").unwrap();
+    assert!(server.watch_config(&path).is_err());
+    assert!(Settings::from_toml(&path).unwrap().encrypt_server);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,60 @@
+extern crate parity_ws as ws;
+
+use std::net::SocketAddr;
+use std::thread;
+
+use ws::sync::Client;
+use ws::{Factory, Handler, Message, Result, Sender, Settings, WebSocket};
+
+struct Echo(Sender);
+
+impl Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.0.send(msg)
+    }
+}
+
+// Gives the first connection a small maximum payload, and every later one the default.
+struct Tiered {
+    accepted: usize,
+}
+
+impl Factory for Tiered {
+    type Handler = Echo;
+
+    fn connection_made(&mut self, out: Sender) -> Echo {
+        Echo(out)
+    }
+
+    fn connection_settings(&mut self, _: SocketAddr, mut settings: Settings) -> Settings {
+        self.accepted += 1;
+        if self.accepted == 1 {
+            settings.max_fragment_size = 16;
+        }
+        settings
+    }
+}
+
+#[test]
+fn limits_differ_per_connection() {
+    let server = WebSocket::new(Tiered { accepted: 0 })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let payload = "x".repeat(100);
+
+    let mut limited = Client::connect(format!("ws://{}", addr)).unwrap();
+    limited.send(payload.as_str()).unwrap();
+    assert!(limited.recv().is_err());
+
+    let mut unlimited = Client::connect(format!("ws://{}", addr)).unwrap();
+    unlimited.send(payload.as_str()).unwrap();
+    assert_eq!(unlimited.recv().unwrap(), Message::text(payload));
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,98 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{Builder, Cors, Handler, Request, Response, Result, Settings};
+
+static CORS: Cors = Cors {
+    origins: &["https://app.example.com"],
+    headers: &["authorization"],
+    credentials: false,
+    max_age: Some(60),
+};
+
+struct Server {
+    requests: mpsc::Sender<String>,
+}
+
+impl Handler for Server {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        self.requests.send(req.method().into()).unwrap();
+        Response::from_request(req)
+    }
+}
+
+// Read the head of an HTTP response.
+fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+#[test]
+fn preflight_and_upgrade() {
+    let mut settings = Settings::default();
+    settings.cors = Some(&CORS);
+    let (requests, received) = mpsc::channel();
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(move |_| Server {
+            requests: requests.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"OPTIONS / HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Origin: https://app.example.com\r\n\
+              Access-Control-Request-Method: GET\r\n\
+              Access-Control-Request-Headers: authorization\r\n\r\n",
+        )
+        .unwrap();
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).unwrap();
+    assert!(answer.starts_with("HTTP/1.1 204 No Content\r\n"));
+    assert!(answer.contains("Access-Control-Allow-Origin: https://app.example.com\r\n"));
+    assert!(answer.contains("Access-Control-Allow-Headers: authorization\r\n"));
+    assert!(answer.contains("Access-Control-Max-Age: 60\r\n"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Origin: https://app.example.com\r\n\
+              Connection: Upgrade\r\n\
+              Upgrade: websocket\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+    let head = read_head(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(head.contains("Access-Control-Allow-Origin: https://app.example.com\r\n"));
+    assert!(!head.contains("Access-Control-Max-Age"));
+    drop(stream);
+
+    // Only the upgrade request reached the handler.
+    assert_eq!(received.try_iter().collect::<Vec<_>>(), vec!["GET"]);
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
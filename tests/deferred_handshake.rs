@@ -0,0 +1,95 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use ws::{CloseCode, Handler, Message, Request, Response, Result, Sender, WebSocket};
+
+// Checks the token of each request on another thread, as if asking a remote service.
+struct Deferred {
+    out: Sender,
+}
+
+impl Handler for Deferred {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let out = self.out.clone();
+        let authorized = req.header("authorization").map(|token| token == b"secret");
+        let response = Response::from_request(req)?;
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            if authorized == Some(true) {
+                out.accept(response).unwrap();
+            } else {
+                out.reject(401).unwrap();
+            }
+        });
+        Ok(Response::pending())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)?;
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+// Send a handshake request with the given token followed by a masked "hi" text frame, and
+// return everything the server writes back.
+fn handshake(token: &'static str) -> Vec<u8> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\
+             Authorization: {}\r\n\r\n",
+            token
+        ).unwrap();
+        // Sent before the handshake is accepted, so it must be held until then.
+        stream
+            .write_all(&[0x81, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2])
+            .unwrap();
+        let mut received = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let read = stream.read(&mut buf).unwrap();
+            if read == 0 {
+                break;
+            }
+            received.extend_from_slice(&buf[..read]);
+            // Hang up after the close frame instead of completing the closing handshake.
+            if received.windows(2).any(|w| w == [0x88, 0x02]) {
+                break;
+            }
+        }
+        received
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut server = WebSocket::new(|out| Deferred { out }).unwrap();
+    server.serve_stream(stream).unwrap();
+    server.run().unwrap();
+    client.join().unwrap()
+}
+
+#[test]
+fn deferred_accept() {
+    let received = handshake("secret");
+    let head = received.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    assert!(received.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+    assert_eq!(&received[head..head + 4], &[0x81, 0x02, b'h', b'i']);
+    assert_eq!(&received[head + 4..head + 6], &[0x88, 0x02]);
+}
+
+#[test]
+fn deferred_reject() {
+    let received = handshake("wrong");
+    assert!(received.starts_with(b"HTTP/1.1 401 Unauthorized\r\n"));
+}
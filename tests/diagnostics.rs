@@ -0,0 +1,83 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::util::Token;
+use ws::{ConnectionPhase, Handler, HandlerExt, Handshake, Message, Result, Sender, WebSocket};
+
+#[test]
+fn serve_snapshot() {
+    let server = WebSocket::new(|out: Sender| {
+        let echo = out.clone();
+        (move |msg| echo.send(msg)).with_diagnostics(&out, "/__ws_debug")
+    }).unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    // Other requests still open WebSocket connections.
+    let mut client = Client::connect(format!("ws://{}/chat", addr)).unwrap();
+    client.send("hello").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("hello"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET /__ws_debug?pretty HTTP/1.1\r\nHost: {}\r\n\r\n", addr).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    assert!(body.starts_with("{\"loop\":{\"connections\":2,\"accepted\":2,"));
+    assert!(body.contains(&format!("\"listeners\":[\"{}\"]", addr)));
+    assert!(body.contains("\"max_connections\":100"));
+    assert!(body.contains("\"state\":\"open\",\"client\":false,\"priority\":\"normal\""));
+    assert!(body.contains("\"resource\":\"/chat\""));
+    assert!(body.ends_with("]}"));
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+struct Waiting(Sender);
+
+impl Handler for Waiting {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.0.timeout(60_000, Token(1))
+    }
+}
+
+#[test]
+fn dump_state() {
+    let server = WebSocket::new(Waiting).unwrap().bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let idle = server.dump_state();
+    assert_eq!(idle.listeners, vec![addr]);
+    assert!(idle.connections.is_empty());
+
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    // The client returns once the handshake is complete, after the server scheduled its timeout.
+    let _client = Client::connect(format!("ws://{}/feed", addr)).unwrap();
+    let state = broadcaster
+        .dump_state()
+        .unwrap()
+        .wait_timeout(Duration::from_secs(5))
+        .unwrap();
+    assert_eq!(state.accepted, 1);
+    assert_eq!(state.timers, 1);
+    let conn = &state.connections[0];
+    assert_eq!(conn.phase, ConnectionPhase::Open);
+    assert_eq!(conn.resource, Some("/feed".into()));
+    assert!(!conn.client);
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,85 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc::{channel, Receiver, Sender as Channel};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use ws::middleware::{EarlyQueue, Overflow};
+use ws::{Builder, CloseCode, Handler, HandlerExt, Handshake, Message, Result, Sender};
+
+struct Client {
+    out: Sender,
+    flushed: Channel<usize>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("login")
+    }
+
+    fn on_flush_after_reconnect(&mut self, count: usize) -> Result<()> {
+        self.flushed.send(count).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+fn serve() -> (String, Receiver<String>) {
+    let (tx, received) = channel();
+    let tx = Mutex::new(tx);
+    let server = Builder::new()
+        .build(move |_| {
+            let tx = tx.lock().unwrap().clone();
+            move |msg: Message| {
+                tx.send(msg.into_text()?).unwrap();
+                Ok(())
+            }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+    (format!("ws://{}", addr), received)
+}
+
+fn connect(url: &str, queue: &EarlyQueue) -> Receiver<usize> {
+    let (tx, flushed) = channel();
+    let mut client = Builder::new()
+        .build(move |out: Sender| {
+            Client {
+                out: out.clone(),
+                flushed: tx.clone(),
+            }
+            .with_early_queue(&out, queue)
+        })
+        .unwrap();
+    client.connect(url.parse().unwrap()).unwrap();
+    client.run().unwrap();
+    flushed
+}
+
+#[test]
+fn flushes_after_reconnect() {
+    let (url, received) = serve();
+    let timeout = Duration::from_secs(5);
+    let queue = EarlyQueue::new(2, Overflow::DropOldest);
+    queue.send("one").unwrap();
+    queue.send("two").unwrap();
+    queue.send("three").unwrap();
+
+    let flushed = connect(&url, &queue);
+    assert_eq!(flushed.recv_timeout(timeout).unwrap(), 2);
+    // Messages sent on opening go before the queued ones.
+    let messages: Vec<String> = (0..3)
+        .map(|_| received.recv_timeout(timeout).unwrap())
+        .collect();
+    assert_eq!(messages, vec!["login", "two", "three"]);
+    assert!(!queue.is_connected());
+
+    queue.send("four").unwrap();
+    let flushed = connect(&url, &queue);
+    assert_eq!(flushed.recv_timeout(timeout).unwrap(), 1);
+    assert_eq!(received.recv_timeout(timeout).unwrap(), "login");
+    assert_eq!(received.recv_timeout(timeout).unwrap(), "four");
+}
@@ -0,0 +1,117 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+use ws::util::Token;
+use ws::{
+    Error, ErrorContext, ErrorKind, ErrorPhase, Handler, Handshake, Message, OpCode, Result,
+    Sender, WebSocket,
+};
+
+struct Server {
+    errors: mpsc::Sender<Error>,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let details = msg.into_text()?;
+        Err(Error::new(
+            ErrorKind::Custom(details.clone().into()),
+            details,
+        ))
+    }
+
+    fn on_error(&mut self, err: Error) {
+        self.errors.send(err).unwrap();
+    }
+}
+
+struct Client {
+    out: Sender,
+    local_addr: mpsc::Sender<Option<SocketAddr>>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.local_addr.send(shake.local_addr).unwrap();
+        self.out.send("boom")
+    }
+}
+
+fn server() -> (
+    String,
+    Sender,
+    mpsc::Receiver<Error>,
+    thread::JoinHandle<()>,
+) {
+    let (errors, rx) = mpsc::channel();
+    let server = WebSocket::new(move |_| Server {
+        errors: errors.clone(),
+    })
+    .unwrap()
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let broadcaster = server.broadcaster();
+    let thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    (addr, broadcaster, rx, thread)
+}
+
+#[test]
+fn open_connection() {
+    let (addr, broadcaster, errors, server) = server();
+
+    let (local_addr, client_addr) = mpsc::channel();
+    let client = thread::spawn(move || {
+        ws::connect(format!("ws://{}", addr), move |out| Client {
+            out,
+            local_addr: local_addr.clone(),
+        })
+        .unwrap()
+    });
+
+    let err = errors.recv().unwrap();
+    assert_eq!(err.details, "boom");
+    assert_eq!(
+        *err.context().unwrap(),
+        ErrorContext {
+            token: Token(0),
+            peer_addr: client_addr.recv().unwrap(),
+            phase: ErrorPhase::Open,
+            opcode: Some(OpCode::Text),
+        }
+    );
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    client.join().unwrap();
+}
+
+#[test]
+fn handshake() {
+    let (addr, broadcaster, errors, server) = server();
+
+    let mut client = TcpStream::connect(&addr).unwrap();
+    client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    let _ = client.read_to_end(&mut response);
+    assert!(response.starts_with(b"HTTP/1.1 "));
+
+    let err = errors.recv().unwrap();
+    let context = err.context().unwrap();
+    assert_eq!(context.phase, ErrorPhase::Handshake);
+    assert_eq!(context.peer_addr, Some(client.local_addr().unwrap()));
+    assert_eq!(context.opcode, None);
+    assert!(format!("{:?}", err).ends_with(&format!(
+        "(token=0 phase=handshake peer={})",
+        client.local_addr().unwrap()
+    )));
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
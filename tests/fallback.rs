@@ -0,0 +1,91 @@
+extern crate parity_ws as ws;
+
+use std::net::TcpListener;
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{CloseCode, Handler, Handshake, Result, Sender, WebSocket};
+
+struct Client {
+    out: Sender,
+    urls: mpsc::Sender<String>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.urls.send(shake.url.unwrap()).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+fn server() -> (String, Sender, thread::JoinHandle<()>) {
+    let server = WebSocket::new(|_| |_| Ok(()))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}/", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    (url, broadcaster, thread)
+}
+
+// A url on which nothing is listening, so that connections to it are refused.
+fn refused() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("ws://{}/", listener.local_addr().unwrap())
+}
+
+// The url that a client given the urls in order of priority reports that it connected to.
+fn connected(urls: &[String]) -> String {
+    let (tx, rx) = mpsc::channel();
+    let mut client = WebSocket::new(move |out| Client {
+        out,
+        urls: tx.clone(),
+    })
+    .unwrap();
+    client
+        .connect_any(urls.iter().map(|url| url.parse().unwrap()).collect())
+        .unwrap();
+    client.run().unwrap();
+    rx.recv().unwrap()
+}
+
+#[test]
+fn primary() {
+    let (primary, primary_out, primary_thread) = server();
+    let (secondary, secondary_out, secondary_thread) = server();
+
+    assert_eq!(connected(&[primary.clone(), secondary]), primary);
+
+    primary_out.shutdown().unwrap();
+    secondary_out.shutdown().unwrap();
+    primary_thread.join().unwrap();
+    secondary_thread.join().unwrap();
+}
+
+#[test]
+fn fallback_after_refusal() {
+    let (secondary, out, thread) = server();
+
+    assert_eq!(
+        connected(&[refused(), refused(), secondary.clone()]),
+        secondary
+    );
+
+    out.shutdown().unwrap();
+    thread.join().unwrap();
+}
+
+#[test]
+fn requires_urls() {
+    let mut client = WebSocket::new(|_| |_| Ok(())).unwrap();
+    assert!(client.connect_any(Vec::new()).is_err());
+    assert!(client
+        .connect_any(vec![
+            "ws://127.0.0.1:1/".parse().unwrap(),
+            "wss://127.0.0.1:2/".parse().unwrap(),
+        ])
+        .is_err());
+}
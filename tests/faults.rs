@@ -0,0 +1,116 @@
+//! Connections driven through the scripted transport failures of `Settings::faults`.
+//!
+//! These run with `cargo test --features testing-hooks --test faults`.
+#![cfg(feature = "testing-hooks")]
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use ws::{
+    Builder, CloseCode, Error, ErrorKind, Fault, FaultPlan, Handler, Handshake, Message, Result,
+    Sender, Settings,
+};
+
+struct Client {
+    out: Sender,
+    events: mpsc::Sender<String>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.events.send("open".into()).unwrap();
+        self.out.send(vec![7u8; 1000])
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(format!("echo {}", msg.len())).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn on_error(&mut self, err: Error) {
+        let event = match err.kind {
+            ErrorKind::Io(ref err) => format!("io {:?}", err.kind()),
+            ref kind => format!("error {:?}", kind),
+        };
+        self.events.send(event).unwrap();
+    }
+}
+
+fn faults(plan: FaultPlan) -> Settings {
+    let mut settings = Settings::default();
+    settings.faults = Some(Box::leak(Box::new(plan)));
+    settings
+}
+
+fn run(server: Settings, client: Settings) -> Vec<String> {
+    let server = Builder::new()
+        .with_settings(server)
+        .build(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (events, received) = mpsc::channel();
+    let mut client = Builder::new()
+        .with_settings(client)
+        .build(|out| Client {
+            out,
+            events: events.clone(),
+        })
+        .unwrap();
+    client.connect(url.parse().unwrap()).unwrap();
+    client.run().unwrap();
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    received.try_iter().collect()
+}
+
+#[test]
+fn adverse_transport_still_delivers() {
+    let server = faults(
+        FaultPlan::new()
+            .read(0, Fault::Short(10))
+            .read(2, Fault::WouldBlock(50))
+            .read(3, Fault::Short(1))
+            .write(0, Fault::Short(5))
+            .write(2, Fault::Delay(Duration::from_millis(100))),
+    );
+    let client = faults(
+        FaultPlan::new()
+            .read(1, Fault::WouldBlock(20))
+            .write(1, Fault::Short(100)),
+    );
+
+    let events = run(server, client);
+    assert_eq!(events, vec!["open", "echo 1000"]);
+}
+
+#[test]
+fn disconnect_mid_handshake() {
+    let client = faults(
+        FaultPlan::new()
+            .read(0, Fault::Short(16))
+            .read(1, Fault::Disconnect),
+    );
+
+    let events = run(Settings::default(), client);
+    assert_eq!(events, vec!["io ConnectionReset"]);
+}
+
+#[test]
+fn spurious_wakeups_are_limited() {
+    let mut server = faults(FaultPlan::new().read(1, Fault::WouldBlock(50)));
+    server.empty_read_limit = 3;
+
+    // The server gives up on the connection before reading the message it should echo.
+    let events = run(server, Settings::default());
+    assert_eq!(events, vec!["open", "io ConnectionReset"]);
+}
@@ -0,0 +1,63 @@
+extern crate parity_ws as ws;
+
+use std::net::TcpListener;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{CloseCode, Handler, Handshake, Result, Sender, WebSocket};
+
+const SIZE: usize = 4 * 1024 * 1024;
+
+// Sends a large message from another thread, waits for it to be flushed and then closes.
+struct Farewell {
+    out: Sender,
+    flushed: ::std::sync::mpsc::Sender<Result<()>>,
+}
+
+impl Handler for Farewell {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        let out = self.out.clone();
+        let flushed = self.flushed.clone();
+        thread::spawn(move || {
+            out.send(vec![7u8; SIZE]).unwrap();
+            let res = out.flush().unwrap().wait_timeout(Duration::from_secs(10));
+            flushed.send(res).unwrap();
+            out.close(CloseCode::Normal).unwrap();
+        });
+        Ok(())
+    }
+}
+
+#[test]
+fn flush_before_close() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let (flushed_tx, flushed) = channel();
+    let (sender_tx, sender) = channel();
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut ws = WebSocket::new(|out: Sender| {
+            sender_tx.send(out.clone()).unwrap();
+            Farewell {
+                out,
+                flushed: flushed_tx.clone(),
+            }
+        }).unwrap();
+        ws.serve_stream(stream).unwrap();
+        ws.run().unwrap();
+    });
+
+    let mut client = Client::connect(url).unwrap();
+    // Slow to read, so the message can not be written out all at once.
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.recv().unwrap().len(), SIZE);
+    assert!(flushed.recv().unwrap().is_ok());
+    assert!(client.recv().is_err());
+    server.join().unwrap();
+
+    // The connection and its event loop are gone.
+    let out = sender.recv().unwrap();
+    assert!(out.flush().and_then(|handle| handle.wait()).is_err());
+}
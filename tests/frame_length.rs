@@ -0,0 +1,49 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use ws::sync::Client;
+use ws::{Message, Sender, WebSocket};
+
+const HANDSHAKE: &[u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+#[test]
+fn rejects_lengths_with_the_top_bit_set() {
+    let server = WebSocket::new(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(HANDSHAKE).unwrap();
+    let mut response = [0u8; 1024];
+    let _ = stream.read(&mut response).unwrap();
+
+    // A masked text frame that claims a payload of 2^64 - 1 bytes.
+    let mut frame = vec![0x81, 0xff];
+    frame.extend_from_slice(&[0xff; 8]);
+    frame.extend_from_slice(&[0; 4]);
+    stream.write_all(&frame).unwrap();
+    let mut close = [0u8; 4];
+    stream.read_exact(&mut close).unwrap();
+    // A close frame with the protocol error status, 1002, followed by a reason.
+    assert_eq!(close[0], 0x88);
+    assert_eq!(&close[2..], &[0x03, 0xea]);
+
+    // The event loop survived, and serves other connections.
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    client.send("still here").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("still here"));
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,120 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{CloseCode, Group, Message, Sender, WebSocket};
+
+#[test]
+fn group_membership() {
+    let (group_tx, group_rx) = channel();
+    let mut group: Option<Group> = None;
+    let server = WebSocket::new(move |out: Sender| {
+        // Every sender of the WebSocket can create groups, so use the first one.
+        let group = group
+            .get_or_insert_with(|| {
+                let group = out.group();
+                group_tx.send(group.clone()).unwrap();
+                group
+            })
+            .clone();
+        // Join the group on request and acknowledge it.
+        move |msg: Message| {
+            group.add(&out)?;
+            out.send(msg)
+        }
+    }).unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let connect = || {
+        let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(500)));
+        client
+    };
+    let mut member = connect();
+    let mut outsider = connect();
+    let group = group_rx.recv().unwrap();
+    member.send("join").unwrap();
+    assert_eq!(member.recv().unwrap(), Message::text("join"));
+
+    group.send("news").unwrap();
+    assert_eq!(member.recv().unwrap(), Message::text("news"));
+    assert!(outsider.recv().is_err());
+
+    // A new connection that takes over the token of the departed member is not in the group.
+    member.close(CloseCode::Normal).unwrap();
+    let mut newcomer = connect();
+    group.send("more news").unwrap();
+    assert!(newcomer.recv().is_err());
+
+    outsider.close(CloseCode::Normal).unwrap();
+    newcomer.close(CloseCode::Normal).unwrap();
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn late_subscribers_catch_up() {
+    let (group_tx, group_rx) = channel();
+    let mut group: Option<Group> = None;
+    let server = WebSocket::new(move |out: Sender| {
+        let group = group
+            .get_or_insert_with(|| {
+                let group = out.group_with_history(2).unwrap();
+                group_tx.send(group.clone()).unwrap();
+                group
+            })
+            .clone();
+        // Join from the requested sequence number and acknowledge it.
+        move |msg: Message| {
+            group.add_since(&out, msg.as_text()?.parse().unwrap())?;
+            out.send("joined")
+        }
+    }).unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let connect = || {
+        let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(500)));
+        client
+    };
+    let mut early = connect();
+    let group = group_rx.recv().unwrap();
+    for msg in &["first", "second", "third"] {
+        group.send(*msg).unwrap();
+    }
+
+    // Only the last two messages are kept.
+    early.send("0").unwrap();
+    assert_eq!(early.recv().unwrap(), Message::text("second"));
+    assert_eq!(early.recv().unwrap(), Message::text("third"));
+    assert_eq!(early.recv().unwrap(), Message::text("joined"));
+
+    let mut late = connect();
+    late.send("2").unwrap();
+    assert_eq!(late.recv().unwrap(), Message::text("third"));
+    assert_eq!(late.recv().unwrap(), Message::text("joined"));
+
+    group.send("fourth").unwrap();
+    assert_eq!(early.recv().unwrap(), Message::text("fourth"));
+    assert_eq!(late.recv().unwrap(), Message::text("fourth"));
+
+    // Rejoining as a member does not replay the history again.
+    late.send("0").unwrap();
+    assert_eq!(late.recv().unwrap(), Message::text("joined"));
+
+    early.close(CloseCode::Normal).unwrap();
+    late.close(CloseCode::Normal).unwrap();
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,87 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Result, Sender, Settings};
+
+const HANDSHAKE: &'static [u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n";
+
+const MESSAGES: usize = 32;
+const MESSAGE_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    HalfClose,
+    Close(CloseCode),
+}
+
+// Sends more data than the socket can hold as soon as the connection opens.
+struct Server {
+    out: Sender,
+    events: ChannelSender<Event>,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        for _ in 0..MESSAGES {
+            self.out.send(vec![0u8; MESSAGE_LEN])?;
+        }
+        Ok(())
+    }
+
+    fn on_peer_half_close(&mut self) -> Result<()> {
+        self.events.send(Event::HalfClose).unwrap();
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.events.send(Event::Close(code)).unwrap();
+    }
+}
+
+#[test]
+fn half_closed_peer_receives_buffered_data() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(HANDSHAKE).unwrap();
+        let mut response = [0u8; 1024];
+        let read = stream.read(&mut response).unwrap();
+        // Give the server time to buffer its messages before it sees the end of stream.
+        thread::sleep(Duration::from_millis(200));
+        stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        read + rest.len()
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let (tx, rx) = channel();
+
+    let mut settings = Settings::default();
+    settings.out_buffer_capacity_hard_limit = 64 * 1024 * 1024;
+    let mut server = Builder::new()
+        .with_settings(settings)
+        .build(|out| Server {
+            out,
+            events: tx.clone(),
+        })
+        .unwrap();
+    server.serve_stream(stream).unwrap();
+    server.run().unwrap();
+
+    assert!(client.join().unwrap() > MESSAGES * MESSAGE_LEN);
+    assert_eq!(rx.try_recv().unwrap(), Event::HalfClose);
+    assert_eq!(rx.try_recv().unwrap(), Event::Close(CloseCode::Abnormal));
+}
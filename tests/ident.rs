@@ -0,0 +1,108 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Request, Response, Result, Sender, Settings};
+
+struct Server {
+    out: Sender,
+    agents: mpsc::Sender<Option<String>>,
+    ident: Option<&'static str>,
+}
+
+impl Handler for Server {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let agent = req
+            .header("user-agent")
+            .map(|agent| String::from_utf8_lossy(agent).into());
+        self.agents.send(agent).unwrap();
+        let mut res = Response::from_request(req)?;
+        if let Some(ident) = self.ident {
+            res.headers_mut().push(("Server".into(), ident.into()));
+        }
+        Ok(res)
+    }
+
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+struct Client {
+    idents: mpsc::Sender<Option<String>>,
+}
+
+impl Handler for Client {
+    fn on_response(&mut self, res: &Response) -> Result<()> {
+        let ident = res
+            .headers()
+            .iter()
+            .find(|&&(ref key, _)| key.eq_ignore_ascii_case("server"))
+            .map(|&(_, ref ident)| String::from_utf8_lossy(ident).into());
+        self.idents.send(ident).unwrap();
+        Ok(())
+    }
+}
+
+// The User-Agent header seen by the server and the Server header seen by the client, when both
+// ends use the given settings and the server handler may set its own header.
+fn exchange(settings: Settings, own: Option<&'static str>) -> (Option<String>, Option<String>) {
+    let (agents, agent) = mpsc::channel();
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(move |out| Server {
+            out,
+            agents: agents.clone(),
+            ident: own,
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (idents, ident) = mpsc::channel();
+    let mut client = Builder::new()
+        .with_settings(settings)
+        .build(move |_| Client {
+            idents: idents.clone(),
+        })
+        .unwrap();
+    client.connect(url.parse().unwrap()).unwrap();
+    client.run().unwrap();
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    (agent.recv().unwrap(), ident.recv().unwrap())
+}
+
+#[test]
+fn absent_by_default() {
+    assert_eq!(exchange(Settings::default(), None), (None, None));
+}
+
+#[test]
+fn from_settings() {
+    let mut settings = Settings::default();
+    settings.user_agent = Some("ws-client/1.0");
+    settings.server_ident = Some("ws-server/2.0");
+    assert_eq!(
+        exchange(settings, None),
+        (Some("ws-client/1.0".into()), Some("ws-server/2.0".into()))
+    );
+}
+
+#[test]
+fn overridden_by_handler() {
+    let mut settings = Settings::default();
+    settings.user_agent = Some("ws-client/1.0");
+    settings.server_ident = Some("ws-server/2.0");
+    assert_eq!(
+        exchange(settings, Some("custom/3.0")),
+        (Some("ws-client/1.0".into()), Some("custom/3.0".into()))
+    );
+}
@@ -0,0 +1,65 @@
+extern crate parity_ws as ws;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::sync::Client;
+use ws::{Builder, CloseCode, ConnectionSnapshot, Message, Sender, Settings};
+
+fn connection(broadcaster: &Sender) -> ConnectionSnapshot {
+    broadcaster
+        .dump_state()
+        .unwrap()
+        .wait_timeout(Duration::from_secs(5))
+        .unwrap()
+        .connections
+        .remove(0)
+}
+
+// Echo a large message, then return the server side of the connection after a quiet period.
+fn after_burst(settings: Settings) -> ConnectionSnapshot {
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut client = Client::connect(url).unwrap();
+    let burst = vec![1; 100_000];
+    client.send(burst.clone()).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::binary(burst));
+
+    let deadline = Instant::now() + Duration::from_millis(500);
+    let mut conn = connection(&broadcaster);
+    while conn.in_capacity != 0 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+        conn = connection(&broadcaster);
+    }
+
+    client.close(CloseCode::Normal).unwrap();
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    conn
+}
+
+#[test]
+fn buffers_kept_by_default() {
+    let conn = after_burst(Settings::default());
+    assert!(conn.in_capacity >= 100_000);
+    assert!(conn.out_capacity >= 100_000);
+}
+
+#[test]
+fn buffers_shrink_when_idle() {
+    let mut settings = Settings::default();
+    settings.idle_shrink_interval = Some(Duration::from_millis(50));
+    let conn = after_burst(settings);
+    assert_eq!(conn.in_capacity, 0);
+    assert_eq!(conn.out_capacity, 0);
+}
@@ -0,0 +1,60 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::middleware::KeepAlive;
+use ws::{CloseCode, Frame, Handler, HandlerExt, OpCode, Result, WebSocket};
+
+struct Client {
+    events: mpsc::Sender<String>,
+}
+
+impl Handler for Client {
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        if frame.opcode() == OpCode::Pong {
+            self.events.send("pong".into()).unwrap();
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.events.send(format!("{:?}", code)).unwrap();
+    }
+}
+
+#[test]
+fn pings_then_retires() {
+    let server = WebSocket::new(|_| |_| Ok(()))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (events, received) = mpsc::channel();
+    let policy = KeepAlive::new(Duration::from_millis(100), Duration::from_secs(5))
+        .max_age(Duration::from_millis(1000), 0.2);
+    let started = Instant::now();
+    ws::connect(url, |out| {
+        Client {
+            events: events.clone(),
+        }
+        .with_keepalive(&out, policy)
+    })
+    .unwrap();
+    let elapsed = started.elapsed();
+
+    let events = received.try_iter().collect::<Vec<_>>();
+    assert!(events.iter().filter(|event| *event == "pong").count() >= 2);
+    assert_eq!(events.last().unwrap(), "Away", "{:?}", events);
+    assert!(elapsed >= Duration::from_millis(800));
+    assert!(elapsed < Duration::from_millis(2000));
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
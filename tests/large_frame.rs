@@ -0,0 +1,122 @@
+extern crate parity_ws as ws;
+
+use std::io::{self, Write};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, OpCode, Result, Sender, Settings};
+
+const LARGE: usize = 300_000;
+
+// Collects a streamed payload.
+struct Sink(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Server {
+    out: Sender,
+    received: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+
+    fn on_large_frame_start(&mut self, opcode: OpCode, len: u64) -> Option<Box<dyn Write + Send>> {
+        assert_eq!((opcode, len), (OpCode::Binary, LARGE as u64));
+        Some(Box::new(Sink(self.received.clone())))
+    }
+
+    fn on_large_frame_end(&mut self, len: u64) -> Result<()> {
+        self.out.send(format!("streamed {}", len))
+    }
+}
+
+// Sends a large message followed by a small one, and closes once both are answered.
+struct Client {
+    out: Sender,
+    replies: mpsc::Sender<String>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        let payload = (0..LARGE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        self.out.send(payload)?;
+        self.out.send("small")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let text = msg.into_text()?;
+        let done = text == "small";
+        self.replies.send(text).unwrap();
+        if done {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.replies.send(format!("{:?}", code)).unwrap();
+    }
+}
+
+fn transfer() -> (Vec<String>, Vec<u8>) {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let mut settings = Settings::default();
+    settings.max_fragment_size = 64 * 1024;
+    let sink = received.clone();
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(move |out| Server {
+            out,
+            received: sink.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (replies, rx) = mpsc::channel();
+    let mut settings = Settings::default();
+    settings.fragment_size = LARGE;
+    let mut client = Builder::new()
+        .with_settings(settings)
+        .build(|out| Client {
+            out,
+            replies: replies.clone(),
+        })
+        .unwrap();
+    client.connect(url.parse().unwrap()).unwrap();
+    client.run().unwrap();
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    let received = received.lock().unwrap().clone();
+    (rx.try_iter().collect(), received)
+}
+
+#[test]
+fn streams_large_frame() {
+    let (replies, received) = transfer();
+    assert_eq!(replies, vec!["streamed 300000", "small", "Normal"]);
+    assert_eq!(received.len(), LARGE);
+    assert!(received
+        .iter()
+        .enumerate()
+        .all(|(i, &byte)| byte == (i % 251) as u8));
+}
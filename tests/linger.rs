@@ -0,0 +1,82 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, Settings};
+
+const HANDSHAKE: &'static [u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n";
+
+// A masked close frame with status 1000.
+const CLOSE: &'static [u8] = &[0x88, 0x82, 1, 2, 3, 4, 0x03 ^ 1, 0xe8 ^ 2];
+// A masked ping that is still in flight when the server finishes the close.
+const PING: &'static [u8] = &[0x89, 0x80, 1, 2, 3, 4];
+
+fn serve(linger: Duration, client: fn(TcpStream)) -> Duration {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = thread::spawn(move || client(TcpStream::connect(addr).unwrap()));
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut settings = Settings::default();
+    settings.close_linger = Some(linger);
+    let mut server = Builder::new()
+        .with_settings(settings)
+        .build(|_| |_| Ok(()))
+        .unwrap();
+    server.serve_stream(stream).unwrap();
+
+    let start = Instant::now();
+    server.run().unwrap();
+    let elapsed = start.elapsed();
+    client.join().unwrap();
+    elapsed
+}
+
+fn close_and_keep_sending(mut stream: TcpStream) {
+    stream.write_all(HANDSHAKE).unwrap();
+    let mut response = [0u8; 1024];
+    let _ = stream.read(&mut response).unwrap();
+
+    stream.write_all(CLOSE).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(PING).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // The server half-closed after its close frame, so everything arrives followed by a clean
+    // end of stream rather than a reset.
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(&rest[..2], &[0x88, 0x02]);
+    stream.shutdown(Shutdown::Write).unwrap();
+}
+
+fn close_and_stay_open(mut stream: TcpStream) {
+    stream.write_all(HANDSHAKE).unwrap();
+    let mut response = [0u8; 1024];
+    let _ = stream.read(&mut response).unwrap();
+
+    stream.write_all(CLOSE).unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(&rest[..2], &[0x88, 0x02]);
+    thread::sleep(Duration::from_secs(1));
+}
+
+#[test]
+fn half_close_drains_inbound_data() {
+    assert!(serve(Duration::from_secs(10), close_and_keep_sending) < Duration::from_secs(5));
+}
+
+#[test]
+fn linger_period_expires() {
+    let elapsed = serve(Duration::from_millis(300), close_and_stay_open);
+    assert!(elapsed >= Duration::from_millis(300));
+    assert!(elapsed < Duration::from_secs(5));
+}
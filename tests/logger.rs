@@ -0,0 +1,69 @@
+extern crate parity_ws as ws;
+
+use std::sync::Mutex;
+use std::thread;
+
+use ws::sync::Client;
+use ws::{Builder, LogLevel, LogRecord, Message, Sender, WsLogger};
+
+struct Collect(Mutex<Vec<String>>);
+
+impl Collect {
+    fn contains(&self, line: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|logged| logged.contains(line))
+    }
+}
+
+impl WsLogger for Collect {
+    fn enabled(&self, level: LogLevel, _: &str) -> bool {
+        level <= LogLevel::Info
+    }
+
+    fn log(&self, record: &LogRecord) {
+        self.0.lock().unwrap().push(format!("{:?}", record));
+    }
+}
+
+static ECHO: Collect = Collect(Mutex::new(Vec::new()));
+static IDLE: Collect = Collect(Mutex::new(Vec::new()));
+
+#[test]
+fn separate_loggers() {
+    let echo = Builder::new()
+        .with_logger(&ECHO)
+        .build(|out: Sender| move |msg: Message| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let idle = Builder::new()
+        .with_logger(&IDLE)
+        .build(|out: Sender| move |msg: Message| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = echo.local_addr().unwrap();
+    let echo_out = echo.broadcaster();
+    let idle_out = idle.broadcaster();
+    let echo = thread::spawn(move || echo.run().unwrap());
+    let idle = thread::spawn(move || idle.run().unwrap());
+
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    client.send("hello").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("hello"));
+
+    echo_out.shutdown().unwrap();
+    idle_out.shutdown().unwrap();
+    echo.join().unwrap();
+    idle.join().unwrap();
+
+    assert!(ECHO.contains(&format!("Listening for new connections on {}.", addr)));
+    assert!(ECHO.contains("INFO parity_ws::io: Accepted a new tcp connection from 127.0.0.1:"));
+    assert!(ECHO.contains(". peer=127.0.0.1:"));
+    assert!(!ECHO.contains("DEBUG parity_ws"));
+    assert!(IDLE.contains("Listening for new connections"));
+    assert!(!IDLE.contains("Accepted"));
+}
@@ -0,0 +1,77 @@
+extern crate parity_ws as ws;
+
+use std::io::{ErrorKind, Read};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{Builder, CloseCode, LoopStateSnapshot, Message, Sender, Settings};
+
+fn connect(addr: &str) -> Client {
+    Client::connect(format!("ws://{}", addr)).unwrap()
+}
+
+// Whether the server closes a new connection right away instead of waiting for its handshake.
+fn refused(addr: &str) -> bool {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let mut buf = [0; 1];
+    match stream.read(&mut buf) {
+        Ok(0) => true,
+        Err(err) => err.kind() == ErrorKind::ConnectionReset,
+        Ok(_) => false,
+    }
+}
+
+// The state once the event loop handled every command queued before.
+fn state(out: &Sender) -> LoopStateSnapshot {
+    out.dump_state()
+        .unwrap()
+        .wait_timeout(Duration::from_secs(5))
+        .unwrap()
+}
+
+#[test]
+fn resize_at_runtime() {
+    let mut settings = Settings::default();
+    settings.max_connections = 1;
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(|out: Sender| move |msg: Message| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let first = connect(&addr);
+    assert!(refused(&addr));
+
+    broadcaster.set_max_connections(2).unwrap();
+    let grown = state(&broadcaster);
+    assert_eq!(grown.settings.max_connections, 2);
+    assert!(grown.slots >= 2);
+    let mut second = connect(&addr);
+
+    // Lowering the limit keeps the open connections.
+    broadcaster.set_max_connections(1).unwrap();
+    assert_eq!(state(&broadcaster).connections.len(), 2);
+    second.send("still open").unwrap();
+    assert_eq!(second.recv().unwrap(), Message::text("still open"));
+    assert!(refused(&addr));
+
+    first.close(CloseCode::Normal).unwrap();
+    second.close(CloseCode::Normal).unwrap();
+    while !state(&broadcaster).connections.is_empty() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let mut third = connect(&addr);
+    third.send("admitted").unwrap();
+    assert_eq!(third.recv().unwrap(), Message::text("admitted"));
+    assert!(refused(&addr));
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
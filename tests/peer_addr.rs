@@ -0,0 +1,56 @@
+extern crate parity_ws as ws;
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+use ws::sync::Client;
+use ws::util::Token;
+use ws::{Handler, Handshake, Message, Result, Sender, WebSocket};
+
+struct Server(Sender);
+
+impl Handler for Server {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        assert_eq!(self.0.peer_addr(), shake.peer_addr);
+        Ok(())
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        let peer = self.0.control().peer_addr().unwrap();
+        self.0.send(peer.to_string())
+    }
+}
+
+#[test]
+fn sender_peer_addr() {
+    let server = WebSocket::new(Server).unwrap().bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    assert_eq!(broadcaster.peer_addr(), None);
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    client.send("who am I?").unwrap();
+    let peer: SocketAddr = client.recv().unwrap().as_text().unwrap().parse().unwrap();
+    assert_eq!(peer.ip(), addr.ip());
+    assert_ne!(peer.port(), addr.port());
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn websocket_connection_info() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (stream, _) = listener.accept().unwrap();
+
+    let mut server = WebSocket::new(Server).unwrap();
+    assert!(server.connection_info(Token(0)).is_none());
+    server.serve_stream(stream).unwrap();
+
+    let info = server.connection_info(Token(0)).unwrap();
+    assert_eq!(info.peer_addr(), Some(client.local_addr().unwrap()));
+    assert_eq!(info.resource(), None);
+    assert!(server.connection_info(Token(1)).is_none());
+}
@@ -0,0 +1,97 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use ws::sync::Client;
+use ws::{Builder, Error, ErrorKind, Handler, Message, Result, Sender, Settings};
+
+struct Echo {
+    out: Sender,
+    errors: ::std::sync::mpsc::Sender<Error>,
+}
+
+impl Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+
+    fn on_error(&mut self, err: Error) {
+        self.errors.send(err).unwrap();
+    }
+}
+
+fn strict_server() -> (String, Receiver<Error>, Sender, thread::JoinHandle<()>) {
+    let mut settings = Settings::default();
+    settings.strict_preallocation = true;
+    settings.in_buffer_capacity = 256;
+    settings.out_buffer_capacity = 256;
+    let (tx, errors) = channel();
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(move |out| Echo {
+            out,
+            errors: tx.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    (addr, errors, broadcaster, server)
+}
+
+fn is_capacity(err: &Error) -> bool {
+    match err.kind {
+        ErrorKind::Capacity => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn fixed_buffers() {
+    let (addr, errors, broadcaster, server) = strict_server();
+
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    let small = "x".repeat(100);
+    client.send(small.as_str()).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text(small));
+
+    // The frame does not fit in the incoming buffer, which is not allowed to grow.
+    client.send("x".repeat(1000)).unwrap();
+    assert!(is_capacity(&errors.recv().unwrap()));
+    assert!(client.recv().is_err());
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn oversized_handshake() {
+    let (addr, errors, broadcaster, server) = strict_server();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let padding = "a".repeat(4096);
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\
+         X-Padding: {}\r\n\r\n",
+        padding
+    ).unwrap();
+    assert!(is_capacity(&errors.recv().unwrap()));
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert!(!response.starts_with("HTTP/1.1 101"));
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
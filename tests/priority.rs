@@ -0,0 +1,104 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::{channel, Receiver, Sender as ChannelSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ws::{Builder, Handler, Message, Priority, Result, Sender};
+
+const HANDSHAKE: &[u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+struct Server {
+    out: Sender,
+    received: ChannelSender<String>,
+    blocked: ChannelSender<()>,
+    resume: Arc<Mutex<Receiver<()>>>,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let text = msg.into_text()?;
+        match text.as_str() {
+            "admin" => {
+                self.out.set_priority(Priority::High)?;
+                self.out.send("ok")
+            }
+            // Keep the event loop busy until the test has made the other connections readable.
+            "block" => {
+                self.blocked.send(()).unwrap();
+                self.resume.lock().unwrap().recv().unwrap();
+                Ok(())
+            }
+            _ => {
+                self.received.send(text).unwrap();
+                Ok(())
+            }
+        }
+    }
+}
+
+fn open(addr: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(HANDSHAKE).unwrap();
+    let mut response = [0u8; 1024];
+    let _ = stream.read(&mut response).unwrap();
+    stream
+}
+
+// Send a text frame with a mask of zeros, which leaves the payload as it is.
+fn send(stream: &mut TcpStream, text: &str) {
+    let mut frame = vec![0x81, 0x80 | text.len() as u8, 0, 0, 0, 0];
+    frame.extend_from_slice(text.as_bytes());
+    stream.write_all(&frame).unwrap();
+}
+
+#[test]
+fn high_priority_first() {
+    let (received, messages) = channel();
+    let (blocked, busy) = channel();
+    let (resume, resumed) = channel();
+    let resumed = Arc::new(Mutex::new(resumed));
+    let server = Builder::new()
+        .build(move |out| Server {
+            out,
+            received: received.clone(),
+            blocked: blocked.clone(),
+            resume: resumed.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let mut admin = open(addr);
+    send(&mut admin, "admin");
+    let mut reply = [0u8; 4];
+    admin.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"\x81\x02ok");
+    let mut bulk: Vec<TcpStream> = (0..8).map(|_| open(addr)).collect();
+    let mut blocker = open(addr);
+
+    // While the event loop is busy, every connection becomes readable, the admin one last.
+    send(&mut blocker, "block");
+    busy.recv().unwrap();
+    for (i, stream) in bulk.iter_mut().enumerate() {
+        send(stream, &format!("bulk {}", i));
+    }
+    send(&mut admin, "urgent");
+    resume.send(()).unwrap();
+
+    let order: Vec<String> = messages.iter().take(9).collect();
+    assert_eq!(order[0], "urgent", "{:?}", order);
+    assert_eq!(order[1], "bulk 0", "{:?}", order);
+
+    handle.shutdown().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,82 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use ws::probe::Probe;
+use ws::{CloseCode, Handler, Handshake, Request, Response, Result, Sender, WebSocket};
+
+// Answers the opening handshake after a delay, like a far away gateway.
+struct Gateway {
+    delay: Duration,
+}
+
+impl Handler for Gateway {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        thread::sleep(self.delay);
+        Response::from_request(req)
+    }
+}
+
+fn gateway(delay: Duration) -> (String, Sender, thread::JoinHandle<()>) {
+    let server = WebSocket::new(move |_| Gateway { delay })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}/", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    (url, broadcaster, thread)
+}
+
+struct Client {
+    out: Sender,
+    opened: mpsc::Sender<Option<String>>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.opened.send(shake.url).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn prefers_fastest() {
+    let (slow, slow_broadcaster, slow_server) = gateway(Duration::from_millis(200));
+    let (fast, fast_broadcaster, fast_server) = gateway(Duration::from_millis(0));
+    // Nothing listens on the discard port.
+    let dead = "ws://127.0.0.1:9/".to_string();
+
+    let mut probe = Probe::new(vec![
+        dead.parse().unwrap(),
+        slow.parse().unwrap(),
+        fast.parse().unwrap(),
+    ])
+    .timeout(Duration::from_secs(2));
+    probe.probe().unwrap();
+    let measured = probe
+        .latencies()
+        .iter()
+        .map(|&(ref url, _)| url.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(measured, vec![fast.clone(), slow.clone()]);
+
+    let (opened, rx) = mpsc::channel();
+    let mut client = WebSocket::new(|out| Client {
+        out,
+        opened: opened.clone(),
+    })
+    .unwrap();
+    client.connect_fastest(&mut probe).unwrap();
+    client.run().unwrap();
+    assert_eq!(rx.recv().unwrap(), Some(fast));
+
+    slow_broadcaster.shutdown().unwrap();
+    fast_broadcaster.shutdown().unwrap();
+    slow_server.join().unwrap();
+    fast_server.join().unwrap();
+}
@@ -0,0 +1,59 @@
+extern crate parity_ws as ws;
+
+use std::thread;
+
+use ws::sync::Client;
+use ws::{Builder, Message, Profile, Settings, WritePolicy};
+
+fn echo(profile: Profile) {
+    let server = Builder::new()
+        .with_profile(profile)
+        .build(|out: ws::Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut client = Client::connect(url).unwrap();
+    // Larger than the buffers of the small profiles, and fragmented by the embedded one.
+    let payload = vec![7; 50_000];
+    client.send(payload.clone()).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::binary(payload));
+    client.close(ws::CloseCode::Normal).unwrap();
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn profiles_echo() {
+    echo(Profile::LowLatency);
+    echo(Profile::HighThroughput);
+    echo(Profile::ManyIdleConnections);
+    echo(Profile::Embedded);
+}
+
+#[test]
+fn profiles_differ_from_defaults() {
+    let defaults = Settings::default();
+
+    let low_latency = Settings::profile(Profile::LowLatency);
+    assert!(low_latency.tcp_nodelay);
+    assert_eq!(low_latency.write_policy, WritePolicy::Requeue);
+
+    let throughput = Settings::profile(Profile::HighThroughput);
+    assert!(throughput.fragment_size > defaults.fragment_size);
+    assert!(throughput.in_buffer_capacity > defaults.in_buffer_capacity);
+
+    let idle = Settings::profile(Profile::ManyIdleConnections);
+    assert!(idle.max_connections > defaults.max_connections);
+    assert!(idle.in_buffer_capacity < defaults.in_buffer_capacity);
+
+    let embedded = Settings::profile(Profile::Embedded);
+    assert!(embedded.max_connections < defaults.max_connections);
+    assert!(embedded.in_buffer_capacity_hard_limit < defaults.in_buffer_capacity_hard_limit);
+}
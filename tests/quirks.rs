@@ -0,0 +1,94 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{
+    Builder, CloseCode, Handler, HeaderCase, Quirks, Request, Response, Result, Sender, Settings,
+};
+
+struct Server;
+
+impl Handler for Server {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let mut res = Response::from_request(req)?;
+        res.headers_mut()
+            .push(("x-served-by".into(), b"quirks".to_vec()));
+        Ok(res)
+    }
+}
+
+struct Client {
+    out: Sender,
+    names: mpsc::Sender<Vec<String>>,
+}
+
+impl Handler for Client {
+    fn on_response(&mut self, res: &Response) -> Result<()> {
+        let names = res.headers().iter().map(|h| h.0.clone()).collect();
+        self.names.send(names).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+// The names of the headers of the handshake response of a server with the given settings.
+fn response_headers(settings: Settings) -> Vec<String> {
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(|_| Server)
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (names, received) = mpsc::channel();
+    ws::connect(url, |out| Client {
+        out,
+        names: names.clone(),
+    })
+    .unwrap();
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    received.recv().unwrap()
+}
+
+#[test]
+fn header_case() {
+    let names = response_headers(Settings::default());
+    assert!(names.contains(&"Sec-WebSocket-Accept".to_string()));
+    assert!(names.contains(&"x-served-by".to_string()));
+
+    let names = response_headers(Settings::default().peer_quirks(Quirks::CaseSensitiveGateway));
+    assert!(names.contains(&"Sec-WebSocket-Accept".to_string()));
+    assert!(names.contains(&"X-Served-By".to_string()));
+
+    let mut settings = Settings::default();
+    settings.header_case = HeaderCase::Lower;
+    let names = response_headers(settings);
+    assert!(names.contains(&"sec-websocket-accept".to_string()));
+    assert!(names.iter().all(|name| *name == name.to_lowercase()));
+}
+
+#[test]
+fn quirks_only_relax() {
+    let mut strict = Settings::default();
+    strict.masking_strict = true;
+    strict.key_strict = true;
+    strict.fragments_grow = false;
+    strict.fragments_capacity = 100;
+
+    let safari = strict.peer_quirks(Quirks::LegacySafari);
+    assert!(safari.fragments_grow);
+    assert_eq!(safari.fragments_capacity, 100);
+    assert!(safari.masking_strict);
+
+    let broker = strict.peer_quirks(Quirks::PongHeartbeats);
+    assert!(!broker.masking_strict);
+    assert!(broker.key_strict);
+    assert!(!broker.fragments_grow);
+}
@@ -0,0 +1,128 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::channel;
+use std::thread;
+
+use ws::{CloseCode, Handler, Handshake, Message, Result, Sender, WebSocket};
+
+const HANDSHAKE: &'static [u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n";
+
+struct Audit {
+    out: Sender,
+    log: ::std::sync::mpsc::Sender<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Handler for Audit {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.log
+            .send((shake.raw_request().to_vec(), shake.raw_response().to_vec()))
+            .unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn raw_handshake_bytes() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (client_tx, client_rx) = channel();
+    let (server_tx, server_rx) = channel();
+
+    let client = thread::spawn(move || {
+        ws::connect(format!("ws://{}/audit", addr), |out| Audit {
+            out,
+            log: client_tx.clone(),
+        }).unwrap();
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut server = WebSocket::new(|out| Audit {
+        out,
+        log: server_tx.clone(),
+    }).unwrap();
+    server.serve_stream(stream).unwrap();
+    server.run().unwrap();
+    assert!(client.join().is_ok());
+
+    let (client_req, client_res) = client_rx.recv().unwrap();
+    let (server_req, server_res) = server_rx.recv().unwrap();
+
+    assert!(client_req.starts_with(b"GET /audit HTTP/1.1\r\n"));
+    assert!(client_req.ends_with(b"\r\n\r\n"));
+    assert!(client_res.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(client_res.ends_with(b"\r\n\r\n"));
+    assert_eq!(client_req, server_req);
+    assert_eq!(client_res, server_res);
+}
+
+// Send a handshake request followed immediately by `trailing`, and return what the server wrote
+// back up to and including its close frame, along with the messages it received.
+fn send_trailing(trailing: &'static [u8]) -> (Vec<u8>, Vec<Message>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = HANDSHAKE.to_vec();
+        request.extend_from_slice(trailing);
+        stream.write_all(&request).unwrap();
+        // Read the response head and the close frame that follows it, then hang up.
+        let mut received = Vec::new();
+        let mut buf = [0u8; 1024];
+        loop {
+            let read = stream.read(&mut buf).unwrap();
+            assert!(read > 0);
+            received.extend_from_slice(&buf[..read]);
+            let head = received.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4);
+            if let Some(head) = head {
+                let frame = &received[head..];
+                if frame.len() >= 2 && frame.len() >= 2 + (frame[1] & 0x7f) as usize {
+                    break;
+                }
+            }
+        }
+        received
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let (tx, rx) = channel();
+    let mut server = WebSocket::new(|out: Sender| {
+        let tx = tx.clone();
+        move |msg| {
+            tx.send(msg).unwrap();
+            out.close(CloseCode::Normal)
+        }
+    }).unwrap();
+    server.serve_stream(stream).unwrap();
+    server.run().unwrap();
+
+    (client.join().unwrap(), rx.try_iter().collect())
+}
+
+#[test]
+fn frames_trailing_handshake() {
+    // A masked text frame containing "hi", sent in the same segment as the request.
+    let (received, messages) = send_trailing(&[0x81, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2]);
+    assert!(received.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+    assert_eq!(messages, vec![Message::text("hi")]);
+    assert!(received.ends_with(&[0x88, 0x02, 0x03, 0xe8]));
+}
+
+#[test]
+fn smuggled_request_is_read_as_frames() {
+    let (received, messages) = send_trailing(b"GET /admin HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Cookie: session=0123456789abcdef0123456789abcdef\r\n\r\n");
+    assert!(received.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(messages.is_empty());
+    // The second request is not a valid frame, so the server fails the connection with a
+    // protocol error instead of answering it.
+    let close = received.windows(2).position(|w| w[0] == 0x88).unwrap();
+    assert_eq!(&received[close + 2..close + 4], &[0x03, 0xea]);
+}
@@ -0,0 +1,82 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{
+    Builder, CloseCode, ConnectionPhase, Direction, Handler, Handshake, Message, OpCode, Result,
+    Sender, StateRecorder,
+};
+
+struct Recorded {
+    out: Sender,
+    recorder: StateRecorder,
+    closed: Channel<StateRecorder>,
+}
+
+impl Handler for Recorded {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.tap(Box::new(self.recorder.clone()))
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        self.closed.send(self.recorder.clone()).unwrap();
+    }
+}
+
+#[test]
+fn records_closing_handshake() {
+    let (tx, closed) = channel();
+    let server = Builder::new()
+        .build(move |out| Recorded {
+            out,
+            recorder: StateRecorder::new(),
+            closed: tx.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    // The tap is attached once the server handles it, so make a round trip first.
+    client.send("hello").unwrap();
+    client.recv().unwrap();
+    client.close(CloseCode::Away).unwrap();
+
+    let recorder = closed.recv_timeout(Duration::from_secs(5)).unwrap();
+    let transitions = recorder.transitions();
+    let steps: Vec<_> = transitions
+        .iter()
+        .map(|transition| (transition.from, transition.to, transition.cause))
+        .collect();
+    assert_eq!(
+        steps,
+        vec![
+            (
+                ConnectionPhase::Open,
+                ConnectionPhase::RespondingClose,
+                Some((Direction::Inbound, OpCode::Close)),
+            ),
+            (
+                ConnectionPhase::RespondingClose,
+                ConnectionPhase::FinishedClose,
+                Some((Direction::Outbound, OpCode::Close)),
+            ),
+        ]
+    );
+    assert_eq!(transitions[0].close_code, Some(CloseCode::Away));
+    assert!(recorder
+        .to_dot()
+        .contains("\"open\" -> \"responding_close\" [label=\"1: received CLOSE 1001\\n+0ms\"]"));
+    assert!(recorder
+        .to_trace()
+        .contains(" responding_close -> finished_close (sent CLOSE 1001)\n"));
+}
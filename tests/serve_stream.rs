@@ -0,0 +1,76 @@
+extern crate parity_ws as ws;
+
+use std::io::Read;
+use std::net::TcpListener;
+use std::thread;
+
+use ws::{CloseCode, Message, Request, Sender, WebSocket};
+
+#[test]
+fn serve_pre_accepted_stream() {
+    const MESSAGE: &'static str = "served from an external accept loop";
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        ws::connect(format!("ws://{}", addr), |out: Sender| {
+            out.send(MESSAGE).unwrap();
+
+            move |msg: Message| {
+                assert_eq!(msg.as_text().unwrap(), MESSAGE);
+                out.close(CloseCode::Normal)
+            }
+        }).unwrap();
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+
+    let mut server = WebSocket::new(|out: Sender| move |msg: Message| out.send(msg)).unwrap();
+    server.serve_stream(stream).unwrap();
+    // Without a listener the event loop finishes once the served connection closes.
+    server.run().unwrap();
+
+    assert!(client.join().is_ok());
+}
+
+#[test]
+fn serve_upgraded_request() {
+    const MESSAGE: &'static str = "handed over from another http server";
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        ws::connect(format!("ws://{}/chat", addr), |out: Sender| {
+            out.send(MESSAGE).unwrap();
+
+            move |msg: Message| {
+                assert_eq!(msg.as_text().unwrap(), MESSAGE);
+                out.close(CloseCode::Normal)
+            }
+        }).unwrap();
+    });
+
+    let (mut stream, _) = listener.accept().unwrap();
+
+    // Read the request the way a foreign HTTP stack would, leaving the socket at the end of it.
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    let parsed = Request::parse(&head).unwrap().unwrap();
+    let request = Request::from_parts(
+        parsed.method(),
+        parsed.resource(),
+        parsed.headers().clone(),
+    );
+
+    ws::from_upgrade(request, stream, |out: Sender| {
+        move |msg: Message| out.send(msg)
+    }).unwrap();
+
+    assert!(client.join().is_ok());
+}
@@ -1,8 +1,13 @@
 extern crate parity_ws as ws;
 
-use std::sync::mpsc::channel;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{channel, Sender as ChannelSender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use ws::sync::Client;
+use ws::{CloseCode, Handler};
 
 #[test]
 fn shutdown_before_connections() {
@@ -39,3 +44,119 @@ fn shutdown_before_connections() {
 
     assert!(t.join().is_ok());
 }
+
+const HANDSHAKE: &'static [u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n";
+
+#[test]
+fn pause_then_drain() {
+    let server = ws::WebSocket::new(|_| |_| Ok(()))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let mut open = Client::connect(format!("ws://{}", addr)).unwrap();
+    handle.pause_accepting().unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // The connection is left in the backlog of the listener without a handshake.
+    let mut waiting = TcpStream::connect(addr).unwrap();
+    waiting.write_all(HANDSHAKE).unwrap();
+    waiting
+        .set_read_timeout(Some(Duration::from_millis(300)))
+        .unwrap();
+    let mut response = [0u8; 1024];
+    assert!(waiting.read(&mut response).is_err());
+
+    // The open connection is closed, after which the server stops.
+    let started = Instant::now();
+    handle.drain(Duration::from_secs(10)).unwrap();
+    assert!(open.recv().is_err());
+    server.join().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+struct Closed {
+    codes: ChannelSender<CloseCode>,
+}
+
+impl Handler for Closed {
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.codes.send(code).unwrap();
+    }
+}
+
+#[test]
+fn drain_aborts_at_deadline() {
+    let (tx, rx) = channel();
+    let server = ws::WebSocket::new(move |_| Closed { codes: tx.clone() })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    // A peer that never answers the closing handshake.
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(HANDSHAKE).unwrap();
+    let mut response = [0u8; 1024];
+    let _ = stream.read(&mut response).unwrap();
+
+    let started = Instant::now();
+    handle.drain(Duration::from_millis(300)).unwrap();
+    server.join().unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(300));
+    assert!(elapsed < Duration::from_secs(5));
+    assert_eq!(rx.try_recv().unwrap(), CloseCode::Abnormal);
+}
+
+struct Restarted {
+    events: ChannelSender<String>,
+}
+
+impl Handler for Restarted {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        self.events.send("open".to_string()).unwrap();
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.events.send(format!("{:?} {}", code, reason)).unwrap();
+    }
+
+    fn on_error(&mut self, err: ws::Error) {
+        self.events.send(format!("error {}", err)).unwrap();
+    }
+}
+
+#[test]
+fn restart_tells_clients_when_to_retry() {
+    let server = ws::WebSocket::new(|_| |_| Ok(()))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let handle = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect(format!("ws://{}", addr), |_| Restarted { events: tx.clone() }).unwrap()
+    });
+    assert_eq!(rx.recv().unwrap(), "open");
+    handle
+        .restart(Duration::from_secs(5), Duration::from_secs(30))
+        .unwrap();
+    server.join().unwrap();
+    client.join().unwrap();
+    let events: Vec<String> = rx.try_iter().collect();
+    assert_eq!(events, vec!["Restart Retry-After: 30".to_string()]);
+}
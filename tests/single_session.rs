@@ -0,0 +1,75 @@
+extern crate parity_ws as ws;
+
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{Authorization, Builder, CloseCode, Credentials, Identity, SessionPolicy, Settings};
+
+// Identifies clients by the resource that they request.
+fn authorize(credentials: &Credentials) -> Authorization {
+    let user = credentials.request().resource().trim_start_matches('/');
+    credentials.context().insert(Identity(user.into()));
+    Authorization::Allow
+}
+
+static AUTHORIZER: fn(&Credentials) -> Authorization = authorize;
+
+fn serve(policy: SessionPolicy) -> String {
+    let mut settings = Settings::default();
+    settings.single_session = Some(policy);
+    let server = Builder::new()
+        .with_settings(settings)
+        .with_authorizer(&AUTHORIZER)
+        .build(|out: ws::Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+    format!("ws://{}", addr)
+}
+
+fn echo(client: &mut Client) {
+    client.send("hello").unwrap();
+    assert_eq!(client.recv().unwrap().as_text().unwrap(), "hello");
+}
+
+#[test]
+fn rejects_new_connection() {
+    let url = serve(SessionPolicy::RejectNew);
+    let mut alice = Client::connect(format!("{}/alice", url)).unwrap();
+    echo(&mut alice);
+
+    assert!(Client::connect(format!("{}/alice", url)).is_err());
+    // Other identities are not affected.
+    echo(&mut Client::connect(format!("{}/bob", url)).unwrap());
+    echo(&mut alice);
+
+    // Once the connection has gone away, the identity may connect again.
+    alice.close(CloseCode::Normal).unwrap();
+    let mut attempts = 0;
+    let mut alice = loop {
+        match Client::connect(format!("{}/alice", url)) {
+            Ok(client) => break client,
+            Err(_) if attempts < 50 => attempts += 1,
+            Err(err) => panic!("{}", err),
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    echo(&mut alice);
+}
+
+#[test]
+fn closes_old_connection() {
+    let url = serve(SessionPolicy::CloseOld(CloseCode::Other(4001)));
+    let mut old = Client::connect(format!("{}/alice", url)).unwrap();
+    echo(&mut old);
+
+    let mut new = Client::connect(format!("{}/alice", url)).unwrap();
+    echo(&mut new);
+    old.set_read_timeout(Some(Duration::from_secs(5)));
+    let err = old.recv().unwrap_err();
+    assert!(err.to_string().contains("4001"), "{}", err);
+    echo(&mut new);
+}
@@ -0,0 +1,65 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc::{channel, Sender as Reporter};
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::util::Token;
+use ws::{Builder, Factory, Handler, Message, Result, Sender, Settings};
+
+struct Echo(Sender);
+
+impl Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if msg.as_text()? == "slow" {
+            thread::sleep(Duration::from_millis(100));
+        }
+        self.0.send(msg)
+    }
+}
+
+struct Reporting(Reporter<(Token, &'static str, Duration)>);
+
+impl Factory for Reporting {
+    type Handler = Echo;
+
+    fn connection_made(&mut self, out: Sender) -> Echo {
+        Echo(out)
+    }
+
+    fn on_slow_callback(&mut self, token: Token, callback: &'static str, duration: Duration) {
+        self.0.send((token, callback, duration)).unwrap();
+    }
+}
+
+#[test]
+fn report_slow_callbacks() {
+    let mut settings = Settings::default();
+    settings.slow_callback_threshold = Some(Duration::from_millis(50));
+    let (tx, reports) = channel();
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(Reporting(tx))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    client.send("fast").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("fast"));
+    client.send("slow").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("slow"));
+
+    let (token, callback, duration) = reports.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(token, Token(0));
+    assert_eq!(callback, "on_message");
+    assert!(duration >= Duration::from_millis(100));
+    assert!(reports.try_recv().is_err());
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,143 @@
+//! Long running checks for memory growth across many connections.
+//!
+//! These take a while, so they only run with `cargo test --release --features soak --test soak`.
+#![cfg(feature = "soak")]
+extern crate parity_ws as ws;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use ws::{Builder, CircularBuffer, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+const ROUNDS: usize = 200;
+const CONNECTIONS_PER_ROUND: usize = 100;
+const WARMUP_ROUNDS: usize = 20;
+const MESSAGE_SIZE: usize = 32 * 1024;
+// Allocator fragmentation and lazily mapped pages make some growth unavoidable, but it must
+// level off rather than scale with the number of connections.
+const RSS_GROWTH_LIMIT: usize = 16 * 1024 * 1024;
+
+// Resident set size of this process, or None where /proc is not available.
+fn rss() -> Option<usize> {
+    let mut statm = String::new();
+    File::open("/proc/self/statm")
+        .and_then(|mut file| file.read_to_string(&mut statm))
+        .ok()?;
+    let pages: usize = statm.split_whitespace().nth(1)?.parse().ok()?;
+    Some(pages * 4096)
+}
+
+struct Echo {
+    out: Sender,
+    max_token: Arc<AtomicUsize>,
+}
+
+impl Handler for Echo {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.max_token.fetch_max(self.out.token().0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+}
+
+struct Client {
+    out: Sender,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send(vec![0x5a; MESSAGE_SIZE])
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        assert_eq!(msg.len(), MESSAGE_SIZE);
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn reconnect_cycles() {
+    let mut settings = Settings::default();
+    settings.max_connections = CONNECTIONS_PER_ROUND * 2;
+    // Make every message outgrow the soft limits so that each connection's buffers grow.
+    settings.in_buffer_capacity_soft_limit = 16 * 1024;
+    settings.out_buffer_capacity_soft_limit = 16 * 1024;
+
+    let max_token = Arc::new(AtomicUsize::new(0));
+    let server_max_token = max_token.clone();
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(move |out| Echo {
+            out,
+            max_token: server_max_token.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let shutdown = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut baseline = None;
+    for round in 0..ROUNDS {
+        let mut client = Builder::new()
+            .with_settings(settings)
+            .build(|out| Client { out })
+            .unwrap();
+        for _ in 0..CONNECTIONS_PER_ROUND {
+            client.connect(url.parse().unwrap()).unwrap();
+        }
+        client.run().unwrap();
+
+        if round + 1 == WARMUP_ROUNDS {
+            baseline = rss();
+        }
+    }
+
+    shutdown.shutdown().unwrap();
+    server.join().unwrap();
+
+    // Closed connections must give their slots back to the slab.
+    assert!(max_token.load(Ordering::SeqCst) < CONNECTIONS_PER_ROUND * 2);
+
+    if let (Some(baseline), Some(end)) = (baseline, rss()) {
+        assert!(
+            end <= baseline + RSS_GROWTH_LIMIT,
+            "resident memory grew from {} to {} bytes",
+            baseline,
+            end
+        );
+    }
+}
+
+#[test]
+fn buffer_shrinks_after_burst() {
+    const INITIAL: usize = 2048;
+    const SOFT_LIMIT: usize = 16 * 1024;
+
+    let mut buffer = CircularBuffer::new(INITIAL, 10 * 1024 * 1024);
+    let burst = vec![0x5a; MESSAGE_SIZE];
+    let mut drained = vec![0; MESSAGE_SIZE];
+
+    for _ in 0..10_000 {
+        buffer.write_all(&burst).unwrap();
+        assert!(buffer.current_capacity() >= MESSAGE_SIZE);
+        buffer.read_exact(&mut drained).unwrap();
+        buffer.apply_soft_limit(SOFT_LIMIT);
+        assert!(buffer.current_capacity() <= SOFT_LIMIT);
+
+        // Small messages after a burst must not keep the large allocation alive either.
+        buffer.write_all(&burst[..100]).unwrap();
+        buffer.read_exact(&mut drained[..100]).unwrap();
+        buffer.apply_soft_limit(SOFT_LIMIT);
+        assert!(buffer.current_capacity() <= SOFT_LIMIT);
+    }
+}
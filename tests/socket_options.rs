@@ -0,0 +1,45 @@
+extern crate parity_ws as ws;
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use ws::{CloseCode, Message, Sender, WebSocket};
+
+#[test]
+fn toggle_nodelay_at_runtime() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        ws::connect(format!("ws://{}", addr), |out: Sender| {
+            out.send("auth").unwrap();
+
+            move |msg: Message| {
+                assert_eq!(msg.as_text().unwrap(), "authenticated");
+                out.close(CloseCode::Normal)
+            }
+        }).unwrap();
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    // A clone shares the socket, so it sees the options set by the event loop.
+    let socket = stream.try_clone().unwrap();
+    assert!(!socket.nodelay().unwrap());
+
+    let mut server = WebSocket::new(|out: Sender| {
+        move |msg: Message| {
+            assert_eq!(msg.as_text().unwrap(), "auth");
+            out.set_nodelay(true)?;
+            out.set_keepalive(Some(Duration::from_secs(60)))?;
+            out.send("authenticated")
+        }
+    }).unwrap();
+    server.serve_stream(stream).unwrap();
+    server.run().unwrap();
+
+    assert!(socket.nodelay().unwrap());
+    // The client waits for the socket to be closed, which the clone would prevent.
+    drop(socket);
+    assert!(client.join().is_ok());
+}
@@ -0,0 +1,100 @@
+extern crate parity_ws as ws;
+extern crate url;
+
+use std::io::{copy, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, CloseCode, Message, Sender, Settings, WebSocket};
+
+// A minimal SOCKS5 proxy that sends every connection to `target`, no matter which host was
+// requested, and records the requested host name.
+fn proxy(listener: TcpListener, target: SocketAddr) -> String {
+    let (mut client, _) = listener.accept().unwrap();
+
+    let mut greeting = [0u8; 3];
+    client.read_exact(&mut greeting).unwrap();
+    assert_eq!(greeting, [5, 1, 0]);
+    client.write_all(&[5, 0]).unwrap();
+
+    let mut head = [0u8; 5];
+    client.read_exact(&mut head).unwrap();
+    assert_eq!(&head[..4], &[5, 1, 0, 3]);
+    let mut host = vec![0u8; head[4] as usize + 2];
+    client.read_exact(&mut host).unwrap();
+    host.truncate(head[4] as usize);
+
+    let server = TcpStream::connect(target).unwrap();
+    client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+
+    let mut client_read = client.try_clone().unwrap();
+    let mut server_write = server.try_clone().unwrap();
+    let (mut server_read, mut client_write) = (server, client);
+    let upstream = thread::spawn(move || copy(&mut client_read, &mut server_write));
+    let _ = copy(&mut server_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+
+    String::from_utf8(host).unwrap()
+}
+
+#[test]
+fn connect_through_socks5() {
+    const MESSAGE: &'static str = "tunnelled";
+
+    let server_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let server_addr = server_listener.local_addr().unwrap();
+    let proxy_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = proxy_listener.local_addr().unwrap();
+
+    let proxy = thread::spawn(move || proxy(proxy_listener, server_addr));
+    let server = thread::spawn(move || {
+        let (stream, _) = server_listener.accept().unwrap();
+        let mut server = WebSocket::new(|out: Sender| move |msg: Message| out.send(msg)).unwrap();
+        server.serve_stream(stream).unwrap();
+        server.run().unwrap();
+    });
+
+    let mut settings = Settings::default();
+    settings.socks5_proxy = Some(proxy_addr);
+
+    let mut client = Builder::new()
+        .with_settings(settings)
+        .build(|out: Sender| {
+            out.send(MESSAGE).unwrap();
+            move |msg: Message| {
+                assert_eq!(msg.as_text().unwrap(), MESSAGE);
+                out.close(CloseCode::Normal)
+            }
+        })
+        .unwrap();
+    client
+        .connect(url::Url::parse("ws://service.onion/").unwrap())
+        .unwrap();
+    client.run().unwrap();
+
+    assert!(server.join().is_ok());
+    assert_eq!(proxy.join().unwrap(), "service.onion");
+}
+
+#[test]
+fn negotiation_does_not_block() {
+    // A proxy that accepts connections but never answers them.
+    let stalled = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut settings = Settings::default();
+    settings.socks5_proxy = Some(stalled.local_addr().unwrap());
+
+    let mut client = Builder::new()
+        .with_settings(settings)
+        .build(|_| |_| Ok(()))
+        .unwrap();
+    client
+        .connect(url::Url::parse("ws://service.onion/").unwrap())
+        .unwrap();
+    client.broadcaster().shutdown().unwrap();
+
+    let started = Instant::now();
+    client.run().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
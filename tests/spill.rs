@@ -0,0 +1,75 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+const MESSAGES: usize = 200;
+const SIZE: usize = 10_000;
+
+// Sends a backlog far larger than its outgoing buffer may grow as soon as the client connects.
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        for i in 0..MESSAGES {
+            self.out.send(vec![i as u8; SIZE])?;
+        }
+        self.out.send("done")
+    }
+}
+
+struct Client {
+    out: Sender,
+    received: mpsc::Sender<Message>,
+}
+
+impl Handler for Client {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let done = msg.is_text();
+        self.received.send(msg).unwrap();
+        if done {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn backlog_spills_to_disk() {
+    let mut settings = Settings::default();
+    settings.out_buffer_capacity = 4096;
+    settings.out_buffer_capacity_hard_limit = 64 * 1024;
+    settings.spill_threshold = 32 * 1024;
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (received, rx) = mpsc::channel();
+    ws::connect(url, |out| Client {
+        out,
+        received: received.clone(),
+    })
+    .unwrap();
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+
+    let messages = rx.try_iter().collect::<Vec<_>>();
+    assert_eq!(messages.len(), MESSAGES + 1);
+    for (i, msg) in messages[..MESSAGES].iter().enumerate() {
+        assert_eq!(msg.clone().into_data(), vec![i as u8; SIZE]);
+    }
+    assert_eq!(messages[MESSAGES], Message::text("done"));
+}
@@ -0,0 +1,174 @@
+extern crate parity_ws as ws;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{channel, Sender as ChannelSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, Error, ErrorKind, Handler, Handshake, Result, Sender, Settings};
+
+struct Server {
+    errors: ChannelSender<bool>,
+}
+
+impl Handler for Server {
+    fn on_error(&mut self, err: Error) {
+        if let ErrorKind::Stalled = err.kind {
+            self.errors.send(true).unwrap();
+        }
+    }
+}
+
+#[test]
+fn trickled_header_is_rejected() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\n\
+                  Connection: Upgrade\r\n\
+                  Upgrade: websocket\r\n\
+                  Sec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = [0u8; 1024];
+        let _ = stream.read(&mut response).unwrap();
+
+        // A masked frame with a 64 bit length has a 14 byte header; send it a byte at a time.
+        for byte in &[0x82u8, 0xFF, 0, 0, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4] {
+            if stream.write_all(&[*byte]).is_err() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let (tx, rx) = channel();
+
+    let mut settings = Settings::default();
+    settings.frame_header_read_limit = 3;
+    let mut server = Builder::new()
+        .with_settings(settings)
+        .build(|_| Server { errors: tx.clone() })
+        .unwrap();
+    server.serve_stream(stream).unwrap();
+    server.run().unwrap();
+
+    assert!(rx.try_recv().unwrap());
+    assert!(client.join().is_ok());
+}
+
+#[test]
+fn split_header_is_accepted() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\n\
+                  Connection: Upgrade\r\n\
+                  Upgrade: websocket\r\n\
+                  Sec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = [0u8; 1024];
+        let _ = stream.read(&mut response).unwrap();
+
+        // More bytes of the header than the limit allows reads arrive in one read, and the rest
+        // of the frame in the next.
+        stream.write_all(&[0x81, 0x82, 0, 0]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(&[0, 0, b'h', b'i']).unwrap();
+        let mut echo = [0u8; 4];
+        stream.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, &[0x81, 2, b'h', b'i']);
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let mut settings = Settings::default();
+    settings.frame_header_read_limit = 1;
+    let mut server = Builder::new()
+        .with_settings(settings)
+        .build(|out: Sender| move |msg| out.send(msg))
+        .unwrap();
+    server.serve_stream(stream).unwrap();
+    server.run().unwrap();
+
+    assert!(client.join().is_ok());
+}
+
+// Floods the peer with data as soon as the connection opens.
+struct Flood {
+    out: Sender,
+    errors: ChannelSender<bool>,
+}
+
+impl Handler for Flood {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        for _ in 0..32 {
+            self.out.send(vec![0u8; 1024 * 1024])?;
+        }
+        Ok(())
+    }
+
+    fn on_error(&mut self, err: Error) {
+        if let ErrorKind::Stalled = err.kind {
+            self.errors.send(true).unwrap();
+        }
+    }
+}
+
+#[test]
+fn peer_that_stops_reading_is_dropped() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (done, finished) = channel();
+    let client = thread::spawn(move || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\n\
+                  Connection: Upgrade\r\n\
+                  Upgrade: websocket\r\n\
+                  Sec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = [0u8; 1024];
+        let _ = stream.read(&mut response).unwrap();
+        // Stop reading, but keep the connection open until the server gives up on it.
+        finished.recv().unwrap();
+    });
+
+    let (stream, _) = listener.accept().unwrap();
+    let (tx, rx) = channel();
+
+    let mut settings = Settings::default();
+    settings.out_buffer_capacity_hard_limit = 64 * 1024 * 1024;
+    settings.write_stall_timeout_ms = 200;
+    let mut server = Builder::new()
+        .with_settings(settings)
+        .build(|out| Flood {
+            out,
+            errors: tx.clone(),
+        })
+        .unwrap();
+    let started = Instant::now();
+    server.serve_stream(stream).unwrap();
+    server.run().unwrap();
+
+    assert!(rx.try_recv().unwrap());
+    assert!(started.elapsed() < Duration::from_secs(5));
+    done.send(()).unwrap();
+    assert!(client.join().is_ok());
+}
@@ -0,0 +1,116 @@
+extern crate parity_ws as ws;
+
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{Builder, CloseCode, ErrorKind, Message, Settings, WebSocket};
+
+// Serve a single connection that echoes messages, returning its url.
+fn echo_server() -> (String, thread::JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut ws = WebSocket::new(|out: ws::Sender| move |msg| out.send(msg)).unwrap();
+        ws.serve_stream(stream).unwrap();
+        ws.run().unwrap();
+    });
+    (url, server)
+}
+
+#[test]
+fn send_and_receive() {
+    let (url, server) = echo_server();
+    let mut client = Client::connect(url).unwrap();
+    client.send("hello").unwrap();
+    client.send(vec![1u8, 2, 3]).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("hello"));
+    assert_eq!(client.recv().unwrap(), Message::binary(vec![1u8, 2, 3]));
+    client.close(CloseCode::Normal).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn read_timeout() {
+    let (url, server) = echo_server();
+    let mut client = Client::connect_timeout(url, Duration::from_secs(5)).unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(100)));
+    match client.recv() {
+        Err(ws::Error {
+            kind: ErrorKind::Io(ref err),
+            ..
+        }) if err.kind() == std::io::ErrorKind::TimedOut => (),
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+    drop(client);
+    server.join().unwrap();
+}
+
+#[test]
+fn server_close() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut ws = WebSocket::new(|out: ws::Sender| {
+            move |msg| {
+                out.send(msg)?;
+                out.close(CloseCode::Away)
+            }
+        }).unwrap();
+        ws.serve_stream(stream).unwrap();
+        ws.run().unwrap();
+    });
+
+    let mut client = Client::connect(url).unwrap();
+    client.send("last").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("last"));
+    match client.recv() {
+        Err(ws::Error {
+            kind: ErrorKind::Io(ref err),
+            ..
+        }) if err.kind() == std::io::ErrorKind::ConnectionAborted => (),
+        other => panic!("expected the connection to be closed, got {:?}", other),
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn skips_control_frames() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut settings = Settings::default();
+        settings.max_connections = 1;
+        settings.queue_size = 100_010;
+        let mut ws = Builder::new()
+            .with_settings(settings)
+            .build(|out: ws::Sender| {
+                move |msg| {
+                    // Unsolicited pongs reach the client as events that `recv` passes over.
+                    for _ in 0..100_000 {
+                        out.pong(Vec::new())?;
+                    }
+                    out.send(msg)
+                }
+            })
+            .unwrap();
+        ws.serve_stream(stream).unwrap();
+        ws.run().unwrap();
+    });
+
+    let mut client = Client::connect(url).unwrap();
+    client.send("after the pongs").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("after the pongs"));
+    client.close(CloseCode::Normal).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn connection_refused() {
+    let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    assert!(Client::connect(format!("ws://{}", addr)).is_err());
+}
@@ -0,0 +1,90 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{
+    CloseCode, Direction, Frame, Handler, Handshake, Message, OpCode, Result, Sender, WebSocket,
+};
+
+// Taps its connection, then echoes messages after saying that it is ready.
+struct Server {
+    out: Sender,
+    frames: mpsc::Sender<(Direction, OpCode, Vec<u8>)>,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        let frames = self.frames.clone();
+        self.out
+            .tap(Box::new(move |direction: Direction, frame: &Frame| {
+                let _ = frames.send((direction, frame.opcode(), frame.payload().clone()));
+            }))?;
+        // The tap is attached before anything queued after it is sent.
+        self.out.send("ready")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+
+    // Frames handed to an observer can not be changed by it, but the handler still can.
+    fn on_frame(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
+        if frame.opcode() == OpCode::Text {
+            frame.payload_mut().make_ascii_uppercase();
+        }
+        Ok(Some(frame))
+    }
+}
+
+#[test]
+fn observe_frames() {
+    let (frames, observed) = mpsc::channel();
+    let server = WebSocket::new(move |out| Server {
+        out,
+        frames: frames.clone(),
+    })
+    .unwrap()
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    ws::connect(url, |out| {
+        move |msg: Message| {
+            if msg.as_text()? == "ready" {
+                out.send("hello")
+            } else {
+                out.close(CloseCode::Normal)
+            }
+        }
+    })
+    .unwrap();
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+
+    let observed = observed.iter().collect::<Vec<_>>();
+    assert_eq!(
+        &observed[..3],
+        &[
+            (Direction::Outbound, OpCode::Text, b"ready".to_vec()),
+            (Direction::Inbound, OpCode::Text, b"hello".to_vec()),
+            (Direction::Outbound, OpCode::Text, b"HELLO".to_vec()),
+        ]
+    );
+    assert_eq!(observed[3].0, Direction::Inbound);
+    assert_eq!(observed[3].1, OpCode::Close);
+    assert_eq!(observed[4].0, Direction::Outbound);
+    assert_eq!(observed[4].1, OpCode::Close);
+}
+
+#[test]
+fn broadcaster_cannot_tap() {
+    let server = WebSocket::new(|_| |_| Ok(())).unwrap();
+    let observer = |_: Direction, _: &Frame| {};
+    assert!(server.broadcaster().tap(Box::new(observer)).is_err());
+}
@@ -0,0 +1,294 @@
+#![cfg(feature = "ssl")]
+extern crate openssl;
+extern crate parity_ws as ws;
+extern crate url;
+
+use std::io::{copy, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream as StdTcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::ssl::{
+    SslAcceptor, SslConnector, SslConnectorBuilder, SslMethod, SslStream, SslVerifyMode,
+};
+use openssl::x509::{X509NameBuilder, X509};
+
+use ws::util::TcpStream;
+use ws::{
+    Builder, CloseCode, Handler, Handshake, HandshakeAcceptor, Message, Result, Sender, Settings,
+    TlsSessionCache,
+};
+
+// An acceptor with a freshly generated, self-signed certificate for localhost.
+fn acceptor() -> SslAcceptor {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    let cert = cert.build();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&cert).unwrap();
+    acceptor.build()
+}
+
+// A connector that trusts the self-signed certificate of the acceptor.
+fn insecure() -> SslConnectorBuilder {
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector.set_verify(SslVerifyMode::NONE);
+    connector
+}
+
+// Records the names of the threads that handshakes are performed on, and whether they resumed
+// an earlier session.
+struct Recording {
+    acceptor: SslAcceptor,
+    handshakes: Arc<Mutex<Vec<(String, bool)>>>,
+}
+
+impl HandshakeAcceptor for Recording {
+    fn accept(&self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        let name = thread::current().name().unwrap_or_default().to_string();
+        let stream = self.acceptor.accept(stream)?;
+        let reused = stream.ssl().session_reused();
+        self.handshakes.lock().unwrap().push((name, reused));
+        Ok(stream)
+    }
+}
+
+// A server that echoes messages, with the TLS handshakes done by a `Recording` acceptor.
+struct Server {
+    addr: SocketAddr,
+    url: String,
+    handshakes: Arc<Mutex<Vec<(String, bool)>>>,
+    // The threads that the handlers are made on.
+    opened: mpsc::Receiver<thread::ThreadId>,
+    broadcaster: Sender,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Server {
+    fn start() -> Server {
+        let mut settings = Settings::default();
+        settings.encrypt_server = true;
+        let (made, opened) = mpsc::channel();
+        let mut server = Builder::new()
+            .with_settings(settings)
+            .build(move |out: Sender| {
+                made.send(thread::current().id()).unwrap();
+                move |msg| out.send(msg)
+            })
+            .unwrap();
+        let handshakes = Arc::new(Mutex::new(Vec::new()));
+        let recording = Recording {
+            acceptor: acceptor(),
+            handshakes: handshakes.clone(),
+        };
+        server.offload_tls_handshakes(recording, 3).unwrap();
+        let server = server.bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let url = format!("wss://localhost:{}", addr.port());
+        let broadcaster = server.broadcaster();
+        let thread = thread::spawn(move || {
+            server.run().unwrap();
+        });
+        Server {
+            addr,
+            url,
+            handshakes,
+            opened,
+            broadcaster,
+            thread,
+        }
+    }
+
+    fn stop(self) {
+        self.broadcaster.shutdown().unwrap();
+        self.thread.join().unwrap();
+    }
+}
+
+// Sends a message and closes the connection once it is echoed back.
+struct Client {
+    out: Sender,
+    echoed: mpsc::Sender<Message>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("hello")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.echoed.send(msg).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn upgrade_ssl_client(
+        &mut self,
+        stream: TcpStream,
+        url: &url::Url,
+    ) -> Result<SslStream<TcpStream>> {
+        insecure()
+            .build()
+            .connect(url.domain().unwrap(), stream)
+            .map_err(From::from)
+    }
+}
+
+#[test]
+fn offload_tls_handshakes() {
+    let server = Server::start();
+
+    let (echoed, echoes) = mpsc::channel();
+    let mut clients = Builder::new()
+        .build(move |out| Client {
+            out,
+            echoed: echoed.clone(),
+        })
+        .unwrap();
+    for _ in 0..8 {
+        clients.connect(server.url.parse().unwrap()).unwrap();
+    }
+    clients.run().unwrap();
+
+    assert_eq!(
+        echoes.iter().collect::<Vec<_>>(),
+        vec![Message::text("hello"); 8]
+    );
+    let handshakes = server.handshakes.lock().unwrap().clone();
+    assert_eq!(handshakes.len(), 8);
+    assert!(handshakes
+        .iter()
+        .all(|(name, _)| name.starts_with("ws-tls-handshake-")));
+    // The encrypted streams are handed back to the event loop, which builds the handlers.
+    for _ in 0..8 {
+        assert_eq!(server.opened.recv().unwrap(), server.thread.thread().id());
+    }
+    server.stop();
+}
+
+// Closes the connection as soon as it opens.
+struct Closer {
+    out: Sender,
+    tls: Option<TlsSessionCache>,
+}
+
+impl Handler for Closer {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn upgrade_ssl_client(
+        &mut self,
+        stream: TcpStream,
+        url: &url::Url,
+    ) -> Result<SslStream<TcpStream>> {
+        let domain = url.domain().unwrap();
+        match self.tls {
+            Some(ref tls) => tls.connect(domain, stream),
+            None => insecure()
+                .build()
+                .connect(domain, stream)
+                .map_err(From::from),
+        }
+    }
+}
+
+#[test]
+fn resumes_tls_sessions() {
+    let server = Server::start();
+    let tls = TlsSessionCache::new(insecure(), 4);
+
+    let connect = |tls: Option<TlsSessionCache>| {
+        let mut client = Builder::new()
+            .build(move |out| Closer {
+                out,
+                tls: tls.clone(),
+            })
+            .unwrap();
+        client.connect(server.url.parse().unwrap()).unwrap();
+        client.run().unwrap();
+    };
+    connect(Some(tls.clone()));
+    assert_eq!(tls.len(), 1);
+    connect(Some(tls.clone()));
+    // Connections that do not use the cache negotiate new sessions.
+    connect(None);
+
+    let reused: Vec<bool> = server
+        .handshakes
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|&(_, reused)| reused)
+        .collect();
+    assert_eq!(reused, vec![false, true, false]);
+    server.stop();
+}
+
+// A SOCKS5 proxy for a single connection, which it tunnels to `target` no matter which host was
+// requested. Returns the requested host name.
+fn proxy(listener: TcpListener, target: SocketAddr) -> String {
+    let (mut client, _) = listener.accept().unwrap();
+    let mut greeting = [0u8; 3];
+    client.read_exact(&mut greeting).unwrap();
+    client.write_all(&[5, 0]).unwrap();
+    let mut head = [0u8; 5];
+    client.read_exact(&mut head).unwrap();
+    let mut host = vec![0u8; head[4] as usize + 2];
+    client.read_exact(&mut host).unwrap();
+    host.truncate(head[4] as usize);
+
+    let server = StdTcpStream::connect(target).unwrap();
+    client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+    let mut client_read = client.try_clone().unwrap();
+    let mut server_write = server.try_clone().unwrap();
+    let (mut server_read, mut client_write) = (server, client);
+    let upstream = thread::spawn(move || copy(&mut client_read, &mut server_write));
+    let _ = copy(&mut server_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+    String::from_utf8(host).unwrap()
+}
+
+#[test]
+fn wss_through_socks5() {
+    let server = Server::start();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut settings = Settings::default();
+    settings.socks5_proxy = Some(listener.local_addr().unwrap());
+    let target = server.addr;
+    let proxy = thread::spawn(move || proxy(listener, target));
+
+    let (echoed, echoes) = mpsc::channel();
+    let mut client = Builder::new()
+        .with_settings(settings)
+        .build(move |out| Client {
+            out,
+            echoed: echoed.clone(),
+        })
+        .unwrap();
+    client.connect(server.url.parse().unwrap()).unwrap();
+    client.run().unwrap();
+
+    assert_eq!(echoes.recv().unwrap(), Message::text("hello"));
+    // TLS runs inside the tunnel, so the proxy only sees the host name.
+    assert_eq!(proxy.join().unwrap(), "localhost");
+    server.stop();
+}
@@ -0,0 +1,116 @@
+extern crate parity_ws as ws;
+
+use std::sync::Mutex;
+use std::thread;
+
+use ws::util::Token;
+use ws::{
+    Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings, TraceId, Tracer,
+};
+
+struct Recorder(Mutex<Vec<(&'static str, TraceId)>>);
+
+impl Recorder {
+    fn events(&self) -> Vec<(&'static str, TraceId)> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Tracer for Recorder {
+    fn on_enqueue(&self, _: Token, trace: TraceId) {
+        self.0.lock().unwrap().push(("enqueue", trace))
+    }
+
+    fn on_flush(&self, _: Token, trace: TraceId) {
+        self.0.lock().unwrap().push(("flush", trace))
+    }
+
+    fn on_deliver(&self, _: Token, trace: TraceId) {
+        self.0.lock().unwrap().push(("deliver", trace))
+    }
+}
+
+static SERVER: Recorder = Recorder(Mutex::new(Vec::new()));
+static CLIENT: Recorder = Recorder(Mutex::new(Vec::new()));
+
+fn settings(tracer: &'static Recorder) -> Settings {
+    let mut settings = Settings::default();
+    settings.trace_ids = true;
+    settings.tracer = Some(tracer);
+    settings
+}
+
+// Echoes messages, passing their trace IDs on.
+struct Relay(Sender);
+
+impl Handler for Relay {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        match self.0.context().get::<TraceId>() {
+            Some(trace) => self.0.send_traced(msg, trace),
+            None => self.0.send(msg),
+        }
+    }
+}
+
+struct Client {
+    out: Sender,
+    received: Vec<(Message, Option<TraceId>)>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send_traced("traced", TraceId(42))?;
+        self.out.send("untraced")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.push((msg, self.out.context().get::<TraceId>()));
+        if self.received.len() == 2 {
+            assert_eq!(
+                self.received,
+                vec![
+                    (Message::text("traced"), Some(TraceId(42))),
+                    (Message::text("untraced"), None),
+                ]
+            );
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn trace_through_both_endpoints() {
+    let server = Builder::new()
+        .with_settings(settings(&SERVER))
+        .build(Relay)
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let mut client = Builder::new()
+        .with_settings(settings(&CLIENT))
+        .build(|out| Client {
+            out,
+            received: Vec::new(),
+        })
+        .unwrap();
+    client.connect(url.parse().unwrap()).unwrap();
+    client.run().unwrap();
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+
+    let trace = TraceId(42);
+    assert_eq!(
+        CLIENT.events(),
+        vec![("enqueue", trace), ("flush", trace), ("deliver", trace)]
+    );
+    assert_eq!(
+        SERVER.events(),
+        vec![("deliver", trace), ("enqueue", trace), ("flush", trace)]
+    );
+}
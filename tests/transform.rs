@@ -0,0 +1,59 @@
+extern crate parity_ws as ws;
+
+use std::thread;
+
+use ws::sync::Client;
+use ws::{Builder, CloseCode, Message, OpCode, Result, Sender, Settings, Transform};
+
+// Swaps the case of ASCII letters, which keeps text valid UTF-8.
+struct SwapCase;
+
+impl Transform for SwapCase {
+    fn encode(&self, opcode: OpCode, payload: &mut Vec<u8>) -> Result<()> {
+        if opcode == OpCode::Text {
+            payload.make_ascii_uppercase();
+        }
+        Ok(())
+    }
+
+    fn decode(&self, opcode: OpCode, payload: &mut Vec<u8>) -> Result<()> {
+        if opcode == OpCode::Text {
+            payload.make_ascii_lowercase();
+        }
+        Ok(())
+    }
+}
+
+static SWAP_CASE: SwapCase = SwapCase;
+
+#[test]
+fn transform_payloads() {
+    let mut settings = Settings::default();
+    settings.transform = Some(&SWAP_CASE);
+    // Force fragmentation to check that whole messages are transformed.
+    settings.fragment_size = 4;
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(|out: Sender| {
+            move |msg: Message| {
+                assert!(msg == Message::text("hello, world") || msg.is_binary());
+                out.send(msg)
+            }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    client.send("Hello, World").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("HELLO, WORLD"));
+    client.send(vec![b'a', b'B']).unwrap();
+    assert_eq!(client.recv().unwrap(), Message::binary(vec![b'a', b'B']));
+
+    client.close(CloseCode::Normal).unwrap();
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
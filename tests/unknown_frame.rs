@@ -0,0 +1,119 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use ws::{
+    CloseCode, Decision, Frame, Handler, Handshake, Message, OpCode, Result, Sender, WebSocket,
+};
+
+// Sends a binary message as a frame with a reserved opcode, followed by a text message.
+struct Experimental {
+    out: Sender,
+    closed: mpsc::Sender<CloseCode>,
+}
+
+impl Handler for Experimental {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send(Message::binary(vec![1, 2, 3]))?;
+        self.out.send("done")
+    }
+
+    fn on_send_frame(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
+        if frame.opcode() == OpCode::Binary {
+            frame.set_opcode(OpCode::Reserved(3));
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+// Records the messages that it receives, and closes the connection after the text message.
+struct Server {
+    out: Sender,
+    decision: Decision,
+    messages: mpsc::Sender<Message>,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let done = msg.is_text();
+        self.messages.send(msg).unwrap();
+        if done {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+
+    fn on_unknown_frame(&mut self, frame: &Frame) -> Decision {
+        assert_eq!(frame.opcode(), OpCode::Reserved(3));
+        self.decision
+    }
+}
+
+fn exchange<H, F>(factory: F) -> CloseCode
+where
+    H: Handler + Send + 'static,
+    F: FnMut(Sender) -> H + Send + 'static,
+{
+    let server = WebSocket::new(factory)
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (closed, close) = mpsc::channel();
+    let client = thread::spawn(move || {
+        ws::connect(url, move |out| Experimental {
+            out,
+            closed: closed.clone(),
+        })
+        .unwrap()
+    });
+    let code = close.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    client.join().unwrap();
+    code
+}
+
+#[test]
+fn deliver() {
+    let (messages, received) = mpsc::channel();
+    let code = exchange(move |out| Server {
+        out,
+        decision: Decision::Deliver,
+        messages: messages.clone(),
+    });
+    assert_eq!(received.recv().unwrap(), Message::binary(vec![1, 2, 3]));
+    assert_eq!(received.recv().unwrap(), Message::text("done"));
+    assert_eq!(code, CloseCode::Normal);
+}
+
+#[test]
+fn drop() {
+    let (messages, received) = mpsc::channel();
+    let code = exchange(move |out| Server {
+        out,
+        decision: Decision::Drop,
+        messages: messages.clone(),
+    });
+    assert_eq!(received.recv().unwrap(), Message::text("done"));
+    assert!(received.try_recv().is_err());
+    assert_eq!(code, CloseCode::Normal);
+}
+
+#[test]
+fn fail_by_default() {
+    let code = exchange(|_| |_| Ok(()));
+    assert_eq!(code, CloseCode::Protocol);
+}
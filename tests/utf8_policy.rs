@@ -0,0 +1,94 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc;
+use std::thread;
+
+use ws::{
+    Builder, CloseCode, Frame, Handler, Handshake, Message, OpCode, Result, Sender, Settings,
+    Utf8Policy,
+};
+
+const INVALID: &[u8] = b"caf\xC3 au lait";
+
+// Sends invalid UTF-8 as a text message, by sending it as binary and changing the opcode.
+struct Legacy {
+    out: Sender,
+    closed: mpsc::Sender<CloseCode>,
+}
+
+impl Handler for Legacy {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send(INVALID)
+    }
+
+    fn on_send_frame(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
+        if frame.opcode() == OpCode::Binary {
+            frame.set_opcode(OpCode::Text);
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+// The message delivered by a server with the given policy, which closes the connection after
+// the first message, and the close code that the client receives.
+fn receive(policy: Utf8Policy) -> (Option<Message>, CloseCode) {
+    let mut settings = Settings::default();
+    settings.utf8_policy = policy;
+    let (messages, received) = mpsc::channel();
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(move |out: Sender| {
+            let messages = messages.clone();
+            move |msg| {
+                messages.send(msg).unwrap();
+                out.close(CloseCode::Normal)
+            }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let url = format!("ws://{}", server.local_addr().unwrap());
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (closed, close) = mpsc::channel();
+    ws::connect(url, move |out| Legacy {
+        out,
+        closed: closed.clone(),
+    })
+    .unwrap();
+    let code = close.recv().unwrap();
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+    (received.try_recv().ok(), code)
+}
+
+#[test]
+fn strict() {
+    assert_eq!(receive(Utf8Policy::Strict), (None, CloseCode::Invalid));
+}
+
+#[test]
+fn replace_lossy() {
+    assert_eq!(
+        receive(Utf8Policy::ReplaceLossy),
+        (
+            Some(Message::text("caf\u{FFFD} au lait")),
+            CloseCode::Normal
+        )
+    );
+}
+
+#[test]
+fn deliver_bytes() {
+    assert_eq!(
+        receive(Utf8Policy::DeliverBytes),
+        (Some(Message::binary(INVALID)), CloseCode::Normal)
+    );
+}
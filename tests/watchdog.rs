@@ -0,0 +1,51 @@
+extern crate parity_ws as ws;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{Builder, Message, Sender, Settings};
+
+static STALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn stalled(_: Duration) {
+    STALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+static STALLED: fn(Duration) = stalled;
+
+#[test]
+fn blocking_handler_trips_watchdog() {
+    let mut settings = Settings::default();
+    settings.watchdog = Some(&STALLED);
+    settings.watchdog_timeout_ms = 100;
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(|out: Sender| {
+            move |msg: Message| {
+                if msg.as_text()? == "block" {
+                    thread::sleep(Duration::from_millis(500));
+                }
+                out.send(msg)
+            }
+        })
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    let broadcaster = server.broadcaster();
+    let server = thread::spawn(move || server.run().unwrap());
+
+    // An idle event loop keeps ticking.
+    let mut client = Client::connect(format!("ws://{}", addr)).unwrap();
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(STALLS.load(Ordering::SeqCst), 0);
+
+    client.send("block").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("block"));
+    assert_eq!(STALLS.load(Ordering::SeqCst), 1);
+
+    broadcaster.shutdown().unwrap();
+    server.join().unwrap();
+}
@@ -0,0 +1,65 @@
+extern crate parity_ws as ws;
+
+use std::thread;
+use std::time::Duration;
+
+use ws::middleware::{Overrun, Workers};
+use ws::sync::Client;
+use ws::{HandlerExt, Message, Result, Sender, WebSocket};
+
+// Echoes messages, taking half a second over those that start with "slow".
+fn process(out: &Sender, msg: Message) -> Result<()> {
+    if msg.as_text()?.starts_with("slow") {
+        thread::sleep(Duration::from_millis(500));
+    }
+    out.send(msg)
+}
+
+fn serve(overrun: Overrun) -> String {
+    let workers = Workers::new(2).unwrap();
+    let server = WebSocket::new(move |out: Sender| {
+        let handler = |_: Message| Ok(());
+        handler
+            .with_workers(&out, &workers, process)
+            .deadline(Duration::from_millis(100), overrun.clone())
+    }).unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+    format!("ws://{}", addr)
+}
+
+#[test]
+fn messages_in_order() {
+    let mut client = Client::connect(serve(Overrun::Close)).unwrap();
+    for n in 0..20 {
+        client.send(format!("{}", n)).unwrap();
+    }
+    for n in 0..20 {
+        assert_eq!(client.recv().unwrap(), Message::text(format!("{}", n)));
+    }
+}
+
+#[test]
+fn busy_past_deadline() {
+    let mut client = Client::connect(serve(Overrun::Notify("busy".into()))).unwrap();
+    client.send("slow").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("busy"));
+
+    // Messages are answered with the busy indication until the slow one is done.
+    client.send("fast").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("busy"));
+    assert_eq!(client.recv().unwrap(), Message::text("slow"));
+
+    client.send("fast").unwrap();
+    assert_eq!(client.recv().unwrap(), Message::text("fast"));
+}
+
+#[test]
+fn close_past_deadline() {
+    let mut client = Client::connect(serve(Overrun::Close)).unwrap();
+    client.send("slow").unwrap();
+    let err = client.recv().unwrap_err();
+    assert!(format!("{:?}", err).contains("Again"), "{:?}", err);
+}
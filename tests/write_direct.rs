@@ -0,0 +1,88 @@
+extern crate parity_ws as ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::sync::Client;
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+const LARGE: usize = 4 * 1024 * 1024;
+
+fn direct() -> Settings {
+    let mut settings = Settings::default();
+    settings.write_direct = true;
+    settings
+}
+
+fn echo_server() -> String {
+    let server = Builder::new()
+        .with_settings(direct())
+        .build(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:0")
+        .unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run().unwrap());
+    format!("ws://{}", addr)
+}
+
+#[test]
+fn replies_are_written_in_order() {
+    let url = echo_server();
+    let mut client = Client::connect(url).unwrap();
+    client.send("first").unwrap();
+    client.send(vec![7u8; LARGE]).unwrap();
+    client.send("last").unwrap();
+    // Slow to read, so the large reply can not be written out all at once, and the one after
+    // it has to wait in the buffer.
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(client.recv().unwrap(), Message::text("first"));
+    assert_eq!(client.recv().unwrap().len(), LARGE);
+    assert_eq!(client.recv().unwrap(), Message::text("last"));
+}
+
+// Sends masked messages straight from a client, and hands the echoes over.
+struct Talker {
+    out: Sender,
+    echoes: Channel<Message>,
+}
+
+impl Handler for Talker {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("hello")?;
+        self.out.send(vec![1u8; 100_000])
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let done = msg.len() == 100_000;
+        self.echoes.send(msg).unwrap();
+        if done {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn client_writes_masked_frames() {
+    let url = echo_server();
+    let (tx, echoes) = channel();
+    let mut client = Builder::new()
+        .with_settings(direct())
+        .build(move |out| Talker {
+            out,
+            echoes: tx.clone(),
+        })
+        .unwrap();
+    client.connect(url.parse().unwrap()).unwrap();
+    let client = thread::spawn(move || client.run().unwrap());
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(echoes.recv_timeout(timeout).unwrap(), Message::text("hello"));
+    assert_eq!(
+        echoes.recv_timeout(timeout).unwrap(),
+        Message::binary(vec![1u8; 100_000])
+    );
+    client.join().unwrap();
+}